@@ -0,0 +1,406 @@
+use artisan_middleware::aggregator::{AppMessage, CommandType, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::applications::child::{pids_in_cgroup, APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use crate::applications::gitmon::handle_repository_change;
+use crate::applications::restart_window::acknowledge_restart;
+use crate::commands::custom_command_processor;
+use crate::network::{required_role, status_json, status_value};
+use crate::pretty::{render_all_status, render_status};
+use crate::system::auth::Role;
+use crate::system::control::{GlobalState, GLOBAL_STATE};
+
+/// Local admin API, only reachable from the node itself
+pub const ADMIN_SOCKET_PATH: &str = "/run/ais_manager.sock";
+
+/// Applications that registered themselves over the admin socket
+static REGISTRATIONS: Lazy<LockWithTimeout<HashMap<Stringy, Registration>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Registration {
+    pub pid: u32,
+    pub uid: u32,
+    pub registered_at: u64,
+}
+
+/// One request per line, answered with one `AdminResponse` line
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Register {
+        app_id: Stringy,
+        pid: u32,
+    },
+    Deregister {
+        app_id: Stringy,
+    },
+    Update {
+        app_id: Stringy,
+        status: Status,
+    },
+    Status {
+        app_id: Stringy,
+        /// Aligned, colorized text instead of JSON
+        #[serde(default)]
+        pretty: bool,
+    },
+    AllStatus {
+        #[serde(default)]
+        pretty: bool,
+    },
+    Registrations,
+    Custom {
+        app_id: Stringy,
+        command: String,
+    },
+    /// The app's answer when asked whether it can be restarted now
+    RestartReady {
+        app_id: Stringy,
+    },
+    /// Sent by ais_gitmon when it pulled new commits for an app
+    RepositoryChanged {
+        app_id: Stringy,
+        #[serde(default)]
+        branch: Option<String>,
+        commit: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminResponse {
+    pub success: bool,
+    pub message: Option<String>,
+}
+
+impl AdminResponse {
+    fn from_result(result: Result<Option<String>, ErrorArrayItem>) -> Self {
+        match result {
+            Ok(message) => Self {
+                success: true,
+                message,
+            },
+            Err(err) => Self {
+                success: false,
+                message: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+fn require_root(peer_uid: u32) -> Result<(), ErrorArrayItem> {
+    match peer_uid {
+        0 => Ok(()),
+        _ => Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            "Only root can query every application",
+        )),
+    }
+}
+
+/// Root can act on anything, everyone else only on the app they run as
+async fn authorize(peer_uid: u32, app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    if peer_uid == 0 {
+        return Ok(());
+    }
+
+    match CLIENT_APPLICATION_ARRAY
+        .read(app_id, |client| client.execution_uid())
+        .await?
+    {
+        Some(uid) if uid == peer_uid => Ok(()),
+        _ => Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!("uid {} can't act on {}", peer_uid, app_id),
+        )),
+    }
+}
+
+/// Root is an admin here, an app's own uid an operator of that app alone
+fn peer_role(peer_uid: u32) -> Role {
+    match peer_uid {
+        0 => Role::Admin,
+        _ => Role::Operator,
+    }
+}
+
+/// Custom commands an app can run on itself over the socket. Anything that
+/// reads or changes more than the app it names is left to root.
+fn app_scoped(command: &str) -> bool {
+    let args: Vec<&str> = command.split_whitespace().collect();
+
+    matches!(
+        args.as_slice(),
+        ["loglevel", _]
+            | ["signal", ..]
+            | ["coredumps", ..]
+            | ["transfer", ..]
+            | ["config", ..]
+            | ["drift", "report"]
+            | ["inventory", ..]
+            | ["dependencies"]
+            | ["manifest"]
+            | ["integrity"]
+            | ["health"]
+            | ["restart", "window"]
+            | ["egress"]
+            | ["unit", "file"]
+            | ["hibernation"]
+            | ["pause"]
+            | ["resume"]
+            | ["quota"]
+            | ["ports"]
+    )
+}
+
+/// The same role check the command port runs, with the role coming from
+/// the peer uid instead of a token
+fn authorize_custom(peer_uid: u32, app_id: &Stringy, command: &str) -> Result<(), ErrorArrayItem> {
+    let role: Role = peer_role(peer_uid);
+    let required: Role = required_role(&CommandType::Custom(command.to_owned()), app_id);
+
+    if role < required || (peer_uid != 0 && !app_scoped(command)) {
+        log!(
+            LogLevel::Warn,
+            "Rejected {} on {} from uid {}",
+            command,
+            app_id,
+            peer_uid
+        );
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!(
+                "uid {} can't run {} over the admin socket",
+                peer_uid, command
+            ),
+        ));
+    }
+    Ok(())
+}
+
+async fn register(app_id: &Stringy, pid: u32, uid: u32) -> Result<Option<String>, ErrorArrayItem> {
+    // Root signals the registered pid later on, an app only gets to name
+    // one of its own processes
+    if uid != 0 && !pids_in_cgroup(app_id.as_str())?.contains(&pid) {
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!("pid {} isn't a process of {}", pid, app_id),
+        ));
+    }
+
+    APP_STATUS_ARRAY
+        .modify(app_id, |app| app.app_data.set_pid(pid))
+        .await?;
+
+    REGISTRATIONS.try_write().await?.insert(
+        app_id.clone(),
+        Registration {
+            pid,
+            uid,
+            registered_at: current_timestamp(),
+        },
+    );
+
+    log!(LogLevel::Info, "{} registered with pid {}", app_id, pid);
+    Ok(None)
+}
+
+async fn update(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    status: Status,
+) -> Result<Option<String>, ErrorArrayItem> {
+    let entry = APP_STATUS_ARRAY.entry(app_id).await?.ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{}, Not registered in the system", app_id),
+        )
+    })?;
+    let mut shared = entry.try_write().await?;
+    let app = Arc::make_mut(&mut shared);
+
+    let from: Status = app.app_data.get_status();
+    app.app_data.set_status(status.clone());
+    drop(shared);
+    APP_STATUS_ARRAY.touch(app_id);
+    gs.events.status_changed(app_id, from, status);
+
+    Ok(None)
+}
+
+async fn status(app_id: &Stringy, pretty: bool) -> Result<Option<String>, ErrorArrayItem> {
+    match APP_STATUS_ARRAY.get(app_id).await? {
+        Some(app) if pretty => Ok(Some(render_status(app_id, &app, true))),
+        Some(app) => Ok(status_json(app_id, &app).await),
+        None => Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("The app: {}, wasn't in our store", app_id),
+        )),
+    }
+}
+
+async fn all_status() -> Result<Option<String>, ErrorArrayItem> {
+    let mut statuses: Vec<serde_json::Value> = Vec::new();
+
+    for (id, entry) in APP_STATUS_ARRAY.entries().await? {
+        let app = entry.try_read().await?.clone();
+        if let Some(value) = status_value(&id, &app).await {
+            statuses.push(value);
+        }
+    }
+
+    // Local tools read a bare array, the schema version is only on the
+    // command port's AllStatus
+    serde_json::to_string(&statuses)
+        .map(Some)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+}
+
+async fn process_request(
+    gs: &Arc<GlobalState>,
+    peer_uid: u32,
+    request: AdminRequest,
+) -> Result<Option<String>, ErrorArrayItem> {
+    match request {
+        AdminRequest::Register { app_id, pid } => {
+            authorize(peer_uid, &app_id).await?;
+            register(&app_id, pid, peer_uid).await
+        }
+        AdminRequest::Deregister { app_id } => {
+            authorize(peer_uid, &app_id).await?;
+            REGISTRATIONS.try_write().await?.remove(&app_id);
+            log!(LogLevel::Info, "{} deregistered", app_id);
+            Ok(None)
+        }
+        AdminRequest::Update { app_id, status } => {
+            authorize(peer_uid, &app_id).await?;
+            update(gs, &app_id, status).await
+        }
+        AdminRequest::Status { app_id, pretty } => {
+            authorize(peer_uid, &app_id).await?;
+            status(&app_id, pretty).await
+        }
+        AdminRequest::AllStatus { pretty: true } => {
+            require_root(peer_uid)?;
+            render_all_status(true).await.map(Some)
+        }
+        AdminRequest::AllStatus { pretty: false } => {
+            require_root(peer_uid)?;
+            all_status().await
+        }
+        AdminRequest::Registrations => {
+            require_root(peer_uid)?;
+            let registrations = REGISTRATIONS.try_read().await?.clone();
+            serde_json::to_string(&registrations)
+                .map(Some)
+                .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+        }
+        AdminRequest::Custom { app_id, command } => {
+            authorize(peer_uid, &app_id).await?;
+            authorize_custom(peer_uid, &app_id, &command)?;
+            match custom_command_processor(app_id, command).await? {
+                AppMessage::Response(response) => match response.success {
+                    true => Ok(response.message),
+                    false => Err(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        response.message.unwrap_or_default(),
+                    )),
+                },
+                _ => Ok(None),
+            }
+        }
+        AdminRequest::RestartReady { app_id } => {
+            authorize(peer_uid, &app_id).await?;
+            acknowledge_restart(&app_id).map(|_| None)
+        }
+        AdminRequest::RepositoryChanged {
+            app_id,
+            branch,
+            commit,
+        } => {
+            // ais_gitmon runs as root like every system app
+            require_root(peer_uid)?;
+            let actions = handle_repository_change(gs, &app_id, branch, commit).await?;
+            serde_json::to_string(&actions)
+                .map(Some)
+                .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+        }
+    }
+}
+
+async fn handle_connection(
+    gs: &Arc<GlobalState>,
+    stream: UnixStream,
+) -> Result<(), ErrorArrayItem> {
+    let peer_uid: u32 = stream.peer_cred()?.uid();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response: AdminResponse = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => AdminResponse::from_result(process_request(gs, peer_uid, request).await),
+            Err(err) => AdminResponse::from_result(Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Malformed request: {}", err),
+            ))),
+        };
+
+        let mut data: String = serde_json::to_string(&response)
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        data.push('\n');
+        writer.write_all(data.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Serves the admin API on a unix socket next to the TCP listener
+pub async fn serve_admin_socket() -> Result<(), ErrorArrayItem> {
+    let gs: &Arc<GlobalState> = match GLOBAL_STATE.get() {
+        Some(gs) => gs,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::AppState,
+                "Failed to get the app state from the global state",
+            ));
+        }
+    };
+
+    // A socket left behind by the last run would make the bind fail
+    if Path::new(ADMIN_SOCKET_PATH).exists() {
+        fs::remove_file(ADMIN_SOCKET_PATH)?;
+    }
+
+    let listener: UnixListener = UnixListener::bind(ADMIN_SOCKET_PATH)?;
+    // Apps connect as their own users, the peer uid decides what they can do
+    fs::set_permissions(ADMIN_SOCKET_PATH, fs::Permissions::from_mode(0o666))?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let gs: Arc<GlobalState> = gs.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(&gs, stream).await {
+                log!(LogLevel::Error, "Admin socket connection failed: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,343 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::config_bundle::ApplicationConfig;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::pathtype::PathType;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::dusa_collection_utils::platform::functions::{create_hash, truncate};
+use artisan_middleware::identity::Identifier;
+use artisan_middleware::state_persistence::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use tokio::process::Command;
+
+use crate::system::cgroups::cgroup_root;
+use crate::system::control::GlobalState;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY, CLIENT_APPLICATION_HANDLER};
+use super::node_spec::{run_probe, ProbeSpec};
+use super::resolve::ClientApplication;
+
+/// Services that were adopted into management, kept across restarts
+pub const ADOPTED_PATH: &str = "/opt/artisan/adopted.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdoptedService {
+    pub adopted_at: u64,
+    pub probe: Option<ProbeSpec>,
+    /// Failure of the last probe, `None` while it passes
+    #[serde(default)]
+    pub probe_failure: Option<String>,
+}
+
+/// What systemd reports about a unit
+struct UnitInfo {
+    loaded: bool,
+    active: bool,
+    pid: u32,
+    control_group: String,
+    exec_path: String,
+}
+
+fn adopt_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_adopted() -> Result<HashMap<Stringy, AdoptedService>, ErrorArrayItem> {
+    match fs::read_to_string(ADOPTED_PATH) {
+        Ok(data) => serde_json::from_str(&data)
+            .map_err(|err| adopt_error(format!("Invalid {}: {}", ADOPTED_PATH, err))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn save_adopted(adopted: &HashMap<Stringy, AdoptedService>) -> Result<(), ErrorArrayItem> {
+    let data: String =
+        serde_json::to_string(adopted).map_err(|err| adopt_error(err.to_string()))?;
+    fs::write(ADOPTED_PATH, data)?;
+    Ok(())
+}
+
+/// Unit names straight from the portal end up on a systemctl command line
+fn validate_unit(unit: &str) -> Result<(), ErrorArrayItem> {
+    let valid: bool = !unit.is_empty()
+        && !unit.starts_with('-')
+        && unit
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(adopt_error(format!("{} is not a valid unit name", unit))),
+    }
+}
+
+async fn unit_info(unit: &str) -> Result<UnitInfo, ErrorArrayItem> {
+    let output = Command::new("systemctl")
+        .args([
+            "show",
+            "--property=LoadState,ActiveState,MainPID,ControlGroup,ExecStart",
+            &format!("{}.service", unit),
+        ])
+        .output()
+        .await?;
+
+    let data: String = String::from_utf8_lossy(&output.stdout).into_owned();
+    let properties: HashMap<&str, &str> = data
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+
+    // ExecStart reads `{ path=/usr/bin/foo ; argv[]=... }`
+    let exec_path: String = properties
+        .get("ExecStart")
+        .and_then(|exec| exec.split("path=").nth(1))
+        .and_then(|rest| rest.split_whitespace().next())
+        .unwrap_or_default()
+        .to_owned();
+
+    Ok(UnitInfo {
+        loaded: properties.get("LoadState") == Some(&"loaded"),
+        active: properties.get("ActiveState") == Some(&"active"),
+        pid: properties
+            .get("MainPID")
+            .and_then(|pid| pid.parse().ok())
+            .unwrap_or(0),
+        control_group: properties
+            .get("ControlGroup")
+            .copied()
+            .unwrap_or_default()
+            .to_owned(),
+        exec_path,
+    })
+}
+
+/// Same derivation `populate_initial_state_lock` uses for managed apps
+fn status_app_id(name: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+    let identity: Identifier = Identifier::load_from_file()?;
+    let hash = create_hash(format!("{}-{}", identity.id, name));
+    Ok(truncate(&*hash, 20).to_owned())
+}
+
+/// Inserts the service into the client and status arrays as if it had been
+/// deployed by us
+async fn register(
+    gs: &Arc<GlobalState>,
+    name: &Stringy,
+    info: &UnitInfo,
+    adopted_at: u64,
+) -> Result<(), ErrorArrayItem> {
+    // The manager's own state is the only complete template we have
+    let mut state: AppState = gs.get_state_clone().await?;
+    state.name = name.to_string();
+    state.data = String::from("Adopted");
+    state.pid = info.pid;
+    state.status = match info.active {
+        true => Status::Running,
+        false => Status::Stopped,
+    };
+    state.system_application = false;
+    state.stared_at = adopted_at;
+    state.last_updated = current_timestamp();
+    state.event_counter = 0;
+    state.error_log.clear();
+    state.stdout.clear();
+    state.stderr.clear();
+
+    let path: PathType = PathType::Content(info.exec_path.clone());
+    let client: ClientApplication = ClientApplication {
+        name: name.clone(),
+        exists: path.exists(),
+        path,
+        config: ApplicationConfig::new(state, None, None),
+        manifest: None,
+    };
+
+    let status: AppStatus = AppStatus {
+        app_id: status_app_id(name)?,
+        git_id: "".into(),
+        app_data: client.config.clone(),
+        uptime: None,
+        metrics: None,
+        timestamp: adopted_at,
+        expected_status: Status::Running,
+    };
+
+    CLIENT_APPLICATION_ARRAY
+        .insert(name.clone(), client)
+        .await?;
+    APP_STATUS_ARRAY
+        .insert_if_absent(name.clone(), Arc::new(status))
+        .await?;
+
+    Ok(())
+}
+
+/// Takes an existing systemd service under management
+pub async fn adopt_service(
+    gs: &Arc<GlobalState>,
+    unit: &str,
+    probe: Option<ProbeSpec>,
+) -> Result<(), ErrorArrayItem> {
+    let unit: &str = unit.trim_end_matches(".service");
+    validate_unit(unit)?;
+
+    let name: Stringy = Stringy::from(unit);
+    if CLIENT_APPLICATION_ARRAY.contains(&name).await? {
+        return Err(adopt_error(format!("{} is already managed", unit)));
+    }
+
+    let info: UnitInfo = unit_info(unit).await?;
+    if !info.loaded {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("No systemd service named {}", unit),
+        ));
+    }
+
+    let adopted_at: u64 = current_timestamp();
+    register(gs, &name, &info, adopted_at).await?;
+
+    let mut adopted = load_adopted()?;
+    adopted.insert(
+        name.clone(),
+        AdoptedService {
+            adopted_at,
+            probe,
+            probe_failure: None,
+        },
+    );
+    save_adopted(&adopted)?;
+
+    log!(LogLevel::Info, "Adopted {} into management", unit);
+    Ok(())
+}
+
+/// Hands a service back, it keeps running but we stop watching it
+pub async fn release_service(unit: &str) -> Result<(), ErrorArrayItem> {
+    let name: Stringy = Stringy::from(unit.trim_end_matches(".service"));
+
+    let mut adopted = load_adopted()?;
+    if adopted.remove(&name).is_none() {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} wasn't adopted", name),
+        ));
+    }
+    save_adopted(&adopted)?;
+
+    CLIENT_APPLICATION_ARRAY.remove(&name).await?;
+    CLIENT_APPLICATION_HANDLER.remove(&name).await?;
+    APP_STATUS_ARRAY.remove(&name).await?;
+
+    log!(LogLevel::Info, "Released {} from management", name);
+    Ok(())
+}
+
+pub fn adopted_services() -> Result<HashMap<Stringy, AdoptedService>, ErrorArrayItem> {
+    load_adopted()
+}
+
+async fn refresh_service(
+    gs: &Arc<GlobalState>,
+    name: &Stringy,
+    service: &mut AdoptedService,
+) -> Result<(), ErrorArrayItem> {
+    let info: UnitInfo = unit_info(name).await?;
+
+    // Adopted services don't survive a manager restart on their own
+    if !CLIENT_APPLICATION_ARRAY.contains(name).await? {
+        register(gs, name, &info, service.adopted_at).await?;
+    }
+
+    let status: Status = match info.active {
+        true => Status::Running,
+        false => Status::Stopped,
+    };
+
+    CLIENT_APPLICATION_ARRAY
+        .update(name, |client| {
+            client.config.state.pid = info.pid;
+            client.config.state.status = status.clone();
+            client.config.state.last_updated = current_timestamp();
+        })
+        .await?;
+
+    // Metrics come from the monitor, it reclaims the main pid like it does
+    // for any client app
+    let from: Option<Status> = APP_STATUS_ARRAY
+        .modify(name, |app| {
+            let from: Status = app.app_data.get_status();
+            app.app_data.set_pid(info.pid);
+            app.app_data.set_status(status.clone());
+            from
+        })
+        .await?;
+    if let Some(from) = from {
+        gs.events.status_changed(name, from, status);
+    }
+
+    // Same treatment as artisan.slice services get from track_pids
+    if let Ok(procs) = fs::read_to_string(format!(
+        "{}{}/cgroup.procs",
+        cgroup_root(),
+        info.control_group
+    )) {
+        for pid in procs.lines().filter_map(|line| line.parse::<u32>().ok()) {
+            gs.network_monitor.track_pid(pid).await?;
+        }
+    }
+
+    if let Some(probe) = &service.probe {
+        let failure: Option<String> = match info.active {
+            true => run_probe(probe).await.err(),
+            false => None,
+        };
+
+        if failure.is_some() && failure != service.probe_failure {
+            log!(
+                LogLevel::Warn,
+                "Adopted service {} failed its probe: {}",
+                name,
+                failure.clone().unwrap_or_default()
+            );
+        }
+        service.probe_failure = failure;
+    }
+
+    Ok(())
+}
+
+/// Keeps the status, metrics and probes of adopted services current
+pub async fn refresh_adopted_services(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let mut refreshed = load_adopted()?;
+    if refreshed.is_empty() {
+        return Ok(());
+    }
+
+    for (name, service) in refreshed.iter_mut() {
+        if let Err(err) = refresh_service(gs, name, service).await {
+            log!(
+                LogLevel::Warn,
+                "Failed to refresh adopted {}: {}",
+                name,
+                err
+            );
+        }
+    }
+
+    // Services released while we were probing stay released
+    let mut adopted = load_adopted()?;
+    for (name, service) in adopted.iter_mut() {
+        if let Some(fresh) = refreshed.remove(name) {
+            service.probe_failure = fresh.probe_failure;
+        }
+    }
+
+    save_adopted(&adopted)
+}
@@ -0,0 +1,280 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::BuildCacheSettings;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+
+pub const BUILD_CACHE_DIR: &str = "/var/cache/artisan";
+
+/// Dependency directories we know how to cache, keyed by the lockfile that
+/// pins their contents
+const DEPENDENCY_LAYERS: [(&str, &str, &str); 2] = [
+    ("node", "package-lock.json", "node_modules"),
+    ("php", "composer.lock", "vendor"),
+];
+
+/// Package manager download caches shared by every client application
+pub fn shared_cache_env() -> [(&'static str, String); 2] {
+    [
+        ("npm_config_cache", format!("{}/npm", BUILD_CACHE_DIR)),
+        (
+            "COMPOSER_CACHE_DIR",
+            format!("{}/composer", BUILD_CACHE_DIR),
+        ),
+    ]
+}
+
+fn objects_dir() -> PathBuf {
+    Path::new(BUILD_CACHE_DIR).join("objects")
+}
+
+/// Index entry pointing a dependency layer key at the object holding it
+fn layer_index(kind: &str, lock_digest: &str) -> PathBuf {
+    Path::new(BUILD_CACHE_DIR)
+        .join("layers")
+        .join(format!("{}-{}", kind, lock_digest))
+}
+
+fn sha256_file(path: &Path) -> Result<String, ErrorArrayItem> {
+    let data: Vec<u8> = fs::read(path)?;
+    Ok(hex::encode(Sha256::digest(&data)))
+}
+
+fn cache_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Moves a file into the object store under its own digest, identical
+/// content from different apps is only stored once
+fn store_object(path: &Path) -> Result<String, ErrorArrayItem> {
+    let digest: String = sha256_file(path)?;
+    let object: PathBuf = objects_dir().join(&digest);
+
+    if object.exists() {
+        fs::remove_file(path)?;
+    } else {
+        fs::rename(path, &object)?;
+    }
+
+    Ok(digest)
+}
+
+/// Marks an object as recently used so eviction keeps it
+fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+async fn save_layer(
+    app_id: &Stringy,
+    kind: &str,
+    lockfile: &str,
+    dir: &str,
+) -> Result<(), ErrorArrayItem> {
+    let app_dir: PathBuf = PathBuf::from(format!("/etc/{}", app_id));
+    if !app_dir.join(lockfile).exists() || !app_dir.join(dir).is_dir() {
+        return Ok(());
+    }
+
+    let lock_digest: String = sha256_file(&app_dir.join(lockfile))?;
+    let index: PathBuf = layer_index(kind, &lock_digest);
+    if index.exists() {
+        return Ok(());
+    }
+
+    let staging: PathBuf = objects_dir().join(format!(".{}-{}.tar.gz", app_id, lock_digest));
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&staging)
+        .arg("-C")
+        .arg(&app_dir)
+        .arg(dir)
+        .status()
+        .await?;
+
+    if !status.success() {
+        let _ = fs::remove_file(&staging);
+        return Err(cache_error(format!("Failed to pack {} of {}", dir, app_id)));
+    }
+
+    let digest: String = store_object(&staging)?;
+    fs::write(&index, &digest)?;
+
+    log!(LogLevel::Info, "Cached the {} layer of {}", kind, app_id);
+    Ok(())
+}
+
+async fn restore_layer(
+    app_id: &Stringy,
+    uid: u32,
+    kind: &str,
+    lockfile: &str,
+    dir: &str,
+) -> Result<(), ErrorArrayItem> {
+    let app_dir: PathBuf = PathBuf::from(format!("/etc/{}", app_id));
+    if !app_dir.join(lockfile).exists() || app_dir.join(dir).exists() {
+        return Ok(());
+    }
+
+    let lock_digest: String = sha256_file(&app_dir.join(lockfile))?;
+    let digest: String = match fs::read_to_string(layer_index(kind, &lock_digest)) {
+        Ok(digest) => digest.trim().to_owned(),
+        Err(_) => return Ok(()),
+    };
+
+    let object: PathBuf = objects_dir().join(&digest);
+    if !object.exists() {
+        return Ok(());
+    }
+    touch(&object);
+
+    let status = Command::new("tar")
+        .arg("-xzf")
+        .arg(&object)
+        .arg("-C")
+        .arg(&app_dir)
+        .status()
+        .await?;
+
+    if !status.success() {
+        return Err(cache_error(format!(
+            "Failed to unpack {} for {}",
+            dir, app_id
+        )));
+    }
+
+    // The layer may have been packed from an app running as someone else
+    Command::new("chown")
+        .arg("-R")
+        .arg(format!("{}:{}", uid, uid))
+        .arg(app_dir.join(dir))
+        .status()
+        .await?;
+
+    log!(
+        LogLevel::Info,
+        "Restored the cached {} layer of {}",
+        kind,
+        app_id
+    );
+    Ok(())
+}
+
+/// Drops the least recently used objects until the store fits the budget
+fn evict(max_bytes: u64) -> Result<(), ErrorArrayItem> {
+    let mut objects: Vec<(PathBuf, u64, SystemTime)> = Vec::new();
+
+    for entry in fs::read_dir(objects_dir())? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            objects.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+    }
+
+    let mut total: u64 = objects.iter().map(|(_, size, _)| size).sum();
+    objects.sort_by(|a, b| a.2.cmp(&b.2));
+
+    for (path, size, _) in objects {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        total -= size;
+        log!(
+            LogLevel::Debug,
+            "Evicted {} from the build cache",
+            path.display()
+        );
+    }
+
+    // Index entries pointing at evicted objects are useless now
+    for entry in fs::read_dir(Path::new(BUILD_CACHE_DIR).join("layers"))? {
+        let path: PathBuf = entry?.path();
+        let digest: String = fs::read_to_string(&path).unwrap_or_default();
+        if !objects_dir().join(digest.trim()).exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores cached dependency layers when a client app starts building and
+/// caches them once the build settles
+pub async fn run_build_cache(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &BuildCacheSettings = &gs.settings.build_cache;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    for dir in ["objects", "layers"] {
+        fs::create_dir_all(Path::new(BUILD_CACHE_DIR).join(dir))?;
+    }
+
+    // Apps run as different users, the download caches are shared like /tmp
+    for dir in ["npm", "composer"] {
+        let path: PathBuf = Path::new(BUILD_CACHE_DIR).join(dir);
+        fs::create_dir_all(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o1777))?;
+    }
+
+    let mut receiver = gs.events.subscribe();
+
+    loop {
+        let (name, from, to) = match receiver.recv().await {
+            Ok(ManagerEvent::StatusChanged { name, from, to }) => (name, from, to),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                log!(LogLevel::Warn, "Build cache missed {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let uid: u32 = match CLIENT_APPLICATION_ARRAY
+            .read(&name, |client| client.execution_uid())
+            .await?
+        {
+            Some(uid) => uid,
+            None => continue,
+        };
+
+        for (kind, lockfile, dir) in DEPENDENCY_LAYERS {
+            let result = if to == Status::Building {
+                restore_layer(&name, uid, kind, lockfile, dir).await
+            } else if from == Status::Building && (to == Status::Running || to == Status::Idle) {
+                save_layer(&name, kind, lockfile, dir).await
+            } else {
+                continue;
+            };
+
+            if let Err(err) = result {
+                log!(LogLevel::Warn, "Build cache error for {}: {}", name, err);
+            }
+        }
+
+        if let Err(err) = evict(settings.max_bytes) {
+            log!(
+                LogLevel::Warn,
+                "Failed to evict from the build cache: {}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use artisan_middleware::aggregator::Metrics;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+use crate::system::cgroups::slice_dir;
+use crate::system::control::GLOBAL_STATE;
+use crate::system::ledger_store::ledger_store;
+use crate::system::settings::BuildSandboxSettings;
+
+use super::build_cache::shared_cache_env;
+use super::child::CLIENT_APPLICATION_ARRAY;
+
+/// Captured output of every on-node build
+pub const BUILD_LOG_DIR: &str = "/opt/artisan/builds";
+
+/// Builds get their own slice so they're capped apart from running apps
+const BUILD_SLICE: &str = "artisan-build.slice";
+
+/// Lines of build output included in a failure message
+const BUILD_ERROR_LINES: usize = 20;
+
+/// Declared as a `[build]` table in /etc/{app}/Config.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct BuildStep {
+    /// Run with `sh -c` from the app's working directory
+    pub command: String,
+    /// Builds are cut off from the network unless they fetch dependencies
+    #[serde(default)]
+    pub network: bool,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout() -> u64 {
+    1800
+}
+
+fn declared_build(app_id: &str) -> Option<BuildStep> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config.get("build")?.clone().try_into::<BuildStep>() {
+        Ok(step) => Some(step),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid build: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn build_unit(app_id: &str) -> String {
+    format!("{}-build", app_id)
+}
+
+fn cgroup_file(unit: &str, file: &str) -> PathBuf {
+    slice_dir()
+        .join(BUILD_SLICE)
+        .join(format!("{}.service", unit))
+        .join(file)
+}
+
+/// Usage of the build unit, sampled while it runs since the unit is
+/// collected as soon as it exits
+#[derive(Debug, Default)]
+struct BuildUsage {
+    cpu_usec: u64,
+    peak_memory: u64,
+}
+
+impl BuildUsage {
+    fn sample(&mut self, unit: &str) {
+        let usec: Option<u64> = fs::read_to_string(cgroup_file(unit, "cpu.stat"))
+            .ok()
+            .and_then(|stat| {
+                stat.lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))
+                    .and_then(|value| value.trim().parse().ok())
+            });
+        let memory: Option<u64> = fs::read_to_string(cgroup_file(unit, "memory.current"))
+            .ok()
+            .and_then(|value| value.trim().parse().ok());
+
+        if let Some(usec) = usec {
+            self.cpu_usec = self.cpu_usec.max(usec);
+        }
+        if let Some(memory) = memory {
+            self.peak_memory = self.peak_memory.max(memory);
+        }
+    }
+}
+
+fn sandboxed_command(
+    settings: &BuildSandboxSettings,
+    app_id: &Stringy,
+    step: &BuildStep,
+    uid: Option<u32>,
+) -> Command {
+    let mut command: Command = Command::new("systemd-run");
+    command
+        .arg(format!("--unit={}", build_unit(app_id)))
+        .arg(format!("--slice={}", BUILD_SLICE))
+        .arg("--wait")
+        .arg("--pipe")
+        .arg("--collect")
+        .arg("--quiet")
+        .arg(format!("--working-directory=/etc/{}", app_id))
+        .arg(format!(
+            "--property=CPUQuota={}%",
+            settings.cpu_quota_percent
+        ))
+        .arg(format!(
+            "--property=MemoryMax={}",
+            settings.memory_max_bytes
+        ))
+        .arg("--property=MemorySwapMax=0")
+        .arg(format!("--property=TasksMax={}", settings.tasks_max))
+        .arg(format!("--property=RuntimeMaxSec={}", step.timeout_secs));
+
+    if !step.network {
+        command.arg("--property=PrivateNetwork=yes");
+    }
+
+    if let Some(uid) = uid {
+        command
+            .arg(format!("--uid={}", uid))
+            .arg(format!("--gid={}", uid));
+    }
+
+    for (key, value) in shared_cache_env() {
+        command.arg(format!("--setenv={}={}", key, value));
+    }
+
+    command
+        .arg("sh")
+        .arg("-c")
+        .arg(&step.command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    command
+}
+
+/// Records the build in the usage ledger next to, not inside, the app's own
+/// usage so build cost can be billed separately
+async fn record_usage(app_id: &Stringy, usage: &BuildUsage, runtime: Duration) {
+    let gs = match GLOBAL_STATE.get() {
+        Some(gs) => gs,
+        None => return,
+    };
+
+    let cpu_percent: f64 = usage.cpu_usec as f64 / runtime.as_micros().max(1) as f64 * 100.0;
+    let metrics: Metrics = Metrics {
+        cpu_usage: cpu_percent as _,
+        memory_usage: usage.peak_memory as _,
+        other: None,
+    };
+
+    let name: Stringy = Stringy::from(format!("{}:build", app_id));
+    if let Err(err) = ledger_store().append(&name, current_timestamp(), &metrics) {
+        log!(
+            LogLevel::Warn,
+            "Failed to store the ledger sample of {}: {}",
+            name,
+            err
+        );
+    }
+
+    match gs.ledger.try_write().await {
+        Ok(mut ledger) => ledger.update_application_usage(name, metrics),
+        Err(err) => log!(
+            LogLevel::Warn,
+            "Failed to record build usage of {}: {}",
+            app_id,
+            err
+        ),
+    }
+}
+
+/// Runs the app's declared build in a transient unit capped by the manager
+/// settings. Output is kept in /opt/artisan/builds/{app}/{version}.log.
+pub async fn run_declared_build(app_id: &Stringy, version: &str) -> Result<(), ErrorArrayItem> {
+    let step: BuildStep = match declared_build(app_id) {
+        Some(step) => step,
+        None => return Ok(()),
+    };
+
+    let settings: BuildSandboxSettings = match GLOBAL_STATE.get() {
+        Some(gs) => gs.settings.build_sandbox.clone(),
+        None => BuildSandboxSettings::default(),
+    };
+
+    // Client builds run as the same user as the application
+    let uid: Option<u32> = CLIENT_APPLICATION_ARRAY
+        .read(app_id, |client| client.execution_uid())
+        .await?;
+
+    fs::create_dir_all(Path::new(BUILD_LOG_DIR).join(app_id.to_string()))?;
+    let log_path: PathBuf = Path::new(BUILD_LOG_DIR)
+        .join(app_id.to_string())
+        .join(format!("{}.log", version));
+
+    log!(LogLevel::Info, "Building {} {}", app_id, version);
+
+    let unit: String = build_unit(app_id);
+    let started_at: Instant = Instant::now();
+    let interval: Duration = Duration::from_secs(settings.sample_interval_secs.max(1));
+    let mut usage: BuildUsage = BuildUsage::default();
+
+    let child = sandboxed_command(&settings, app_id, &step, uid).spawn()?;
+    let output = child.wait_with_output();
+    tokio::pin!(output);
+
+    let output = loop {
+        tokio::select! {
+            output = &mut output => break output?,
+            _ = tokio::time::sleep(interval) => usage.sample(&unit),
+        }
+    };
+
+    record_usage(app_id, &usage, started_at.elapsed()).await;
+
+    let mut captured: Vec<u8> = output.stdout;
+    captured.extend_from_slice(&output.stderr);
+    fs::write(&log_path, &captured)?;
+
+    if !output.status.success() {
+        let captured: String = String::from_utf8_lossy(&captured).to_string();
+        let lines: Vec<&str> = captured.lines().collect();
+        let tail: String = lines[lines.len().saturating_sub(BUILD_ERROR_LINES)..].join("\n");
+
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("Build failed with {}: {}", output.status, tail),
+        ));
+    }
+
+    log!(
+        LogLevel::Info,
+        "Built {} {} in {}s",
+        app_id,
+        version,
+        started_at.elapsed().as_secs()
+    );
+    Ok(())
+}
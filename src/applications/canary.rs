@@ -0,0 +1,403 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::system::cgroups::service_file;
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::CanarySettings;
+use crate::system::transfer::finish_transfer;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+use super::deploy::{is_tarball, roll_out, verify_artifact};
+
+/// Applications with a canary currently baking
+static ACTIVE_CANARIES: Lazy<LockWithTimeout<HashSet<Stringy>>> =
+    Lazy::new(|| LockWithTimeout::new(HashSet::new()));
+
+/// Averages over the bake period for one side of the comparison
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CanaryMetrics {
+    pub cpu_percent: f64,
+    pub memory_bytes: f64,
+    pub errors: usize,
+    pub samples: usize,
+}
+
+fn canary_unit(app_id: &str) -> String {
+    format!("{}-canary", app_id)
+}
+
+fn canary_binary(app_id: &str) -> PathBuf {
+    PathBuf::from(format!("/opt/artisan/bin/{}.canary", app_id))
+}
+
+fn cpu_usage_usec(unit: &str) -> Option<u64> {
+    fs::read_to_string(service_file(unit, "cpu.stat"))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn memory_current(unit: &str) -> Option<u64> {
+    fs::read_to_string(service_file(unit, "memory.current"))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Accumulates cgroup usage of a unit between samples
+struct Sampler {
+    unit: String,
+    last_usec: Option<u64>,
+    cpu_total: f64,
+    memory_total: f64,
+    samples: usize,
+}
+
+impl Sampler {
+    fn new(unit: String) -> Self {
+        let last_usec: Option<u64> = cpu_usage_usec(&unit);
+        Self {
+            unit,
+            last_usec,
+            cpu_total: 0.0,
+            memory_total: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Returns false once the unit's cgroup is gone
+    fn sample(&mut self, interval: Duration) -> bool {
+        let (usec, memory) = match (cpu_usage_usec(&self.unit), memory_current(&self.unit)) {
+            (Some(usec), Some(memory)) => (usec, memory),
+            _ => return false,
+        };
+
+        if let Some(last) = self.last_usec {
+            let used: f64 = usec.saturating_sub(last) as f64;
+            self.cpu_total += used / interval.as_micros().max(1) as f64 * 100.0;
+            self.memory_total += memory as f64;
+            self.samples += 1;
+        }
+
+        self.last_usec = Some(usec);
+        true
+    }
+
+    fn finish(self, errors: usize) -> CanaryMetrics {
+        let samples: f64 = self.samples.max(1) as f64;
+        CanaryMetrics {
+            cpu_percent: self.cpu_total / samples,
+            memory_bytes: self.memory_total / samples,
+            errors,
+            samples: self.samples,
+        }
+    }
+}
+
+/// Lines logged at error priority or worse by a unit since the timestamp,
+/// up to `until` when given
+pub async fn error_count(unit: &str, since: u64, until: Option<u64>) -> usize {
+    let mut command: Command = Command::new("journalctl");
+    command
+        .arg("-u")
+        .arg(format!("{}.service", unit))
+        .arg("-p")
+        .arg("err")
+        .arg("--since")
+        .arg(format!("@{}", since));
+    if let Some(until) = until {
+        command.arg("--until").arg(format!("@{}", until));
+    }
+
+    let output = command
+        .arg("-o")
+        .arg("cat")
+        .arg("--no-pager")
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).lines().count(),
+        Err(err) => {
+            log!(LogLevel::Warn, "Failed to read errors of {}: {}", unit, err);
+            0
+        }
+    }
+}
+
+/// Describes every metric the canary did worse on than the thresholds allow
+fn regressions(
+    settings: &CanarySettings,
+    baseline: &CanaryMetrics,
+    canary: &CanaryMetrics,
+) -> Vec<String> {
+    // Floors keep an idle or error free baseline from failing on noise
+    let increase = |baseline: f64, canary: f64, floor: f64| -> f64 {
+        (canary - baseline) / baseline.max(floor) * 100.0
+    };
+
+    let mut regressions: Vec<String> = Vec::new();
+
+    let cpu: f64 = increase(baseline.cpu_percent, canary.cpu_percent, 1.0);
+    if cpu > settings.max_cpu_increase_percent {
+        regressions.push(format!("cpu up {:.0}%", cpu));
+    }
+
+    let memory: f64 = increase(baseline.memory_bytes, canary.memory_bytes, 1.0);
+    if memory > settings.max_memory_increase_percent {
+        regressions.push(format!("memory up {:.0}%", memory));
+    }
+
+    let errors: f64 = increase(baseline.errors as f64, canary.errors as f64, 1.0);
+    if errors > settings.max_error_increase_percent {
+        regressions.push(format!(
+            "{} errors against {} from the current version",
+            canary.errors, baseline.errors
+        ));
+    }
+
+    regressions
+}
+
+async fn launch_canary(app_id: &Stringy, uid: u32) -> Result<(), ErrorArrayItem> {
+    let output = Command::new("systemd-run")
+        .arg(format!("--unit={}", canary_unit(app_id)))
+        .arg("--slice=artisan.slice")
+        .arg(format!("--uid={}", uid))
+        .arg(format!("--gid={}", uid))
+        .arg(format!("--working-directory=/etc/{}", app_id))
+        .arg("--setenv=ARTISAN_CANARY=1")
+        .arg(canary_binary(app_id))
+        .output()
+        .await?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "Failed to launch canary of {}: {}",
+                app_id,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        )),
+    }
+}
+
+async fn stop_canary(app_id: &Stringy) {
+    let unit: String = format!("{}.service", canary_unit(app_id));
+    if let Err(err) = Command::new("systemctl")
+        .arg("stop")
+        .arg(&unit)
+        .status()
+        .await
+    {
+        log!(LogLevel::Error, "Failed to stop {}: {}", unit, err);
+    }
+}
+
+/// Runs the canary next to the current version for the bake period, samples
+/// both the same way and returns what the canary regressed on
+async fn bake(
+    settings: &CanarySettings,
+    app_id: &Stringy,
+    uid: u32,
+    bake_secs: u64,
+) -> Result<Vec<String>, ErrorArrayItem> {
+    let started_at: u64 = current_timestamp();
+    launch_canary(app_id, uid).await?;
+
+    let interval: Duration = Duration::from_secs(settings.sample_interval_secs.max(1));
+    let mut baseline: Sampler = Sampler::new(app_id.to_string());
+    let mut canary: Sampler = Sampler::new(canary_unit(app_id));
+
+    while current_timestamp() < started_at + bake_secs {
+        tokio::time::sleep(interval).await;
+        baseline.sample(interval);
+
+        if !canary.sample(interval) {
+            return Ok(vec!["canary exited during the bake period".to_owned()]);
+        }
+    }
+
+    let baseline: CanaryMetrics = baseline.finish(error_count(app_id, started_at, None).await);
+    let canary: CanaryMetrics =
+        canary.finish(error_count(&canary_unit(app_id), started_at, None).await);
+
+    log!(
+        LogLevel::Info,
+        "Canary of {}: baseline {:?}, canary {:?}",
+        app_id,
+        baseline,
+        canary
+    );
+
+    Ok(regressions(settings, &baseline, &canary))
+}
+
+async fn run_canary(
+    gs: Arc<GlobalState>,
+    app_id: Stringy,
+    version: String,
+    signature: String,
+    uid: u32,
+    bake_secs: u64,
+) {
+    let settings: &CanarySettings = &gs.settings.canary;
+    gs.events.publish(ManagerEvent::CanaryStarted {
+        name: app_id.clone(),
+        bake_secs,
+    });
+
+    let verdict: Result<Vec<String>, ErrorArrayItem> =
+        bake(settings, &app_id, uid, bake_secs).await;
+    stop_canary(&app_id).await;
+
+    let reason: Option<String> = match verdict {
+        Ok(regressions) if regressions.is_empty() => {
+            // Promote the canary the same way a regular upload is rolled out
+            roll_out(&app_id, &canary_binary(&app_id), &version, &signature)
+                .await
+                .err()
+                .map(|err| err.to_string())
+        }
+        Ok(regressions) => Some(regressions.join(", ")),
+        Err(err) => Some(err.to_string()),
+    };
+
+    match reason {
+        None => {
+            log!(LogLevel::Info, "Promoted the canary of {}", app_id);
+            Decision::new(
+                "canary",
+                Some(&app_id),
+                "promote",
+                String::from("it held up against the running version while baking"),
+            )
+            .input("version", &version)
+            .input("bake_secs", bake_secs)
+            .record();
+            gs.events.publish(ManagerEvent::DeployFinished {
+                name: app_id.clone(),
+            });
+        }
+        Some(reason) => {
+            let _ = fs::remove_file(canary_binary(&app_id));
+            log!(
+                LogLevel::Error,
+                "Rolled back the canary of {}: {}",
+                app_id,
+                reason
+            );
+            Decision::new("canary", Some(&app_id), "roll back", reason.clone())
+                .input("version", &version)
+                .input("bake_secs", bake_secs)
+                .record();
+            gs.events.publish(ManagerEvent::CanaryRolledBack {
+                name: app_id.clone(),
+                reason,
+            });
+        }
+    }
+
+    if let Ok(mut active) = ACTIVE_CANARIES.try_write().await {
+        active.remove(&app_id);
+    }
+}
+
+/// Verifies a finished upload and bakes it as a canary next to the running
+/// version. It's promoted if it holds up, otherwise thrown away.
+pub async fn deploy_canary(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    transfer_id: &Stringy,
+    signature: &str,
+    bake_secs: Option<u64>,
+) -> Result<(), ErrorArrayItem> {
+    // System applications are managed by systemd units we don't duplicate
+    let uid: u32 = match CLIENT_APPLICATION_ARRAY
+        .read(app_id, |client| client.execution_uid())
+        .await?
+    {
+        Some(uid) => uid,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("{} is not a client application", app_id),
+            ))
+        }
+    };
+
+    if !ACTIVE_CANARIES.try_write().await?.insert(app_id.clone()) {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("A canary of {} is already baking", app_id),
+        ));
+    }
+
+    let prepared: Result<String, ErrorArrayItem> = async {
+        let artifact: PathBuf = finish_transfer(app_id, transfer_id).await?;
+
+        let version: String = match verify_artifact(&artifact, signature) {
+            Ok(version) => version,
+            Err(err) => {
+                let _ = fs::remove_file(&artifact);
+                return Err(err);
+            }
+        };
+
+        if is_tarball(&artifact)? {
+            let _ = fs::remove_file(&artifact);
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Only binaries can be deployed as a canary",
+            ));
+        }
+
+        fs::rename(&artifact, canary_binary(app_id))?;
+        fs::set_permissions(canary_binary(app_id), fs::Permissions::from_mode(0o755))?;
+        Ok(version)
+    }
+    .await;
+
+    let version: String = match prepared {
+        Ok(version) => version,
+        Err(err) => {
+            ACTIVE_CANARIES.try_write().await?.remove(app_id);
+            return Err(err);
+        }
+    };
+
+    let bake_secs: u64 = bake_secs.unwrap_or(gs.settings.canary.bake_secs);
+    log!(
+        LogLevel::Info,
+        "Baking a canary of {} for {}s",
+        app_id,
+        bake_secs
+    );
+    tokio::spawn(run_canary(
+        gs.clone(),
+        app_id.clone(),
+        version,
+        signature.to_owned(),
+        uid,
+        bake_secs,
+    ));
+
+    Ok(())
+}
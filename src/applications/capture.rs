@@ -0,0 +1,196 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+
+use super::redaction::{redactor_for, Redactor};
+
+/// Captured output of every application, bounded per app
+static CAPTURES: Lazy<LockWithTimeout<HashMap<Stringy, AppCaptures>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// A line as it was taken in. The app only stamps lines to the second, the
+/// sequence number is what tells lines within one second apart.
+#[derive(Debug, Clone)]
+pub struct CapturedLine {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub line: String,
+}
+
+/// Fixed size buffer of captured output lines, the oldest line makes room
+/// for the newest one
+#[derive(Debug, Clone)]
+pub struct CaptureRing {
+    capacity: usize,
+    lines: VecDeque<CapturedLine>,
+    /// Given to the next line taken in, never reused even once it's dropped
+    next_seq: u64,
+    /// Timestamp of the newest line taken in, survives lines being dropped
+    newest: u64,
+    /// How many lines taken in share the newest timestamp
+    newest_count: usize,
+}
+
+impl CaptureRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: VecDeque::with_capacity(capacity),
+            next_seq: 1,
+            newest: 0,
+            newest_count: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    pub fn push(&mut self, (timestamp, line): (u64, String)) {
+        let seq: u64 = self.next_seq;
+        self.next_seq += 1;
+
+        match timestamp {
+            timestamp if timestamp > self.newest => {
+                self.newest = timestamp;
+                self.newest_count = 1;
+            }
+            timestamp if timestamp == self.newest => self.newest_count += 1,
+            _ => (),
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(CapturedLine {
+            seq,
+            timestamp,
+            line,
+        });
+    }
+
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.lines.len() > capacity {
+            self.lines.pop_front();
+        }
+    }
+
+    /// Drops lines captured before the cutoff
+    pub fn retain_since(&mut self, cutoff: u64) {
+        self.lines.retain(|line| line.timestamp >= cutoff);
+    }
+
+    /// Takes in the lines of a fresh capture that haven't been seen yet. The
+    /// app hands over its whole capture every time.
+    fn ingest(&mut self, incoming: Vec<(u64, String)>, redactor: Option<&Redactor>) {
+        let mut already_seen: usize = self.newest_count;
+
+        let fresh: Vec<(u64, String)> = incoming
+            .into_iter()
+            .filter(|(timestamp, _)| {
+                if *timestamp == self.newest && already_seen > 0 {
+                    already_seen -= 1;
+                    return false;
+                }
+                *timestamp >= self.newest
+            })
+            .collect();
+
+        // Anything past the capacity would be evicted straight away
+        let skip: usize = fresh.len().saturating_sub(self.capacity);
+        for (timestamp, line) in fresh.into_iter().skip(skip) {
+            let line: String = match redactor {
+                Some(redactor) => redactor.redact(&line),
+                None => line,
+            };
+            self.push((timestamp, line));
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<(u64, String)> {
+        self.lines
+            .iter()
+            .map(|line| (line.timestamp, line.line.clone()))
+            .collect()
+    }
+
+    pub fn lines(&self) -> Vec<CapturedLine> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AppCaptures {
+    stdout: CaptureRing,
+    stderr: CaptureRing,
+}
+
+/// Moves freshly loaded output into the app's ring buffers and leaves the
+/// bounded, redacted result in its place
+pub async fn capture_output(
+    app_id: &Stringy,
+    capacity: usize,
+    cutoff: u64,
+    stdout: &mut Vec<(u64, String)>,
+    stderr: &mut Vec<(u64, String)>,
+) -> Result<(), ErrorArrayItem> {
+    let redactor = redactor_for(app_id).await;
+    let mut captures = CAPTURES.try_write().await?;
+
+    let app: &mut AppCaptures = captures
+        .entry(app_id.clone())
+        .or_insert_with(|| AppCaptures {
+            stdout: CaptureRing::new(capacity),
+            stderr: CaptureRing::new(capacity),
+        });
+
+    for (ring, lines) in [(&mut app.stdout, stdout), (&mut app.stderr, stderr)] {
+        ring.resize(capacity);
+        ring.ingest(std::mem::take(lines), redactor.as_deref());
+        ring.retain_since(cutoff);
+        *lines = ring.to_vec();
+    }
+
+    Ok(())
+}
+
+/// The stdout and stderr lines held for an app, empty until its output was
+/// first captured
+pub async fn captured_lines(
+    app_id: &Stringy,
+) -> Result<(Vec<CapturedLine>, Vec<CapturedLine>), ErrorArrayItem> {
+    Ok(match CAPTURES.try_read().await?.get(app_id) {
+        Some(app) => (app.stdout.lines(), app.stderr.lines()),
+        None => (Vec::new(), Vec::new()),
+    })
+}
+
+/// Empties the ring buffers of an app, returning how many lines were dropped
+pub async fn clear_captures(app_id: &Stringy) -> Result<usize, ErrorArrayItem> {
+    let mut captures = CAPTURES.try_write().await?;
+
+    Ok(match captures.get_mut(app_id) {
+        Some(app) => {
+            let dropped: usize = app.stdout.len() + app.stderr.len();
+            app.stdout.clear();
+            app.stderr.clear();
+            dropped
+        }
+        None => 0,
+    })
+}
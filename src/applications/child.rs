@@ -14,13 +14,17 @@ use artisan_middleware::{
     state_persistence::AppState,
 };
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::{collections::HashMap, time::Duration};
+use std::sync::Arc;
 use tokio::process::Command;
 
+use crate::system::cgroups::service_file;
 use crate::system::state::save_state;
 
+use super::build_cache::shared_cache_env;
+use super::registry::Registry;
 use super::resolve::Application;
 use super::{
     pid::reclaim_child,
@@ -32,22 +36,17 @@ pub enum SupervisedProcesses {
     Process(SupervisedProcess),
 }
 
-pub static APP_STATUS_ARRAY: Lazy<LockWithTimeout<HashMap<Stringy, AppStatus>>> =
-    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+pub static APP_STATUS_ARRAY: Lazy<Registry<Arc<AppStatus>>> = Lazy::new(Registry::new);
 
-pub static SYSTEM_APPLICATION_HANDLER: Lazy<
-    LockWithTimeout<HashMap<Stringy, SupervisedProcesses>>,
-> = Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+pub static SYSTEM_APPLICATION_HANDLER: Lazy<Registry<SupervisedProcesses>> =
+    Lazy::new(Registry::new);
 
-pub static CLIENT_APPLICATION_HANDLER: Lazy<
-    LockWithTimeout<HashMap<Stringy, SupervisedProcesses>>,
-> = Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+pub static CLIENT_APPLICATION_HANDLER: Lazy<Registry<SupervisedProcesses>> =
+    Lazy::new(Registry::new);
 
-pub static CLIENT_APPLICATION_ARRAY: Lazy<LockWithTimeout<HashMap<Stringy, ClientApplication>>> =
-    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+pub static CLIENT_APPLICATION_ARRAY: Lazy<Registry<ClientApplication>> = Lazy::new(Registry::new);
 
-pub static SYSTEM_APPLICATION_ARRAY: Lazy<LockWithTimeout<HashMap<Stringy, SystemApplication>>> =
-    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+pub static SYSTEM_APPLICATION_ARRAY: Lazy<Registry<SystemApplication>> = Lazy::new(Registry::new);
 
 pub async fn _spawn_system_applications(
     system_application_handler: LockWithTimeout<HashMap<String, SupervisedProcesses>>,
@@ -261,6 +260,8 @@ pub async fn _spawn_client_applications(
                     }
                 };
 
+                command.envs(shared_cache_env());
+
                 let config_path: PathType = PathType::Content(format!("/etc/{}/", client_app.0));
                 match spawn_complex_process(command, Some(config_path), false, true).await {
                     Ok(child) => {
@@ -380,12 +381,9 @@ pub async fn spawn_single_application(
                 }
             };
 
-            // pushing application into the write lock
-            let mut system_handler_write_lock: tokio::sync::RwLockWriteGuard<
-                '_,
-                HashMap<Stringy, SupervisedProcesses>,
-            > = SYSTEM_APPLICATION_HANDLER
-                .try_write()
+            // pushing application into the handler
+            SYSTEM_APPLICATION_HANDLER
+                .insert(system_application.name, system_process)
                 .await
                 .map_err(|mut err| {
                     err.err_mesg = format!(
@@ -396,9 +394,6 @@ pub async fn spawn_single_application(
                     err
                 })?;
 
-            system_handler_write_lock.insert(system_application.name, system_process);
-            drop(system_handler_write_lock);
-
             return Ok(());
         }
         Application::Client(client_application) => {
@@ -497,6 +492,8 @@ pub async fn spawn_single_application(
                         }
                     };
 
+                    command.envs(shared_cache_env());
+
                     let config_path: PathType =
                         PathType::Content(format!("/etc/{}/", client_application.name));
                     match spawn_complex_process(command, Some(config_path), false, true).await {
@@ -522,19 +519,18 @@ pub async fn spawn_single_application(
                 }
             };
 
-            // pushing application into the write lock
-            let mut client_application_handler_write_lock =
-                CLIENT_APPLICATION_HANDLER.try_write().await.map_err(|mut err| {
+            // pushing application into the handler
+            CLIENT_APPLICATION_HANDLER
+                .insert(client_application.name.into(), client_child)
+                .await
+                .map_err(|mut err| {
                     err.err_mesg = format!(
-                        "Error getting write lock on system handler in spawn single application: {}",
+                        "Error getting write lock on client handler in spawn single application: {}",
                         err.err_mesg
                     )
                     .into();
                     err
                 })?;
-            client_application_handler_write_lock
-                .insert(client_application.name.into(), client_child);
-            drop(client_application_handler_write_lock);
 
             return Ok(());
         }
@@ -542,20 +538,12 @@ pub async fn spawn_single_application(
 }
 
 pub async fn populate_initial_state_lock(state: &mut AppState) -> Result<(), ErrorArrayItem> {
-    let mut application_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        HashMap<Stringy, AppStatus>,
-    > = APP_STATUS_ARRAY
-        .try_write_with_timeout(Some(Duration::from_secs(2)))
-        .await?;
-
     let mut applications: Vec<Application> = Vec::new();
     let mut app_states: Vec<(Stringy, ApplicationConfig, bool)> = Vec::new();
 
     // working on the system applications
     {
-        let system_application_array_read_lock = SYSTEM_APPLICATION_ARRAY.try_read().await?;
-        for app in system_application_array_read_lock.clone().into_iter() {
+        for app in SYSTEM_APPLICATION_ARRAY.snapshot().await? {
             applications.push(Application::System(app.1));
         }
         state.data = "Re-populating system applications in status array".to_owned();
@@ -563,9 +551,7 @@ pub async fn populate_initial_state_lock(state: &mut AppState) -> Result<(), Err
 
     // add gate for client applications
     if state.config.environment != "systemonly" {
-        let client_application_array_read_lock = CLIENT_APPLICATION_ARRAY.try_read().await?;
-
-        for app in client_application_array_read_lock.clone().into_iter() {
+        for app in CLIENT_APPLICATION_ARRAY.snapshot().await? {
             applications.push(Application::Client(app.1));
         }
 
@@ -624,8 +610,9 @@ pub async fn populate_initial_state_lock(state: &mut AppState) -> Result<(), Err
             expected_status,
         };
 
-        if let Some(old) = application_status_array_write_lock.insert(app.0, app_status.clone()) {
-            log!(LogLevel::Debug, "Updated? {}", old.app_id)
+        let app_status: Arc<AppStatus> = Arc::new(app_status);
+        if APP_STATUS_ARRAY.insert(app.0, app_status.clone()).await? {
+            log!(LogLevel::Debug, "Updated? {}", app_status.app_id)
         } else {
             log!(
                 LogLevel::Debug,
@@ -639,11 +626,7 @@ pub async fn populate_initial_state_lock(state: &mut AppState) -> Result<(), Err
 }
 
 pub fn pids_in_cgroup(service_name: &str) -> std::io::Result<Vec<u32>> {
-    let path = format!(
-        "/sys/fs/cgroup/artisan.slice/{}.service/cgroup.procs",
-        service_name
-    );
-    let file = fs::File::open(path)?;
+    let file = fs::File::open(service_file(service_name, "cgroup.procs"))?;
     let reader = BufReader::new(file);
 
     let pids = reader
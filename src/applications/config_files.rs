@@ -0,0 +1,107 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::enviornment::definitions::Enviornment;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+use super::child::APP_STATUS_ARRAY;
+
+/// Files under /etc/{app}/ that can be read and written remotely
+pub const EDITABLE_CONFIG_FILES: [&str; 3] = [".env", "Config.toml", "Overrides.toml"];
+
+/// Largest config file we'll accept
+const CONFIG_FILE_MAX_SIZE: usize = 256 * 1024;
+
+fn config_path(app_id: &Stringy, file: &str) -> Result<PathBuf, ErrorArrayItem> {
+    if !EDITABLE_CONFIG_FILES.contains(&file) {
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!("{} is not an editable config file", file),
+        ));
+    }
+
+    Ok(PathBuf::from(format!("/etc/{}/{}", app_id, file)))
+}
+
+async fn ensure_registered(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    match APP_STATUS_ARRAY.contains(app_id).await? {
+        true => Ok(()),
+        false => Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{}, Not registered in the system", app_id),
+        )),
+    }
+}
+
+/// Checks the new contents parse the same way the application will read them
+async fn validate_config(file: &str, data: &str) -> Result<(), ErrorArrayItem> {
+    if data.len() > CONFIG_FILE_MAX_SIZE {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("{} is larger than {} bytes", file, CONFIG_FILE_MAX_SIZE),
+        ));
+    }
+
+    if file == ".env" {
+        return Enviornment::parse(data.as_bytes())
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                ErrorArrayItem::new(
+                    Errors::ConfigParsing,
+                    format!("Invalid environment file: {}", err),
+                )
+            });
+    }
+
+    toml::from_str::<toml::Value>(data)
+        .map(|_| ())
+        .map_err(|err| {
+            ErrorArrayItem::new(Errors::ConfigParsing, format!("Invalid {}: {}", file, err))
+        })
+}
+
+pub async fn read_config_file(app_id: &Stringy, file: &str) -> Result<String, ErrorArrayItem> {
+    ensure_registered(app_id).await?;
+    let path: PathBuf = config_path(app_id, file)?;
+    Ok(fs::read_to_string(path)?)
+}
+
+/// Validates and writes a config file, the previous version is kept in
+/// /etc/{app}/.backups/ and the change is published on the event bus
+pub async fn write_config_file(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    file: &str,
+    data: &str,
+) -> Result<(), ErrorArrayItem> {
+    ensure_registered(app_id).await?;
+    let path: PathBuf = config_path(app_id, file)?;
+    validate_config(file, data).await?;
+
+    if path.exists() {
+        let backup_dir: PathBuf = PathBuf::from(format!("/etc/{}/.backups", app_id));
+        fs::create_dir_all(&backup_dir)?;
+        fs::copy(
+            &path,
+            backup_dir.join(format!("{}.{}", file, current_timestamp())),
+        )?;
+    }
+
+    fs::write(&path, data)?;
+
+    log!(LogLevel::Info, "Updated {} for {}", file, app_id);
+    gs.events.publish(ManagerEvent::ConfigChanged {
+        name: app_id.clone(),
+        file: file.to_string(),
+    });
+
+    Ok(())
+}
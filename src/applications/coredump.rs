@@ -0,0 +1,384 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+
+use crate::system::control::GlobalState;
+use crate::system::settings::RetentionSettings;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+
+pub const CORE_DUMP_DIR: &str = "/opt/artisan/coredumps";
+
+/// Total bytes of dumps kept per application
+const CORE_DUMP_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Dumps already taken out of the journal, kept across reloads so pruned
+/// ones aren't collected again
+const COLLECTED_PATH: &str = "/opt/artisan/collected_coredumps.json";
+
+static COLLECTED: Lazy<Mutex<Collected>> = Lazy::new(|| Mutex::new(load_collected()));
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Collected {
+    /// `{time}-{pid}` of every dump collected, by app
+    dumps: HashMap<String, HashSet<String>>,
+    /// When the app's dumps were last purged, crashes from before stay in
+    /// the journal but aren't collected again
+    #[serde(default)]
+    purged: HashMap<String, u64>,
+}
+
+/// Entry from `coredumpctl list --json=short`
+#[derive(Debug, Deserialize)]
+struct CoredumpctlEntry {
+    time: u64,
+    pid: u32,
+    exe: String,
+    corefile: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CoreDump {
+    pub file: String,
+    pub size: u64,
+    /// When the app crashed
+    pub timestamp: u64,
+}
+
+fn coredump_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_collected() -> Collected {
+    fs::read_to_string(COLLECTED_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_collected(collected: &Collected) -> Result<(), ErrorArrayItem> {
+    let data: String =
+        serde_json::to_string(collected).map_err(|err| coredump_error(err.to_string()))?;
+    let temp: String = format!("{}.tmp", COLLECTED_PATH);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, COLLECTED_PATH)?;
+    Ok(())
+}
+
+fn was_collected(app_name: &str, key: &str) -> bool {
+    COLLECTED
+        .lock()
+        .map(|collected| {
+            collected
+                .dumps
+                .get(app_name)
+                .map_or(false, |dumps| dumps.contains(key))
+                || match (collected.purged.get(app_name), crash_time(key)) {
+                    (Some(purged_at), Some(time)) => time <= *purged_at,
+                    _ => false,
+                }
+        })
+        .unwrap_or(false)
+}
+
+fn mark_collected(app_name: &str, key: String) -> Result<(), ErrorArrayItem> {
+    let mut collected = COLLECTED
+        .lock()
+        .map_err(|err| coredump_error(err.to_string()))?;
+    collected
+        .dumps
+        .entry(app_name.to_owned())
+        .or_default()
+        .insert(key);
+    save_collected(&collected)
+}
+
+/// Dumps past the retention window are never collected again, there's no
+/// need to remember them
+fn forget_expired(retention: &RetentionSettings) -> Result<(), ErrorArrayItem> {
+    let mut collected = COLLECTED
+        .lock()
+        .map_err(|err| coredump_error(err.to_string()))?;
+    for (app_name, dumps) in collected.dumps.iter_mut() {
+        let max_age: u64 = retention.policy_for(app_name).post_mortem_days * 24 * 60 * 60;
+        dumps.retain(|key| {
+            crash_time(key).map_or(false, |time| time + max_age >= current_timestamp())
+        });
+    }
+    collected.dumps.retain(|_, dumps| !dumps.is_empty());
+    save_collected(&collected)
+}
+
+/// coredumpctl reports times in microseconds
+fn journal_secs(time: u64) -> u64 {
+    time / 1_000_000
+}
+
+/// Crash time in seconds of a `{time}-{pid}` key or dump file name
+fn crash_time(name: &str) -> Option<u64> {
+    name.split('-')
+        .next()
+        .and_then(|time| time.parse::<u64>().ok())
+        .map(journal_secs)
+}
+
+fn app_dump_dir(app_name: &str) -> PathBuf {
+    Path::new(CORE_DUMP_DIR).join(app_name)
+}
+
+/// A single path component, nothing that steps out of the directory it's
+/// joined to
+fn plain_name(name: &str) -> Result<(), ErrorArrayItem> {
+    let plain: bool = !name.is_empty()
+        && name != "."
+        && !name.contains(['/', '\\', '\0'])
+        && !name.contains("..");
+
+    match plain {
+        true => Ok(()),
+        false => Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!("{:?} isn't a valid name", name),
+        )),
+    }
+}
+
+/// App ids from commands end up in paths, they have to be plain names of an
+/// app the manager knows before anything is joined to them
+pub async fn registered_app(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    plain_name(app_id.as_str())?;
+
+    match CLIENT_APPLICATION_ARRAY.contains(app_id).await?
+        || APP_STATUS_ARRAY.contains(app_id).await?
+    {
+        true => Ok(()),
+        false => Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} isn't registered", app_id),
+        )),
+    }
+}
+
+/// The path with every link resolved, refused unless it's still inside the
+/// dump directory
+fn contained(path: &Path) -> Result<PathBuf, ErrorArrayItem> {
+    let root: PathBuf = fs::canonicalize(CORE_DUMP_DIR)?;
+    let resolved: PathBuf = fs::canonicalize(path)?;
+
+    match resolved.starts_with(&root) && resolved != root {
+        true => Ok(resolved),
+        false => Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            format!("{} is outside of {}", path.display(), CORE_DUMP_DIR),
+        )),
+    }
+}
+
+/// Pulls new dumps for supervised binaries out of systemd-coredump and
+/// enforces the per application retention limits
+pub async fn collect_core_dumps(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let output = Command::new("coredumpctl")
+        .args(["list", "--json=short", "--no-pager"])
+        .output()
+        .await?;
+
+    // coredumpctl exits non zero when there is nothing to list
+    if !output.status.success() {
+        return Ok(());
+    }
+
+    let entries: Vec<CoredumpctlEntry> = serde_json::from_slice(&output.stdout)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+    for entry in entries {
+        let app_name = match entry.exe.strip_prefix("/opt/artisan/bin/") {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if entry.corefile != "present" {
+            continue;
+        }
+
+        // Retention would only prune it again
+        let max_age: u64 =
+            gs.settings.retention.policy_for(&app_name).post_mortem_days * 24 * 60 * 60;
+        if journal_secs(entry.time) + max_age < current_timestamp() {
+            continue;
+        }
+
+        let key: String = format!("{}-{}", entry.time, entry.pid);
+        let dump_dir: PathBuf = app_dump_dir(&app_name);
+        let dump_path: PathBuf = dump_dir.join(format!("{}.core", key));
+        if was_collected(&app_name, &key) || dump_path.exists() {
+            continue;
+        }
+
+        fs::create_dir_all(&dump_dir)?;
+
+        let status = Command::new("coredumpctl")
+            .arg("dump")
+            .arg(entry.pid.to_string())
+            .arg(format!("--output={}", dump_path.display()))
+            .output()
+            .await?
+            .status;
+
+        if status.success() {
+            mark_collected(&app_name, key)?;
+            log!(
+                LogLevel::Warn,
+                "Collected core dump for {} (pid {})",
+                app_name,
+                entry.pid
+            );
+        } else {
+            log!(
+                LogLevel::Error,
+                "Failed to collect core dump for {} (pid {})",
+                app_name,
+                entry.pid
+            );
+        }
+    }
+
+    enforce_retention(&gs.settings.retention)?;
+    forget_expired(&gs.settings.retention)
+}
+
+fn enforce_retention(retention: &RetentionSettings) -> Result<(), ErrorArrayItem> {
+    let root = Path::new(CORE_DUMP_DIR);
+    if !root.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let app_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let max_age: u64 = retention.policy_for(&app_name).post_mortem_days * 24 * 60 * 60;
+        let mut dumps: Vec<CoreDump> = dumps_in(&path)?;
+        // Newest first so the oldest are the ones that fall off
+        dumps.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        let mut kept_bytes: u64 = 0;
+        for dump in dumps {
+            let too_old = dump.timestamp + max_age < current_timestamp();
+            let too_big = kept_bytes + dump.size > CORE_DUMP_MAX_BYTES;
+
+            if too_old || too_big {
+                fs::remove_file(path.join(&dump.file))?;
+                log!(
+                    LogLevel::Info,
+                    "Pruned core dump {} of {}",
+                    dump.file,
+                    app_name
+                );
+            } else {
+                kept_bytes += dump.size;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Dumps of a registered app
+pub async fn list_core_dumps(app_id: &Stringy) -> Result<Vec<CoreDump>, ErrorArrayItem> {
+    registered_app(app_id).await?;
+
+    let dump_dir: PathBuf = app_dump_dir(app_id.as_str());
+    if !dump_dir.exists() {
+        return Ok(Vec::new());
+    }
+    dumps_in(&contained(&dump_dir)?)
+}
+
+fn dumps_in(dump_dir: &Path) -> Result<Vec<CoreDump>, ErrorArrayItem> {
+    let mut dumps: Vec<CoreDump> = Vec::new();
+
+    if !dump_dir.exists() {
+        return Ok(dumps);
+    }
+
+    for entry in fs::read_dir(dump_dir)? {
+        let entry = entry?;
+        let file = match entry.file_name().into_string() {
+            Ok(name) if name.ends_with(".core") => name,
+            _ => continue,
+        };
+
+        // The file is only as old as its collection, the name says when
+        // the app crashed
+        let metadata = entry.metadata()?;
+        let timestamp: u64 = match crash_time(&file) {
+            Some(time) => time,
+            None => metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+        };
+
+        dumps.push(CoreDump {
+            file,
+            size: metadata.len(),
+            timestamp,
+        });
+    }
+
+    Ok(dumps)
+}
+
+/// Removes every stored dump of an application, returns how many there were.
+/// Only ever the app's own directory, resolved and checked to be inside the
+/// dump directory. Crashes from before the purge aren't collected again.
+pub async fn purge_core_dumps(app_id: &Stringy) -> Result<usize, ErrorArrayItem> {
+    let dumps: Vec<CoreDump> = list_core_dumps(app_id).await?;
+
+    // Before the files go, or the next collection could bring them back
+    {
+        let mut collected = COLLECTED
+            .lock()
+            .map_err(|err| coredump_error(err.to_string()))?;
+        collected.dumps.remove(app_id.as_str());
+        collected
+            .purged
+            .insert(app_id.to_string(), current_timestamp());
+        save_collected(&collected)?;
+    }
+
+    if !dumps.is_empty() {
+        fs::remove_dir_all(contained(&app_dump_dir(app_id.as_str()))?)?;
+    }
+    Ok(dumps.len())
+}
+
+/// Path of a stored dump, the file name has to be one we listed
+pub async fn core_dump_path(app_id: &Stringy, file: &str) -> Result<PathBuf, ErrorArrayItem> {
+    plain_name(file)?;
+    if !list_core_dumps(app_id)
+        .await?
+        .iter()
+        .any(|dump| dump.file == file)
+    {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("No core dump named {} for {}", file, app_id),
+        ));
+    }
+
+    contained(&app_dump_dir(app_id.as_str()).join(file))
+}
@@ -0,0 +1,307 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::DependencySettings;
+
+use super::canary::error_count;
+use super::child::CLIENT_APPLICATION_ARRAY;
+
+/// Latest probe results of every app's dependencies. AppStatus comes from
+/// the middleware, so they are kept here and added to the status JSON.
+static DEPENDENCIES: Lazy<Mutex<HashMap<String, Vec<DependencyState>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Longest stretch before an outage the error rate is compared against
+const MAX_BASELINE_SECS: u64 = 3600;
+
+/// Declared as `[[dependencies]]` tables in /etc/{app}/Config.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct DependencySpec {
+    pub name: String,
+    #[serde(flatten)]
+    pub probe: ExternalProbe,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ExternalProbe {
+    /// Reachable when the endpoint answers with anything below a 500
+    Http {
+        url: String,
+        #[serde(default = "default_probe_timeout")]
+        timeout_secs: u64,
+    },
+    Tcp {
+        host: String,
+        port: u16,
+        #[serde(default = "default_probe_timeout")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+impl ExternalProbe {
+    fn target(&self) -> String {
+        match self {
+            ExternalProbe::Http { url, .. } => url.clone(),
+            ExternalProbe::Tcp { host, port, .. } => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// Errors the app logged during an outage against the same stretch before it
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorSpike {
+    pub before: usize,
+    pub during: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyState {
+    pub name: String,
+    pub target: String,
+    pub reachable: bool,
+    /// Failed probes in a row
+    pub failures: u32,
+    /// When probes started failing, the outage is dated from here once it
+    /// counts as one
+    pub failing_since: Option<u64>,
+    pub last_error: Option<String>,
+    /// Set while down and the app's errors went up with the outage
+    pub error_spike: Option<ErrorSpike>,
+    pub checked_at: u64,
+}
+
+fn declared_dependencies(app_id: &str) -> Vec<DependencySpec> {
+    let data: String = match fs::read_to_string(format!("/etc/{}/Config.toml", app_id)) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+    let config: toml::Value = match toml::from_str(&data) {
+        Ok(config) => config,
+        Err(_) => return Vec::new(),
+    };
+
+    match config.get("dependencies").cloned() {
+        Some(dependencies) => match dependencies.try_into::<Vec<DependencySpec>>() {
+            Ok(dependencies) => dependencies,
+            Err(err) => {
+                log!(
+                    LogLevel::Error,
+                    "{} declares invalid dependencies: {}",
+                    app_id,
+                    err
+                );
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    }
+}
+
+async fn probe(probe: &ExternalProbe) -> Result<(), String> {
+    match probe {
+        ExternalProbe::Tcp {
+            host,
+            port,
+            timeout_secs,
+        } => {
+            let timeout: Duration = Duration::from_secs(*timeout_secs);
+            match tokio::time::timeout(timeout, TcpStream::connect((host.as_str(), *port))).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(format!("connecting to {}:{} failed: {}", host, port, err)),
+                Err(_) => Err(format!("connecting to {}:{} timed out", host, port)),
+            }
+        }
+        ExternalProbe::Http { url, timeout_secs } => {
+            let output = Command::new("curl")
+                .args([
+                    "--silent",
+                    "--show-error",
+                    "--output",
+                    "/dev/null",
+                    "--write-out",
+                    "%{http_code}",
+                    "--max-time",
+                    &timeout_secs.to_string(),
+                    url,
+                ])
+                .output()
+                .await
+                .map_err(|err| err.to_string())?;
+
+            // An auth error or a missing page still means it's up
+            let code: u16 = String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .parse()
+                .unwrap_or(0);
+            match code {
+                1..=499 => Ok(()),
+                0 => Err(format!(
+                    "{} is unreachable: {}",
+                    url,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+                code => Err(format!("{} answered {}", url, code)),
+            }
+        }
+    }
+}
+
+/// Whether the app's errors went up with the outage, compared with the
+/// same stretch of time right before it
+async fn error_spike(
+    app_id: &str,
+    down_since: u64,
+    now: u64,
+    settings: &DependencySettings,
+) -> Option<ErrorSpike> {
+    let window: u64 = now.saturating_sub(down_since).clamp(1, MAX_BASELINE_SECS);
+    let during: usize = error_count(app_id, down_since, None).await;
+    let before: usize = error_count(app_id, down_since - window, Some(down_since)).await;
+
+    match during as f64 >= before.max(1) as f64 * settings.error_spike_factor {
+        true => Some(ErrorSpike { before, during }),
+        false => None,
+    }
+}
+
+/// Probes every dependency the client apps declare, each endpoint once per
+/// round however many apps share it
+pub async fn probe_dependencies(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &DependencySettings = &gs.settings.dependencies;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mut probed: HashMap<String, Result<(), String>> = HashMap::new();
+    let mut all: HashMap<String, Vec<DependencyState>> = HashMap::new();
+
+    for app_id in CLIENT_APPLICATION_ARRAY.keys().await? {
+        let declared: Vec<DependencySpec> = declared_dependencies(app_id.as_str());
+        if declared.is_empty() {
+            continue;
+        }
+
+        let previous: Vec<DependencyState> = dependencies_of(app_id.as_str());
+        let mut states: Vec<DependencyState> = Vec::new();
+
+        for spec in declared {
+            let target: String = spec.probe.target();
+            let result: Result<(), String> = match probed.get(&target) {
+                Some(result) => result.clone(),
+                None => {
+                    let result: Result<(), String> = probe(&spec.probe).await;
+                    probed.insert(target.clone(), result.clone());
+                    result
+                }
+            };
+
+            let now: u64 = current_timestamp();
+            let last: Option<&DependencyState> = previous
+                .iter()
+                .find(|state| state.name == spec.name && state.target == target);
+            let was_down: bool = last.map_or(false, |state| !state.reachable);
+
+            let mut state: DependencyState = DependencyState {
+                name: spec.name.clone(),
+                target,
+                reachable: true,
+                failures: 0,
+                failing_since: None,
+                last_error: None,
+                error_spike: None,
+                checked_at: now,
+            };
+
+            if let Err(err) = result {
+                state.failures = last.map_or(0, |state| state.failures) + 1;
+                state.last_error = Some(err);
+                state.reachable = state.failures < settings.failures_before_down.max(1);
+                state.failing_since =
+                    Some(last.and_then(|state| state.failing_since).unwrap_or(now));
+            }
+
+            if !state.reachable {
+                let since: u64 = state.failing_since.unwrap_or(now);
+                state.error_spike = error_spike(app_id.as_str(), since, now, settings).await;
+
+                if !was_down {
+                    log!(
+                        LogLevel::Warn,
+                        "{} is degraded, its dependency {} at {} is unreachable",
+                        app_id,
+                        state.name,
+                        state.target
+                    );
+                    gs.events.publish(ManagerEvent::DependencyUnreachable {
+                        name: app_id.clone(),
+                        dependency: state.name.clone(),
+                        error_spike: state.error_spike.is_some(),
+                    });
+                }
+            } else if was_down {
+                log!(
+                    LogLevel::Info,
+                    "The dependency {} of {} is reachable again",
+                    state.name,
+                    app_id
+                );
+            }
+
+            states.push(state);
+        }
+
+        all.insert(app_id.to_string(), states);
+    }
+
+    *DEPENDENCIES
+        .lock()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))? = all;
+    Ok(())
+}
+
+/// Puts a running app with an unreachable dependency into Warning, naming
+/// the upstream so support can tell it apart from the app's own faults
+pub fn flag_dependencies(name: &Stringy, app: &mut AppStatus) {
+    let down: Vec<DependencyState> = dependencies_of(name)
+        .into_iter()
+        .filter(|state| !state.reachable)
+        .collect();
+    if down.is_empty() || app.app_data.get_status() != Status::Running {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    for state in down {
+        app.app_data.state.error_log.push(ErrorArrayItem::new(
+            Errors::AppState,
+            format!("DEGRADED: UPSTREAM {} UNREACHABLE", state.name),
+        ));
+    }
+}
+
+pub fn dependencies_of(name: &str) -> Vec<DependencyState> {
+    DEPENDENCIES
+        .lock()
+        .ok()
+        .and_then(|dependencies| dependencies.get(name).cloned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,298 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::process::Command;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::transfer::finish_transfer;
+
+use super::build_sandbox::run_declared_build;
+use super::child::APP_STATUS_ARRAY;
+use super::deploy_queue::{acquire_deploy_slot, DeploySlot};
+use super::integrity::trust_deployed_binary;
+use super::inventory::record_inventory;
+use super::maintenance::{begin_maintenance, end_maintenance_when_ready};
+use super::migration::run_pending_migration;
+use super::start_stop::restart_application;
+
+/// Hex encoded ed25519 key the portal signs artifacts with
+pub const DEPLOY_KEY_PATH: &str = "/opt/artisan/deploy.pub";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+fn deploy_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_deploy_key() -> Result<VerifyingKey, ErrorArrayItem> {
+    let raw: String = fs::read_to_string(DEPLOY_KEY_PATH)?;
+    let bytes: [u8; 32] = hex::decode(raw.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| deploy_error(format!("{} is not a valid key", DEPLOY_KEY_PATH)))?;
+
+    VerifyingKey::from_bytes(&bytes).map_err(|err| deploy_error(err.to_string()))
+}
+
+/// Checks the portal's signature over a sha256 digest
+pub fn verify_digest(digest: &[u8], signature: &str) -> Result<(), ErrorArrayItem> {
+    let key: VerifyingKey = load_deploy_key()?;
+
+    let signature: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| deploy_error("Artifact signature is malformed".to_owned()))?;
+
+    key.verify(digest, &Signature::from_bytes(&signature))
+        .map_err(|_| {
+            ErrorArrayItem::new(
+                Errors::AuthenticationError,
+                "Artifact signature verification failed",
+            )
+        })
+}
+
+/// The portal signs the sha256 digest of the artifact, the hex digest is
+/// returned and doubles as the version of the artifact
+pub fn verify_artifact(path: &Path, signature: &str) -> Result<String, ErrorArrayItem> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path)?;
+    let mut buffer: Vec<u8> = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let digest = hasher.finalize();
+    verify_digest(&digest, signature)?;
+
+    Ok(hex::encode(digest))
+}
+
+pub fn is_tarball(path: &Path) -> Result<bool, ErrorArrayItem> {
+    let mut magic: [u8; 2] = [0; 2];
+    let read = fs::File::open(path)?.read(&mut magic)?;
+    Ok(read == 2 && magic == GZIP_MAGIC)
+}
+
+/// Tarballs are unpacked into the app's working directory, anything else is
+/// treated as the app binary. The previous binary is kept next to the new one.
+async fn install_artifact(app_id: &Stringy, artifact: &Path) -> Result<(), ErrorArrayItem> {
+    if is_tarball(artifact)? {
+        let working_dir: PathBuf = PathBuf::from(format!("/etc/{}/", app_id));
+        fs::create_dir_all(&working_dir)?;
+
+        let status = Command::new("tar")
+            .arg("-xzf")
+            .arg(artifact)
+            .arg("-C")
+            .arg(&working_dir)
+            .status()
+            .await?;
+
+        if !status.success() {
+            return Err(deploy_error(format!(
+                "Failed to unpack artifact into {}",
+                working_dir.display()
+            )));
+        }
+
+        fs::remove_file(artifact)?;
+    } else {
+        let binary: PathBuf = PathBuf::from(format!("/opt/artisan/bin/{}", app_id));
+        if binary.exists() {
+            fs::rename(&binary, binary.with_extension("previous"))?;
+        }
+
+        fs::rename(artifact, &binary)?;
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Installs a verified artifact, builds unpacked sources, runs any pending
+/// migration and restarts the app. An installed binary is trusted from then
+/// on with the portal's signature. A failed migration puts the previous binary
+/// back and stops the rollout. Waits for a deploy slot before touching anything.
+/// Web apps serve their maintenance page from then until they're ready again.
+pub async fn roll_out(
+    app_id: &Stringy,
+    artifact: &Path,
+    version: &str,
+    signature: &str,
+) -> Result<(), ErrorArrayItem> {
+    let _slot: DeploySlot = acquire_deploy_slot(app_id).await?;
+
+    if let Err(err) = begin_maintenance(app_id, "deploying").await {
+        log!(
+            LogLevel::Warn,
+            "Failed to put up the maintenance page of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    let result: Result<(), ErrorArrayItem> =
+        install_and_restart(app_id, artifact, version, signature).await;
+
+    // Failed or not, whatever runs now decides when the page comes down
+    if let Err(err) = end_maintenance_when_ready(app_id).await {
+        log!(
+            LogLevel::Warn,
+            "Failed to schedule taking down the maintenance page of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    result
+}
+
+/// Rebuilds the app from the sources already in its working directory, as
+/// ais_gitmon leaves them after pulling a change, then migrates and restarts
+/// it. Waits for a deploy slot like an upload does.
+pub async fn rebuild_from_source(app_id: &Stringy, version: &str) -> Result<(), ErrorArrayItem> {
+    let _slot: DeploySlot = acquire_deploy_slot(app_id).await?;
+
+    if let Err(err) = begin_maintenance(app_id, "deploying").await {
+        log!(
+            LogLevel::Warn,
+            "Failed to put up the maintenance page of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    let result: Result<(), ErrorArrayItem> = rebuild_and_restart(app_id, version).await;
+
+    if let Err(err) = end_maintenance_when_ready(app_id).await {
+        log!(
+            LogLevel::Warn,
+            "Failed to schedule taking down the maintenance page of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    result
+}
+
+async fn rebuild_and_restart(app_id: &Stringy, version: &str) -> Result<(), ErrorArrayItem> {
+    run_declared_build(app_id, version).await?;
+
+    if let Err(err) = record_inventory(app_id, version) {
+        log!(
+            LogLevel::Warn,
+            "Failed to record the inventory of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    run_pending_migration(app_id, version).await?;
+    restart_application(app_id).await
+}
+
+async fn install_and_restart(
+    app_id: &Stringy,
+    artifact: &Path,
+    version: &str,
+    signature: &str,
+) -> Result<(), ErrorArrayItem> {
+    let binary_artifact: bool = !is_tarball(artifact)?;
+    install_artifact(app_id, artifact).await?;
+
+    match binary_artifact {
+        // The version is the signed digest of what's now the binary
+        true => trust_deployed_binary(app_id, version, signature)?,
+        false => run_declared_build(app_id, version).await?,
+    }
+
+    // Compliance reporting shouldn't hold up the rollout
+    if let Err(err) = record_inventory(app_id, version) {
+        log!(
+            LogLevel::Warn,
+            "Failed to record the inventory of {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    if let Err(err) = run_pending_migration(app_id, version).await {
+        let binary: PathBuf = PathBuf::from(format!("/opt/artisan/bin/{}", app_id));
+        let previous: PathBuf = binary.with_extension("previous");
+
+        if binary_artifact && previous.exists() {
+            fs::rename(&previous, &binary)?;
+            log!(LogLevel::Warn, "Restored the previous binary of {}", app_id);
+        }
+
+        return Err(err);
+    }
+
+    restart_application(app_id).await
+}
+
+/// Verifies a finished upload and hands the install and restart off to a
+/// background task. Progress is reported on the event bus.
+pub async fn deploy_upload(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    transfer_id: &Stringy,
+    signature: &str,
+) -> Result<(), ErrorArrayItem> {
+    if !APP_STATUS_ARRAY.contains(app_id).await? {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{}, Not registered in the system", app_id),
+        ));
+    }
+
+    let artifact: PathBuf = finish_transfer(app_id, transfer_id).await?;
+
+    let version: String = match verify_artifact(&artifact, signature) {
+        Ok(version) => version,
+        Err(err) => {
+            let _ = fs::remove_file(&artifact);
+            return Err(err);
+        }
+    };
+
+    let gs: Arc<GlobalState> = gs.clone();
+    let app_id: Stringy = app_id.clone();
+    let signature: String = signature.to_owned();
+
+    tokio::spawn(async move {
+        log!(LogLevel::Info, "Deploying uploaded artifact for {}", app_id);
+
+        match roll_out(&app_id, &artifact, &version, &signature).await {
+            Ok(_) => {
+                log!(LogLevel::Info, "Deployed uploaded artifact for {}", app_id);
+                gs.events
+                    .publish(ManagerEvent::DeployFinished { name: app_id });
+            }
+            Err(err) => {
+                log!(LogLevel::Error, "Failed to deploy {}: {}", app_id, err);
+                gs.events.publish(ManagerEvent::DeployFailed {
+                    name: app_id,
+                    reason: err.to_string(),
+                });
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::system::control::{GlobalState, GLOBAL_STATE};
+use crate::system::events::ManagerEvent;
+
+use super::maintenance::{maintenance_windows, MaintenanceWindow};
+use super::priority::{priority_of, PriorityClass};
+
+/// Deploys waiting for a slot, most important first
+static DEPLOY_QUEUE: Lazy<LockWithTimeout<Vec<QueuedDeploy>>> =
+    Lazy::new(|| LockWithTimeout::new(Vec::new()));
+
+static ACTIVE_DEPLOYS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedDeploy {
+    pub ticket: u64,
+    pub name: Stringy,
+    pub priority: PriorityClass,
+    pub queued_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployQueueReport {
+    pub active: usize,
+    pub max_concurrent: usize,
+    pub queued: Vec<QueuedDeploy>,
+    /// Apps behind their maintenance page, while deploying or stopped
+    pub maintenance: HashMap<Stringy, MaintenanceWindow>,
+}
+
+/// Held for the length of a deploy, the slot frees up when it's dropped
+pub struct DeploySlot;
+
+impl Drop for DeploySlot {
+    fn drop(&mut self) {
+        ACTIVE_DEPLOYS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A place in the queue, given up when the deploy waiting on it goes away
+/// before it got a slot
+struct QueueTicket {
+    ticket: u64,
+    granted: bool,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+
+        // The queue's lock can't be awaited here
+        let ticket: u64 = self.ticket;
+        tokio::spawn(async move {
+            match DEPLOY_QUEUE.try_write().await {
+                Ok(mut queue) => queue.retain(|queued| queued.ticket != ticket),
+                Err(err) => log!(
+                    LogLevel::Error,
+                    "Failed to drop deploy ticket {}: {}",
+                    ticket,
+                    err
+                ),
+            }
+        });
+    }
+}
+
+fn max_concurrent() -> usize {
+    match GLOBAL_STATE.get() {
+        Some(gs) => gs.settings.deploy_scheduler.max_concurrent.max(1),
+        None => 1,
+    }
+}
+
+/// Waits until the app is at the front of the queue and a deploy slot is free
+pub async fn acquire_deploy_slot(app_id: &Stringy) -> Result<DeploySlot, ErrorArrayItem> {
+    let ticket: u64 = NEXT_TICKET.fetch_add(1, Ordering::SeqCst);
+    let priority: PriorityClass = priority_of(app_id).await;
+
+    {
+        let mut queue = DEPLOY_QUEUE.try_write().await?;
+
+        // Behind everything of the same or a more important class
+        let position: usize = queue
+            .iter()
+            .position(|queued| queued.priority > priority)
+            .unwrap_or(queue.len());
+
+        queue.insert(
+            position,
+            QueuedDeploy {
+                ticket,
+                name: app_id.clone(),
+                priority,
+                queued_at: current_timestamp(),
+            },
+        );
+
+        if position > 0 || ACTIVE_DEPLOYS.load(Ordering::SeqCst) >= max_concurrent() {
+            log!(
+                LogLevel::Info,
+                "Deploy of {} queued at position {}",
+                app_id,
+                position + 1
+            );
+            if let Some(gs) = GLOBAL_STATE.get() {
+                gs.events.publish(ManagerEvent::DeployQueued {
+                    name: app_id.clone(),
+                    position: position + 1,
+                });
+            }
+        }
+    }
+
+    // Given up on every way out of here but the slot being granted
+    let mut queued: QueueTicket = QueueTicket {
+        ticket,
+        granted: false,
+    };
+
+    loop {
+        {
+            let mut queue = DEPLOY_QUEUE.try_write().await?;
+            let at_front: bool = queue.first().map(|queued| queued.ticket) == Some(ticket);
+
+            if at_front && ACTIVE_DEPLOYS.load(Ordering::SeqCst) < max_concurrent() {
+                queue.remove(0);
+                ACTIVE_DEPLOYS.fetch_add(1, Ordering::SeqCst);
+                queued.granted = true;
+                return Ok(DeploySlot);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+pub async fn deploy_queue(gs: &Arc<GlobalState>) -> Result<DeployQueueReport, ErrorArrayItem> {
+    Ok(DeployQueueReport {
+        active: ACTIVE_DEPLOYS.load(Ordering::SeqCst),
+        max_concurrent: gs.settings.deploy_scheduler.max_concurrent,
+        queued: DEPLOY_QUEUE.try_read().await?.clone(),
+        maintenance: maintenance_windows().await?,
+    })
+}
@@ -0,0 +1,138 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+use crate::system::settings::DescriptorSettings;
+
+/// Latest counts of every app. Metrics comes from the middleware, so they
+/// are kept here and added to the status JSON.
+static COUNTS: Lazy<Mutex<HashMap<String, ProcessCounts>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessCounts {
+    pub pid: u32,
+    pub open_fds: usize,
+    /// Soft `Max open files` of the process, `None` when unlimited
+    pub fd_limit: Option<usize>,
+    pub threads: usize,
+    /// Why the counts are a problem, `None` while they're under the thresholds
+    pub warning: Option<String>,
+    pub sampled_at: u64,
+}
+
+fn count_fds(pid: u32) -> Result<usize, ErrorArrayItem> {
+    Ok(fs::read_dir(format!("/proc/{}/fd", pid))?.count())
+}
+
+fn count_threads(pid: u32) -> Result<usize, ErrorArrayItem> {
+    fs::read_to_string(format!("/proc/{}/status", pid))?
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|threads| threads.trim().parse().ok())
+        .ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("No thread count in /proc/{}/status", pid),
+            )
+        })
+}
+
+/// `Max open files   1024   524288   files`
+fn fd_limit(pid: u32) -> Option<usize> {
+    fs::read_to_string(format!("/proc/{}/limits", pid))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn check_thresholds(settings: &DescriptorSettings, counts: &ProcessCounts) -> Option<String> {
+    if let Some(limit) = counts.fd_limit {
+        let used: f64 = counts.open_fds as f64 / limit.max(1) as f64 * 100.0;
+        if used >= settings.fd_warn_percent {
+            return Some(format!(
+                "{} of {} file descriptors open",
+                counts.open_fds, limit
+            ));
+        }
+    }
+
+    if settings.thread_warn > 0 && counts.threads >= settings.thread_warn {
+        return Some(format!("{} threads running", counts.threads));
+    }
+
+    None
+}
+
+/// Counts the open descriptors and threads of the app's main process
+pub fn sample_process_counts(
+    settings: &DescriptorSettings,
+    name: &Stringy,
+    pid: u32,
+) -> Result<(), ErrorArrayItem> {
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mut counts: ProcessCounts = ProcessCounts {
+        pid,
+        open_fds: count_fds(pid)?,
+        fd_limit: fd_limit(pid),
+        threads: count_threads(pid)?,
+        warning: None,
+        sampled_at: current_timestamp(),
+    };
+    counts.warning = check_thresholds(settings, &counts);
+
+    let mut all = COUNTS
+        .lock()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+    let was_warning: bool = all
+        .get(&name.to_string())
+        .map(|previous| previous.warning.is_some())
+        .unwrap_or(false);
+    if let (Some(warning), false) = (&counts.warning, was_warning) {
+        log!(LogLevel::Warn, "{} may be leaking: {}", name, warning);
+    }
+
+    all.insert(name.to_string(), counts);
+    Ok(())
+}
+
+/// Puts a running app whose counts crossed a threshold into Warning
+pub fn flag_process_counts(name: &Stringy, app: &mut AppStatus) {
+    let warning: String = match process_counts_of(name).and_then(|counts| counts.warning) {
+        Some(warning) => warning,
+        None => return,
+    };
+
+    if app.app_data.get_status() != Status::Running {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    app.app_data.state.error_log.push(ErrorArrayItem::new(
+        Errors::AppState,
+        format!("POSSIBLE LEAK. {}", warning.to_uppercase()),
+    ));
+}
+
+pub fn process_counts_of(name: &str) -> Option<ProcessCounts> {
+    COUNTS
+        .lock()
+        .ok()
+        .and_then(|counts| counts.get(name).cloned())
+}
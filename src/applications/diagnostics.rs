@@ -0,0 +1,125 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::state_persistence::AppState;
+use serde::Serialize;
+use std::fs;
+use std::str::FromStr;
+use tokio::process::Command;
+
+use super::child::APP_STATUS_ARRAY;
+use super::coredump::{list_core_dumps, CoreDump};
+use super::redaction::redactor_for;
+
+/// Lines of journal output handed back for `recent_logs`
+const DIAGNOSTIC_LOG_LINES: u32 = 200;
+
+/// Key fragments that mark an env value as secret
+const SECRET_MARKERS: [&str; 7] = [
+    "SECRET",
+    "PASSWORD",
+    "PASS",
+    "TOKEN",
+    "KEY",
+    "AUTH",
+    "CREDENTIAL",
+];
+
+/// The only things support is allowed to pull off a node
+#[derive(Debug, Clone, Copy)]
+pub enum DiagnosticArtifact {
+    PostMortem,
+    RecentLogs,
+    EnvFile,
+}
+
+impl FromStr for DiagnosticArtifact {
+    type Err = ErrorArrayItem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "post_mortem" => Ok(Self::PostMortem),
+            "recent_logs" => Ok(Self::RecentLogs),
+            "env" => Ok(Self::EnvFile),
+            _ => Err(ErrorArrayItem::new(
+                Errors::Unauthorized,
+                format!("{} is not an allowed diagnostic artifact", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PostMortem {
+    state: AppState,
+    core_dumps: Vec<CoreDump>,
+}
+
+pub async fn fetch_diagnostics(
+    app_id: &Stringy,
+    artifact: DiagnosticArtifact,
+) -> Result<String, ErrorArrayItem> {
+    let state: AppState = match APP_STATUS_ARRAY
+        .read(app_id, |app| app.app_data.get_state())
+        .await?
+    {
+        Some(state) => state,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("{}, Not registered in the system", app_id),
+            ))
+        }
+    };
+
+    match artifact {
+        DiagnosticArtifact::PostMortem => {
+            let post_mortem: PostMortem = PostMortem {
+                state,
+                core_dumps: list_core_dumps(app_id).await?,
+            };
+
+            serde_json::to_string(&post_mortem)
+                .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+        }
+        DiagnosticArtifact::RecentLogs => {
+            let output = Command::new("journalctl")
+                .arg("-u")
+                .arg(format!("{}.service", app_id))
+                .arg("-n")
+                .arg(DIAGNOSTIC_LOG_LINES.to_string())
+                .arg("--no-pager")
+                .output()
+                .await?;
+
+            let logs: String = String::from_utf8_lossy(&output.stdout).to_string();
+            match redactor_for(app_id).await {
+                Some(redactor) => Ok(logs
+                    .lines()
+                    .map(|line| redactor.redact(line))
+                    .collect::<Vec<String>>()
+                    .join("\n")),
+                None => Ok(logs),
+            }
+        }
+        DiagnosticArtifact::EnvFile => {
+            let data: String = fs::read_to_string(format!("/etc/{}/.env", app_id))?;
+            Ok(redact_env(&data))
+        }
+    }
+}
+
+/// Blanks out the value of any variable that looks like it holds a secret
+pub fn redact_env(data: &str) -> String {
+    data.lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if is_secret_key(key) => format!("{}=<redacted>", key),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn is_secret_key(key: &str) -> bool {
+    let key = key.trim().to_uppercase();
+    SECRET_MARKERS.iter().any(|marker| key.contains(marker))
+}
@@ -0,0 +1,289 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::journal::JournalEntry;
+use crate::system::settings::{TelemetryLevel, TenantDigest};
+use crate::system::timezone::{format_timestamp, zone_for, Zone};
+use crate::system::units::{format_bytes, format_percent};
+
+use super::child::APP_STATUS_ARRAY;
+
+/// ais_mailler delivers every message dropped in here
+pub const MAILLER_OUTBOX: &str = "/opt/artisan/mailler/outbox";
+
+/// When each tenant last got a digest, kept across restarts
+const DIGEST_STATE_PATH: &str = "/opt/artisan/digests.json";
+
+/// Resource usage sampled between digests
+static USAGE_SAMPLES: Lazy<LockWithTimeout<HashMap<Stringy, UsageSample>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default)]
+struct UsageSample {
+    cpu_total: f64,
+    peak_memory: f64,
+    samples: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutgoingMail {
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Digest {
+    pub uptime_percent: f64,
+    pub deploys: usize,
+    pub failed_deploys: usize,
+    pub incidents: Vec<String>,
+}
+
+/// Folds the tenant's journal entries over the period into a digest
+pub fn summarize(entries: &[&JournalEntry], since: u64, now: u64) -> Digest {
+    let mut digest: Digest = Digest::default();
+    let mut down_since: Option<u64> = None;
+    let mut downtime: u64 = 0;
+
+    for entry in entries {
+        match &entry.event {
+            ManagerEvent::AppDied { .. } => {
+                down_since.get_or_insert(entry.timestamp);
+            }
+            ManagerEvent::AppStarted { .. } => {
+                if let Some(died) = down_since.take() {
+                    downtime += entry.timestamp.saturating_sub(died);
+                }
+            }
+            ManagerEvent::DeployFinished { .. } => digest.deploys += 1,
+            ManagerEvent::DeployFailed { .. } | ManagerEvent::CanaryRolledBack { .. } => {
+                digest.failed_deploys += 1
+            }
+            _ => (),
+        }
+
+        let incident: bool = matches!(
+            entry.event,
+            ManagerEvent::AppDied { .. }
+                | ManagerEvent::CrashLoop { .. }
+                | ManagerEvent::RestartsExhausted { .. }
+                | ManagerEvent::DeployFailed { .. }
+                | ManagerEvent::CanaryRolledBack { .. }
+                | ManagerEvent::PortExhaustion { .. }
+                | ManagerEvent::SecurityFinding { .. }
+                | ManagerEvent::EgressBlocked { .. }
+        );
+        if incident {
+            digest.incidents.push(entry.event.to_string());
+        }
+    }
+
+    if let Some(died) = down_since {
+        downtime += now.saturating_sub(died);
+    }
+
+    let period: u64 = now.saturating_sub(since).max(1);
+    digest.uptime_percent = 100.0 * period.saturating_sub(downtime) as f64 / period as f64;
+    digest
+}
+
+fn compose(
+    app_id: &Stringy,
+    since: u64,
+    now: u64,
+    digest: &Digest,
+    usage: Option<&UsageSample>,
+) -> String {
+    let mut body: String = String::new();
+
+    let _ = writeln!(
+        body,
+        "Health report for {} from {} to {}\n",
+        app_id,
+        format_timestamp(since, Some(app_id.as_str())),
+        format_timestamp(now, Some(app_id.as_str()))
+    );
+    let _ = writeln!(body, "Uptime: {}", format_percent(digest.uptime_percent));
+
+    if let Some(usage) = usage.filter(|usage| usage.samples > 0) {
+        let _ = writeln!(
+            body,
+            "Average cpu: {}",
+            format_percent(usage.cpu_total / usage.samples as f64)
+        );
+        let _ = writeln!(
+            body,
+            "Peak memory: {}",
+            format_bytes(usage.peak_memory as u64)
+        );
+    }
+
+    let _ = writeln!(
+        body,
+        "Deploys: {} succeeded, {} failed",
+        digest.deploys, digest.failed_deploys
+    );
+
+    match digest.incidents.is_empty() {
+        true => {
+            let _ = writeln!(body, "\nNo incidents.");
+        }
+        false => {
+            let _ = writeln!(body, "\nIncidents:");
+            for incident in &digest.incidents {
+                let _ = writeln!(body, "  - {}", incident);
+            }
+        }
+    }
+
+    body
+}
+
+/// Drops a message in the ais_mailler outbox, `name` only has to be unique
+/// for the second it's sent in
+pub fn queue_mail(name: &str, mail: &OutgoingMail) -> Result<(), ErrorArrayItem> {
+    fs::create_dir_all(MAILLER_OUTBOX)?;
+
+    let data: String = serde_json::to_string(mail)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+    // Written aside and renamed so the mailer never picks up half a message
+    let name: String = format!("{}-{}", name, current_timestamp());
+    let temp: PathBuf = Path::new(MAILLER_OUTBOX).join(format!(".{}", name));
+    fs::write(&temp, data)?;
+    fs::rename(
+        &temp,
+        Path::new(MAILLER_OUTBOX).join(format!("{}.json", name)),
+    )?;
+
+    Ok(())
+}
+
+fn load_last_sent() -> HashMap<String, u64> {
+    fs::read_to_string(DIGEST_STATE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Folds the current metrics of every app into the running usage samples
+async fn sample_usage() -> Result<(), ErrorArrayItem> {
+    let mut samples = USAGE_SAMPLES.try_write().await?;
+
+    for (name, app) in APP_STATUS_ARRAY.entries().await? {
+        if let Some(metrics) = &app.try_read().await?.metrics {
+            let sample: &mut UsageSample = samples.entry(name).or_default();
+            sample.cpu_total += metrics.cpu_usage as f64;
+            sample.peak_memory = sample.peak_memory.max(metrics.memory_usage as f64);
+            sample.samples += 1;
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_digest(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    tenant: &TenantDigest,
+    since: u64,
+) -> Result<(), ErrorArrayItem> {
+    let now: u64 = current_timestamp();
+    let entries: Vec<JournalEntry> = gs.journal.since(0, usize::MAX)?;
+    let relevant: Vec<&JournalEntry> = entries
+        .iter()
+        .filter(|entry| entry.timestamp >= since && entry.event.app_name() == Some(app_id))
+        .collect();
+
+    let digest: Digest = summarize(&relevant, since, now);
+    let usage: Option<UsageSample> = USAGE_SAMPLES
+        .try_write()
+        .await?
+        .remove(app_id)
+        // The mail leaves the node like any other telemetry
+        .filter(|_| gs.settings.telemetry.level_for(app_id.as_str()) != TelemetryLevel::Nothing);
+
+    queue_mail(
+        &format!("digest-{}", app_id),
+        &OutgoingMail {
+            to: tenant.recipients.clone(),
+            subject: format!("Health report for {}", app_id),
+            body: compose(app_id, since, now, &digest, usage.as_ref()),
+        },
+    )?;
+
+    log!(
+        LogLevel::Info,
+        "Queued the health digest of {} for {} recipients",
+        app_id,
+        tenant.recipients.len()
+    );
+    Ok(())
+}
+
+/// Samples resource usage and queues a digest for every tenant whose
+/// interval has passed. Intervals roll over at midnight in the tenant's
+/// timezone, not whenever the last digest happened to go out.
+pub async fn send_health_digests(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    if gs.settings.digest.tenants.is_empty() {
+        return Ok(());
+    }
+
+    sample_usage().await?;
+
+    let now: u64 = current_timestamp();
+    let mut last_sent: HashMap<String, u64> = load_last_sent();
+    let mut changed: bool = false;
+
+    for (name, tenant) in &gs.settings.digest.tenants {
+        if !tenant.enabled || tenant.recipients.is_empty() {
+            continue;
+        }
+
+        let zone: Zone = zone_for(Some(name));
+        // A new tenant gets its first digest after a full period
+        let since: u64 = *last_sent.entry(name.clone()).or_insert_with(|| {
+            changed = true;
+            now
+        });
+
+        if zone.local_day(now) < zone.local_day(since) + tenant.interval_days.max(1) as i64 {
+            continue;
+        }
+
+        match send_digest(gs, &Stringy::from(name.as_str()), tenant, since).await {
+            Ok(_) => {
+                last_sent.insert(name.clone(), now);
+                changed = true;
+            }
+            Err(err) => log!(
+                LogLevel::Warn,
+                "Failed to send the digest of {}: {}",
+                name,
+                err
+            ),
+        }
+    }
+
+    if changed {
+        let data: String = serde_json::to_string(&last_sent)
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        fs::write(DIGEST_STATE_PATH, data)?;
+    }
+
+    Ok(())
+}
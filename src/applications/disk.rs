@@ -0,0 +1,155 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::system::settings::DiskUsageSettings;
+use crate::system::units::format_bytes;
+
+use super::child::{CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+
+/// Last sample of every app, walking the directories on every pass of the
+/// monitor loop would cost more than the apps themselves
+static DISK_USAGE: Lazy<Mutex<HashMap<String, DiskUsage>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    pub config_bytes: u64,
+    pub binary_bytes: u64,
+    pub data_bytes: u64,
+    pub total_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub sampled_at: u64,
+}
+
+impl DiskUsage {
+    pub fn over_quota(&self) -> bool {
+        matches!(self.quota_bytes, Some(quota) if self.total_bytes > quota)
+    }
+}
+
+/// Size of a file or everything below a directory, symlinks aren't followed
+fn path_size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| path_size(&entry.path()))
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+async fn binary_path(name: &Stringy) -> Result<Option<PathBuf>, ErrorArrayItem> {
+    if let Some(path) = CLIENT_APPLICATION_ARRAY
+        .read(name, |client| client.path.to_path_buf())
+        .await?
+    {
+        return Ok(Some(path));
+    }
+
+    SYSTEM_APPLICATION_ARRAY
+        .read(name, |system| system.path.to_path_buf())
+        .await
+}
+
+/// Measures the app's config, binary and data directory unless the last
+/// sample is still fresh
+pub async fn sample_disk_usage(
+    settings: &DiskUsageSettings,
+    name: &Stringy,
+) -> Result<(), ErrorArrayItem> {
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let now: u64 = current_timestamp();
+    let fresh: bool = DISK_USAGE
+        .lock()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?
+        .get(&name.to_string())
+        .map(|usage| now.saturating_sub(usage.sampled_at) < settings.refresh_secs)
+        .unwrap_or(false);
+    if fresh {
+        return Ok(());
+    }
+
+    let config_bytes: u64 = path_size(Path::new(&format!("/etc/{}", name)));
+    let binary_bytes: u64 = binary_path(name)
+        .await?
+        .map(|path| path_size(&path))
+        .unwrap_or(0);
+    let data_bytes: u64 = path_size(Path::new(&settings.data_dir_for(name)));
+
+    let usage: DiskUsage = DiskUsage {
+        config_bytes,
+        binary_bytes,
+        data_bytes,
+        total_bytes: config_bytes + binary_bytes + data_bytes,
+        quota_bytes: settings.quota_for(name),
+        sampled_at: now,
+    };
+
+    if usage.over_quota() {
+        log!(
+            LogLevel::Warn,
+            "{} uses {} of disk, over its quota of {}",
+            name,
+            format_bytes(usage.total_bytes),
+            format_bytes(usage.quota_bytes.unwrap_or_default())
+        );
+    }
+
+    DISK_USAGE
+        .lock()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?
+        .insert(name.to_string(), usage);
+
+    Ok(())
+}
+
+/// Puts an app over its disk quota into Warning
+pub fn flag_disk_quota(name: &Stringy, app: &mut AppStatus) {
+    let usage: DiskUsage = match disk_usage_of(name) {
+        Some(usage) if usage.over_quota() => usage,
+        _ => return,
+    };
+
+    if app.app_data.get_status() != Status::Running {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    app.app_data.state.error_log.push(ErrorArrayItem::new(
+        Errors::AppState,
+        format!(
+            "DISK QUOTA EXCEEDED. USING {} OF {}",
+            format_bytes(usage.total_bytes),
+            format_bytes(usage.quota_bytes.unwrap_or_default())
+        ),
+    ));
+}
+
+pub fn disk_usage_of(name: &str) -> Option<DiskUsage> {
+    DISK_USAGE
+        .lock()
+        .ok()
+        .and_then(|usage| usage.get(name).cloned())
+}
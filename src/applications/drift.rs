@@ -0,0 +1,210 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+use super::child::{CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+
+/// Golden templates live in a directory per app class, `client` covers every
+/// client application and system apps use their own name. A directory named
+/// after a single app overrides its class.
+pub const TEMPLATE_DIR: &str = "/opt/artisan/templates";
+
+/// Latest drift report for every application that has a template
+pub static DRIFT_REPORTS: Lazy<LockWithTimeout<HashMap<Stringy, DriftReport>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileDrift {
+    pub file: String,
+    pub missing_lines: usize,
+    pub extra_lines: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub app: Stringy,
+    pub template: String,
+    pub missing_files: Vec<String>,
+    pub drifted_files: Vec<FileDrift>,
+    pub checked_at: u64,
+}
+
+impl DriftReport {
+    pub fn has_drift(&self) -> bool {
+        !self.missing_files.is_empty() || !self.drifted_files.is_empty()
+    }
+}
+
+fn template_for(app_name: &str, class: &str) -> Option<PathBuf> {
+    [app_name, class]
+        .iter()
+        .map(|name| Path::new(TEMPLATE_DIR).join(name))
+        .find(|path| path.is_dir())
+}
+
+/// Lines that matter for comparison, `KEY=*` or `key = "*"` in a template
+/// accepts any value for that key
+fn significant_lines(data: &str) -> Vec<String> {
+    data.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn wildcard_key(line: &str) -> Option<&str> {
+    let (key, value) = line.split_once('=')?;
+    match value.trim().trim_matches('"') {
+        "*" => Some(key.trim()),
+        _ => None,
+    }
+}
+
+fn compare_file(file: &str, template: &str, actual: &str) -> Option<FileDrift> {
+    let template_lines: Vec<String> = significant_lines(template);
+    let actual_lines: Vec<String> = significant_lines(actual);
+    let actual_set: HashSet<&String> = actual_lines.iter().collect();
+
+    let mut matched: HashSet<&String> = HashSet::new();
+    let mut missing_lines: usize = 0;
+
+    for line in &template_lines {
+        let found = match wildcard_key(line) {
+            Some(key) => actual_lines.iter().find(|actual| {
+                actual
+                    .split_once('=')
+                    .map_or(false, |(actual_key, _)| actual_key.trim() == key)
+            }),
+            None => actual_set.get(line).copied(),
+        };
+
+        match found {
+            Some(actual) => {
+                matched.insert(actual);
+            }
+            None => missing_lines += 1,
+        }
+    }
+
+    let extra_lines: usize = actual_lines
+        .iter()
+        .filter(|line| !matched.contains(line))
+        .count();
+
+    if missing_lines == 0 && extra_lines == 0 {
+        return None;
+    }
+
+    Some(FileDrift {
+        file: file.to_string(),
+        missing_lines,
+        extra_lines,
+    })
+}
+
+fn check_app(app_name: &str, class: &str) -> Result<Option<DriftReport>, ErrorArrayItem> {
+    let template: PathBuf = match template_for(app_name, class) {
+        Some(template) => template,
+        None => return Ok(None),
+    };
+
+    let mut report: DriftReport = DriftReport {
+        app: app_name.into(),
+        template: template.display().to_string(),
+        missing_files: Vec::new(),
+        drifted_files: Vec::new(),
+        checked_at: current_timestamp(),
+    };
+
+    for entry in fs::read_dir(&template)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let file: String = entry.file_name().to_string_lossy().to_string();
+        let expected: String = fs::read_to_string(entry.path())?;
+
+        match fs::read_to_string(format!("/etc/{}/{}", app_name, file)) {
+            Ok(actual) => {
+                if let Some(drift) = compare_file(&file, &expected, &actual) {
+                    report.drifted_files.push(drift);
+                }
+            }
+            Err(_) => report.missing_files.push(file),
+        }
+    }
+
+    Ok(Some(report))
+}
+
+/// Compares every application's config directory against its golden template
+pub async fn detect_config_drift(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let mut apps: Vec<(Stringy, String)> = Vec::new();
+
+    for name in SYSTEM_APPLICATION_ARRAY.keys().await? {
+        apps.push((name.clone(), name.to_string()));
+    }
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        apps.push((name, "client".to_owned()));
+    }
+
+    for (name, class) in apps {
+        let report: DriftReport = match check_app(&name, &class) {
+            Ok(Some(report)) => report,
+            Ok(None) => continue,
+            Err(err) => {
+                log!(
+                    LogLevel::Warn,
+                    "Failed to check {} for drift: {}",
+                    name,
+                    err
+                );
+                continue;
+            }
+        };
+
+        let mut reports = DRIFT_REPORTS.try_write().await?;
+        let was_drifted: bool = reports.get(&name).map_or(false, |old| old.has_drift());
+
+        if report.has_drift() && !was_drifted {
+            log!(LogLevel::Warn, "Config drift detected for {}", name);
+            gs.events.publish(ManagerEvent::ConfigDrift {
+                name: name.clone(),
+                files: report
+                    .missing_files
+                    .iter()
+                    .cloned()
+                    .chain(report.drifted_files.iter().map(|drift| drift.file.clone()))
+                    .collect(),
+            });
+        }
+
+        reports.insert(name, report);
+    }
+
+    Ok(())
+}
+
+pub async fn drift_reports() -> Result<Vec<DriftReport>, ErrorArrayItem> {
+    Ok(DRIFT_REPORTS
+        .try_read()
+        .await?
+        .values()
+        .filter(|report| report.has_drift())
+        .cloned()
+        .collect())
+}
@@ -0,0 +1,298 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+use crate::system::ebpf::{BandwidthTracker, EgressNetwork};
+use crate::system::events::ManagerEvent;
+
+/// Where every app with an allowlist stands, added to the status JSON as
+/// `egress`
+static REPORTS: Lazy<Mutex<HashMap<String, EgressReport>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Addresses allowed domains resolved to, with when they were looked up
+static RESOLVED: Lazy<Mutex<HashMap<String, (u64, Vec<IpAddr>)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Drop counts of every service and destination as of the last pass, only
+/// what came on top of them is reported
+static BLOCKED: Lazy<Mutex<HashMap<(String, IpAddr), u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Declared as an `[egress]` table in /etc/{app}/Config.toml. Anything the
+/// app sends outside of `allow` is dropped, loopback and the node's
+/// nameservers are always reachable.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgressPolicy {
+    /// CIDRs, plain addresses or domains, domains are looked up again every
+    /// `resolve_secs`
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EgressReport {
+    pub allow: Vec<String>,
+    /// Networks handed to the egress program once domains were resolved
+    pub networks: usize,
+    /// Entries that are neither a network nor a domain that resolves
+    pub unresolved: Vec<String>,
+    /// Whether the egress program drops what isn't allowed, the allowlist is
+    /// only reported without it
+    pub enforced: bool,
+    /// Packets dropped since the program was attached
+    pub blocked_packets: u64,
+    pub last_blocked: Option<BlockedDestination>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedDestination {
+    pub destination: IpAddr,
+    pub packets: u64,
+    pub at: u64,
+}
+
+fn egress_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn declared_policy(app_id: &str) -> Option<EgressPolicy> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config.get("egress")?.clone().try_into::<EgressPolicy>() {
+        Ok(policy) => Some(policy),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid egress allowlist: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// `10.0.0.0/8` or a plain address, with the host bits cleared. `None` for
+/// anything else, which is taken for a domain.
+fn parse_network(entry: &str) -> Option<EgressNetwork> {
+    let (address, prefix): (IpAddr, Option<u8>) = match entry.split_once('/') {
+        Some((address, prefix)) => (address.parse().ok()?, Some(prefix.parse().ok()?)),
+        None => (entry.parse().ok()?, None),
+    };
+
+    match address {
+        IpAddr::V4(address) => {
+            let prefix: u8 = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return None;
+            }
+            let mask: u32 = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            Some((
+                IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask)),
+                prefix,
+            ))
+        }
+        IpAddr::V6(address) => {
+            let prefix: u8 = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return None;
+            }
+            let mask: u128 = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            Some((
+                IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask)),
+                prefix,
+            ))
+        }
+    }
+}
+
+/// Looks the domain up unless that was done within `resolve_secs`. The last
+/// answer is kept when a lookup fails.
+async fn resolve(domain: &str, resolve_secs: u64) -> Vec<IpAddr> {
+    let now: u64 = current_timestamp();
+    let cached: Option<(u64, Vec<IpAddr>)> = RESOLVED
+        .lock()
+        .ok()
+        .and_then(|resolved| resolved.get(domain).cloned());
+
+    if let Some((resolved_at, addresses)) = &cached {
+        if now < resolved_at + resolve_secs {
+            return addresses.clone();
+        }
+    }
+
+    match tokio::net::lookup_host((domain, 0)).await {
+        Ok(found) => {
+            let mut addresses: Vec<IpAddr> = found.map(|address| address.ip()).collect();
+            addresses.sort();
+            addresses.dedup();
+            if let Ok(mut resolved) = RESOLVED.lock() {
+                resolved.insert(domain.to_owned(), (now, addresses.clone()));
+            }
+            addresses
+        }
+        Err(err) => {
+            log!(
+                LogLevel::Warn,
+                "Can't resolve {} for an egress allowlist: {}",
+                domain,
+                err
+            );
+            cached.map(|(_, addresses)| addresses).unwrap_or_default()
+        }
+    }
+}
+
+/// Apps with an allowlist still have to look their domains up
+fn nameservers() -> Vec<EgressNetwork> {
+    fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| parse_network(address.trim()))
+        .collect()
+}
+
+/// Hands the allowlist of every service in artisan.slice to the egress
+/// program and reports what it dropped since the last pass. Cgroups change
+/// on every restart, so this runs on every pass of the tracking loop.
+pub async fn enforce_egress_allowlists(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let cgroups: HashMap<u64, String> = BandwidthTracker::service_cgroup_map()?;
+    let resolvers: Vec<EgressNetwork> = nameservers();
+    let enforced: bool = gs.network_monitor.enforces_egress();
+
+    let mut allowlists: HashMap<u64, Vec<EgressNetwork>> = HashMap::new();
+    let mut reports: HashMap<String, EgressReport> = HashMap::new();
+    for (cgroup, service_name) in &cgroups {
+        let policy: EgressPolicy = match declared_policy(service_name) {
+            Some(policy) => policy,
+            None => continue,
+        };
+
+        let mut networks: Vec<EgressNetwork> = resolvers.clone();
+        let mut unresolved: Vec<String> = Vec::new();
+        for entry in &policy.allow {
+            if let Some(network) = parse_network(entry) {
+                networks.push(network);
+                continue;
+            }
+
+            let addresses: Vec<IpAddr> = resolve(entry, gs.settings.egress.resolve_secs).await;
+            if addresses.is_empty() {
+                unresolved.push(entry.clone());
+            }
+            networks.extend(
+                addresses
+                    .into_iter()
+                    .filter_map(|address| parse_network(&address.to_string())),
+            );
+        }
+        networks.sort();
+        networks.dedup();
+
+        reports.insert(
+            service_name.clone(),
+            EgressReport {
+                allow: policy.allow,
+                networks: networks.len(),
+                unresolved,
+                enforced,
+                blocked_packets: 0,
+                last_blocked: None,
+            },
+        );
+        allowlists.insert(*cgroup, networks);
+    }
+
+    gs.network_monitor
+        .sync_egress_allowlists(&allowlists)
+        .await?;
+
+    let blocked: HashMap<u64, Vec<(IpAddr, u64)>> = gs.network_monitor.blocked_egress().await?;
+    let previous: HashMap<String, EgressReport> = REPORTS
+        .lock()
+        .map_err(|err| egress_error(err.to_string()))?
+        .clone();
+    let mut seen = BLOCKED
+        .lock()
+        .map_err(|err| egress_error(err.to_string()))?;
+    let now: u64 = current_timestamp();
+
+    for (cgroup, destinations) in blocked {
+        let service_name: &String = match cgroups.get(&cgroup) {
+            Some(service_name) => service_name,
+            None => continue,
+        };
+        let report: &mut EgressReport = match reports.get_mut(service_name) {
+            Some(report) => report,
+            None => continue,
+        };
+
+        for (destination, count) in destinations {
+            report.blocked_packets += count;
+
+            // The counters start over with the program
+            let before: u64 = seen
+                .insert((service_name.clone(), destination), count)
+                .unwrap_or(0);
+            let packets: u64 = match count >= before {
+                true => count - before,
+                false => count,
+            };
+            if packets == 0 {
+                continue;
+            }
+
+            log!(
+                LogLevel::Warn,
+                "Dropped {} packets {} sent to {}, it's not in its egress allowlist",
+                packets,
+                service_name,
+                destination
+            );
+            gs.events.publish(ManagerEvent::EgressBlocked {
+                name: Stringy::from(service_name.as_str()),
+                destination: destination.to_string(),
+                packets,
+            });
+            report.last_blocked = Some(BlockedDestination {
+                destination,
+                packets,
+                at: now,
+            });
+        }
+    }
+
+    // Carried over for apps that got no new drops this pass
+    for (service_name, report) in reports.iter_mut() {
+        if report.last_blocked.is_none() {
+            report.last_blocked = previous
+                .get(service_name)
+                .and_then(|report| report.last_blocked.clone());
+        }
+    }
+    seen.retain(|(service_name, _), _| reports.contains_key(service_name));
+    drop(seen);
+
+    *REPORTS
+        .lock()
+        .map_err(|err| egress_error(err.to_string()))? = reports;
+    Ok(())
+}
+
+pub fn egress_of(app_id: &str) -> Option<EgressReport> {
+    REPORTS
+        .lock()
+        .ok()
+        .and_then(|reports| reports.get(app_id).cloned())
+}
@@ -0,0 +1,238 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::state_persistence::AppState;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::{ChangeAction, GitmonSettings};
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+use super::deploy::rebuild_from_source;
+use super::resolve::git_project_ids;
+
+/// The last change ais_gitmon reported for every app
+static CHANGES: Lazy<Mutex<HashMap<String, RepositoryChange>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Apps with a rebuild running, with the commit to rebuild once it's done
+/// when another change came in meanwhile
+static REBUILDS: Lazy<Mutex<HashMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepositoryChange {
+    pub branch: Option<String>,
+    pub commit: String,
+    pub received_at: u64,
+    pub actions: Vec<ChangeAction>,
+    /// Why the actions were cut short, `None` when all of them went ahead
+    pub error: Option<String>,
+}
+
+fn gitmon_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// The app's own `[gitmon] actions` from its Config.toml, if it picked any
+fn declared_actions(app_id: &str) -> Option<Vec<ChangeAction>> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+    let actions: toml::Value = config.get("gitmon")?.get("actions")?.clone();
+
+    match actions.try_into::<Vec<ChangeAction>>() {
+        Ok(actions) => Some(actions),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares invalid gitmon actions: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Whether the app's project is still in the git credentials file
+async fn revalidate_credentials(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+) -> Result<(), ErrorArrayItem> {
+    let app_state: AppState = gs.get_state_clone().await?;
+    let project: Stringy = Stringy::from(app_id.replace("ais_", ""));
+
+    match git_project_ids(&app_state).await?.contains(&project) {
+        true => Ok(()),
+        false => Err(gitmon_error(format!(
+            "{} isn't in the git credentials anymore",
+            app_id
+        ))),
+    }
+}
+
+/// Rebuilds in the background. A change reported while a rebuild runs is
+/// picked up once it's done, only its latest commit is built.
+fn queue_rebuild(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    commit: &str,
+) -> Result<(), ErrorArrayItem> {
+    {
+        let mut rebuilds = REBUILDS
+            .lock()
+            .map_err(|err| gitmon_error(err.to_string()))?;
+        if let Some(next) = rebuilds.get_mut(app_id.as_str()) {
+            *next = Some(commit.to_owned());
+            log!(
+                LogLevel::Info,
+                "{} is already rebuilding, {} is built after it",
+                app_id,
+                commit
+            );
+            return Ok(());
+        }
+        rebuilds.insert(app_id.to_string(), None);
+    }
+
+    let gs: Arc<GlobalState> = gs.clone();
+    let app_id: Stringy = app_id.clone();
+    let mut commit: String = commit.to_owned();
+
+    tokio::spawn(async move {
+        loop {
+            log!(LogLevel::Info, "Rebuilding {} at {}", app_id, commit);
+            match rebuild_from_source(&app_id, &commit).await {
+                Ok(_) => {
+                    log!(LogLevel::Info, "Rebuilt {} at {}", app_id, commit);
+                    gs.events.publish(ManagerEvent::DeployFinished {
+                        name: app_id.clone(),
+                    });
+                }
+                Err(err) => {
+                    log!(LogLevel::Error, "Failed to rebuild {}: {}", app_id, err);
+                    gs.events.publish(ManagerEvent::DeployFailed {
+                        name: app_id.clone(),
+                        reason: err.to_string(),
+                    });
+                }
+            }
+
+            let next: Option<String> = match REBUILDS.lock() {
+                Ok(mut rebuilds) => {
+                    match rebuilds.get_mut(app_id.as_str()).and_then(Option::take) {
+                        Some(next) => Some(next),
+                        None => {
+                            rebuilds.remove(app_id.as_str());
+                            None
+                        }
+                    }
+                }
+                Err(_) => None,
+            };
+            match next {
+                Some(next) => commit = next,
+                None => break,
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Acts on a change ais_gitmon reported, returns what it set off
+pub async fn handle_repository_change(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    branch: Option<String>,
+    commit: String,
+) -> Result<Vec<ChangeAction>, ErrorArrayItem> {
+    let settings: &GitmonSettings = &gs.settings.gitmon;
+    if !settings.enabled {
+        return Err(gitmon_error(String::from(
+            "Changes from ais_gitmon are turned off on this node",
+        )));
+    }
+    if !CLIENT_APPLICATION_ARRAY.contains(app_id).await? {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{}, Not registered in the system", app_id),
+        ));
+    }
+
+    let actions: Vec<ChangeAction> =
+        declared_actions(app_id.as_str()).unwrap_or_else(|| settings.actions.clone());
+    log!(
+        LogLevel::Info,
+        "ais_gitmon reported {} for {}, acting on it with {:?}",
+        commit,
+        app_id,
+        actions
+    );
+
+    gs.events.publish(ManagerEvent::RepositoryChanged {
+        name: app_id.clone(),
+        commit: commit.clone(),
+        alert: actions.contains(&ChangeAction::Alert),
+    });
+
+    let mut result: Result<(), ErrorArrayItem> = Ok(());
+    if actions.contains(&ChangeAction::RevalidateCredentials) {
+        result = revalidate_credentials(gs, app_id).await;
+    }
+
+    if actions.contains(&ChangeAction::QueueDeploy) {
+        let decision = Decision::new(
+            "gitmon",
+            Some(app_id),
+            "rebuild",
+            String::from("the repository changed"),
+        )
+        .input("commit", &commit)
+        .input("branch", &branch);
+
+        result = match result {
+            Ok(_) => {
+                decision.record();
+                queue_rebuild(gs, app_id, &commit)
+            }
+            Err(err) => {
+                Decision {
+                    action: String::from("skip rebuild"),
+                    reason: err.to_string(),
+                    ..decision
+                }
+                .record();
+                Err(err)
+            }
+        };
+    }
+
+    let change: RepositoryChange = RepositoryChange {
+        branch,
+        commit,
+        received_at: current_timestamp(),
+        actions: actions.clone(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    if let Ok(mut changes) = CHANGES.lock() {
+        changes.insert(app_id.to_string(), change);
+    }
+
+    result.map(|_| actions)
+}
+
+pub fn repository_change_of(app_id: &str) -> Option<RepositoryChange> {
+    CHANGES
+        .lock()
+        .ok()
+        .and_then(|changes| changes.get(app_id).cloned())
+}
@@ -0,0 +1,118 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::CrashLoopSettings;
+use crate::system::systemd_bus;
+
+use super::child::APP_STATUS_ARRAY;
+
+/// When each application was (re)started within the crash loop window
+static START_HISTORY: Lazy<LockWithTimeout<HashMap<Stringy, VecDeque<u64>>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// Applications the circuit breaker tripped on, they're stopped and left
+/// alone until someone starts them by hand
+static TRIPPED: Lazy<LockWithTimeout<HashSet<Stringy>>> =
+    Lazy::new(|| LockWithTimeout::new(HashSet::new()));
+
+pub async fn is_tripped(app_id: &Stringy) -> bool {
+    match TRIPPED.try_read().await {
+        Ok(tripped) => tripped.contains(app_id),
+        Err(_) => false,
+    }
+}
+
+/// Closes the breaker again, called when an application is started manually
+pub async fn reset_breaker(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    if TRIPPED.try_write().await?.remove(app_id) {
+        log!(LogLevel::Info, "Reset the crash loop breaker of {}", app_id);
+    }
+    START_HISTORY.try_write().await?.remove(app_id);
+    Ok(())
+}
+
+/// Records a start of the application and trips the breaker when it keeps
+/// coming back up within the window. Returns true if the breaker tripped.
+pub async fn record_start(gs: &Arc<GlobalState>, app_id: &Stringy) -> Result<bool, ErrorArrayItem> {
+    let settings: &CrashLoopSettings = &gs.settings.crash_loop;
+    let now: u64 = current_timestamp();
+
+    let restarts: usize = {
+        let mut history = START_HISTORY.try_write().await?;
+        let starts: &mut VecDeque<u64> = history.entry(app_id.clone()).or_default();
+
+        starts.push_back(now);
+        while starts
+            .front()
+            .map_or(false, |started| started + settings.window_secs < now)
+        {
+            starts.pop_front();
+        }
+
+        // The first start in the window isn't a restart
+        starts.len().saturating_sub(1)
+    };
+
+    if restarts <= settings.max_restarts {
+        return Ok(false);
+    }
+
+    TRIPPED.try_write().await?.insert(app_id.clone());
+
+    // Left running it would carry on unsupervised
+    if let Err(err) = systemd_bus::stop_unit(app_id).await {
+        log!(
+            LogLevel::Error,
+            "Failed to stop crash looping {}: {}",
+            app_id,
+            err
+        );
+    }
+
+    let from: Option<Status> = APP_STATUS_ARRAY
+        .modify(app_id, |app| {
+            let from: Status = app.app_data.get_status();
+            app.app_data.set_status(Status::Failed);
+            from
+        })
+        .await?;
+    if let Some(from) = from {
+        gs.events.status_changed(app_id, from, Status::Failed);
+    }
+
+    log!(
+        LogLevel::Error,
+        "{} restarted {} times in {}s, no longer reclaiming it",
+        app_id,
+        restarts,
+        settings.window_secs
+    );
+    gs.events.publish(ManagerEvent::CrashLoop {
+        name: app_id.clone(),
+        restarts,
+        window_secs: settings.window_secs,
+    });
+    Decision::new(
+        "crash_loop",
+        Some(app_id),
+        "stop reclaiming",
+        String::from("it restarted more often than the crash loop window allows"),
+    )
+    .input("restarts", restarts)
+    .input("max_restarts", settings.max_restarts)
+    .input("window_secs", settings.window_secs)
+    .record();
+
+    Ok(true)
+}
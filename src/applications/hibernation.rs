@@ -0,0 +1,437 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::task::AbortHandle;
+use tokio::time::sleep;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::ebpf::TrafficStats;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::HibernationSettings;
+use crate::system::systemd_bus;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+use super::pause::is_paused;
+use super::start_stop::{start_application, stop_application};
+
+/// Where every app that hibernates stands, added to the status JSON as
+/// `hibernation`
+static REPORTS: Lazy<Mutex<HashMap<String, HibernationReport>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Traffic counters of every awake app as of the last pass, with when they
+/// last moved
+static ACTIVITY: Lazy<Mutex<HashMap<String, (u64, u64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Shims holding the port of a hibernated app
+static SHIMS: Lazy<Mutex<HashMap<String, AbortHandle>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Declared as a `[hibernation]` table in /etc/{app}/Config.toml. Only apps
+/// that declare one are put to sleep, the manager has to know the port to
+/// wake them on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HibernationConfig {
+    /// Address the app serves on, held by the manager while it sleeps
+    pub listen: SocketAddr,
+    /// No traffic for this long puts the app to sleep
+    #[serde(default = "default_idle")]
+    pub idle_secs: u64,
+    /// How long the first connection waits for the app to come up
+    #[serde(default = "default_wake_timeout")]
+    pub wake_timeout_secs: u64,
+}
+
+fn default_idle() -> u64 {
+    1800
+}
+
+fn default_wake_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HibernationState {
+    Awake,
+    Hibernated,
+    /// A connection came in and the app is being started for it
+    Waking,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HibernationReport {
+    pub state: HibernationState,
+    pub listen: SocketAddr,
+    pub idle_secs: u64,
+    /// When the app last sent or received anything
+    pub last_active: Option<u64>,
+    pub hibernated_at: Option<u64>,
+    pub woken_at: Option<u64>,
+    /// How long the last wake took until the app accepted the connection
+    pub last_wake_ms: Option<u64>,
+    pub wakes: u64,
+}
+
+fn hibernation_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn declared_config(app_id: &str) -> Option<HibernationConfig> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config
+        .get("hibernation")?
+        .clone()
+        .try_into::<HibernationConfig>()
+    {
+        Ok(config) => Some(config),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid hibernation config: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn update_report(app_id: &str, update: impl FnOnce(&mut HibernationReport)) {
+    if let Ok(mut reports) = REPORTS.lock() {
+        if let Some(report) = reports.get_mut(app_id) {
+            update(report);
+        }
+    }
+}
+
+/// Where the woken app is reached, a wildcard address is reached on
+/// loopback
+fn target(listen: SocketAddr) -> SocketAddr {
+    match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), listen.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), listen.port())
+        }
+        _ => listen,
+    }
+}
+
+/// The app just let go of the port, so it's reused rather than waiting out
+/// its connections in TIME_WAIT
+fn bind(listen: SocketAddr) -> Result<TcpListener, ErrorArrayItem> {
+    let socket: TcpSocket = match listen {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(listen)?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Holds the app's port until the first connection comes in
+fn arm(
+    gs: Arc<GlobalState>,
+    app_id: Stringy,
+    config: HibernationConfig,
+) -> Result<(), ErrorArrayItem> {
+    let listener: TcpListener = bind(config.listen).map_err(|err| {
+        hibernation_error(format!(
+            "Can't hold {} for {}: {}",
+            config.listen, app_id, err
+        ))
+    })?;
+
+    let name: String = app_id.to_string();
+    let shim = tokio::spawn(async move {
+        let stream: TcpStream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log!(
+                    LogLevel::Error,
+                    "The shim of {} stopped accepting: {}",
+                    app_id,
+                    err
+                );
+                return;
+            }
+        };
+
+        // The app binds the port itself, so the shim lets go before waking it
+        drop(listener);
+        if let Ok(mut shims) = SHIMS.lock() {
+            shims.remove(app_id.as_str());
+        }
+        wake(gs, app_id, config, stream).await;
+    });
+
+    SHIMS
+        .lock()
+        .map_err(|err| hibernation_error(err.to_string()))?
+        .insert(name, shim.abort_handle());
+    Ok(())
+}
+
+/// Starts the app for the connection that came in and hands it over once
+/// the app accepts connections. Connections in between are refused, the
+/// port is nobody's while the app comes up.
+async fn wake(
+    gs: Arc<GlobalState>,
+    app_id: Stringy,
+    config: HibernationConfig,
+    mut stream: TcpStream,
+) {
+    log!(LogLevel::Info, "Waking {} for a connection", app_id);
+    update_report(app_id.as_str(), |report| {
+        report.state = HibernationState::Waking
+    });
+    let started: Instant = Instant::now();
+    let deadline: Duration = Duration::from_secs(config.wake_timeout_secs);
+
+    let woken: Result<TcpStream, ErrorArrayItem> = async {
+        start_application(&app_id).await?;
+        loop {
+            if let Ok(upstream) = TcpStream::connect(target(config.listen)).await {
+                return Ok(upstream);
+            }
+            if started.elapsed() >= deadline {
+                return Err(hibernation_error(format!(
+                    "{} didn't accept connections within {}s",
+                    app_id, config.wake_timeout_secs
+                )));
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+    .await;
+
+    let mut upstream: TcpStream = match woken {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            log!(LogLevel::Error, "Couldn't wake {}: {}", app_id, err);
+            // Back to sleep if it never came up, the next connection tries again
+            if !matches!(systemd_bus::is_active(app_id.as_str()).await, Ok(true)) {
+                let _ = stop_application(&app_id).await;
+                update_report(app_id.as_str(), |report| {
+                    report.state = HibernationState::Hibernated
+                });
+                if let Err(err) = arm(gs, app_id.clone(), config) {
+                    log!(LogLevel::Error, "{} stays down: {}", app_id, err);
+                }
+            }
+            return;
+        }
+    };
+
+    let wake_ms: u64 = started.elapsed().as_millis() as u64;
+    update_report(app_id.as_str(), |report| {
+        report.wakes += 1;
+        report.woken_at = Some(current_timestamp());
+        report.last_wake_ms = Some(wake_ms);
+    });
+    gs.events.publish(ManagerEvent::AppWoken {
+        name: app_id.clone(),
+        wake_ms,
+    });
+    log!(LogLevel::Info, "{} woke up in {}ms", app_id, wake_ms);
+
+    if let Err(err) = copy_bidirectional(&mut stream, &mut upstream).await {
+        log!(
+            LogLevel::Debug,
+            "The connection that woke {} ended: {}",
+            app_id,
+            err
+        );
+    }
+}
+
+/// Stops the app and holds its port in its place
+async fn hibernate(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    config: &HibernationConfig,
+    idle: u64,
+) -> Result<(), ErrorArrayItem> {
+    // Marked first so its death isn't taken for a crash
+    update_report(app_id.as_str(), |report| {
+        report.state = HibernationState::Hibernated;
+        report.hibernated_at = Some(current_timestamp());
+    });
+
+    let armed: Result<(), ErrorArrayItem> = match stop_application(app_id).await {
+        Ok(_) => arm(gs.clone(), app_id.clone(), config.clone()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = armed {
+        // Nothing would wake it, so it isn't left down
+        update_report(app_id.as_str(), |report| {
+            report.state = HibernationState::Awake;
+            report.hibernated_at = None;
+        });
+        let _ = start_application(app_id).await;
+        return Err(err);
+    }
+
+    Decision::new(
+        "hibernation",
+        Some(app_id),
+        "hibernate",
+        format!("no traffic for {}s", idle),
+    )
+    .input("idle_secs", config.idle_secs)
+    .input("listen", config.listen)
+    .record();
+    gs.events.publish(ManagerEvent::AppHibernated {
+        name: app_id.clone(),
+        idle_secs: idle,
+    });
+    log!(
+        LogLevel::Info,
+        "{} hibernated after {}s without traffic, holding {}",
+        app_id,
+        idle,
+        config.listen
+    );
+    Ok(())
+}
+
+/// Puts client apps that declare hibernation to sleep once their traffic
+/// counters haven't moved for their idle period
+pub async fn hibernate_idle_apps(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &HibernationSettings = &gs.settings.hibernation;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let traffic: HashMap<String, TrafficStats> =
+        gs.network_monitor.aggregate_bandwidth_by_service().await?;
+    let now: u64 = current_timestamp();
+    let mut idle_apps: Vec<(Stringy, HibernationConfig, u64)> = Vec::new();
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        let config: HibernationConfig = match declared_config(name.as_str()) {
+            Some(config) => config,
+            None => {
+                if let Ok(mut reports) = REPORTS.lock() {
+                    reports.remove(name.as_str());
+                }
+                continue;
+            }
+        };
+
+        let state: HibernationState =
+            hibernation_of(name.as_str()).map_or(HibernationState::Awake, |report| report.state);
+        if state != HibernationState::Awake || is_paused(name.as_str()) {
+            continue;
+        }
+
+        // Down for some other reason, its idle time starts over once it's back
+        let bytes: Option<u64> =
+            match matches!(systemd_bus::is_active(name.as_str()).await, Ok(true)) {
+                true => Some(
+                    traffic
+                        .get(name.as_str())
+                        .map_or(0, |stats| stats.total_bytes()),
+                ),
+                false => None,
+            };
+        let last_active: Option<u64> = {
+            let mut activity = ACTIVITY
+                .lock()
+                .map_err(|err| hibernation_error(err.to_string()))?;
+            match bytes {
+                Some(bytes) => {
+                    let entry = activity.entry(name.to_string()).or_insert((bytes, now));
+                    if entry.0 != bytes {
+                        *entry = (bytes, now);
+                    }
+                    Some(entry.1)
+                }
+                None => {
+                    activity.remove(name.as_str());
+                    None
+                }
+            }
+        };
+
+        if let Ok(mut reports) = REPORTS.lock() {
+            let report = reports
+                .entry(name.to_string())
+                .or_insert_with(|| HibernationReport {
+                    state: HibernationState::Awake,
+                    listen: config.listen,
+                    idle_secs: config.idle_secs,
+                    last_active,
+                    hibernated_at: None,
+                    woken_at: None,
+                    last_wake_ms: None,
+                    wakes: 0,
+                });
+            report.listen = config.listen;
+            report.idle_secs = config.idle_secs;
+            report.last_active = last_active;
+        }
+
+        if let Some(last_active) = last_active {
+            let idle: u64 = now.saturating_sub(last_active);
+            if idle >= config.idle_secs {
+                idle_apps.push((name, config, idle));
+            }
+        }
+    }
+
+    for (name, config, idle) in idle_apps {
+        if let Err(err) = hibernate(gs, &name, &config, idle).await {
+            log!(LogLevel::Error, "Couldn't hibernate {}: {}", name, err);
+        }
+    }
+    Ok(())
+}
+
+/// Lets go of the app's port when it's started some other way than by a
+/// connection, called from `start_application`
+pub fn release(app_id: &Stringy) {
+    if let Some(shim) = SHIMS
+        .lock()
+        .ok()
+        .and_then(|mut shims| shims.remove(app_id.as_str()))
+    {
+        shim.abort();
+        log!(LogLevel::Info, "Released the port of {}", app_id);
+    }
+
+    if let Ok(mut activity) = ACTIVITY.lock() {
+        activity.remove(app_id.as_str());
+    }
+    update_report(app_id.as_str(), |report| {
+        report.state = HibernationState::Awake
+    });
+}
+
+/// Whether the app is down because it's hibernating, its death is no crash
+pub fn is_hibernating(app_id: &str) -> bool {
+    hibernation_of(app_id).map_or(false, |report| report.state != HibernationState::Awake)
+}
+
+pub fn hibernation_of(app_id: &str) -> Option<HibernationReport> {
+    REPORTS
+        .lock()
+        .ok()
+        .and_then(|reports| reports.get(app_id).cloned())
+}
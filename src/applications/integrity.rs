@@ -0,0 +1,344 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::IntegritySettings;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use super::deploy::verify_digest;
+use super::manifest::manifest_of;
+use super::start_stop::{start_application, stop_application};
+
+/// Digests and signatures of the binaries the portal deployed
+const TRUSTED_DIR: &str = "/opt/artisan/integrity";
+
+/// Quarantined apps, kept across restarts
+const QUARANTINE_PATH: &str = "/opt/artisan/quarantine.json";
+
+static QUARANTINED: Lazy<Mutex<HashMap<String, Quarantine>>> =
+    Lazy::new(|| Mutex::new(load_quarantine()));
+
+/// Binary checksums by path, hashed again only when size or mtime change
+static BINARY_HASHES: Lazy<Mutex<HashMap<String, (u64, u64, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// What the portal deployed as the app's binary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedBinary {
+    pub sha256: String,
+    /// The portal's signature over the digest, checked again on every
+    /// verification so the record itself can't be swapped
+    pub signature: Option<String>,
+    pub recorded_at: u64,
+}
+
+/// Why an app's binary isn't trusted. The middleware's `Status` has no
+/// quarantined state, so a quarantined app reads as stopped with this next
+/// to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quarantine {
+    pub reason: String,
+    pub expected: Option<String>,
+    pub found: Option<String>,
+    pub since: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityReport {
+    pub binary: String,
+    pub sha256: Option<String>,
+    /// What the app's manifest pins the binary to
+    pub manifest: Option<String>,
+    pub portal: Option<TrustedBinary>,
+    pub quarantine: Option<Quarantine>,
+}
+
+fn integrity_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn binary_path(app_id: &str) -> String {
+    format!("/opt/artisan/bin/{}", app_id)
+}
+
+fn trusted_path(app_id: &str) -> String {
+    format!("{}/{}.json", TRUSTED_DIR, app_id)
+}
+
+fn load_quarantine() -> HashMap<String, Quarantine> {
+    fs::read_to_string(QUARANTINE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_quarantine(quarantined: &HashMap<String, Quarantine>) -> Result<(), ErrorArrayItem> {
+    let data: String =
+        serde_json::to_string(quarantined).map_err(|err| integrity_error(err.to_string()))?;
+    fs::write(QUARANTINE_PATH, data)?;
+    Ok(())
+}
+
+fn with_quarantine<T>(
+    f: impl FnOnce(&mut HashMap<String, Quarantine>) -> T,
+) -> Result<T, ErrorArrayItem> {
+    let mut quarantined = QUARANTINED
+        .lock()
+        .map_err(|err| integrity_error(err.to_string()))?;
+    Ok(f(&mut quarantined))
+}
+
+pub fn quarantine_of(app_id: &str) -> Option<Quarantine> {
+    with_quarantine(|quarantined| quarantined.get(app_id).cloned())
+        .ok()
+        .flatten()
+}
+
+pub fn binary_sha256(path: &str) -> Result<String, ErrorArrayItem> {
+    let metadata: fs::Metadata = fs::metadata(path)?;
+    let mtime: u64 = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs());
+
+    if let Ok(hashes) = BINARY_HASHES.lock() {
+        if let Some((len, modified, hash)) = hashes.get(path) {
+            if *len == metadata.len() && *modified == mtime {
+                return Ok(hash.clone());
+            }
+        }
+    }
+
+    let hash: String = hex::encode(Sha256::digest(fs::read(path)?));
+    if let Ok(mut hashes) = BINARY_HASHES.lock() {
+        hashes.insert(path.to_owned(), (metadata.len(), mtime, hash.clone()));
+    }
+    Ok(hash)
+}
+
+fn trusted_binary(app_id: &str) -> Option<TrustedBinary> {
+    fs::read_to_string(trusted_path(app_id))
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+}
+
+/// Records a binary the portal deployed as the one to expect. A deploy is
+/// a verification of its own, so it also ends a quarantine.
+pub fn trust_deployed_binary(
+    app_id: &Stringy,
+    sha256: &str,
+    signature: &str,
+) -> Result<(), ErrorArrayItem> {
+    let record: TrustedBinary = TrustedBinary {
+        sha256: sha256.to_owned(),
+        signature: Some(signature.to_owned()),
+        recorded_at: current_timestamp(),
+    };
+    let data: String =
+        serde_json::to_string(&record).map_err(|err| integrity_error(err.to_string()))?;
+    fs::create_dir_all(TRUSTED_DIR)?;
+    fs::write(trusted_path(app_id), data)?;
+
+    let lifted: bool = with_quarantine(|quarantined| match quarantined.remove(app_id.as_str()) {
+        Some(_) => save_quarantine(quarantined).map(|_| true),
+        None => Ok(false),
+    })??;
+    if lifted {
+        log!(
+            LogLevel::Info,
+            "Lifted the quarantine of {}, the portal deployed a new binary",
+            app_id
+        );
+    }
+    Ok(())
+}
+
+/// What's wrong with the binary, `None` when it matches everything it's
+/// expected to or there's nothing to check it against
+fn mismatch(
+    settings: &IntegritySettings,
+    app_id: &str,
+) -> Result<Option<Quarantine>, ErrorArrayItem> {
+    let path: String = binary_path(app_id);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let found: String = binary_sha256(&path)?;
+
+    let mismatched = |reason: &str, expected: Option<String>| Quarantine {
+        reason: reason.to_owned(),
+        expected,
+        found: Some(found.clone()),
+        since: current_timestamp(),
+    };
+
+    if let Some(expected) = manifest_of(app_id).and_then(|manifest| manifest.binary_sha256) {
+        if !expected.eq_ignore_ascii_case(&found) {
+            return Ok(Some(mismatched(
+                "the binary doesn't match the checksum in its manifest",
+                Some(expected),
+            )));
+        }
+    }
+
+    match trusted_binary(app_id) {
+        Some(record) if !record.sha256.eq_ignore_ascii_case(&found) => Ok(Some(mismatched(
+            "the binary isn't the one the portal deployed",
+            Some(record.sha256),
+        ))),
+        Some(TrustedBinary {
+            signature: Some(signature),
+            sha256,
+            ..
+        }) => {
+            let digest: Vec<u8> = hex::decode(&found).unwrap_or_default();
+            match verify_digest(&digest, &signature) {
+                Ok(_) => Ok(None),
+                Err(err) if err.err_type == Errors::AuthenticationError => Ok(Some(mismatched(
+                    "the portal's signature of the binary doesn't verify",
+                    Some(sha256),
+                ))),
+                // Without the deploy key nothing can be said about it
+                Err(err) => Err(err),
+            }
+        }
+        _ if settings.require_signature => {
+            Ok(Some(mismatched("the binary has no portal signature", None)))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn quarantine(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    quarantine: Quarantine,
+) -> Result<(), ErrorArrayItem> {
+    let reason: String = quarantine.reason.clone();
+    Decision::new("integrity", Some(app_id), "quarantine", reason.clone())
+        .input("expected_sha256", &quarantine.expected)
+        .input("found_sha256", &quarantine.found)
+        .record();
+    with_quarantine(|quarantined| {
+        quarantined.insert(app_id.to_string(), quarantine);
+        save_quarantine(quarantined)
+    })??;
+
+    // Quarantined even if the unit won't stop, nothing starts it again
+    if let Err(err) = stop_application(app_id).await {
+        log!(
+            LogLevel::Error,
+            "Failed to stop quarantined {}: {}",
+            app_id,
+            err
+        );
+    }
+    APP_STATUS_ARRAY
+        .modify(app_id, |status| {
+            status.app_data.set_status(Status::Stopped);
+            status.expected_status = Status::Stopped;
+        })
+        .await?;
+
+    log!(LogLevel::Error, "Quarantined {}, {}", app_id, reason);
+    gs.events.publish(ManagerEvent::BinaryQuarantined {
+        name: app_id.clone(),
+        reason,
+    });
+    Ok(())
+}
+
+/// Checks the app's binary before it's started or reclaimed. A mismatch
+/// quarantines the app, a quarantined app isn't let through until its
+/// binary is verified again.
+pub async fn verify_binary(gs: &Arc<GlobalState>, app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let settings: &IntegritySettings = &gs.settings.integrity;
+    // System applications come with the node image, systemd answers for them
+    if !settings.enabled || !CLIENT_APPLICATION_ARRAY.contains(app_id).await? {
+        return Ok(());
+    }
+
+    if let Some(quarantine) = quarantine_of(app_id.as_str()) {
+        return Err(integrity_error(format!(
+            "{} is quarantined, {}. Verify its binary again first",
+            app_id, quarantine.reason
+        )));
+    }
+
+    match mismatch(settings, app_id.as_str())? {
+        Some(found) => {
+            let reason: String = found.reason.clone();
+            quarantine(gs, app_id, found).await?;
+            Err(integrity_error(format!(
+                "{} is quarantined, {}",
+                app_id, reason
+            )))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Checks a quarantined app's binary again and starts the app when it
+/// passes now
+pub async fn reverify_binary(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+) -> Result<(), ErrorArrayItem> {
+    if quarantine_of(app_id.as_str()).is_none() {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} isn't quarantined", app_id),
+        ));
+    }
+
+    if let Some(found) = mismatch(&gs.settings.integrity, app_id.as_str())? {
+        return Err(integrity_error(format!(
+            "{} is still quarantined, {}",
+            app_id, found.reason
+        )));
+    }
+
+    with_quarantine(|quarantined| {
+        quarantined.remove(app_id.as_str());
+        save_quarantine(quarantined)
+    })??;
+    APP_STATUS_ARRAY
+        .modify(app_id, |status| status.expected_status = Status::Running)
+        .await?;
+    log!(
+        LogLevel::Info,
+        "The binary of {} verified, lifted its quarantine",
+        app_id
+    );
+    gs.events.publish(ManagerEvent::QuarantineLifted {
+        name: app_id.clone(),
+    });
+
+    start_application(app_id).await
+}
+
+pub fn integrity_report(app_id: &str) -> IntegrityReport {
+    let binary: String = binary_path(app_id);
+    IntegrityReport {
+        sha256: binary_sha256(&binary).ok(),
+        binary,
+        manifest: manifest_of(app_id).and_then(|manifest| manifest.binary_sha256),
+        portal: trusted_binary(app_id),
+        quarantine: quarantine_of(app_id),
+    }
+}
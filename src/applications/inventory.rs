@@ -0,0 +1,236 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::system::control::GLOBAL_STATE;
+use crate::system::events::ManagerEvent;
+
+/// Package inventories of every deployed version, one json file per version
+pub const INVENTORY_DIR: &str = "/opt/artisan/inventory";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Package {
+    pub ecosystem: String,
+    pub name: String,
+    pub version: String,
+    pub license: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Inventory {
+    pub app: Stringy,
+    pub version: String,
+    pub generated_at: u64,
+    pub packages: Vec<Package>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryEntry {
+    pub version: String,
+    pub generated_at: u64,
+    pub packages: usize,
+}
+
+fn inventory_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn inventory_path(app_id: &str, version: &str) -> Result<PathBuf, ErrorArrayItem> {
+    // Versions come from the portal, keep them from walking the filesystem
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(inventory_error(format!(
+            "{} is not a valid version",
+            version
+        )));
+    }
+
+    Ok(Path::new(INVENTORY_DIR)
+        .join(app_id)
+        .join(format!("{}.json", version)))
+}
+
+fn npm_packages(lockfile: &Path) -> Result<Vec<Package>, ErrorArrayItem> {
+    let lock: Value = serde_json::from_slice(&fs::read(lockfile)?)
+        .map_err(|err| inventory_error(format!("Invalid package-lock.json: {}", err)))?;
+
+    let packages = match lock["packages"].as_object() {
+        Some(packages) => packages,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(packages
+        .iter()
+        .filter_map(|(path, details)| {
+            // The "" entry is the app itself
+            let name: &str = path.rsplit("node_modules/").next()?;
+            if name.is_empty() {
+                return None;
+            }
+
+            Some(Package {
+                ecosystem: "npm".to_owned(),
+                name: name.to_owned(),
+                version: details["version"].as_str()?.to_owned(),
+                license: details["license"]
+                    .as_str()
+                    .map(|license| license.to_owned()),
+            })
+        })
+        .collect())
+}
+
+fn composer_packages(lockfile: &Path) -> Result<Vec<Package>, ErrorArrayItem> {
+    let lock: Value = serde_json::from_slice(&fs::read(lockfile)?)
+        .map_err(|err| inventory_error(format!("Invalid composer.lock: {}", err)))?;
+
+    Ok(["packages", "packages-dev"]
+        .iter()
+        .filter_map(|section| lock[*section].as_array())
+        .flatten()
+        .filter_map(|package| {
+            let licenses: Vec<&str> = package["license"]
+                .as_array()
+                .map(|list| list.iter().filter_map(|l| l.as_str()).collect())
+                .unwrap_or_default();
+
+            Some(Package {
+                ecosystem: "composer".to_owned(),
+                name: package["name"].as_str()?.to_owned(),
+                version: package["version"].as_str()?.to_owned(),
+                license: match licenses.is_empty() {
+                    true => None,
+                    false => Some(licenses.join(" OR ")),
+                },
+            })
+        })
+        .collect())
+}
+
+fn cargo_packages(lockfile: &Path) -> Result<Vec<Package>, ErrorArrayItem> {
+    let lock: toml::Value = toml::from_str(&fs::read_to_string(lockfile)?)
+        .map_err(|err| inventory_error(format!("Invalid Cargo.lock: {}", err)))?;
+
+    // Cargo.lock doesn't carry licenses
+    Ok(lock
+        .get("package")
+        .and_then(|packages| packages.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|package| {
+            Some(Package {
+                ecosystem: "cargo".to_owned(),
+                name: package.get("name")?.as_str()?.to_owned(),
+                version: package.get("version")?.as_str()?.to_owned(),
+                license: None,
+            })
+        })
+        .collect())
+}
+
+/// Builds the inventory of the app's working directory from whatever
+/// lockfiles the deploy shipped
+fn collect_packages(app_id: &str) -> Result<Vec<Package>, ErrorArrayItem> {
+    let app_dir: PathBuf = PathBuf::from(format!("/etc/{}", app_id));
+    let mut packages: Vec<Package> = Vec::new();
+
+    let parsers: [(&str, fn(&Path) -> Result<Vec<Package>, ErrorArrayItem>); 3] = [
+        ("package-lock.json", npm_packages),
+        ("composer.lock", composer_packages),
+        ("Cargo.lock", cargo_packages),
+    ];
+
+    for (lockfile, parser) in parsers {
+        let path: PathBuf = app_dir.join(lockfile);
+        if path.exists() {
+            packages.extend(parser(&path)?);
+        }
+    }
+
+    packages.sort();
+    packages.dedup();
+    Ok(packages)
+}
+
+/// Records the package inventory of a freshly installed version
+pub fn record_inventory(app_id: &Stringy, version: &str) -> Result<(), ErrorArrayItem> {
+    let inventory: Inventory = Inventory {
+        app: app_id.clone(),
+        version: version.to_owned(),
+        generated_at: current_timestamp(),
+        packages: collect_packages(app_id)?,
+    };
+
+    let path: PathBuf = inventory_path(app_id, version)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let data: String =
+        serde_json::to_string(&inventory).map_err(|err| inventory_error(err.to_string()))?;
+    fs::write(&path, data)?;
+
+    log!(
+        LogLevel::Info,
+        "Recorded {} packages for {} {}",
+        inventory.packages.len(),
+        app_id,
+        version
+    );
+
+    // The portal pulls the full inventory when it sees this
+    if let Some(gs) = GLOBAL_STATE.get() {
+        gs.events.publish(ManagerEvent::InventoryRecorded {
+            name: app_id.clone(),
+            version: version.to_owned(),
+            packages: inventory.packages.len(),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn read_inventory(app_id: &Stringy, version: &str) -> Result<Inventory, ErrorArrayItem> {
+    let path: PathBuf = inventory_path(app_id, version)?;
+    if !path.exists() {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("No inventory of {} {}", app_id, version),
+        ));
+    }
+
+    serde_json::from_slice(&fs::read(path)?).map_err(|err| inventory_error(err.to_string()))
+}
+
+/// Every recorded version of an app, newest first
+pub fn list_inventories(app_id: &Stringy) -> Result<Vec<InventoryEntry>, ErrorArrayItem> {
+    let dir: PathBuf = Path::new(INVENTORY_DIR).join(app_id.to_string());
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries: Vec<InventoryEntry> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path: PathBuf = entry?.path();
+        let version: &str = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(version) => version,
+            None => continue,
+        };
+
+        if let Ok(inventory) = read_inventory(app_id, version) {
+            entries.push(InventoryEntry {
+                version: inventory.version,
+                generated_at: inventory.generated_at,
+                packages: inventory.packages.len(),
+            });
+        }
+    }
+
+    entries.sort_by(|a, b| b.generated_at.cmp(&a.generated_at));
+    Ok(entries)
+}
@@ -0,0 +1,231 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::cgroups::{controllers_available, service_file};
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::ebpf::BandwidthTracker;
+
+use super::manifest::manifest_of;
+use super::node_spec::LimitSpec;
+
+/// cpu.max period, quotas are written against it
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Apps currently refused over their limits, so the refusal is logged once
+/// and not on every pass of the monitor loop
+static REFUSED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Bandwidth caps set at runtime, they take over from the declared ones.
+/// `None` lifts the declared cap.
+static BANDWIDTH_OVERRIDES: Lazy<Mutex<HashMap<String, Option<u64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Caps the bandwidth probes hold right now, in bytes per second
+static ENFORCED_BANDWIDTH: Lazy<Mutex<HashMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// What the app's cgroup enforces right now, `None` where it can't be read
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentLimits {
+    pub memory_max: Option<String>,
+    pub cpu_max: Option<String>,
+    pub pids_max: Option<String>,
+    /// Bytes per second, `None` when the app isn't capped or the cap isn't
+    /// enforced
+    pub bandwidth_max: Option<u64>,
+}
+
+fn limit_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Declared as a `[limits]` table in /etc/{app}/Config.toml, with the same
+/// fields as the node spec. The app's manifest takes over when it has limits.
+pub fn declared_limits(app_name: &str) -> Result<Option<LimitSpec>, ErrorArrayItem> {
+    if let Some(limits) = manifest_of(app_name).and_then(|manifest| manifest.limits) {
+        return Ok(Some(limits));
+    }
+
+    let data: String = match fs::read_to_string(format!("/etc/{}/Config.toml", app_name)) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+
+    let config: toml::Value = toml::from_str(&data)
+        .map_err(|err| limit_error(format!("Invalid config of {}: {}", app_name, err)))?;
+
+    match config.get("limits") {
+        Some(limits) => limits
+            .clone()
+            .try_into::<LimitSpec>()
+            .map(Some)
+            .map_err(|err| limit_error(format!("Invalid limits of {}: {}", app_name, err))),
+        None => Ok(None),
+    }
+}
+
+/// Writes the app's declared limits into its cgroup
+pub fn apply_limits(app_name: &str) -> Result<(), ErrorArrayItem> {
+    let limits: LimitSpec = match declared_limits(app_name)? {
+        Some(limits) => limits,
+        None => return Ok(()),
+    };
+
+    let cgroup_limits: bool = limits.memory_max_bytes.is_some()
+        || limits.cpu_quota_percent.is_some()
+        || limits.tasks_max.is_some();
+    if cgroup_limits && !controllers_available() {
+        return Err(limit_error(format!(
+            "{} declares limits but this host has no cgroup v2 controllers",
+            app_name
+        )));
+    }
+
+    let mut writes: Vec<(&str, String)> = Vec::new();
+    if let Some(memory) = limits.memory_max_bytes {
+        writes.push(("memory.max", memory.to_string()));
+    }
+    if let Some(cpu) = limits.cpu_quota_percent {
+        let quota: u64 = CPU_PERIOD_US * cpu as u64 / 100;
+        writes.push(("cpu.max", format!("{} {}", quota.max(1000), CPU_PERIOD_US)));
+    }
+    if let Some(tasks) = limits.tasks_max {
+        writes.push(("pids.max", tasks.to_string()));
+    }
+
+    for (file, value) in writes {
+        fs::write(service_file(app_name, file), &value).map_err(|err| {
+            limit_error(format!(
+                "Couldn't set {} of {} to {}: {}",
+                file, app_name, value, err
+            ))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Applies the app's limits before it's taken under management, false when
+/// they couldn't be applied and the app has to be left alone
+pub fn enforce_limits(app_name: &str) -> bool {
+    let result: Result<(), ErrorArrayItem> = apply_limits(app_name);
+
+    let mut refused = match REFUSED.lock() {
+        Ok(refused) => refused,
+        Err(_) => return result.is_ok(),
+    };
+
+    match result {
+        Ok(_) => {
+            if refused.remove(app_name) {
+                log!(LogLevel::Info, "Limits of {} applied", app_name);
+            }
+            true
+        }
+        Err(err) => {
+            if refused.insert(app_name.to_owned()) {
+                log!(
+                    LogLevel::Error,
+                    "Not managing {}, its limits can't be applied: {}",
+                    app_name,
+                    err
+                );
+                Decision::new(
+                    "limits",
+                    Some(&Stringy::from(app_name)),
+                    "leave unmanaged",
+                    String::from("its declared limits can't be applied"),
+                )
+                .input("error", err.to_string())
+                .record();
+            }
+            false
+        }
+    }
+}
+
+pub fn current_limits(app_name: &str) -> CurrentLimits {
+    let read = |file: &str| {
+        fs::read_to_string(service_file(app_name, file))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+
+    CurrentLimits {
+        memory_max: read("memory.max"),
+        cpu_max: read("cpu.max"),
+        pids_max: read("pids.max"),
+        bandwidth_max: ENFORCED_BANDWIDTH
+            .lock()
+            .ok()
+            .and_then(|enforced| enforced.get(app_name).copied()),
+    }
+}
+
+/// Changes the app's bandwidth cap until the manager restarts. `Some(None)`
+/// lifts the cap, `None` goes back to the declared one.
+pub fn set_bandwidth_limit(
+    app_name: &str,
+    bytes_per_sec: Option<Option<u64>>,
+) -> Result<(), ErrorArrayItem> {
+    let mut overrides = BANDWIDTH_OVERRIDES
+        .lock()
+        .map_err(|err| limit_error(err.to_string()))?;
+
+    match bytes_per_sec {
+        Some(cap) => overrides.insert(app_name.to_owned(), cap),
+        None => overrides.remove(app_name),
+    };
+
+    Ok(())
+}
+
+fn bandwidth_cap(app_name: &str) -> Option<u64> {
+    if let Some(cap) = BANDWIDTH_OVERRIDES
+        .lock()
+        .ok()
+        .and_then(|overrides| overrides.get(app_name).copied())
+    {
+        return cap;
+    }
+
+    declared_limits(app_name)
+        .ok()
+        .flatten()
+        .and_then(|limits| limits.bandwidth_bytes_per_sec)
+}
+
+/// Hands the caps of every service in artisan.slice to the bandwidth
+/// probes. Cgroups change on every restart, so this runs on every pass of
+/// the tracking loop.
+pub async fn enforce_bandwidth_limits(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let cgroups: HashMap<u64, String> = BandwidthTracker::service_cgroup_map()?;
+
+    let mut caps: HashMap<u64, u64> = HashMap::new();
+    let mut enforced: HashMap<String, u64> = HashMap::new();
+    for (cgroup, service_name) in cgroups {
+        if let Some(cap) = bandwidth_cap(&service_name).filter(|cap| *cap > 0) {
+            caps.insert(cgroup, cap);
+            enforced.insert(service_name, cap);
+        }
+    }
+
+    gs.network_monitor.sync_bandwidth_limits(&caps).await?;
+    if !gs.network_monitor.enforces_bandwidth() {
+        enforced.clear();
+    }
+
+    *ENFORCED_BANDWIDTH
+        .lock()
+        .map_err(|err| limit_error(err.to_string()))? = enforced;
+
+    Ok(())
+}
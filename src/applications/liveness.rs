@@ -0,0 +1,243 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use super::manifest::manifest_of;
+use super::node_spec::{run_probe_as, ProbeSpec};
+use super::pause::is_paused;
+
+/// Latest liveness of every app with a probe. AppStatus comes from the
+/// middleware, so it's kept here and added to the status JSON as `health`.
+static HEALTH: Lazy<Mutex<HashMap<String, Health>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Declared as a `[liveness]` table in /etc/{app}/Config.toml, or in the
+/// app's manifest which takes over from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LivenessProbe {
+    #[serde(flatten)]
+    pub probe: ProbeSpec,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    /// Failed probes in a row before the app counts as dead
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Probes this soon after the app came up don't count, it may still be
+    /// starting
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_initial_delay() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    /// Failed probes in a row
+    pub failures: u32,
+    pub failure_threshold: u32,
+    pub last_error: Option<String>,
+    pub last_success: Option<u64>,
+    pub checked_at: Option<u64>,
+    /// When the app was first seen running, the initial delay counts from
+    /// here
+    pub running_since: u64,
+}
+
+fn liveness_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn declared_liveness(app_id: &str) -> Option<LivenessProbe> {
+    if let Some(liveness) = manifest_of(app_id).and_then(|manifest| manifest.liveness) {
+        return Some(liveness);
+    }
+
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config.get("liveness")?.clone().try_into::<LivenessProbe>() {
+        Ok(liveness) => Some(liveness),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid liveness probe: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Runs the liveness probes that are due. An app failing its probe
+/// `failure_threshold` times in a row is reported once, the restart
+/// supervisor treats that like the app dying.
+pub async fn probe_liveness(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    if !gs.settings.liveness.enabled {
+        return Ok(());
+    }
+
+    let now: u64 = current_timestamp();
+    let previous: HashMap<String, Health> = HEALTH
+        .lock()
+        .map_err(|err| liveness_error(err.to_string()))?
+        .clone();
+    let mut health: HashMap<String, Health> = HashMap::new();
+    let mut due: Vec<(Stringy, LivenessProbe, Option<u32>, Health)> = Vec::new();
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        let liveness: LivenessProbe = match declared_liveness(name.as_str()) {
+            Some(liveness) => liveness,
+            None => continue,
+        };
+
+        // A stopped app starts over, its next start gets the initial delay
+        let running: bool = APP_STATUS_ARRAY
+            .read(&name, |status| status.app_data.get_status())
+            .await?
+            .map_or(false, |status| {
+                matches!(status, Status::Running | Status::Warning)
+            });
+        // Nothing answers while it's paused, it starts over once resumed
+        if !running || is_paused(name.as_str()) {
+            continue;
+        }
+
+        let state: Health = previous.get(name.as_str()).cloned().unwrap_or(Health {
+            healthy: true,
+            failures: 0,
+            failure_threshold: liveness.failure_threshold,
+            last_error: None,
+            last_success: None,
+            checked_at: None,
+            running_since: now,
+        });
+
+        let settled: bool = now >= state.running_since + liveness.initial_delay_secs;
+        let checked_recently: bool = state.checked_at.map_or(false, |checked| {
+            now < checked + liveness.interval_secs.max(1)
+        });
+        if !settled || checked_recently {
+            health.insert(name.to_string(), state);
+            continue;
+        }
+
+        let uid: Option<u32> = CLIENT_APPLICATION_ARRAY
+            .read(&name, |client| client.execution_uid())
+            .await?;
+        due.push((name, liveness, uid, state));
+    }
+
+    // Probed side by side, one hanging app shouldn't delay the others
+    let tasks: Vec<_> = due
+        .into_iter()
+        .map(|(name, liveness, uid, state)| {
+            tokio::spawn(async move {
+                let result: Result<(), String> = run_probe_as(&liveness.probe, uid).await;
+                (name, liveness, state, result)
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let (name, liveness, mut state, result) = match task.await {
+            Ok(probed) => probed,
+            Err(err) => {
+                log!(LogLevel::Error, "A liveness probe panicked: {}", err);
+                continue;
+            }
+        };
+
+        state.checked_at = Some(current_timestamp());
+        state.failure_threshold = liveness.failure_threshold;
+        match result {
+            Ok(_) => {
+                if !state.healthy {
+                    log!(LogLevel::Info, "{} passes its liveness probe again", name);
+                }
+                state.healthy = true;
+                state.failures = 0;
+                state.last_error = None;
+                state.last_success = state.checked_at;
+            }
+            Err(err) => {
+                state.failures += 1;
+                state.last_error = Some(err.clone());
+                log!(
+                    LogLevel::Warn,
+                    "{} failed its liveness probe ({} of {}): {}",
+                    name,
+                    state.failures,
+                    liveness.failure_threshold,
+                    err
+                );
+
+                if state.failures == liveness.failure_threshold.max(1) {
+                    state.healthy = false;
+                    gs.events.publish(ManagerEvent::LivenessFailed {
+                        name: name.clone(),
+                        failures: state.failures,
+                        reason: err,
+                    });
+                }
+            }
+        }
+
+        health.insert(name.to_string(), state);
+    }
+
+    *HEALTH
+        .lock()
+        .map_err(|err| liveness_error(err.to_string()))? = health;
+    Ok(())
+}
+
+/// Puts a running app that's failing its liveness probe into Warning
+pub fn flag_liveness(name: &Stringy, app: &mut AppStatus) {
+    let error: String = match health_of(name) {
+        Some(Health {
+            failures: 1..,
+            last_error: Some(error),
+            ..
+        }) => error,
+        _ => return,
+    };
+    if app.app_data.get_status() != Status::Running {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    app.app_data.state.error_log.push(ErrorArrayItem::new(
+        Errors::AppState,
+        format!("UNHEALTHY: {}", error.to_uppercase()),
+    ));
+}
+
+pub fn health_of(name: &str) -> Option<Health> {
+    HEALTH
+        .lock()
+        .ok()
+        .and_then(|health| health.get(name).cloned())
+}
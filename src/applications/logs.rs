@@ -0,0 +1,116 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::Serialize;
+
+use super::capture::{captured_lines, CapturedLine};
+use super::child::APP_STATUS_ARRAY;
+
+/// Most lines a single tail returns, the monitor keeps 500 of each stream
+pub const MAX_LOG_LINES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogLine {
+    /// Order the line was captured in within its stream
+    #[serde(skip)]
+    pub seq: u64,
+    pub timestamp: u64,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+/// Tracks what a follower has already been sent, as the newest sequence
+/// number of each stream. Captures are trimmed and reshuffled by the
+/// monitor, so positions can't be used for this, and the same text can
+/// repeat within a second.
+#[derive(Debug, Default)]
+pub struct LogCursor {
+    stdout: u64,
+    stderr: u64,
+}
+
+impl LogCursor {
+    fn advance(&mut self, lines: &[LogLine]) {
+        for line in lines {
+            let sent: &mut u64 = match line.stream {
+                LogStream::Stdout => &mut self.stdout,
+                LogStream::Stderr => &mut self.stderr,
+            };
+            *sent = (*sent).max(line.seq);
+        }
+    }
+
+    fn is_new(&self, line: &LogLine) -> bool {
+        match line.stream {
+            LogStream::Stdout => line.seq > self.stdout,
+            LogStream::Stderr => line.seq > self.stderr,
+        }
+    }
+}
+
+/// Captured stdout and stderr of an app merged in time order
+async fn captured(app_id: &Stringy) -> Result<Vec<LogLine>, ErrorArrayItem> {
+    if APP_STATUS_ARRAY.entry(app_id).await?.is_none() {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("The app: {}, wasn't in our store", app_id),
+        ));
+    }
+
+    // The ring buffers rather than the status, only they number the lines
+    let (stdout, stderr) = captured_lines(app_id).await?;
+    let tag = |stream: LogStream| {
+        move |captured: CapturedLine| LogLine {
+            seq: captured.seq,
+            timestamp: captured.timestamp,
+            stream,
+            line: captured.line,
+        }
+    };
+
+    let mut lines: Vec<LogLine> = stdout
+        .into_iter()
+        .map(tag(LogStream::Stdout))
+        .chain(stderr.into_iter().map(tag(LogStream::Stderr)))
+        .collect();
+
+    // Stable, so lines of one stream keep their order within a second
+    lines.sort_by_key(|line| line.timestamp);
+    Ok(lines)
+}
+
+/// The last `count` captured lines of an app, along with a cursor to follow
+/// from
+pub async fn tail_logs(
+    app_id: &Stringy,
+    count: usize,
+) -> Result<(Vec<LogLine>, LogCursor), ErrorArrayItem> {
+    let mut lines: Vec<LogLine> = captured(app_id).await?;
+    let mut cursor: LogCursor = LogCursor::default();
+    cursor.advance(&lines);
+
+    let count: usize = count.min(MAX_LOG_LINES);
+    lines.drain(..lines.len().saturating_sub(count));
+    Ok((lines, cursor))
+}
+
+/// Lines captured since the cursor was last advanced
+pub async fn follow_logs(
+    app_id: &Stringy,
+    cursor: &mut LogCursor,
+) -> Result<Vec<LogLine>, ErrorArrayItem> {
+    let lines: Vec<LogLine> = captured(app_id)
+        .await?
+        .into_iter()
+        .filter(|line| cursor.is_new(line))
+        .collect();
+
+    cursor.advance(&lines);
+    Ok(lines)
+}
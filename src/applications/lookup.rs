@@ -0,0 +1,155 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use procfs::process::{FDTarget, Process};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+
+use crate::system::cgroups::slice_dir;
+
+use super::child::{pids_in_cgroup, APP_STATUS_ARRAY};
+
+/// Result of mapping a pid or port back to the application that owns it
+#[derive(Debug, Serialize, Clone)]
+pub struct LookupResult {
+    pub name: Stringy,
+    pub app_id: Stringy,
+    pub status: Status,
+    pub pid: u32,
+    pub port: Option<u16>,
+}
+
+/// Returns the artisan service name a pid belongs to, if it lives in artisan.slice
+pub fn service_for_pid(pid: u32) -> Option<String> {
+    let process = Process::new(pid as i32).ok()?;
+    let cgroups = process.cgroups().ok()?;
+
+    for cgroup in cgroups {
+        if let Some(service) = cgroup.pathname.split("artisan.slice/").nth(1) {
+            let service = service.split('/').next().unwrap_or_default();
+            if service.ends_with(".service") {
+                return Some(service.trim_end_matches(".service").to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds the application running the given pid using the cgroup membership
+pub async fn lookup_by_pid(pid: u32) -> Result<LookupResult, ErrorArrayItem> {
+    let service_name = service_for_pid(pid).ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("PID {} is not part of artisan.slice", pid),
+        )
+    })?;
+
+    resolve_status(&service_name, pid, None).await
+}
+
+/// Finds the application holding a socket bound to the given local port
+pub async fn lookup_by_port(port: u16) -> Result<LookupResult, ErrorArrayItem> {
+    let mut inodes: HashSet<u64> = HashSet::new();
+
+    let tcp = procfs::net::tcp()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(procfs::net::tcp6().unwrap_or_default());
+    for entry in tcp {
+        if entry.local_address.port() == port {
+            inodes.insert(entry.inode);
+        }
+    }
+
+    let udp = procfs::net::udp()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(procfs::net::udp6().unwrap_or_default());
+    for entry in udp {
+        if entry.local_address.port() == port {
+            inodes.insert(entry.inode);
+        }
+    }
+
+    if inodes.is_empty() {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("No socket bound to port {}", port),
+        ));
+    }
+
+    // Only walk the processes we manage instead of the whole proc table
+    for entry in fs::read_dir(slice_dir())? {
+        let path = entry?.path();
+        let service_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if name.ends_with(".service") => name.trim_end_matches(".service"),
+            _ => continue,
+        };
+
+        let pids = match pids_in_cgroup(service_name) {
+            Ok(pids) => pids,
+            Err(_) => continue,
+        };
+
+        for pid in pids {
+            if pid_owns_inode(pid, &inodes) {
+                return resolve_status(service_name, pid, Some(port)).await;
+            }
+        }
+    }
+
+    Err(ErrorArrayItem::new(
+        Errors::NotFound,
+        format!("Port {} is not owned by a managed application", port),
+    ))
+}
+
+fn pid_owns_inode(pid: u32, inodes: &HashSet<u64>) -> bool {
+    let process = match Process::new(pid as i32) {
+        Ok(process) => process,
+        Err(_) => return false,
+    };
+
+    let fds = match process.fd() {
+        Ok(fds) => fds,
+        Err(err) => {
+            log!(LogLevel::Trace, "Can't read fds of {}: {}", pid, err);
+            return false;
+        }
+    };
+
+    fds.filter_map(|fd| fd.ok()).any(|fd| match fd.target {
+        FDTarget::Socket(inode) => inodes.contains(&inode),
+        _ => false,
+    })
+}
+
+async fn resolve_status(
+    service_name: &str,
+    pid: u32,
+    port: Option<u16>,
+) -> Result<LookupResult, ErrorArrayItem> {
+    let name: Stringy = service_name.into();
+
+    let found: Option<(Stringy, Status)> = APP_STATUS_ARRAY
+        .read(&name, |app| (app.app_id.clone(), app.app_data.get_status()))
+        .await?;
+
+    match found {
+        Some((app_id, status)) => Ok(LookupResult {
+            name,
+            app_id,
+            status,
+            pid,
+            port,
+        }),
+        None => Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} owns pid {} but isn't in our store", service_name, pid),
+        )),
+    }
+}
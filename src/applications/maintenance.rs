@@ -0,0 +1,203 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::system::control::GLOBAL_STATE;
+use crate::system::settings::MaintenanceSettings;
+
+use super::node_spec::{run_probe, ProbeSpec};
+
+/// Apps whose domains are behind the maintenance page right now
+static WINDOWS: Lazy<LockWithTimeout<HashMap<Stringy, MaintenanceWindow>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// Declared as a `[maintenance]` table in /etc/{app}/Config.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceSpec {
+    pub domains: Vec<String>,
+    /// The page comes down once this passes, right after the restart when
+    /// there's none
+    pub probe: Option<ProbeSpec>,
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout_secs: u64,
+}
+
+fn default_ready_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceWindow {
+    pub domains: Vec<String>,
+    pub reason: String,
+    pub since: u64,
+    /// Set once the app is back and we're waiting on its probe
+    pub awaiting_probe: bool,
+}
+
+fn declared_maintenance(app_id: &str) -> Option<MaintenanceSpec> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config
+        .get("maintenance")?
+        .clone()
+        .try_into::<MaintenanceSpec>()
+    {
+        Ok(spec) => Some(spec),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid maintenance page: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn maintenance_settings() -> Option<MaintenanceSettings> {
+    GLOBAL_STATE
+        .get()
+        .map(|gs| gs.settings.maintenance.clone())
+        .filter(|settings| settings.enabled)
+}
+
+/// Lets the proxy pick up changed flag files
+async fn reload_proxy(settings: &MaintenanceSettings) {
+    let command: &str = match &settings.reload_command {
+        Some(command) => command,
+        None => return,
+    };
+
+    match Command::new("sh").arg("-c").arg(command).status().await {
+        Ok(status) if status.success() => (),
+        Ok(status) => log!(
+            LogLevel::Warn,
+            "Proxy reload exited with {}, maintenance pages may be stale",
+            status
+        ),
+        Err(err) => log!(LogLevel::Warn, "Failed to reload the proxy: {}", err),
+    }
+}
+
+/// Puts the app's domains behind the maintenance page by dropping a flag
+/// file per domain where the proxy looks for them. Apps that declare no
+/// domains are left alone.
+pub async fn begin_maintenance(app_id: &Stringy, reason: &str) -> Result<(), ErrorArrayItem> {
+    let (settings, spec) = match (maintenance_settings(), declared_maintenance(app_id)) {
+        (Some(settings), Some(spec)) if !spec.domains.is_empty() => (settings, spec),
+        _ => return Ok(()),
+    };
+
+    fs::create_dir_all(&settings.flag_dir)?;
+    for domain in &spec.domains {
+        fs::write(
+            Path::new(&settings.flag_dir).join(domain),
+            format!("{} {}\n", app_id, reason),
+        )?;
+    }
+    reload_proxy(&settings).await;
+
+    log!(
+        LogLevel::Info,
+        "Serving the maintenance page for {} while it's {}",
+        app_id,
+        reason
+    );
+    WINDOWS.try_write().await?.insert(
+        app_id.clone(),
+        MaintenanceWindow {
+            domains: spec.domains,
+            reason: reason.to_owned(),
+            since: current_timestamp(),
+            awaiting_probe: false,
+        },
+    );
+
+    Ok(())
+}
+
+async fn end_maintenance(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let window: MaintenanceWindow = match WINDOWS.try_write().await?.remove(app_id) {
+        Some(window) => window,
+        None => return Ok(()),
+    };
+
+    if let Some(settings) = maintenance_settings() {
+        for domain in &window.domains {
+            let _ = fs::remove_file(Path::new(&settings.flag_dir).join(domain));
+        }
+        reload_proxy(&settings).await;
+    }
+
+    log!(
+        LogLevel::Info,
+        "Took down the maintenance page of {}",
+        app_id
+    );
+    Ok(())
+}
+
+/// Takes the page down once the app passes its readiness probe. Runs in the
+/// background, an app that never gets ready keeps the page until the
+/// declared timeout.
+pub async fn end_maintenance_when_ready(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    match WINDOWS.try_write().await?.get_mut(app_id) {
+        Some(window) => window.awaiting_probe = true,
+        None => return Ok(()),
+    }
+
+    let spec: Option<MaintenanceSpec> = declared_maintenance(app_id);
+    let app_id: Stringy = app_id.clone();
+
+    tokio::spawn(async move {
+        if let Some(MaintenanceSpec {
+            probe: Some(probe),
+            ready_timeout_secs,
+            ..
+        }) = spec
+        {
+            let deadline: u64 = current_timestamp() + ready_timeout_secs;
+            while let Err(err) = run_probe(&probe).await {
+                if current_timestamp() >= deadline {
+                    log!(
+                        LogLevel::Warn,
+                        "{} isn't ready after {}s, taking the maintenance page down anyway: {}",
+                        app_id,
+                        ready_timeout_secs,
+                        err
+                    );
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        }
+
+        if let Err(err) = end_maintenance(&app_id).await {
+            log!(
+                LogLevel::Error,
+                "Failed to take down the maintenance page of {}: {}",
+                app_id,
+                err
+            );
+        }
+    });
+
+    Ok(())
+}
+
+pub async fn maintenance_windows() -> Result<HashMap<Stringy, MaintenanceWindow>, ErrorArrayItem> {
+    Ok(WINDOWS.try_read().await?.clone())
+}
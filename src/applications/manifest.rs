@@ -0,0 +1,272 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::enviornment::definitions::Enviornment;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use super::integrity::binary_sha256;
+use super::liveness::LivenessProbe;
+use super::node_spec::{run_probe, LimitSpec, ProbeSpec};
+use super::ports::port_usage_of;
+use super::resolve::ClientApplication;
+use super::restart::{RestartConfig, RestartPolicy};
+
+/// Latest checks of every app against its manifest. AppStatus comes from
+/// the middleware, so they are kept here and added to the status JSON.
+static MANIFEST_CHECKS: Lazy<Mutex<HashMap<String, ManifestCheck>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Declared in /etc/{app}/manifest.toml. Limits and the restart policy
+/// given here take over from the ones in Config.toml.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppManifest {
+    /// Hex sha256 of the binary, a different binary isn't started
+    pub binary_sha256: Option<String>,
+    /// Ports the app is expected to be listening on while running
+    pub ports: Vec<u16>,
+    pub environment_version: Option<EnvironmentVersion>,
+    pub limits: Option<LimitSpec>,
+    pub restart: Option<RestartConfig>,
+    pub health_check: Option<ProbeSpec>,
+    pub liveness: Option<LivenessProbe>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvironmentVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestCheck {
+    /// Where the app differs from its manifest, empty when it doesn't
+    pub problems: Vec<String>,
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestReport {
+    pub manifest: Option<AppManifest>,
+    pub check: Option<ManifestCheck>,
+}
+
+fn manifest_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+impl AppManifest {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(hash) = &self.binary_sha256 {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("binary_sha256 {} isn't a sha256 checksum", hash));
+            }
+        }
+
+        let mut seen: HashSet<u16> = HashSet::new();
+        for port in &self.ports {
+            if *port == 0 || !seen.insert(*port) {
+                return Err(format!("port {} is invalid or listed twice", port));
+            }
+        }
+
+        if let Some(limits) = &self.limits {
+            if limits.cpu_quota_percent == Some(0)
+                || limits.memory_max_bytes == Some(0)
+                || limits.tasks_max == Some(0)
+                || limits.bandwidth_bytes_per_sec == Some(0)
+            {
+                return Err(String::from("a limit of 0 would keep the app from running"));
+            }
+        }
+
+        if let Some(restart) = &self.restart {
+            if restart.policy != RestartPolicy::Never
+                && restart.backoff_secs > restart.max_backoff_secs
+            {
+                return Err(String::from(
+                    "the restart backoff is longer than its maximum",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads and validates the app's manifest, `None` when it has none
+pub fn load_manifest(app_id: &str) -> Result<Option<AppManifest>, ErrorArrayItem> {
+    let data: String = match fs::read_to_string(format!("/etc/{}/manifest.toml", app_id)) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+
+    let manifest: AppManifest = toml::from_str(&data)
+        .map_err(|err| manifest_error(format!("Invalid manifest of {}: {}", app_id, err)))?;
+    manifest
+        .validate()
+        .map_err(|err| manifest_error(format!("Invalid manifest of {}: {}", app_id, err)))?;
+
+    Ok(Some(manifest))
+}
+
+/// The manifest for whoever needs one part of it, an invalid one was
+/// already reported when the app was resolved
+pub fn manifest_of(app_id: &str) -> Option<AppManifest> {
+    load_manifest(app_id).ok().flatten()
+}
+
+/// What the deployed app itself contradicts in its manifest
+fn static_problems(app: &ClientApplication, manifest: &AppManifest) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    if let Some(expected) = &manifest.binary_sha256 {
+        match binary_sha256(&app.path.to_string()) {
+            Ok(hash) if hash.eq_ignore_ascii_case(expected) => (),
+            Ok(hash) => problems.push(format!(
+                "binary checksum is {}, the manifest expects {}",
+                hash, expected
+            )),
+            Err(err) => problems.push(format!("couldn't hash the binary: {}", err)),
+        }
+    }
+
+    if let Some(expected) = manifest.environment_version {
+        let found: Option<EnvironmentVersion> = match app.config.get_enviornmentals() {
+            Some(Enviornment::V1(_)) => Some(EnvironmentVersion::V1),
+            Some(Enviornment::V2(_)) => Some(EnvironmentVersion::V2),
+            None => None,
+        };
+        if found != Some(expected) {
+            problems.push(format!(
+                "environment is {:?}, the manifest expects {:?}",
+                found, expected
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Ports the manifest lists that the app isn't listening on
+async fn missing_ports(name: &Stringy, ports: &[u16]) -> Vec<String> {
+    let listening: Vec<u16> = port_usage_of(name)
+        .await
+        .map(|usage| usage.listening.iter().map(|l| l.address.port()).collect())
+        .unwrap_or_default();
+
+    ports
+        .iter()
+        .filter(|port| !listening.contains(port))
+        .map(|port| format!("not listening on port {}", port))
+        .collect()
+}
+
+/// Checks every client app against its manifest. Ports and the health check
+/// only count while the app is supposed to be running.
+pub async fn check_manifests(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    if !gs.settings.manifests.enabled {
+        return Ok(());
+    }
+
+    let mut checks: HashMap<String, ManifestCheck> = HashMap::new();
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        let app: ClientApplication = match CLIENT_APPLICATION_ARRAY.get(&name).await? {
+            Some(app) => app,
+            None => continue,
+        };
+        let manifest: &AppManifest = match &app.manifest {
+            Some(manifest) => manifest,
+            None => continue,
+        };
+
+        let mut problems: Vec<String> = static_problems(&app, manifest);
+
+        let running: bool = APP_STATUS_ARRAY
+            .read(&name, |status| status.app_data.get_status())
+            .await?
+            .map_or(false, |status| {
+                matches!(status, Status::Running | Status::Warning)
+            });
+        if running {
+            problems.extend(missing_ports(&name, &manifest.ports).await);
+            if let Some(probe) = &manifest.health_check {
+                if let Err(problem) = run_probe(probe).await {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        // Logged once when it shows up rather than on every pass
+        for problem in &problems {
+            let known: bool =
+                manifest_check_of(&name).map_or(false, |last| last.problems.contains(problem));
+            if !known {
+                log!(
+                    LogLevel::Warn,
+                    "{} doesn't match its manifest: {}",
+                    name,
+                    problem
+                );
+            }
+        }
+
+        checks.insert(
+            name.to_string(),
+            ManifestCheck {
+                problems,
+                checked_at: current_timestamp(),
+            },
+        );
+    }
+
+    *MANIFEST_CHECKS
+        .lock()
+        .map_err(|err| manifest_error(err.to_string()))? = checks;
+    Ok(())
+}
+
+/// Puts a running app that differs from its manifest into Warning
+pub fn flag_manifest(name: &Stringy, app: &mut AppStatus) {
+    let problems: Vec<String> = match manifest_check_of(name) {
+        Some(check) if !check.problems.is_empty() => check.problems,
+        _ => return,
+    };
+    if app.app_data.get_status() != Status::Running {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    for problem in problems {
+        app.app_data.state.error_log.push(ErrorArrayItem::new(
+            Errors::AppState,
+            format!("MANIFEST MISMATCH: {}", problem.to_uppercase()),
+        ));
+    }
+}
+
+pub fn manifest_check_of(name: &str) -> Option<ManifestCheck> {
+    MANIFEST_CHECKS
+        .lock()
+        .ok()
+        .and_then(|checks| checks.get(name).cloned())
+}
+
+pub fn manifest_report(app_id: &str) -> ManifestReport {
+    ManifestReport {
+        manifest: manifest_of(app_id),
+        check: manifest_check_of(app_id),
+    }
+}
@@ -0,0 +1,217 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+
+/// Applied versions, captured output and locks of every migration
+pub const MIGRATION_DIR: &str = "/opt/artisan/migrations";
+
+/// Lines of migration output included in a failure message
+const MIGRATION_ERROR_LINES: usize = 20;
+
+/// Declared as a `[migration]` table in /etc/{app}/Config.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct MigrationStep {
+    /// Run with `sh -c` from the app's working directory
+    pub command: String,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    /// Apps sharing a database should share a lock name so their migrations
+    /// never overlap, defaults to the app name
+    pub lock: Option<String>,
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+fn declared_migration(app_id: &str) -> Option<MigrationStep> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config.get("migration")?.clone().try_into::<MigrationStep>() {
+        Ok(step) => Some(step),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid migration: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn applied_path(app_id: &str) -> PathBuf {
+    Path::new(MIGRATION_DIR).join(format!("{}.applied", app_id))
+}
+
+fn is_applied(app_id: &str, version: &str) -> bool {
+    fs::read_to_string(applied_path(app_id))
+        .map(|data| {
+            data.lines()
+                .any(|line| line.split_whitespace().next() == Some(version))
+        })
+        .unwrap_or(false)
+}
+
+fn mark_applied(app_id: &str, version: &str) -> Result<(), ErrorArrayItem> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(applied_path(app_id))?;
+    writeln!(file, "{} {}", version, current_timestamp())?;
+    Ok(())
+}
+
+/// Lock file holding the pid of the manager running the migration
+struct MigrationLock {
+    path: PathBuf,
+}
+
+impl MigrationLock {
+    fn acquire(name: &str) -> Result<Self, ErrorArrayItem> {
+        let lock_dir: PathBuf = Path::new(MIGRATION_DIR).join("locks");
+        fs::create_dir_all(&lock_dir)?;
+        let path: PathBuf = lock_dir.join(format!("{}.lock", name));
+
+        // A lock left behind by a manager that died mid migration is stale
+        if let Ok(holder) = fs::read_to_string(&path) {
+            let alive: bool = holder
+                .trim()
+                .parse::<u32>()
+                .map_or(false, |pid| Path::new(&format!("/proc/{}", pid)).exists());
+
+            if alive {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("Migration lock {} is held by pid {}", name, holder.trim()),
+                ));
+            }
+
+            log!(LogLevel::Warn, "Clearing stale migration lock {}", name);
+            fs::remove_file(&path)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for MigrationLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn execute(
+    app_id: &Stringy,
+    step: &MigrationStep,
+    log_path: &Path,
+) -> Result<(), ErrorArrayItem> {
+    let mut command: Command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(&step.command)
+        .current_dir(format!("/etc/{}/", app_id))
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+
+    // Client migrations run as the same user as the application
+    if let Some(uid) = CLIENT_APPLICATION_ARRAY
+        .read(app_id, |client| client.execution_uid())
+        .await?
+    {
+        command.uid(uid).gid(uid);
+    }
+
+    let timeout: Duration = Duration::from_secs(step.timeout_secs);
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Migration timed out after {}s", step.timeout_secs),
+            ))
+        }
+    };
+
+    let mut captured: Vec<u8> = output.stdout;
+    captured.extend_from_slice(&output.stderr);
+    fs::write(log_path, &captured)?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let captured: String = String::from_utf8_lossy(&captured).to_string();
+    let lines: Vec<&str> = captured.lines().collect();
+    let tail: String = lines[lines.len().saturating_sub(MIGRATION_ERROR_LINES)..].join("\n");
+
+    Err(ErrorArrayItem::new(
+        Errors::GeneralError,
+        format!("Migration failed with {}: {}", output.status, tail),
+    ))
+}
+
+/// Runs the app's declared migration for `version` unless it already ran.
+/// Output is kept in /opt/artisan/migrations/{app}/{version}.log.
+pub async fn run_pending_migration(app_id: &Stringy, version: &str) -> Result<(), ErrorArrayItem> {
+    let step: MigrationStep = match declared_migration(app_id) {
+        Some(step) => step,
+        None => return Ok(()),
+    };
+
+    fs::create_dir_all(Path::new(MIGRATION_DIR).join(app_id.to_string()))?;
+
+    let lock_name: String = step.lock.clone().unwrap_or_else(|| app_id.to_string());
+    let _lock: MigrationLock = MigrationLock::acquire(&lock_name)?;
+
+    // Checked under the lock so a concurrent rollout can't run it twice
+    if is_applied(app_id, version) {
+        log!(
+            LogLevel::Debug,
+            "Migration for {} {} already applied",
+            app_id,
+            version
+        );
+        return Ok(());
+    }
+
+    log!(
+        LogLevel::Info,
+        "Running migration for {} {}",
+        app_id,
+        version
+    );
+    let log_path: PathBuf = Path::new(MIGRATION_DIR)
+        .join(app_id.to_string())
+        .join(format!("{}.log", version));
+
+    execute(app_id, &step, &log_path).await?;
+    mark_applied(app_id, version)?;
+
+    log!(
+        LogLevel::Info,
+        "Migration for {} {} applied",
+        app_id,
+        version
+    );
+    Ok(())
+}
@@ -1,5 +1,56 @@
+pub mod adopt;
+pub mod build_cache;
+pub mod build_sandbox;
+pub mod canary;
+pub mod capture;
 pub mod child;
+pub mod config_files;
+pub mod coredump;
+pub mod descriptors;
+pub mod dependencies;
+pub mod deploy;
+pub mod deploy_queue;
+pub mod diagnostics;
+pub mod digest;
+pub mod disk;
+pub mod drift;
+pub mod egress;
+pub mod gitmon;
+pub mod health;
+pub mod hibernation;
+pub mod integrity;
+pub mod inventory;
+pub mod limits;
+pub mod liveness;
+pub mod logs;
+pub mod lookup;
+pub mod maintenance;
+pub mod manifest;
+pub mod migration;
 pub mod monitor;
+pub mod node_spec;
+pub mod pause;
+pub mod oom;
+pub mod permissions;
 pub mod pid;
+pub mod ports;
+pub mod priority;
+pub mod quota;
+pub mod reconcile;
+pub mod redaction;
+pub mod registry;
+pub mod relocate;
 pub mod resolve;
+pub mod restart;
+pub mod restart_window;
+pub mod retention;
+pub mod service_audit;
 pub mod start_stop;
+pub mod status_delta;
+pub mod status_page;
+pub mod status_store;
+pub mod unit_files;
+pub mod usage_history;
+pub mod usage_report;
+pub mod vulnerabilities;
+pub mod webhooks;
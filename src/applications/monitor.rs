@@ -1,7 +1,6 @@
 use artisan_middleware::aggregator::{AppStatus, Metrics, Status};
 use artisan_middleware::dusa_collection_utils::core::errors::Errors;
 use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
-use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
 use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
 use artisan_middleware::dusa_collection_utils::log;
 use artisan_middleware::dusa_collection_utils::{
@@ -17,28 +16,39 @@ use std::time::Duration;
 use crate::applications::child::{
     CLIENT_APPLICATION_ARRAY, CLIENT_APPLICATION_HANDLER, SYSTEM_APPLICATION_HANDLER,
 };
-use crate::applications::resolve::{
-    resolve_client_applications, resolve_system_applications, SystemApplication,
-};
+use crate::applications::resolve::{resolve_client_applications, resolve_system_applications};
 use crate::system::control::GlobalState;
 use crate::system::ebpf::{debug_print_aggregated, TrafficStats};
+use crate::system::events::ManagerEvent;
+use crate::system::ledger_store::ledger_store;
+use crate::system::settings::RetentionPolicy;
 
+use super::capture::capture_output;
 use super::child::{SupervisedProcesses, APP_STATUS_ARRAY, SYSTEM_APPLICATION_ARRAY};
-use super::pid::reclaim_child;
-use super::resolve::ClientApplication;
+use super::dependencies::flag_dependencies;
+use super::descriptors::{flag_process_counts, sample_process_counts};
+use super::disk::{flag_disk_quota, sample_disk_usage};
+use super::health::is_tripped;
+use super::hibernation::is_hibernating;
+use super::liveness::flag_liveness;
+use super::manifest::flag_manifest;
+use super::oom::flag_oom_kills;
+use super::pause::is_paused;
+use super::priority::{priority_of, PriorityClass};
+use super::registry::Registry;
+use super::retention::capture_cutoff;
+use super::usage_history::record_usage;
 
 pub async fn monitor_application_resource_usage(
-    handler: LockWithTimeout<HashMap<Stringy, SupervisedProcesses>>,
+    handler: &Registry<SupervisedProcesses>,
     gs: &Arc<GlobalState>,
 ) -> Result<(), ErrorArrayItem> {
-    let application_handler_read_lock = handler.try_read().await?;
-
     // Define an inner asynchronous function (note: use fn, not closure)
     async fn update_usage(
         name: &Stringy,
         pid: u32,
         monitor: &ResourceMonitorLock,
-        app_status_array_write_lock: &mut HashMap<Stringy, AppStatus>,
+        net_usage: &HashMap<String, TrafficStats>,
         gs: &Arc<GlobalState>,
     ) -> Result<(), ErrorArrayItem> {
         match monitor.0.try_write_with_timeout(None).await {
@@ -54,8 +64,6 @@ pub async fn monitor_application_resource_usage(
                 monitor_lock.cpu = usage.0;
                 monitor_lock.ram = usage.1;
 
-                let net_usage: HashMap<String, TrafficStats> =
-                    gs.network_monitor.aggregate_bandwidth_by_service().await?;
                 let service_network: Option<artisan_middleware::aggregator::NetworkUsage> =
                     if let Some(net) = net_usage.get(&name.to_string()) {
                         Some(net.to_network_usage())
@@ -74,11 +82,30 @@ pub async fn monitor_application_resource_usage(
                     .try_write()
                     .await?
                     .update_application_usage(name.clone(), current.clone());
-
-                debug_print_aggregated(net_usage);
-
-                if let Some(app_status) = app_status_array_write_lock.get_mut(name) {
-                    app_status.metrics = Some(current);
+                if let Err(err) = ledger_store().append(name, current_timestamp(), &current) {
+                    log!(
+                        LogLevel::Warn,
+                        "Failed to store the ledger sample of {}: {}",
+                        name,
+                        err
+                    );
+                }
+                record_usage(&gs.settings.usage_history, name, &current);
+
+                APP_STATUS_ARRAY
+                    .modify(name, |app_status| app_status.metrics = Some(current))
+                    .await?;
+
+                sample_disk_usage(&gs.settings.disk_usage, name).await?;
+
+                // The process can exit between the usage sample and here
+                if let Err(err) = sample_process_counts(&gs.settings.descriptors, name, pid) {
+                    log!(
+                        LogLevel::Debug,
+                        "Couldn't count the fds of {}: {}",
+                        name,
+                        err
+                    );
                 }
                 Ok(())
             }
@@ -86,84 +113,62 @@ pub async fn monitor_application_resource_usage(
         }
     }
 
-    for (name, app) in application_handler_read_lock.iter() {
+    // Walked once per pass, every app picks its own service out of it
+    let net_usage: HashMap<String, TrafficStats> =
+        gs.network_monitor.aggregate_bandwidth_by_service().await?;
+
+    for (name, entry) in handler.entries().await? {
         log!(LogLevel::Debug, "USAGE MONITOR: -> {}", name);
-        match app {
-            SupervisedProcesses::Child(child) => {
-                let mut app_status_array_write_lock = APP_STATUS_ARRAY.try_write().await?;
+        let app = entry.try_read().await?;
 
+        let (pid, monitor) = match &*app {
+            SupervisedProcesses::Child(child) => {
                 if !child.running().await {
                     continue;
                 }
-                let pid = child.get_pid().await?;
-                if let Err(err) = update_usage(
-                    name,
-                    pid,
-                    &child.monitor,
-                    &mut app_status_array_write_lock,
-                    &gs.clone(),
-                )
-                .await
-                {
-                    drop(app_status_array_write_lock);
-                    log!(LogLevel::Error, "Error locking monitor: {}", err);
-                    break;
-                }
-                drop(app_status_array_write_lock);
+                (child.get_pid().await?, &child.monitor)
             }
             SupervisedProcesses::Process(process) => {
-                let mut app_status_array_write_lock = APP_STATUS_ARRAY.try_write().await?;
-
                 if !process.active() {
                     continue;
                 }
-                let pid = process.get_pid() as u32;
-                if let Err(err) = update_usage(
-                    name,
-                    pid,
-                    &process.monitor,
-                    &mut app_status_array_write_lock,
-                    &gs.clone(),
-                )
-                .await
-                {
-                    drop(app_status_array_write_lock);
-                    log!(LogLevel::Error, "Error locking monitor: {}", err);
-                    break;
-                }
-                drop(app_status_array_write_lock);
+                (process.get_pid() as u32, &process.monitor)
             }
         };
+
+        if let Err(err) = update_usage(&name, pid, monitor, &net_usage, &gs.clone()).await {
+            log!(LogLevel::Error, "Error locking monitor: {}", err);
+            break;
+        }
     }
 
+    debug_print_aggregated(net_usage);
+
     Ok(())
 }
 
-pub async fn handle_dead_applications() -> Result<(), ErrorArrayItem> {
+pub async fn handle_dead_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
     log!(LogLevel::Debug, "Handling dead applications");
 
-    let mut system_handler_write_lock = SYSTEM_APPLICATION_HANDLER
-        .try_write_with_timeout(Some(Duration::from_secs(2)))
-        .await?;
-    let mut client_handler_write_lock = CLIENT_APPLICATION_HANDLER
-        .try_write_with_timeout(Some(Duration::from_secs(2)))
-        .await?;
-
     let mut system_handler_to_remove = HashSet::new();
     let mut client_handler_to_remove = HashSet::new();
 
     // Closure to process handlers
     async fn process_handlers(
-        handler: &mut HashMap<Stringy, SupervisedProcesses>,
-        app_statuses: &mut std::collections::HashMap<
-            Stringy,
-            artisan_middleware::aggregator::AppStatus,
-        >,
+        handler: &Registry<SupervisedProcesses>,
         to_remove: &mut HashSet<Stringy>,
-    ) {
-        for (app_name, process) in handler.iter_mut() {
-            if let Some(app_status) = app_statuses.get_mut(app_name) {
-                let should_remove = match process {
+    ) -> Result<(), ErrorArrayItem> {
+        for (app_name, entry) in handler.entries().await? {
+            if !APP_STATUS_ARRAY.contains(&app_name).await? {
+                continue;
+            }
+
+            let should_remove = {
+                let mut process = entry
+                    .try_write_with_timeout(Some(Duration::from_secs(2)))
+                    .await?;
+
+                match &mut *process {
                     SupervisedProcesses::Child(child) => {
                         let running = child.running().await;
                         if !running {
@@ -178,49 +183,38 @@ pub async fn handle_dead_applications() -> Result<(), ErrorArrayItem> {
                         }
                         !active
                     }
-                };
-
-                if should_remove {
-                    app_status.app_data.set_status(Status::Stopped);
-                    app_status.metrics = None;
-                    app_status.uptime = None;
-                    app_status.timestamp = current_timestamp();
-
-                    to_remove.insert(app_status.app_data.get_name().into());
                 }
+            };
+
+            if should_remove {
+                APP_STATUS_ARRAY
+                    .modify(&app_name, |app_status| {
+                        app_status.app_data.set_status(Status::Stopped);
+                        app_status.metrics = None;
+                        app_status.uptime = None;
+                        app_status.timestamp = current_timestamp();
+
+                        to_remove.insert(app_status.app_data.get_name().into());
+                    })
+                    .await?;
             }
         }
-    }
 
-    let mut app_status_array_write_lock = APP_STATUS_ARRAY
-        .try_write_with_timeout(Some(Duration::from_secs(2)))
-        .await?;
+        Ok(())
+    }
 
     // Process system and client handlers
-    process_handlers(
-        &mut system_handler_write_lock,
-        &mut app_status_array_write_lock,
-        &mut system_handler_to_remove,
-    )
-    .await;
-
-    process_handlers(
-        &mut client_handler_write_lock,
-        &mut app_status_array_write_lock,
-        &mut client_handler_to_remove,
-    )
-    .await;
-
-    drop(app_status_array_write_lock);
+    process_handlers(&SYSTEM_APPLICATION_HANDLER, &mut system_handler_to_remove).await?;
+    process_handlers(&CLIENT_APPLICATION_HANDLER, &mut client_handler_to_remove).await?;
 
     // Generic removal function
-    fn remove_dead_apps(
-        handler: &mut HashMap<Stringy, SupervisedProcesses>,
+    async fn remove_dead_apps(
+        handler: &Registry<SupervisedProcesses>,
         to_remove: &HashSet<Stringy>,
         handler_name: &str,
-    ) {
+    ) -> Result<(), ErrorArrayItem> {
         for id in to_remove {
-            if handler.remove(id).is_some() {
+            if handler.remove(id).await? {
                 log!(
                     LogLevel::Info,
                     "Removed: {} from {} handler",
@@ -229,320 +223,210 @@ pub async fn handle_dead_applications() -> Result<(), ErrorArrayItem> {
                 );
             }
         }
+        Ok(())
     }
 
     remove_dead_apps(
-        &mut system_handler_write_lock,
+        &SYSTEM_APPLICATION_HANDLER,
         &system_handler_to_remove,
         "system",
-    );
+    )
+    .await?;
 
     remove_dead_apps(
-        &mut client_handler_write_lock,
+        &CLIENT_APPLICATION_HANDLER,
         &client_handler_to_remove,
         "client",
-    );
-
-    Ok(())
-}
-
-pub async fn handle_new_system_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
-    // resolve current applications
-    resolve_system_applications(gs).await?;
-
-    let mut system_handler_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        HashMap<Stringy, SupervisedProcesses>,
-    > = SYSTEM_APPLICATION_HANDLER.try_write().await?;
-
-    let system_application_read_lock: tokio::sync::RwLockReadGuard<
-        '_,
-        HashMap<Stringy, crate::applications::resolve::SystemApplication>,
-    > = SYSTEM_APPLICATION_ARRAY.try_read().await?;
-
-    let mut system_to_start: HashMap<Stringy, SystemApplication> = HashMap::new();
+    )
+    .await?;
 
-    for new_app in system_application_read_lock.iter() {
-        if !system_handler_write_lock.contains_key(new_app.0) {
-            system_to_start.insert(new_app.0.clone(), new_app.1.clone());
+    for name in system_handler_to_remove
+        .into_iter()
+        .chain(client_handler_to_remove.into_iter())
+    {
+        // Put to sleep on purpose, it comes back with its next connection
+        if is_hibernating(name.as_str()) {
+            log!(LogLevel::Info, "{} is hibernating", name);
+            continue;
         }
-    }
-
-    drop(system_application_read_lock);
-
-    // Starting the applications.
-    // TODO if system apps are started here, they more than likly failed with systemd
-    // TODO Send a email or notification to check on this system if apps are running like this
-
-    for id in system_to_start {
-        // spawn_single_application(Application::System(id.1), &mut state, state_path).await?;
-        // instead of spawning let's just try to reclaim the pid
-
-        let app_state = id.clone().1.config;
-
-        match reclaim_child(app_state.get_pid()).await {
-            Ok(mut process) => {
-                if !process.monitoring() {
-                    process.monitor_usage().await;
-                }
-
-                // Updating the status array
-                let mut app_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-                    '_,
-                    HashMap<Stringy, AppStatus>,
-                > = APP_STATUS_ARRAY.try_write().await?;
-
-                if let Some(app) = app_status_array_write_lock.get_mut(&id.0) {
-                    app.app_data.set_pid(process.get_pid() as u32);
-                    app.app_data.set_status(app_state.get_status());
-                    if app.app_data.get_status() == Status::Idle {
-                        app.metrics = None;
-                    }
-                }
-
-                drop(app_status_array_write_lock);
 
-                // Adding to handler
-                system_handler_write_lock
-                    .insert(id.clone().0, SupervisedProcesses::Process(process));
-                log!(
-                    LogLevel::Info,
-                    "{} Started and added to the system handler",
-                    id.0
-                );
-            }
-            Err(err) => {
-                if err.err_type == Errors::SupervisedChild {
-                    log!(LogLevel::Trace, "{} not currently running", id.0);
-                    continue;
-                } else {
-                    return Err(err);
-                }
-            }
-        }
+        let priority: PriorityClass = priority_of(&name).await;
+        log!(
+            priority.alert_level(),
+            "{} ({:?}) is no longer running",
+            name,
+            priority
+        );
+        gs.events.publish(ManagerEvent::AppDied { name });
     }
 
     Ok(())
 }
 
-pub async fn handle_new_client_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
-    // resolve current applications
+pub async fn update_client_state(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    // Updating state files for system applications
+
     resolve_client_applications(&gs.clone()).await?;
 
-    let mut client_handler_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        HashMap<Stringy, SupervisedProcesses>,
-    > = CLIENT_APPLICATION_HANDLER
-        .try_write()
-        .await
-        .map_err(|mut err| {
+    for (name, entry) in APP_STATUS_ARRAY.entries().await? {
+        log!(LogLevel::Debug, "looking for {} in status array", name);
+
+        let state: AppState = match CLIENT_APPLICATION_ARRAY
+            .read(&name, |client| client.config.get_state())
+            .await?
+        {
+            Some(state) => state,
+            None => continue,
+        };
+
+        let mut shared = entry.try_write().await.map_err(|mut err| {
             err.err_mesg = format!(
-                "Error getting write lock on client handler: {}",
+                "Error getting write lock on app status in update client state: {}",
                 err.err_mesg
             )
             .into();
             err
         })?;
 
-    let client_application_read_lock: tokio::sync::RwLockReadGuard<
-        '_,
-        HashMap<Stringy, crate::applications::resolve::ClientApplication>,
-    > = CLIENT_APPLICATION_ARRAY.try_read().await?;
-
-    let mut client_to_start: HashMap<Stringy, ClientApplication> = HashMap::new();
+        let client_status: &mut AppStatus = Arc::make_mut(&mut shared);
 
-    for new_app in client_application_read_lock.iter() {
-        if !client_handler_write_lock.contains_key(new_app.0) {
-            client_to_start.insert(new_app.0.clone(), new_app.1.clone());
+        let previous_status: Status = client_status.app_data.get_status();
+        client_status.app_data.update_state(state.clone());
+        if !is_pid_active(state.pid as i32).map_err(ErrorArrayItem::from)? {
+            client_status.app_data.clear_errors();
+            client_status.app_data.set_status(Status::Stopped);
+        } else {
+            client_status.app_data.state.error_log.truncate(5);
         }
-    }
-
-    drop(client_application_read_lock);
-
-    // Starting the applications.
-    // TODO if system apps are started here, they more than likly failed with systemd
-    // TODO Send a email or notification to check on this system if apps are running like this
-
-    for id in client_to_start {
-        // spawn_single_application(Application::System(id.1), &mut state, state_path).await?;
-        // instead of spawning let's just try to reclaim the pid
-
-        let app_state = id.clone().1.config;
-
-        match reclaim_child(app_state.get_pid()).await {
-            Ok(mut process) => {
-                if !process.monitoring() {
-                    process.monitor_usage().await;
-                }
-                // Updating the status array
-                let mut app_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-                    '_,
-                    HashMap<Stringy, AppStatus>,
-                > = APP_STATUS_ARRAY.try_write().await.map_err(|mut err| {
-                    err.err_mesg = format!(
-                        "Error getting write lock on reclaiming child app status array: {}",
-                        err.err_mesg
-                    )
-                    .into();
-                    err
-                })?;
-
-                if let Some(app) = app_status_array_write_lock.get_mut(&id.0) {
-                    app.app_data.set_pid(process.get_pid() as u32);
-                    app.app_data.set_status(app_state.get_status());
-                }
 
-                drop(app_status_array_write_lock);
+        let retention: &RetentionPolicy = gs.settings.retention.policy_for(&name);
+        let captured: &mut AppState = &mut client_status.app_data.state;
+        capture_output(
+            &name,
+            gs.settings.capture.capacity_for(&name),
+            capture_cutoff(retention),
+            &mut captured.stdout,
+            &mut captured.stderr,
+        )
+        .await?;
 
-                // Adding to handler
-                client_handler_write_lock
-                    .insert(id.clone().1.name, SupervisedProcesses::Process(process));
-                log!(
-                    LogLevel::Info,
-                    "{} Started and added to the client handler",
-                    id.0
-                );
-            }
-            Err(err) => {
-                if err.err_type == Errors::SupervisedChild {
-                    log!(LogLevel::Trace, "{} not currently running", id.0);
-                    continue;
-                } else {
-                    return Err(err);
-                }
-            }
+        calculate_uptime(client_status, &state);
+        flag_oom_kills(gs, &name, client_status);
+        flag_disk_quota(&name, client_status);
+        flag_process_counts(&name, client_status);
+        flag_dependencies(&name, client_status);
+        flag_manifest(&name, client_status);
+        flag_liveness(&name, client_status);
+        // The state file still says whatever the app wrote last
+        if is_tripped(&name).await {
+            client_status.app_data.set_status(Status::Failed);
         }
+        publish_transition(
+            gs,
+            &name,
+            previous_status,
+            client_status.app_data.get_status(),
+        );
+        drop(shared);
+        APP_STATUS_ARRAY.touch(&name);
     }
 
     Ok(())
 }
 
-pub async fn update_client_state(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+pub async fn update_system_state(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
     // Updating state files for system applications
+    resolve_system_applications(gs).await?;
 
-    resolve_client_applications(&gs.clone()).await?;
+    for (name, entry) in APP_STATUS_ARRAY.entries().await? {
+        let state: AppState = match SYSTEM_APPLICATION_ARRAY
+            .read(&name, |system| system.config.get_state())
+            .await?
+        {
+            Some(state) => state,
+            None => continue,
+        };
 
-    let mut application_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        HashMap<Stringy, AppStatus>,
-    > = APP_STATUS_ARRAY.try_write().await.map_err(|mut err| {
-        err.err_mesg = format!(
-            "Error getting write lock on app status in update client state: {}",
-            err.err_mesg
-        )
-        .into();
-        err
-    })?;
+        let mut shared = entry.try_write().await.map_err(|mut err| {
+            err.err_mesg = format!(
+                "Error getting write lock on app status in update system state: {}",
+                err.err_mesg
+            )
+            .into();
+            err
+        })?;
 
-    let client_application_array_read_lock: tokio::sync::RwLockReadGuard<
-        '_,
-        HashMap<Stringy, crate::applications::resolve::ClientApplication>,
-    > = CLIENT_APPLICATION_ARRAY.try_read().await?;
+        let system_status: &mut AppStatus = Arc::make_mut(&mut shared);
 
-    for mut_client_status in application_status_array_write_lock.iter_mut() {
-        log!(
-            LogLevel::Debug,
-            "looking for {} in status array",
-            mut_client_status.0
-        );
-        if let Some(new_client_state) = client_application_array_read_lock.get(&mut_client_status.0)
-        {
-            let state = new_client_state.config.get_state();
-            mut_client_status.1.app_data.update_state(state.clone());
-            if !is_pid_active(state.pid as i32).map_err(ErrorArrayItem::from)? {
-                mut_client_status.1.app_data.clear_errors();
-                mut_client_status.1.app_data.set_status(Status::Stopped);
-            } else {
-                mut_client_status.1.app_data.state.error_log.truncate(5);
+        let previous_status: Status = system_status.app_data.get_status();
 
-                if !mut_client_status.1.app_data.state.stdout.is_empty() {
-                    mut_client_status.1.app_data.state.stdout.reverse();
-                    mut_client_status.1.app_data.state.stdout.truncate(500);
-                    mut_client_status.1.app_data.state.stdout.reverse();
-                }
-                if !mut_client_status.1.app_data.state.stderr.is_empty() {
-                    mut_client_status.1.app_data.state.stderr.reverse();
-                    mut_client_status.1.app_data.state.stderr.truncate(500);
-                    mut_client_status.1.app_data.state.stderr.reverse();
-                }
-            }
+        system_status.app_data.set_status(state.status);
 
-            calculate_uptime(mut_client_status.1, &state);
+        if !state.error_log.is_empty() {
+            system_status
+                .app_data
+                .update_error_log(state.clone().error_log, false);
+        } else {
+            system_status.app_data.clear_errors();
         }
-    }
-
-    drop(application_status_array_write_lock);
-
-    Ok(())
-}
 
-pub async fn update_system_state(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
-    // Updating state files for system applications
-    resolve_system_applications(gs).await?;
+        if !is_pid_active(state.pid as i32).map_err(ErrorArrayItem::from)? {
+            system_status.app_data.clear_errors();
+            system_status.app_data.set_status(Status::Stopped);
+        } else {
+            system_status.app_data.state.error_log.truncate(5);
+        }
 
-    let mut application_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        HashMap<Stringy, AppStatus>,
-    > = APP_STATUS_ARRAY.try_write().await.map_err(|mut err| {
-        err.err_mesg = format!(
-            "Error getting write lock on app status in update system state: {}",
-            err.err_mesg
+        let retention: &RetentionPolicy = gs.settings.retention.policy_for(&name);
+        let captured: &mut AppState = &mut system_status.app_data.state;
+        capture_output(
+            &name,
+            gs.settings.capture.capacity_for(&name),
+            capture_cutoff(retention),
+            &mut captured.stdout,
+            &mut captured.stderr,
         )
-        .into();
-        err
-    })?;
-
-    let system_application_array_read_lock: tokio::sync::RwLockReadGuard<
-        '_,
-        HashMap<Stringy, crate::applications::resolve::SystemApplication>,
-    > = SYSTEM_APPLICATION_ARRAY.try_read().await?;
-
-    for mut_system_status in application_status_array_write_lock.iter_mut() {
-        if let Some(new_client_state) = system_application_array_read_lock.get(&mut_system_status.0)
-        {
-            let state = new_client_state.config.get_state();
-
-            mut_system_status.1.app_data.set_status(state.status);
-
-            if !state.error_log.is_empty() {
-                mut_system_status
-                    .1
-                    .app_data
-                    .update_error_log(state.clone().error_log, false);
-            } else {
-                mut_system_status.1.app_data.clear_errors();
-            }
+        .await?;
 
-            if !is_pid_active(state.pid as i32).map_err(ErrorArrayItem::from)? {
-                mut_system_status.1.app_data.clear_errors();
-                mut_system_status.1.app_data.set_status(Status::Stopped);
-            } else {
-                mut_system_status.1.app_data.state.error_log.truncate(5);
+        calculate_uptime(system_status, &state);
+        flag_oom_kills(gs, &name, system_status);
+        flag_disk_quota(&name, system_status);
+        flag_process_counts(&name, system_status);
+        if is_tripped(&name).await {
+            system_status.app_data.set_status(Status::Failed);
+        }
+        publish_transition(
+            gs,
+            &name,
+            previous_status,
+            system_status.app_data.get_status(),
+        );
+        drop(shared);
+        APP_STATUS_ARRAY.touch(&name);
+    }
 
-                if !mut_system_status.1.app_data.state.stdout.is_empty() {
-                    mut_system_status.1.app_data.state.stdout.reverse();
-                    mut_system_status.1.app_data.state.stdout.truncate(500);
-                    mut_system_status.1.app_data.state.stdout.reverse();
-                }
-                if !mut_system_status.1.app_data.state.stderr.is_empty() {
-                    mut_system_status.1.app_data.state.stderr.reverse();
-                    mut_system_status.1.app_data.state.stderr.truncate(500);
-                    mut_system_status.1.app_data.state.stderr.reverse();
-                }
-            }
+    Ok(())
+}
 
-            calculate_uptime(mut_system_status.1, &state);
-        }
+fn publish_transition(gs: &Arc<GlobalState>, name: &Stringy, from: Status, to: Status) {
+    if from == to {
+        return;
     }
 
-    drop(application_status_array_write_lock);
+    // A build settling into a runnable state is the end of a deploy
+    if from == Status::Building && (to == Status::Running || to == Status::Idle) {
+        gs.events
+            .publish(ManagerEvent::DeployFinished { name: name.clone() });
+    }
 
-    Ok(())
+    gs.events.status_changed(name, from, to);
 }
 
 fn calculate_uptime(app: &mut AppStatus, state: &AppState) {
     check_balances(app);
-    let timedout = state.last_updated <= (current_timestamp() - 30);
+    // A paused app can't update its state, that's no time out
+    let timedout = state.last_updated <= (current_timestamp() - 30)
+        && !is_paused(app.app_data.get_name().as_str());
 
     if timedout {
         if let Ok(active) = is_pid_active(app.app_data.get_pid() as i32) {
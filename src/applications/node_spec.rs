@@ -0,0 +1,445 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::units::format_bytes;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+use super::limits::set_bandwidth_limit;
+use super::lookup::lookup_by_port;
+use super::start_stop::{start_application, stop_application};
+
+/// Where provisioning tooling drops the node spec, the toml one wins when
+/// both exist
+pub const NODE_SPEC_PATHS: [&str; 3] = [
+    "/opt/artisan/node.toml",
+    "/opt/artisan/node.yaml",
+    "/opt/artisan/node.yml",
+];
+
+/// Result of the last reconciliation
+static LAST_RECONCILIATION: Lazy<LockWithTimeout<Option<Reconciliation>>> =
+    Lazy::new(|| LockWithTimeout::new(None));
+
+/// Checksum of the spec that was last applied
+static APPLIED_SPEC: Lazy<LockWithTimeout<Option<String>>> =
+    Lazy::new(|| LockWithTimeout::new(None));
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NodeSpec {
+    pub apps: BTreeMap<String, AppSpec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DesiredState {
+    Running,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AppSpec {
+    pub state: DesiredState,
+    pub limits: Option<LimitSpec>,
+    /// Ports the app is expected to be listening on
+    pub ports: Vec<u16>,
+    pub probe: Option<ProbeSpec>,
+}
+
+impl Default for AppSpec {
+    fn default() -> Self {
+        Self {
+            state: DesiredState::Running,
+            limits: None,
+            ports: Vec::new(),
+            probe: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitSpec {
+    pub cpu_quota_percent: Option<u32>,
+    pub memory_max_bytes: Option<u64>,
+    pub tasks_max: Option<u32>,
+    /// Enforced by the bandwidth probes rather than the cgroup
+    pub bandwidth_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ProbeSpec {
+    Tcp {
+        port: u16,
+        #[serde(default = "default_probe_timeout")]
+        timeout_secs: u64,
+    },
+    Http {
+        port: u16,
+        #[serde(default = "default_probe_path")]
+        path: String,
+        #[serde(default = "default_probe_timeout")]
+        timeout_secs: u64,
+    },
+    /// Passes when the command exits with 0
+    Exec {
+        command: Vec<String>,
+        #[serde(default = "default_probe_timeout")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+fn default_probe_path() -> String {
+    "/".to_owned()
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AppReconciliation {
+    pub name: String,
+    /// What was changed to match the spec
+    pub actions: Vec<String>,
+    /// Differences that couldn't be fixed from here
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Reconciliation {
+    pub spec: String,
+    pub checksum: String,
+    pub reconciled_at: u64,
+    pub apps: Vec<AppReconciliation>,
+}
+
+fn spec_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Reads the node spec, `None` when the node isn't provisioned from one
+fn load_node_spec() -> Result<Option<(String, String, NodeSpec)>, ErrorArrayItem> {
+    let path: &str = match NODE_SPEC_PATHS.iter().find(|path| Path::new(path).exists()) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let data: String = fs::read_to_string(path)?;
+    let checksum: String = hex::encode(Sha256::digest(data.as_bytes()));
+
+    let spec: NodeSpec = match path.ends_with(".toml") {
+        true => toml::from_str(&data)
+            .map_err(|err| spec_error(format!("Invalid node spec {}: {}", path, err)))?,
+        false => serde_yaml::from_str(&data)
+            .map_err(|err| spec_error(format!("Invalid node spec {}: {}", path, err)))?,
+    };
+
+    Ok(Some((path.to_owned(), checksum, spec)))
+}
+
+/// The state the node spec wants each app in, empty when the node isn't
+/// provisioned from one
+pub fn desired_states() -> Result<HashMap<String, DesiredState>, ErrorArrayItem> {
+    Ok(match load_node_spec()? {
+        Some((_, _, spec)) => spec
+            .apps
+            .into_iter()
+            .map(|(name, app)| (name, app.state))
+            .collect(),
+        None => HashMap::new(),
+    })
+}
+
+async fn is_known(name: &Stringy) -> Result<bool, ErrorArrayItem> {
+    Ok(CLIENT_APPLICATION_ARRAY.contains(name).await?
+        || SYSTEM_APPLICATION_ARRAY.contains(name).await?)
+}
+
+async fn reconcile_state(name: &Stringy, desired: DesiredState, report: &mut AppReconciliation) {
+    let status: Option<Status> = match APP_STATUS_ARRAY
+        .read(name, |app| app.app_data.get_status())
+        .await
+    {
+        Ok(status) => status,
+        Err(err) => {
+            report.problems.push(err.to_string());
+            return;
+        }
+    };
+
+    let result = match (desired, status) {
+        (DesiredState::Running, Some(Status::Running)) => return,
+        (DesiredState::Stopped, Some(Status::Stopped)) | (DesiredState::Stopped, None) => return,
+        (DesiredState::Running, _) => start_application(name).await.map(|_| "started"),
+        (DesiredState::Stopped, _) => stop_application(name).await.map(|_| "stopped"),
+    };
+
+    match result {
+        Ok(action) => report.actions.push(action.to_owned()),
+        Err(err) => report
+            .problems
+            .push(format!("couldn't reach {:?}: {}", desired, err)),
+    }
+}
+
+async fn apply_limits(name: &Stringy, limits: &LimitSpec, report: &mut AppReconciliation) {
+    let mut properties: Vec<String> = Vec::new();
+    if let Some(cpu) = limits.cpu_quota_percent {
+        properties.push(format!("CPUQuota={}%", cpu));
+    }
+    if let Some(memory) = limits.memory_max_bytes {
+        properties.push(format!("MemoryMax={}", memory));
+    }
+    if let Some(tasks) = limits.tasks_max {
+        properties.push(format!("TasksMax={}", tasks));
+    }
+    if let Some(bandwidth) = limits.bandwidth_bytes_per_sec {
+        // Picked up by the tracking loop, systemd has nothing for it
+        match set_bandwidth_limit(name, Some(Some(bandwidth))) {
+            Ok(_) => report
+                .actions
+                .push(format!("capped bandwidth at {}/s", format_bytes(bandwidth))),
+            Err(err) => report
+                .problems
+                .push(format!("couldn't cap bandwidth: {}", err)),
+        }
+    }
+
+    if properties.is_empty() {
+        return;
+    }
+
+    // Runtime only, the manager applies the spec again when it starts
+    let output = Command::new("systemctl")
+        .args(["set-property", "--runtime", &format!("{}.service", name)])
+        .args(&properties)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            report.actions.push(format!("set {}", properties.join(" ")))
+        }
+        Ok(output) => report.problems.push(format!(
+            "couldn't set limits: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(err) => report
+            .problems
+            .push(format!("couldn't set limits: {}", err)),
+    }
+}
+
+async fn check_ports(name: &Stringy, ports: &[u16], report: &mut AppReconciliation) {
+    for port in ports {
+        match lookup_by_port(*port).await {
+            Ok(found) if found.app_id == *name || found.name == *name => (),
+            Ok(found) => report
+                .problems
+                .push(format!("port {} is held by {}", port, found.name)),
+            Err(_) => report
+                .problems
+                .push(format!("nothing is listening on port {}", port)),
+        }
+    }
+}
+
+pub async fn run_probe(probe: &ProbeSpec) -> Result<(), String> {
+    run_probe_as(probe, None).await
+}
+
+/// Runs the probe, an exec probe as `uid` when given so an app's own check
+/// can't do more than the app could
+pub async fn run_probe_as(probe: &ProbeSpec, uid: Option<u32>) -> Result<(), String> {
+    match probe {
+        ProbeSpec::Tcp { port, timeout_secs } => {
+            let timeout: Duration = Duration::from_secs(*timeout_secs);
+            match tokio::time::timeout(timeout, TcpStream::connect(("127.0.0.1", *port))).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(format!("tcp probe on {} failed: {}", port, err)),
+                Err(_) => Err(format!("tcp probe on {} timed out", port)),
+            }
+        }
+        ProbeSpec::Http {
+            port,
+            path,
+            timeout_secs,
+        } => {
+            let url: String = format!("http://127.0.0.1:{}/{}", port, path.trim_start_matches('/'));
+            let output = Command::new("curl")
+                .args([
+                    "--silent",
+                    "--show-error",
+                    "--fail",
+                    "--output",
+                    "/dev/null",
+                    "--max-time",
+                    &timeout_secs.to_string(),
+                    &url,
+                ])
+                .output()
+                .await
+                .map_err(|err| err.to_string())?;
+
+            match output.status.success() {
+                true => Ok(()),
+                false => Err(format!(
+                    "http probe of {} failed: {}",
+                    url,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            }
+        }
+        ProbeSpec::Exec {
+            command,
+            timeout_secs,
+        } => {
+            let (program, args) = command
+                .split_first()
+                .ok_or_else(|| String::from("exec probe has no command"))?;
+
+            let mut exec: Command = Command::new(program);
+            exec.args(args).kill_on_drop(true);
+            if let Some(uid) = uid {
+                exec.uid(uid);
+            }
+
+            let timeout: Duration = Duration::from_secs(*timeout_secs);
+            match tokio::time::timeout(timeout, exec.output()).await {
+                Ok(Ok(output)) if output.status.success() => Ok(()),
+                Ok(Ok(output)) => Err(format!(
+                    "exec probe {} exited with {}: {}",
+                    program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+                Ok(Err(err)) => Err(format!("exec probe {} failed: {}", program, err)),
+                Err(_) => Err(format!("exec probe {} timed out", program)),
+            }
+        }
+    }
+}
+
+async fn reconcile_app(name: &str, spec: &AppSpec) -> AppReconciliation {
+    let app_id: Stringy = Stringy::from(name);
+    let mut report: AppReconciliation = AppReconciliation {
+        name: name.to_owned(),
+        ..Default::default()
+    };
+
+    match is_known(&app_id).await {
+        Ok(true) => (),
+        Ok(false) => {
+            report.problems.push("not deployed on this node".to_owned());
+            return report;
+        }
+        Err(err) => {
+            report.problems.push(err.to_string());
+            return report;
+        }
+    }
+
+    if let Some(limits) = &spec.limits {
+        apply_limits(&app_id, limits, &mut report).await;
+    }
+
+    reconcile_state(&app_id, spec.state, &mut report).await;
+
+    // Listening and probes only mean something for a running app
+    if spec.state == DesiredState::Running {
+        check_ports(&app_id, &spec.ports, &mut report).await;
+
+        if let Some(probe) = &spec.probe {
+            if let Err(problem) = run_probe(probe).await {
+                report.problems.push(problem);
+            }
+        }
+    }
+
+    report
+}
+
+/// Reconciles the node to its spec when the spec is new or changed since it
+/// was last applied
+pub async fn reconcile_node_spec(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let (path, checksum, spec) = match load_node_spec()? {
+        Some(spec) => spec,
+        None => return Ok(()),
+    };
+
+    if APPLIED_SPEC.try_read().await?.as_deref() == Some(checksum.as_str()) {
+        return Ok(());
+    }
+
+    log!(LogLevel::Info, "Reconciling the node with {}", path);
+
+    let mut apps: Vec<AppReconciliation> = Vec::new();
+    for (name, app) in &spec.apps {
+        apps.push(reconcile_app(name, app).await);
+    }
+
+    let actions: usize = apps.iter().map(|app| app.actions.len()).sum();
+    let problems: usize = apps.iter().map(|app| app.problems.len()).sum();
+
+    for app in &apps {
+        for problem in &app.problems {
+            log!(LogLevel::Warn, "Node spec: {} {}", app.name, problem);
+        }
+        for action in &app.actions {
+            Decision::new(
+                "node_spec",
+                Some(&Stringy::from(app.name.as_str())),
+                action,
+                format!("the node spec {} asks for it", path),
+            )
+            .input("checksum", &checksum)
+            .record();
+        }
+    }
+
+    log!(
+        LogLevel::Info,
+        "Reconciled {} apps with {} changes and {} problems",
+        apps.len(),
+        actions,
+        problems
+    );
+
+    gs.events
+        .publish(ManagerEvent::NodeReconciled { actions, problems });
+
+    *APPLIED_SPEC.try_write().await? = Some(checksum.clone());
+    *LAST_RECONCILIATION.try_write().await? = Some(Reconciliation {
+        spec: path,
+        checksum,
+        reconciled_at: current_timestamp(),
+        apps,
+    });
+
+    Ok(())
+}
+
+pub async fn last_reconciliation() -> Result<Option<Reconciliation>, ErrorArrayItem> {
+    Ok(LAST_RECONCILIATION.try_read().await?.clone())
+}
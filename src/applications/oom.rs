@@ -0,0 +1,116 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::cgroups::service_file;
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+/// How long an app stays in Warning after the kernel OOM killed it, long
+/// enough for the portal to notice even when systemd restarts it right away
+const OOM_WARNING_SECS: u64 = 300;
+
+static OOM_KILLS: Lazy<Mutex<HashMap<String, OomKills>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OomKills {
+    /// Kills seen since the manager started
+    pub total: u64,
+    pub last_kill_at: Option<u64>,
+    /// `oom_kill` as last read from memory.events, `None` before the first
+    /// read so kills from before we started aren't counted
+    #[serde(skip)]
+    seen: Option<u64>,
+}
+
+/// The `oom_kill` counter of the app's cgroup, `None` while there's no cgroup
+fn read_oom_kills(app_name: &str) -> Option<u64> {
+    let events: String = fs::read_to_string(service_file(app_name, "memory.events")).ok()?;
+
+    events
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(key, _)| *key == "oom_kill")
+        .and_then(|(_, count)| count.trim().parse().ok())
+}
+
+/// Kills since the last poll
+fn poll_oom_kills(app_name: &str) -> u64 {
+    let current: Option<u64> = read_oom_kills(app_name);
+    let mut kills = match OOM_KILLS.lock() {
+        Ok(kills) => kills,
+        Err(_) => return 0,
+    };
+    let record: &mut OomKills = kills.entry(app_name.to_owned()).or_default();
+
+    let new_kills: u64 = match (record.seen, current) {
+        (Some(seen), Some(current)) if current >= seen => current - seen,
+        // The cgroup was recreated by a restart and counts from zero again
+        (Some(_), Some(current)) => current,
+        _ => 0,
+    };
+
+    // The next cgroup starts at zero, whatever it was before
+    record.seen = Some(current.unwrap_or(0));
+
+    if new_kills > 0 {
+        record.total += new_kills;
+        record.last_kill_at = Some(current_timestamp());
+    }
+
+    new_kills
+}
+
+/// Turns an OOM kill into a Warning with an error log entry, where it would
+/// otherwise look like the app simply stopped
+pub fn flag_oom_kills(gs: &Arc<GlobalState>, name: &Stringy, app: &mut AppStatus) {
+    let new_kills: u64 = poll_oom_kills(name);
+    let record: OomKills = oom_kills_of(name);
+
+    if new_kills > 0 {
+        log!(
+            LogLevel::Warn,
+            "{} was killed by the kernel for running out of memory, {} kills so far",
+            name,
+            record.total
+        );
+        gs.events.publish(ManagerEvent::OomKilled {
+            name: name.clone(),
+            kills: record.total,
+        });
+    }
+
+    let last_kill_at: u64 = match record.last_kill_at {
+        Some(last_kill_at) => last_kill_at,
+        None => return,
+    };
+
+    if current_timestamp().saturating_sub(last_kill_at) > OOM_WARNING_SECS {
+        return;
+    }
+
+    app.app_data.set_status(Status::Warning);
+    app.app_data.state.error_log.push(ErrorArrayItem::new(
+        Errors::AppState,
+        format!(
+            "OOM KILLED AT {}, {} KILLS SINCE MANAGER START",
+            last_kill_at, record.total
+        ),
+    ));
+}
+
+pub fn oom_kills_of(name: &str) -> OomKills {
+    OOM_KILLS
+        .lock()
+        .ok()
+        .and_then(|kills| kills.get(name).cloned())
+        .unwrap_or_default()
+}
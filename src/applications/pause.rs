@@ -0,0 +1,140 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use nix::libc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+use crate::system::systemd_bus;
+
+/// Paused apps, kept across reloads since systemd keeps them frozen
+const PAUSED_PATH: &str = "/opt/artisan/paused.json";
+
+/// Apps paused from the portal. AppStatus comes from the middleware and has
+/// no paused status, so it is kept here and added to the status JSON.
+static PAUSED: Lazy<Mutex<HashMap<String, Pause>>> = Lazy::new(|| Mutex::new(load_paused()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseMethod {
+    /// systemd froze the unit's cgroup
+    Freezer,
+    /// SIGSTOP to every process of the unit, where the host has no freezer
+    Signal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pause {
+    pub paused_at: u64,
+    pub method: PauseMethod,
+}
+
+fn pause_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_paused() -> HashMap<String, Pause> {
+    fs::read_to_string(PAUSED_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_paused(paused: &HashMap<String, Pause>) -> Result<(), ErrorArrayItem> {
+    let data: String = serde_json::to_string(paused).map_err(|err| pause_error(err.to_string()))?;
+    let temp: String = format!("{}.tmp", PAUSED_PATH);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, PAUSED_PATH)?;
+    Ok(())
+}
+
+/// Freezes every process of the app until it's resumed. It keeps its
+/// memory and connections, but answers nothing in the meantime.
+pub async fn pause_application(app_id: &Stringy) -> Result<Pause, ErrorArrayItem> {
+    if *app_id == "ais_manager".into() {
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            "The manager can't pause itself",
+        ));
+    }
+    if let Some(pause) = pause_of(app_id.as_str()) {
+        return Ok(pause);
+    }
+    if !systemd_bus::is_active(app_id.as_str()).await? {
+        return Err(pause_error(format!("{} isn't running", app_id)));
+    }
+
+    let method: PauseMethod = match systemd_bus::freeze_unit(app_id.as_str()).await {
+        Ok(_) => PauseMethod::Freezer,
+        Err(err) => {
+            log!(LogLevel::Debug, "Stopping {} with signals, {}", app_id, err);
+            systemd_bus::kill_unit(app_id.as_str(), libc::SIGSTOP).await?;
+            PauseMethod::Signal
+        }
+    };
+
+    let pause: Pause = Pause {
+        paused_at: current_timestamp(),
+        method,
+    };
+    let mut paused = PAUSED.lock().map_err(|err| pause_error(err.to_string()))?;
+    paused.insert(app_id.to_string(), pause.clone());
+    // It's frozen either way, resuming still works until the next reload
+    if let Err(err) = save_paused(&paused) {
+        log!(LogLevel::Error, "Failed to save paused apps: {}", err);
+    }
+    drop(paused);
+
+    log!(LogLevel::Info, "Paused {} ({:?})", app_id, method);
+    Ok(pause)
+}
+
+/// Lets a paused app carry on where it stopped
+pub async fn resume_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let pause: Pause = pause_of(app_id.as_str())
+        .ok_or_else(|| ErrorArrayItem::new(Errors::NotFound, format!("{} isn't paused", app_id)))?;
+
+    match pause.method {
+        PauseMethod::Freezer => systemd_bus::thaw_unit(app_id.as_str()).await?,
+        PauseMethod::Signal => systemd_bus::kill_unit(app_id.as_str(), libc::SIGCONT).await?,
+    }
+
+    forget_pause(app_id);
+    log!(
+        LogLevel::Info,
+        "Resumed {} after {}s",
+        app_id,
+        current_timestamp().saturating_sub(pause.paused_at)
+    );
+    Ok(())
+}
+
+/// Called when the app is started or stopped, either way it isn't paused
+/// anymore
+pub fn forget_pause(app_id: &Stringy) {
+    if let Ok(mut paused) = PAUSED.lock() {
+        if paused.remove(app_id.as_str()).is_some() {
+            if let Err(err) = save_paused(&paused) {
+                log!(LogLevel::Error, "Failed to save paused apps: {}", err);
+            }
+        }
+    }
+}
+
+/// Whether the app is paused, it doesn't update its state or answer probes
+/// while it is
+pub fn is_paused(app_id: &str) -> bool {
+    pause_of(app_id).is_some()
+}
+
+pub fn pause_of(app_id: &str) -> Option<Pause> {
+    PAUSED
+        .lock()
+        .ok()
+        .and_then(|paused| paused.get(app_id).cloned())
+}
@@ -0,0 +1,193 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+use super::child::{CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+
+/// Latest permission audit for every application
+pub static PERMISSION_REPORTS: Lazy<LockWithTimeout<HashMap<Stringy, PermissionReport>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// How deep we walk into an application's config and data directory
+const AUDIT_MAX_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PermissionFinding {
+    pub path: String,
+    pub issue: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionReport {
+    pub compliant: bool,
+    pub findings: Vec<PermissionFinding>,
+    pub checked_at: u64,
+}
+
+/// Who is allowed to own an application's files
+struct Expectation {
+    name: Stringy,
+    /// Uid the application runs as, root for system applications
+    uid: u32,
+}
+
+impl Expectation {
+    fn binary(&self) -> PathBuf {
+        PathBuf::from(format!("/opt/artisan/bin/{}", self.name))
+    }
+
+    fn config_dir(&self) -> PathBuf {
+        PathBuf::from(format!("/etc/{}", self.name))
+    }
+}
+
+fn check_path(path: &Path, allowed_uids: &[u32], findings: &mut Vec<PermissionFinding>) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+
+    // Symlink permissions are meaningless, the target gets checked if it's ours
+    if metadata.file_type().is_symlink() {
+        return;
+    }
+
+    let mode: u32 = metadata.mode() & 0o7777;
+    let mut finding = |issue: String| {
+        findings.push(PermissionFinding {
+            path: path.display().to_string(),
+            issue,
+        })
+    };
+
+    if mode & 0o002 != 0 {
+        finding(format!("world writable ({:o})", mode));
+    }
+
+    if !allowed_uids.contains(&metadata.uid()) {
+        finding(format!("owned by uid {}", metadata.uid()));
+    }
+
+    if path.file_name().map_or(false, |name| name == ".env") && mode & 0o004 != 0 {
+        finding(format!("world readable secrets ({:o})", mode));
+    }
+}
+
+fn walk(dir: &Path, depth: usize, allowed_uids: &[u32], findings: &mut Vec<PermissionFinding>) {
+    check_path(dir, allowed_uids, findings);
+
+    if depth >= AUDIT_MAX_DEPTH {
+        return;
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                walk(&entry.path(), depth + 1, allowed_uids, findings)
+            }
+            Ok(_) => check_path(&entry.path(), allowed_uids, findings),
+            Err(_) => continue,
+        }
+    }
+}
+
+/// Binaries must belong to root, config and data may also belong to the
+/// user the application runs as
+fn audit(expectation: &Expectation) -> PermissionReport {
+    let mut findings: Vec<PermissionFinding> = Vec::new();
+
+    check_path(&expectation.binary(), &[0], &mut findings);
+    walk(
+        &expectation.config_dir(),
+        0,
+        &[0, expectation.uid],
+        &mut findings,
+    );
+
+    PermissionReport {
+        compliant: findings.is_empty(),
+        findings,
+        checked_at: current_timestamp(),
+    }
+}
+
+async fn expectations() -> Result<Vec<Expectation>, ErrorArrayItem> {
+    let mut expectations: Vec<Expectation> = Vec::new();
+
+    for name in SYSTEM_APPLICATION_ARRAY.keys().await? {
+        expectations.push(Expectation { name, uid: 0 });
+    }
+
+    for (name, client) in CLIENT_APPLICATION_ARRAY.entries().await? {
+        expectations.push(Expectation {
+            name,
+            uid: client.try_read().await?.execution_uid(),
+        });
+    }
+
+    Ok(expectations)
+}
+
+/// Audits the binary, config and data directory of every application and
+/// publishes any finding that wasn't there on the previous run
+pub async fn audit_permissions(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    for expectation in expectations().await? {
+        let report: PermissionReport = audit(&expectation);
+        let mut reports = PERMISSION_REPORTS.try_write().await?;
+
+        let previous: Vec<PermissionFinding> = reports
+            .get(&expectation.name)
+            .map(|old| old.findings.clone())
+            .unwrap_or_default();
+
+        for finding in report
+            .findings
+            .iter()
+            .filter(|finding| !previous.contains(finding))
+        {
+            log!(
+                LogLevel::Warn,
+                "{}: {} is {}",
+                expectation.name,
+                finding.path,
+                finding.issue
+            );
+            gs.events.publish(ManagerEvent::SecurityFinding {
+                name: expectation.name.clone(),
+                path: finding.path.clone(),
+                issue: finding.issue.clone(),
+            });
+        }
+
+        reports.insert(expectation.name, report);
+    }
+
+    Ok(())
+}
+
+pub async fn permission_report_of(app_name: &Stringy) -> Option<PermissionReport> {
+    PERMISSION_REPORTS
+        .try_read()
+        .await
+        .ok()?
+        .get(app_name)
+        .cloned()
+}
@@ -0,0 +1,274 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use procfs::net::TcpState;
+use procfs::process::{FDTarget, Process};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::system::cgroups::slice_dir;
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::PortUsageSettings;
+use crate::system::units::format_percent;
+
+use super::child::pids_in_cgroup;
+
+/// Latest socket and conntrack usage sample for every application
+pub static PORT_USAGE: Lazy<LockWithTimeout<HashMap<Stringy, PortUsage>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PortUsage {
+    pub sockets: usize,
+    /// Outbound sockets bound to a port in the kernel's ephemeral range
+    pub ephemeral_ports: usize,
+    pub conntrack_entries: usize,
+    /// TCP connections in ESTABLISHED
+    pub established: usize,
+    /// Listening TCP sockets and unconnected UDP ones, sorted
+    pub listening: Vec<ListeningPort>,
+    pub sampled_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ListeningPort {
+    pub protocol: String,
+    pub address: SocketAddr,
+}
+
+/// Reads net.ipv4.ip_local_port_range, falling back to the kernel default
+fn ephemeral_range() -> (u16, u16) {
+    fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+        .ok()
+        .and_then(|data| {
+            let mut parts = data.split_whitespace().map(|part| part.parse::<u16>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(low)), Some(Ok(high))) => Some((low, high)),
+                _ => None,
+            }
+        })
+        .unwrap_or((32768, 60999))
+}
+
+fn conntrack_max() -> Option<usize> {
+    fs::read_to_string("/proc/sys/net/netfilter/nf_conntrack_max")
+        .ok()
+        .and_then(|data| data.trim().parse().ok())
+}
+
+/// Maps every socket inode held by a process in artisan.slice to its app
+fn socket_owners() -> Result<HashMap<u64, Stringy>, ErrorArrayItem> {
+    let mut owners: HashMap<u64, Stringy> = HashMap::new();
+
+    for entry in fs::read_dir(slice_dir())? {
+        let path = entry?.path();
+        let service_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if name.ends_with(".service") => name.trim_end_matches(".service"),
+            _ => continue,
+        };
+
+        let pids = match pids_in_cgroup(service_name) {
+            Ok(pids) => pids,
+            Err(_) => continue,
+        };
+
+        for pid in pids {
+            let fds = match Process::new(pid as i32).and_then(|process| process.fd()) {
+                Ok(fds) => fds,
+                Err(err) => {
+                    log!(LogLevel::Trace, "Can't read fds of {}: {}", pid, err);
+                    continue;
+                }
+            };
+
+            for fd in fds.filter_map(|fd| fd.ok()) {
+                if let FDTarget::Socket(inode) = fd.target {
+                    owners.insert(inode, service_name.into());
+                }
+            }
+        }
+    }
+
+    Ok(owners)
+}
+
+/// The original direction source address and port of a conntrack line
+fn conntrack_source(line: &str) -> Option<SocketAddr> {
+    let field = |key: &str| {
+        line.split_whitespace()
+            .find_map(|part| part.strip_prefix(key))
+    };
+
+    let ip: IpAddr = field("src=")?.parse().ok()?;
+    let port: u16 = field("sport=")?.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Attributes sockets and conntrack entries to the applications that own them.
+/// Sockets in TIME_WAIT no longer have an owner and aren't counted.
+fn sample_port_usage() -> Result<HashMap<Stringy, PortUsage>, ErrorArrayItem> {
+    let owners: HashMap<u64, Stringy> = socket_owners()?;
+    let (low, high) = ephemeral_range();
+    let timestamp: u64 = current_timestamp();
+
+    let mut usage: HashMap<Stringy, PortUsage> = HashMap::new();
+    let mut local_addresses: HashMap<SocketAddr, Stringy> = HashMap::new();
+
+    let tcp = procfs::net::tcp()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(procfs::net::tcp6().unwrap_or_default())
+        .map(|entry| {
+            let listening: bool = entry.state == TcpState::Listen;
+            let established: bool = entry.state == TcpState::Established;
+            (
+                entry.inode,
+                entry.local_address,
+                entry.remote_address,
+                "tcp",
+                listening,
+                established,
+            )
+        });
+    // An unconnected UDP socket is as close to listening as UDP gets
+    let udp = procfs::net::udp()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(procfs::net::udp6().unwrap_or_default())
+        .map(|entry| {
+            let listening: bool = entry.remote_address.port() == 0;
+            (
+                entry.inode,
+                entry.local_address,
+                entry.remote_address,
+                "udp",
+                listening,
+                false,
+            )
+        });
+
+    for (inode, local, remote, protocol, listening, established) in tcp.chain(udp) {
+        let owner: &Stringy = match owners.get(&inode) {
+            Some(owner) => owner,
+            None => continue,
+        };
+
+        let entry: &mut PortUsage = usage.entry(owner.clone()).or_insert_with(|| PortUsage {
+            sampled_at: timestamp,
+            ..Default::default()
+        });
+        entry.sockets += 1;
+
+        if established {
+            entry.established += 1;
+        }
+        if listening {
+            entry.listening.push(ListeningPort {
+                protocol: protocol.to_owned(),
+                address: local,
+            });
+        }
+
+        let port: u16 = local.port();
+        if remote.port() != 0 && port >= low && port <= high {
+            entry.ephemeral_ports += 1;
+        }
+
+        local_addresses.insert(local, owner.clone());
+    }
+
+    // Not every host has the conntrack module loaded
+    if let Ok(conntrack) = fs::read_to_string("/proc/net/nf_conntrack") {
+        for line in conntrack.lines() {
+            let owner = conntrack_source(line).and_then(|source| {
+                local_addresses.get(&source).or_else(|| {
+                    // Sockets bound to the wildcard address
+                    let any: IpAddr = match source.ip() {
+                        IpAddr::V4(_) => IpAddr::from([0u8; 4]),
+                        IpAddr::V6(_) => IpAddr::from([0u16; 8]),
+                    };
+                    local_addresses.get(&SocketAddr::new(any, source.port()))
+                })
+            });
+
+            if let Some(entry) = owner.and_then(|owner| usage.get_mut(owner)) {
+                entry.conntrack_entries += 1;
+            }
+        }
+    }
+
+    for entry in usage.values_mut() {
+        entry.listening.sort();
+        entry.listening.dedup();
+    }
+
+    Ok(usage)
+}
+
+/// Samples per application port usage and raises an event for any application
+/// consuming more than its share of the ephemeral range or conntrack table
+pub async fn monitor_port_usage(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &PortUsageSettings = &gs.settings.port_usage;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let usage: HashMap<Stringy, PortUsage> = sample_port_usage()?;
+    let (low, high) = ephemeral_range();
+    let range_size: f64 = (high.saturating_sub(low) as f64 + 1.0).max(1.0);
+    let conntrack_limit: Option<usize> = conntrack_max();
+
+    // Share of the ephemeral range and of the conntrack table
+    let percentages = |sample: &PortUsage| -> (f64, f64) {
+        let ephemeral: f64 = sample.ephemeral_ports as f64 / range_size * 100.0;
+        let conntrack: f64 = conntrack_limit
+            .map(|max| sample.conntrack_entries as f64 / max.max(1) as f64 * 100.0)
+            .unwrap_or_default();
+        (ephemeral, conntrack)
+    };
+    let exhausting = |sample: &PortUsage| -> bool {
+        let (ephemeral, conntrack) = percentages(sample);
+        ephemeral >= settings.alert_percent || conntrack >= settings.alert_percent
+    };
+
+    let mut store = PORT_USAGE.try_write().await?;
+
+    for (name, sample) in &usage {
+        // Only alert when an application crosses the line, not every sample
+        let was_exhausting: bool = store.get(name).map_or(false, |old| exhausting(old));
+        if exhausting(sample) && !was_exhausting {
+            let (ephemeral_percent, conntrack_percent) = percentages(sample);
+            log!(
+                LogLevel::Warn,
+                "{} is using {} of ephemeral ports and {} of conntrack",
+                name,
+                format_percent(ephemeral_percent),
+                format_percent(conntrack_percent)
+            );
+            gs.events.publish(ManagerEvent::PortExhaustion {
+                name: name.clone(),
+                ephemeral_ports: sample.ephemeral_ports,
+                conntrack_entries: sample.conntrack_entries,
+            });
+        }
+    }
+
+    *store = usage;
+    Ok(())
+}
+
+pub async fn port_usage_of(app_name: &Stringy) -> Option<PortUsage> {
+    PORT_USAGE.try_read().await.ok()?.get(app_name).cloned()
+}
+
+pub async fn all_port_usage() -> Result<HashMap<Stringy, PortUsage>, ErrorArrayItem> {
+    Ok(PORT_USAGE.try_read().await?.clone())
+}
@@ -0,0 +1,126 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::system::cgroups::service_file;
+
+use super::child::{CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+
+pub static APP_PRIORITIES: Lazy<LockWithTimeout<HashMap<Stringy, PriorityClass>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// Declared as `priority = "critical" | "standard" | "best-effort"` in /etc/{app}/Config.toml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PriorityClass {
+    Critical,
+    Standard,
+    BestEffort,
+}
+
+impl PriorityClass {
+    /// cgroup v2 cpu.weight and io.weight for the class
+    pub fn cgroup_weight(&self) -> u32 {
+        match self {
+            PriorityClass::Critical => 400,
+            PriorityClass::Standard => 100,
+            PriorityClass::BestEffort => 25,
+        }
+    }
+
+    /// How loudly we complain when an app of this class has problems
+    pub fn alert_level(&self) -> LogLevel {
+        match self {
+            PriorityClass::Critical => LogLevel::Error,
+            PriorityClass::Standard => LogLevel::Warn,
+            PriorityClass::BestEffort => LogLevel::Info,
+        }
+    }
+}
+
+fn declared_priority(app_name: &str) -> Option<PriorityClass> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_name)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+    let priority: String = config.get("priority")?.as_str()?.to_owned();
+
+    match toml::Value::String(priority.clone()).try_into::<PriorityClass>() {
+        Ok(class) => Some(class),
+        Err(_) => {
+            log!(
+                LogLevel::Warn,
+                "{} declares an unknown priority class: {}",
+                app_name,
+                priority
+            );
+            None
+        }
+    }
+}
+
+fn apply_cgroup_weight(app_name: &str, class: PriorityClass) {
+    for file in ["cpu.weight", "io.weight"] {
+        let path = service_file(app_name, file);
+        if let Err(err) = fs::write(&path, class.cgroup_weight().to_string()) {
+            log!(LogLevel::Trace, "Couldn't set {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Re-reads the declared priority of every application and updates the cgroup
+/// weights of any whose class changed. System apps default to critical.
+pub async fn refresh_priorities() -> Result<(), ErrorArrayItem> {
+    let mut apps: Vec<(Stringy, PriorityClass)> = Vec::new();
+
+    for name in SYSTEM_APPLICATION_ARRAY.keys().await? {
+        apps.push((name, PriorityClass::Critical));
+    }
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        apps.push((name, PriorityClass::Standard));
+    }
+
+    let mut priorities = APP_PRIORITIES.try_write().await?;
+
+    for (name, default) in apps {
+        let class: PriorityClass = declared_priority(&name).unwrap_or(default);
+
+        if priorities.get(&name) != Some(&class) {
+            log!(LogLevel::Debug, "{} priority class is {:?}", name, class);
+            apply_cgroup_weight(&name, class);
+            priorities.insert(name, class);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn priority_of(app_name: &Stringy) -> PriorityClass {
+    match APP_PRIORITIES.try_read().await {
+        Ok(priorities) => priorities
+            .get(app_name)
+            .copied()
+            .unwrap_or(PriorityClass::Standard),
+        Err(_) => PriorityClass::Standard,
+    }
+}
+
+/// Orders applications so the most important come first
+pub async fn sort_by_priority(apps: &mut Vec<Stringy>) {
+    let priorities: HashMap<Stringy, PriorityClass> = match APP_PRIORITIES.try_read().await {
+        Ok(priorities) => priorities.clone(),
+        Err(_) => return,
+    };
+
+    apps.sort_by_key(|name| {
+        priorities
+            .get(name)
+            .copied()
+            .unwrap_or(PriorityClass::Standard)
+    });
+}
@@ -0,0 +1,292 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::{AppQuota, QuotaSettings};
+use crate::system::timezone::{zone_for, Zone};
+
+use super::child::APP_STATUS_ARRAY;
+use super::start_stop::{start_application, stop_application};
+use super::usage_history::{usage_totals, UsageTotals};
+use super::usage_report::{current_label, period_bounds, AppUsage, ReportPeriod};
+
+/// Suspensions and lifted quotas, kept across restarts
+const SUSPENSIONS_PATH: &str = "/opt/artisan/suspensions.json";
+
+static SUSPENSIONS: Lazy<Mutex<SuspensionState>> = Lazy::new(|| Mutex::new(load_state()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    TransferGb,
+    CpuCoreHours,
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKind::TransferGb => write!(f, "GB of transfer"),
+            QuotaKind::CpuCoreHours => write!(f, "CPU core hours"),
+        }
+    }
+}
+
+/// Why an app is suspended. The middleware's `Status` has no suspended
+/// state, so a suspended app reads as stopped with this next to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suspension {
+    pub quota: QuotaKind,
+    pub used: f64,
+    pub limit: f64,
+    /// The month the quota was exceeded in, `2026-10`
+    pub period: String,
+    pub since: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SuspensionState {
+    suspended: HashMap<String, Suspension>,
+    /// Apps whose suspension was lifted by hand, they're left alone for the
+    /// rest of that month
+    lifted: HashMap<String, String>,
+}
+
+/// An app's usage this month against its quota
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub period: String,
+    pub quota: AppQuota,
+    pub transfer_gb: f64,
+    pub cpu_core_hours: f64,
+    pub suspension: Option<Suspension>,
+}
+
+fn quota_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_state() -> SuspensionState {
+    fs::read_to_string(SUSPENSIONS_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(state: &SuspensionState) -> Result<(), ErrorArrayItem> {
+    let data: String = serde_json::to_string(state).map_err(|err| quota_error(err.to_string()))?;
+    fs::write(SUSPENSIONS_PATH, data)?;
+    Ok(())
+}
+
+fn with_state<T>(f: impl FnOnce(&mut SuspensionState) -> T) -> Result<T, ErrorArrayItem> {
+    let mut state = SUSPENSIONS
+        .lock()
+        .map_err(|err| quota_error(err.to_string()))?;
+    Ok(f(&mut state))
+}
+
+pub fn suspension_of(app_id: &str) -> Option<Suspension> {
+    with_state(|state| state.suspended.get(app_id).cloned())
+        .ok()
+        .flatten()
+}
+
+/// Refuses to start a suspended app, whoever asks
+pub fn check_not_suspended(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    match suspension_of(app_id.as_str()) {
+        Some(suspension) => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "{} is suspended for going over its {} quota, lift the suspension first",
+                app_id, suspension.quota
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Label and usage of every app for the month so far
+fn month_to_date() -> Result<(String, BTreeMap<String, UsageTotals>), ErrorArrayItem> {
+    let zone: Zone = zone_for(None);
+    let now: u64 = current_timestamp();
+    let label: String = current_label(&zone, ReportPeriod::Monthly, now);
+    let (from, _) = period_bounds(&zone, ReportPeriod::Monthly, &label)?;
+
+    Ok((label, usage_totals(from, now + 1)?))
+}
+
+/// The first quota the usage is over, with what was used and the limit
+fn exceeded(quota: &AppQuota, usage: &AppUsage) -> Option<(QuotaKind, f64, f64)> {
+    let cpu_core_hours: f64 = usage.cpu_seconds / 3600.0;
+
+    match (quota.transfer_gb, quota.cpu_core_hours) {
+        (Some(limit), _) if usage.transfer_gb > limit => {
+            Some((QuotaKind::TransferGb, usage.transfer_gb, limit))
+        }
+        (_, Some(limit)) if cpu_core_hours > limit => {
+            Some((QuotaKind::CpuCoreHours, cpu_core_hours, limit))
+        }
+        _ => None,
+    }
+}
+
+async fn suspend(
+    gs: &Arc<GlobalState>,
+    name: &Stringy,
+    suspension: Suspension,
+) -> Result<(), ErrorArrayItem> {
+    let (quota, used, limit) = (suspension.quota, suspension.used, suspension.limit);
+    Decision::new(
+        "quota",
+        Some(name),
+        "suspend",
+        format!("it went over its monthly {} quota", quota),
+    )
+    .input("quota", quota)
+    .input("used", used)
+    .input("limit", limit)
+    .input("period", &suspension.period)
+    .record();
+    with_state(|state| {
+        state.suspended.insert(name.to_string(), suspension);
+        save_state(state)
+    })??;
+
+    // Suspended even if the unit won't stop, nothing starts it again
+    if let Err(err) = stop_application(name).await {
+        log!(
+            LogLevel::Error,
+            "Failed to stop suspended {}: {}",
+            name,
+            err
+        );
+    }
+    APP_STATUS_ARRAY
+        .modify(name, |status| {
+            status.app_data.set_status(Status::Stopped);
+            status.expected_status = Status::Stopped;
+        })
+        .await?;
+
+    log!(
+        LogLevel::Warn,
+        "Suspended {}, it used {:.2} of its {:.2} {} this month",
+        name,
+        used,
+        limit,
+        quota
+    );
+    gs.events.publish(ManagerEvent::QuotaSuspended {
+        name: name.clone(),
+        quota: quota.to_string(),
+        used,
+        limit,
+    });
+    Ok(())
+}
+
+/// Suspends every app the month's usage shows over one of its quotas
+pub async fn enforce_quotas(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &QuotaSettings = &gs.settings.quotas;
+    if !settings.enabled || settings.apps.is_empty() {
+        return Ok(());
+    }
+
+    let (period, totals) = month_to_date()?;
+    with_state(|state| state.lifted.retain(|_, lifted| *lifted == period))?;
+
+    for (name, quota) in &settings.apps {
+        let skip: bool = with_state(|state| {
+            state.suspended.contains_key(name) || state.lifted.contains_key(name)
+        })?;
+        if skip {
+            continue;
+        }
+
+        let usage: AppUsage = totals.get(name).map(AppUsage::from).unwrap_or_default();
+        if let Some((kind, used, limit)) = exceeded(quota, &usage) {
+            let suspension: Suspension = Suspension {
+                quota: kind,
+                used,
+                limit,
+                period: period.clone(),
+                since: current_timestamp(),
+            };
+            suspend(gs, &Stringy::from(name.as_str()), suspension).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Lifts the suspension and starts the app again. The quota isn't checked
+/// for it again until the next month.
+pub async fn lift_suspension(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+) -> Result<(), ErrorArrayItem> {
+    let lifted: bool = with_state(|state| match state.suspended.remove(app_id.as_str()) {
+        Some(suspension) => {
+            state.lifted.insert(app_id.to_string(), suspension.period);
+            save_state(state).map(|_| true)
+        }
+        None => Ok(false),
+    })??;
+
+    if !lifted {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} isn't suspended", app_id),
+        ));
+    }
+
+    APP_STATUS_ARRAY
+        .modify(app_id, |status| status.expected_status = Status::Running)
+        .await?;
+    log!(LogLevel::Info, "Lifted the quota suspension of {}", app_id);
+    gs.events.publish(ManagerEvent::SuspensionLifted {
+        name: app_id.clone(),
+    });
+
+    start_application(app_id).await
+}
+
+pub fn quota_usage(
+    settings: &QuotaSettings,
+    app_id: &Stringy,
+) -> Result<QuotaUsage, ErrorArrayItem> {
+    let quota: AppQuota = match settings.apps.get(app_id.as_str()) {
+        Some(quota) => quota.clone(),
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("{} has no quota", app_id),
+            ))
+        }
+    };
+
+    let (period, totals) = month_to_date()?;
+    let usage: AppUsage = totals
+        .get(app_id.as_str())
+        .map(AppUsage::from)
+        .unwrap_or_default();
+
+    Ok(QuotaUsage {
+        period,
+        quota,
+        transfer_gb: usage.transfer_gb,
+        cpu_core_hours: usage.cpu_seconds / 3600.0,
+        suspension: suspension_of(app_id.as_str()),
+    })
+}
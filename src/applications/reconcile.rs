@@ -0,0 +1,427 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::process_manager::is_pid_active;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::applications::resolve::{resolve_client_applications, resolve_system_applications};
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::ReconcileSettings;
+use crate::system::systemd_bus;
+
+use super::child::{
+    SupervisedProcesses, APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY, CLIENT_APPLICATION_HANDLER,
+    SYSTEM_APPLICATION_ARRAY, SYSTEM_APPLICATION_HANDLER,
+};
+use super::health::{is_tripped, record_start};
+use super::integrity::{quarantine_of, verify_binary};
+use super::limits::enforce_limits;
+use super::node_spec::{desired_states, DesiredState};
+use super::pid::reclaim_child;
+use super::priority::sort_by_priority;
+use super::quota::suspension_of;
+use super::registry::Registry;
+use super::restart::left_down;
+use super::start_stop::{start_application, stop_application};
+
+/// Outcome of the last pass over every app. AppStatus comes from the
+/// middleware, so it is kept here and added to the status JSON.
+static RECONCILED: Lazy<Mutex<HashMap<String, AppReconcile>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AppKind {
+    System,
+    Client,
+}
+
+impl AppKind {
+    fn handler(&self) -> &'static Registry<SupervisedProcesses> {
+        match self {
+            AppKind::System => &SYSTEM_APPLICATION_HANDLER,
+            AppKind::Client => &CLIENT_APPLICATION_HANDLER,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AppKind::System => "system",
+            AppKind::Client => "client",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Desired {
+    Running,
+    Stopped,
+    /// Down on purpose for now, left to the operator, the restart
+    /// supervisor or the crash loop breaker
+    Unmanaged,
+}
+
+/// What's actually there, from systemd and /proc
+#[derive(Debug, Clone, Serialize)]
+pub struct Observed {
+    pub unit_active: bool,
+    pub pid: u32,
+    pub pid_alive: bool,
+    /// The process is under our supervision
+    pub supervised: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Take a running process under supervision
+    Adopt,
+    Start,
+    Stop,
+}
+
+impl Action {
+    fn verb(&self) -> &'static str {
+        match self {
+            Action::Adopt => "adopt",
+            Action::Start => "start",
+            Action::Stop => "stop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AppReconcile {
+    pub desired: Desired,
+    pub observed: Observed,
+    pub action: Option<Action>,
+    /// How observed and desired differ when nothing could be done about it
+    pub drift: Option<String>,
+    pub reconciled_at: u64,
+}
+
+fn reconcile_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// System applications run as systemd services, one outside a service cgroup
+/// was likely started by hand after systemd gave up on it
+fn outside_systemd(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+        Ok(cgroup) => !cgroup.lines().any(|line| line.ends_with(".service")),
+        Err(_) => false,
+    }
+}
+
+/// Running unless the node spec, a suspension, a quarantine, a masked unit
+/// or whoever stopped the app says otherwise
+async fn desired_state(name: &Stringy, spec: &HashMap<String, DesiredState>) -> Desired {
+    if suspension_of(name.as_str()).is_some()
+        || quarantine_of(name.as_str()).is_some()
+        || spec.get(name.as_str()) == Some(&DesiredState::Stopped)
+        || matches!(
+            systemd_bus::unit_file_state(name.as_str()).await.as_deref(),
+            Ok("masked")
+        )
+    {
+        return Desired::Stopped;
+    }
+
+    match is_tripped(name).await || left_down(name).await {
+        true => Desired::Unmanaged,
+        false => Desired::Running,
+    }
+}
+
+async fn observe(name: &Stringy, pid: u32, kind: AppKind) -> Result<Observed, ErrorArrayItem> {
+    let unit_active: bool = systemd_bus::is_active(name.as_str())
+        .await
+        .map_err(|err| reconcile_error(format!("Can't ask systemd about {}: {}", name, err)))?;
+
+    Ok(Observed {
+        unit_active,
+        pid,
+        pid_alive: pid != 0 && matches!(is_pid_active(pid as i32), Ok(true)),
+        supervised: kind.handler().contains(name).await?,
+    })
+}
+
+fn plan(
+    desired: Desired,
+    observed: &Observed,
+    settings: &ReconcileSettings,
+) -> (Option<Action>, Option<String>) {
+    let up: bool = observed.unit_active || observed.pid_alive;
+
+    match desired {
+        Desired::Stopped if up => match settings.stop_unexpected {
+            true => (Some(Action::Stop), None),
+            false => (None, Some(String::from("running but expected stopped"))),
+        },
+        // Adopting would record a start, tripping the breaker all over again
+        Desired::Unmanaged => (None, None),
+        _ if observed.pid_alive && !observed.supervised => (Some(Action::Adopt), None),
+        Desired::Running if !up => match settings.start_missing {
+            true => (Some(Action::Start), None),
+            false => (None, Some(String::from("stopped but expected running"))),
+        },
+        Desired::Running if observed.unit_active && !observed.pid_alive => (
+            None,
+            Some(format!(
+                "systemd reports the unit active but pid {} is gone",
+                observed.pid
+            )),
+        ),
+        _ => (None, None),
+    }
+}
+
+/// Takes a running process under supervision, what monitoring used to do
+/// on its own before there was a desired state to compare with
+async fn adopt(
+    gs: &Arc<GlobalState>,
+    name: &Stringy,
+    pid: u32,
+    status: Status,
+    kind: AppKind,
+) -> Result<bool, ErrorArrayItem> {
+    // A binary that doesn't verify is quarantined rather than supervised
+    if kind == AppKind::Client {
+        verify_binary(gs, name).await?;
+    }
+
+    let mut process = match reclaim_child(pid).await {
+        Ok(process) => process,
+        Err(err) if err.err_type == Errors::SupervisedChild => {
+            log!(LogLevel::Trace, "{} not currently running", name);
+            return Ok(false);
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !enforce_limits(name) || record_start(gs, name).await? {
+        return Ok(false);
+    }
+
+    if !process.monitoring() {
+        process.monitor_usage().await;
+    }
+
+    let from: Option<Status> = APP_STATUS_ARRAY
+        .modify(name, |app| {
+            let from: Status = app.app_data.get_status();
+            app.app_data.set_pid(process.get_pid() as u32);
+            app.app_data.set_status(status.clone());
+            if kind == AppKind::System && app.app_data.get_status() == Status::Idle {
+                app.metrics = None;
+            }
+            from
+        })
+        .await
+        .map_err(|mut err| {
+            err.err_mesg = format!(
+                "Error getting write lock on reclaiming child app status array: {}",
+                err.err_mesg
+            )
+            .into();
+            err
+        })?;
+
+    if let Some(from) = from {
+        gs.events.status_changed(name, from, status);
+    }
+    gs.events.publish(ManagerEvent::AppStarted {
+        name: name.clone(),
+        pid: process.get_pid() as u32,
+    });
+
+    // Mailed to the operators by the alert bridge
+    if kind == AppKind::System && outside_systemd(process.get_pid() as u32) {
+        log!(
+            LogLevel::Warn,
+            "System application {} is running outside systemd",
+            name
+        );
+        gs.events.publish(ManagerEvent::SystemAppOutsideSystemd {
+            name: name.clone(),
+            pid: process.get_pid() as u32,
+        });
+    }
+
+    kind.handler()
+        .insert(name.clone(), SupervisedProcesses::Process(process))
+        .await?;
+    log!(
+        LogLevel::Info,
+        "{} Started and added to the {} handler",
+        name,
+        kind.name()
+    );
+    Ok(true)
+}
+
+async fn reconcile_app(
+    gs: &Arc<GlobalState>,
+    name: &Stringy,
+    pid: u32,
+    status: Status,
+    kind: AppKind,
+    spec: &HashMap<String, DesiredState>,
+) -> Result<AppReconcile, ErrorArrayItem> {
+    let settings: &ReconcileSettings = &gs.settings.reconcile;
+    let desired: Desired = desired_state(name, spec).await;
+    let observed: Observed = observe(name, pid, kind).await?;
+    let (action, mut drift) = plan(desired, &observed, settings);
+
+    // A start or stop that failed isn't tried again on every pass
+    if let Some(previous) = reconciliation_of(name) {
+        let failed: bool = previous.action == action && previous.drift.is_some();
+        if failed
+            && matches!(action, Some(Action::Start) | Some(Action::Stop))
+            && previous.reconciled_at + settings.retry_secs > current_timestamp()
+        {
+            return Ok(AppReconcile {
+                observed,
+                ..previous
+            });
+        }
+    }
+
+    let result: Result<(), ErrorArrayItem> = match action {
+        Some(Action::Adopt) => adopt(gs, name, pid, status, kind).await.map(|adopted| {
+            if !adopted {
+                drift = Some(String::from(
+                    "running but couldn't be taken under supervision",
+                ));
+            }
+        }),
+        Some(Action::Start) => {
+            log!(
+                LogLevel::Info,
+                "Starting {}, it's expected to be running",
+                name
+            );
+            start_application(name).await
+        }
+        Some(Action::Stop) => {
+            log!(
+                LogLevel::Info,
+                "Stopping {}, it's expected to be stopped",
+                name
+            );
+            stop_application(name).await
+        }
+        None => Ok(()),
+    };
+    if let Some(attempted) = action {
+        let reason: &str = match attempted {
+            Action::Adopt => "it's running without supervision",
+            Action::Start => "it's expected to be running",
+            Action::Stop => "it's expected to be stopped",
+        };
+        Decision::new("reconcile", Some(name), attempted.verb(), reason.to_owned())
+            .input("desired", desired)
+            .input("observed", &observed)
+            .input("error", result.as_ref().err().map(|err| err.to_string()))
+            .record();
+    }
+    if let (Err(err), Some(attempted)) = (result, action) {
+        drift = Some(format!("couldn't {}: {}", attempted.verb(), err));
+    }
+
+    Ok(AppReconcile {
+        desired,
+        observed,
+        action,
+        drift,
+        reconciled_at: current_timestamp(),
+    })
+}
+
+fn record(name: &Stringy, outcome: Result<AppReconcile, ErrorArrayItem>) {
+    let outcome: AppReconcile = match outcome {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            log!(LogLevel::Warn, "Couldn't reconcile {}: {}", name, err);
+            return;
+        }
+    };
+
+    let mut reconciled = match RECONCILED.lock() {
+        Ok(reconciled) => reconciled,
+        Err(_) => return,
+    };
+    // Logged once when it shows up rather than on every pass
+    if let Some(drift) = &outcome.drift {
+        if reconciled
+            .get(name.as_str())
+            .and_then(|last| last.drift.as_ref())
+            != Some(drift)
+        {
+            log!(
+                LogLevel::Warn,
+                "{} drifted from its desired state: {}",
+                name,
+                drift
+            );
+        }
+    }
+    reconciled.insert(name.to_string(), outcome);
+}
+
+/// Resolves system applications and brings each to its desired state
+pub async fn reconcile_system_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    resolve_system_applications(gs).await?;
+    let spec: HashMap<String, DesiredState> = desired_states()?;
+
+    for name in SYSTEM_APPLICATION_ARRAY.keys().await? {
+        let app = match SYSTEM_APPLICATION_ARRAY.get(&name).await? {
+            Some(app) => app,
+            None => continue,
+        };
+        let (pid, status) = (app.config.get_pid(), app.config.get_status());
+        record(
+            &name,
+            reconcile_app(gs, &name, pid, status, AppKind::System, &spec).await,
+        );
+    }
+    Ok(())
+}
+
+/// Resolves client applications from the git credentials and brings each
+/// to its desired state, most important first
+pub async fn reconcile_client_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    resolve_client_applications(&gs.clone()).await?;
+    let spec: HashMap<String, DesiredState> = desired_states()?;
+
+    let mut order: Vec<Stringy> = CLIENT_APPLICATION_ARRAY.keys().await?;
+    sort_by_priority(&mut order).await;
+
+    for name in order {
+        let app = match CLIENT_APPLICATION_ARRAY.get(&name).await? {
+            Some(app) => app,
+            None => continue,
+        };
+        let (pid, status) = (app.config.get_pid(), app.config.get_status());
+        record(
+            &name,
+            reconcile_app(gs, &name, pid, status, AppKind::Client, &spec).await,
+        );
+    }
+    Ok(())
+}
+
+pub fn reconciliation_of(name: &str) -> Option<AppReconcile> {
+    RECONCILED
+        .lock()
+        .ok()
+        .and_then(|reconciled| reconciled.get(name).cloned())
+}
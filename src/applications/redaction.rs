@@ -0,0 +1,136 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::settings::RedactionSettings;
+
+use super::child::{CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+use super::diagnostics::is_secret_key;
+
+/// Compiled redaction rules for every application
+static REDACTORS: Lazy<LockWithTimeout<HashMap<Stringy, Arc<Redactor>>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+const REDACTED: &str = "<redacted>";
+
+/// Env values shorter than this are too likely to show up in normal output
+const MIN_SECRET_LENGTH: usize = 6;
+
+#[derive(Debug, Default)]
+pub struct Redactor {
+    /// Values of the secret looking variables in the app's .env
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn redact(&self, line: &str) -> String {
+        let mut line: String = line.to_string();
+
+        for secret in &self.secrets {
+            if line.contains(secret.as_str()) {
+                line = line.replace(secret.as_str(), REDACTED);
+            }
+        }
+
+        for pattern in &self.patterns {
+            if pattern.is_match(&line) {
+                line = pattern.replace_all(&line, REDACTED).into_owned();
+            }
+        }
+
+        line
+    }
+}
+
+fn env_secrets(app_name: &str) -> Vec<String> {
+    let data: String = match fs::read_to_string(format!("/etc/{}/.env", app_name)) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+
+    data.lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| is_secret_key(key))
+        .map(|(_, value)| {
+            value
+                .trim()
+                .trim_matches('"')
+                .trim_matches('\'')
+                .to_string()
+        })
+        .filter(|value| value.len() >= MIN_SECRET_LENGTH)
+        .collect()
+}
+
+/// Extra patterns declared as `redact_patterns = ["..."]` in /etc/{app}/Config.toml
+fn declared_patterns(app_name: &str) -> Vec<String> {
+    let data: String = match fs::read_to_string(format!("/etc/{}/Config.toml", app_name)) {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+
+    toml::from_str::<toml::Value>(&data)
+        .ok()
+        .and_then(|config| config.get("redact_patterns").cloned())
+        .and_then(|patterns| patterns.try_into::<Vec<String>>().ok())
+        .unwrap_or_default()
+}
+
+fn compile(app_name: &str, patterns: &[String]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(err) => {
+                log!(
+                    LogLevel::Warn,
+                    "Ignoring bad redaction pattern for {}: {}",
+                    app_name,
+                    err
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Rebuilds the redaction rules of every application from the manager
+/// settings, its .env and its Config.toml
+pub async fn refresh_redaction_rules(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &RedactionSettings = &gs.settings.redaction;
+    if !settings.enabled {
+        REDACTORS.try_write().await?.clear();
+        return Ok(());
+    }
+
+    let mut names: Vec<Stringy> = SYSTEM_APPLICATION_ARRAY.keys().await?;
+    names.extend(CLIENT_APPLICATION_ARRAY.keys().await?);
+
+    let mut redactors: HashMap<Stringy, Arc<Redactor>> = HashMap::new();
+    for name in names {
+        let mut patterns: Vec<String> = settings.patterns.clone();
+        patterns.extend(declared_patterns(&name));
+
+        let redactor: Redactor = Redactor {
+            secrets: env_secrets(&name),
+            patterns: compile(&name, &patterns),
+        };
+        redactors.insert(name, Arc::new(redactor));
+    }
+
+    *REDACTORS.try_write().await? = redactors;
+    Ok(())
+}
+
+pub async fn redactor_for(app_name: &Stringy) -> Option<Arc<Redactor>> {
+    REDACTORS.try_read().await.ok()?.get(app_name).cloned()
+}
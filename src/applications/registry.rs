@@ -0,0 +1,266 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Enough to keep the monitor, network and signal paths off each other's
+/// shards on a node with a few dozen apps
+const SHARDS: usize = 16;
+
+/// How long a removed entry is remembered, in seconds. Callers further
+/// behind than that start over from everything.
+const TOMBSTONE_SECS: u64 = 24 * 60 * 60;
+
+type Shard<T> = LockWithTimeout<HashMap<Stringy, LockWithTimeout<T>>>;
+
+/// When an entry last changed, removed entries are kept as tombstones so a
+/// caller catching up hears they're gone
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+    pub generation: u64,
+    pub removed: bool,
+    /// Time of the change, in seconds
+    pub at: u64,
+}
+
+/// What changed since a generation a caller got before
+#[derive(Debug, Clone)]
+pub struct Changes {
+    /// The generation the changes are current as of
+    pub generation: u64,
+    /// The removals since then can't be told, `changes` holds every entry
+    /// there is instead
+    pub full: bool,
+    pub changes: Vec<(Stringy, Change)>,
+}
+
+/// Per app storage with a lock for every entry. The shard locks are only
+/// held long enough to find or add an entry, so working on one app never
+/// waits on another.
+pub struct Registry<T> {
+    shards: Vec<Shard<T>>,
+    /// Bumped on every change. It starts at the time in milliseconds, so
+    /// generations handed out before a reload stay behind the new ones.
+    generation: AtomicU64,
+    /// Removals up to this generation may be forgotten. It starts at the
+    /// first generation, the previous instance's tombstones went with it.
+    horizon: AtomicU64,
+    changes: Mutex<HashMap<Stringy, Change>>,
+}
+
+impl<T> Registry<T> {
+    pub fn new() -> Self {
+        let start: u64 = current_timestamp().saturating_mul(1000);
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| LockWithTimeout::new(HashMap::new()))
+                .collect(),
+            generation: AtomicU64::new(start),
+            horizon: AtomicU64::new(start),
+            changes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The generation is bumped under the lock, a reader never sees it ahead
+    /// of the change it belongs to. Tombstones past their time go on the way.
+    fn changed(&self, key: &Stringy, removed: bool) {
+        if let Ok(mut changes) = self.changes.lock() {
+            let now: u64 = current_timestamp();
+            let generation: u64 = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+            changes.insert(
+                key.clone(),
+                Change {
+                    generation,
+                    removed,
+                    at: now,
+                },
+            );
+
+            changes.retain(|_, change| {
+                let kept: bool = !change.removed || change.at + TOMBSTONE_SECS > now;
+                if !kept {
+                    self.horizon.fetch_max(change.generation, Ordering::Relaxed);
+                }
+                kept
+            });
+        }
+    }
+
+    /// Records a change made through a handle from `entry`, those bypass
+    /// `update` and would be missed by `changes_since` otherwise
+    pub fn touch(&self, key: &Stringy) {
+        self.changed(key, false);
+    }
+
+    /// Generation of the most recent change
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Keys changed or removed after the given generation. A generation
+    /// behind the horizon, or ahead of ours, gets every entry instead.
+    pub fn changes_since(&self, since: u64) -> Changes {
+        match self.changes.lock() {
+            Ok(changes) => {
+                let generation: u64 = self.generation();
+                let full: bool = since < self.horizon.load(Ordering::Relaxed) || since > generation;
+                Changes {
+                    generation,
+                    full,
+                    changes: changes
+                        .iter()
+                        .filter(|(_, change)| match full {
+                            true => !change.removed,
+                            false => change.generation > since,
+                        })
+                        .map(|(key, change)| (key.clone(), *change))
+                        .collect(),
+                }
+            }
+            Err(_) => Changes {
+                generation: since,
+                full: false,
+                changes: Vec::new(),
+            },
+        }
+    }
+
+    fn shard(&self, key: &Stringy) -> &Shard<T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Handle to a single entry, for callers that hold it across awaits
+    pub async fn entry(&self, key: &Stringy) -> Result<Option<LockWithTimeout<T>>, ErrorArrayItem> {
+        Ok(self.shard(key).try_read().await?.get(key).cloned())
+    }
+
+    /// Handles to every entry, none of them are locked yet
+    pub async fn entries(&self) -> Result<Vec<(Stringy, LockWithTimeout<T>)>, ErrorArrayItem> {
+        let mut entries: Vec<(Stringy, LockWithTimeout<T>)> = Vec::new();
+        for shard in &self.shards {
+            entries.extend(
+                shard
+                    .try_read()
+                    .await?
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.clone())),
+            );
+        }
+        Ok(entries)
+    }
+
+    pub async fn contains(&self, key: &Stringy) -> Result<bool, ErrorArrayItem> {
+        Ok(self.shard(key).try_read().await?.contains_key(key))
+    }
+
+    pub async fn keys(&self) -> Result<Vec<Stringy>, ErrorArrayItem> {
+        let mut keys: Vec<Stringy> = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.try_read().await?.keys().cloned());
+        }
+        Ok(keys)
+    }
+
+    /// Replaces whatever was stored under the key, returns whether there was
+    /// anything to replace
+    pub async fn insert(&self, key: Stringy, value: T) -> Result<bool, ErrorArrayItem> {
+        let replaced: bool = self
+            .shard(&key)
+            .try_write()
+            .await?
+            .insert(key.clone(), LockWithTimeout::new(value))
+            .is_some();
+        self.changed(&key, false);
+        Ok(replaced)
+    }
+
+    /// Stores the value unless the key is taken, returns whether it was stored
+    pub async fn insert_if_absent(&self, key: Stringy, value: T) -> Result<bool, ErrorArrayItem> {
+        let mut shard = self.shard(&key).try_write().await?;
+        if shard.contains_key(&key) {
+            return Ok(false);
+        }
+        shard.insert(key.clone(), LockWithTimeout::new(value));
+        self.changed(&key, false);
+        Ok(true)
+    }
+
+    /// Returns whether there was anything to remove
+    pub async fn remove(&self, key: &Stringy) -> Result<bool, ErrorArrayItem> {
+        let removed: bool = self.shard(key).try_write().await?.remove(key).is_some();
+        if removed {
+            self.changed(key, true);
+        }
+        Ok(removed)
+    }
+
+    /// Runs `f` against a single entry, `None` when the key isn't stored
+    pub async fn read<R>(
+        &self,
+        key: &Stringy,
+        f: impl FnOnce(&T) -> R,
+    ) -> Result<Option<R>, ErrorArrayItem> {
+        match self.entry(key).await? {
+            Some(entry) => Ok(Some(f(&*entry.try_read().await?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Changes a single entry in place, `None` when the key isn't stored
+    pub async fn update<R>(
+        &self,
+        key: &Stringy,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<Option<R>, ErrorArrayItem> {
+        match self.entry(key).await? {
+            Some(entry) => {
+                let output: R = f(&mut *entry.try_write().await?);
+                self.changed(key, false);
+                Ok(Some(output))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> Registry<T> {
+    pub async fn get(&self, key: &Stringy) -> Result<Option<T>, ErrorArrayItem> {
+        self.read(key, |value| value.clone()).await
+    }
+
+    /// Copy of every entry, taken one entry at a time
+    pub async fn snapshot(&self) -> Result<HashMap<Stringy, T>, ErrorArrayItem> {
+        let mut snapshot: HashMap<Stringy, T> = HashMap::new();
+        for (key, entry) in self.entries().await? {
+            snapshot.insert(key, entry.try_read().await?.clone());
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Entries shared as immutable snapshots, `get` and `snapshot` hand out
+/// `Arc`s instead of copies and an entry is only copied when it's modified
+/// while someone still holds a snapshot of it
+impl<T: Clone> Registry<Arc<T>> {
+    /// Copy on write version of `update`
+    pub async fn modify<R>(
+        &self,
+        key: &Stringy,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<Option<R>, ErrorArrayItem> {
+        self.update(key, |shared| f(Arc::make_mut(shared))).await
+    }
+}
@@ -0,0 +1,614 @@
+use artisan_middleware::aggregator::{AppMessage, Command, CommandResponse, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use simple_comms::{
+    network::send_receive::send_message,
+    protocol::{flags::Flags, proto::Proto},
+};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command as Process;
+use tokio::time::sleep;
+
+use crate::system::auth::attach_token;
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::RelocationSettings;
+use crate::system::transfer::{
+    checksum, file_checksum, finish_transfer, TransferManifest, TRANSFER_CHUNK_SIZE, TRANSFER_DIR,
+};
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use super::deploy::is_tarball;
+use super::resolve::{git_project_ids, resolve_client_applications};
+use super::start_stop::{start_application, stop_application};
+
+/// Attempts at a single chunk before the relocation is given up
+const CHUNK_ATTEMPTS: usize = 3;
+
+/// Progress of every relocation started on this node, kept after they end so
+/// `migrate status` can still tell how they went
+static RELOCATIONS: Lazy<Mutex<HashMap<String, Relocation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelocationStage {
+    Stopping,
+    Packaging,
+    Transferring,
+    Starting,
+    Decommissioning,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Relocation {
+    pub target: String,
+    pub stage: RelocationStage,
+    pub chunks_sent: u64,
+    pub chunk_count: u64,
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+fn relocation_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn update(app_id: &Stringy, change: impl FnOnce(&mut Relocation)) {
+    if let Ok(mut relocations) = RELOCATIONS.lock() {
+        if let Some(relocation) = relocations.get_mut(&app_id.to_string()) {
+            change(relocation);
+            relocation.updated_at = current_timestamp();
+        }
+    }
+}
+
+fn set_stage(app_id: &Stringy, stage: RelocationStage) {
+    update(app_id, |relocation| relocation.stage = stage);
+}
+
+pub fn relocations() -> HashMap<String, Relocation> {
+    RELOCATIONS
+        .lock()
+        .map(|relocations| relocations.clone())
+        .unwrap_or_default()
+}
+
+/// Everything of an app that can move with it, relative to `/`. The env
+/// file is left out, it travels encrypted in the command that finishes the
+/// move.
+fn app_paths(gs: &Arc<GlobalState>, app_id: &Stringy) -> Vec<String> {
+    [
+        format!("/opt/artisan/bin/{}", app_id),
+        format!("/etc/{}", app_id),
+        gs.settings.disk_usage.data_dir_for(app_id),
+        format!("/etc/systemd/system/{}.service", app_id),
+    ]
+    .into_iter()
+    .map(|path| {
+        path.trim_start_matches('/')
+            .trim_end_matches('/')
+            .to_owned()
+    })
+    .collect()
+}
+
+/// The paths of the app that exist here, what gets packaged
+fn bundle_paths(gs: &Arc<GlobalState>, app_id: &Stringy) -> Vec<String> {
+    app_paths(gs, app_id)
+        .into_iter()
+        .filter(|path| Path::new("/").join(path).exists())
+        .collect()
+}
+
+async fn package(gs: &Arc<GlobalState>, app_id: &Stringy) -> Result<PathBuf, ErrorArrayItem> {
+    fs::create_dir_all(TRANSFER_DIR)?;
+    let bundle: PathBuf = Path::new(TRANSFER_DIR).join(format!("{}.relocate.tar.gz", app_id));
+
+    let status = Process::new("tar")
+        .arg("-czf")
+        .arg(&bundle)
+        .arg(format!("--exclude=etc/{}/.env", app_id))
+        .arg("-C")
+        .arg("/")
+        .args(bundle_paths(gs, app_id))
+        .status()
+        .await?;
+
+    if !status.success() {
+        let _ = fs::remove_file(&bundle);
+        return Err(relocation_error(format!("Failed to package {}", app_id)));
+    }
+
+    Ok(bundle)
+}
+
+/// Runs a custom command on the manager of another node and returns what it
+/// answered with
+async fn remote_command(
+    settings: &RelocationSettings,
+    target: &str,
+    app_id: &Stringy,
+    raw: String,
+) -> Result<String, ErrorArrayItem> {
+    // Never the whole command, `migrate receive` carries the env file
+    let verb: String = raw.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+
+    let mut stream: TcpStream = TcpStream::connect(format!("{}:{}", target, settings.port))
+        .await
+        .map_err(|err| ErrorArrayItem::new(Errors::ConnectionError, err.to_string()))?;
+
+    let app_id: Stringy = match &settings.token {
+        Some(token) => attach_token(token, app_id),
+        None => app_id.clone(),
+    };
+    let message: AppMessage = AppMessage::Command(Command {
+        app_id,
+        command_type: CommandType::Custom(raw),
+    });
+
+    match send_message::<TcpStream, AppMessage, AppMessage>(
+        &mut stream,
+        Flags::ENCRYPTED | Flags::COMPRESSED,
+        message,
+        Proto::TCP,
+        false,
+    )
+    .await?
+    {
+        Ok(response) => match response.get_payload().await {
+            AppMessage::Response(CommandResponse {
+                success: true,
+                message,
+                ..
+            }) => Ok(message.unwrap_or_default()),
+            AppMessage::Response(CommandResponse { message, .. }) => {
+                Err(relocation_error(format!(
+                    "{} refused {}: {}",
+                    target,
+                    verb,
+                    message.unwrap_or_default()
+                )))
+            }
+            _ => Err(ErrorArrayItem::new(
+                Errors::ConnectionError,
+                format!("{} sent an unexpected answer to {}", target, verb),
+            )),
+        },
+        Err(status) => Err(ErrorArrayItem::new(
+            Errors::ConnectionError,
+            format!("{} failed {}: {}", target, verb, status),
+        )),
+    }
+}
+
+fn parse_manifest(raw: &str) -> Result<TransferManifest, ErrorArrayItem> {
+    serde_json::from_str(raw).map_err(|err| relocation_error(err.to_string()))
+}
+
+/// Uploads the bundle to the target with the chunked transfer protocol and
+/// returns the id of the upload there
+async fn upload_bundle(
+    settings: &RelocationSettings,
+    target: &str,
+    app_id: &Stringy,
+    bundle: &Path,
+) -> Result<Stringy, ErrorArrayItem> {
+    let size: u64 = fs::metadata(bundle)?.len();
+    let manifest: TransferManifest = parse_manifest(
+        &remote_command(
+            settings,
+            target,
+            app_id,
+            format!(
+                "transfer begin {}.tar.gz {} {}",
+                app_id,
+                size,
+                file_checksum(bundle)?
+            ),
+        )
+        .await?,
+    )?;
+
+    update(app_id, |relocation| {
+        relocation.chunk_count = manifest.chunk_count
+    });
+
+    let mut file: File = File::open(bundle)?;
+    for index in 0..manifest.chunk_count {
+        let mut chunk: Vec<u8> = Vec::with_capacity(TRANSFER_CHUNK_SIZE as usize);
+        file.seek(SeekFrom::Start(index * TRANSFER_CHUNK_SIZE))?;
+        (&mut file)
+            .take(TRANSFER_CHUNK_SIZE)
+            .read_to_end(&mut chunk)?;
+
+        let raw: String = format!(
+            "transfer put {} {} {} {}",
+            manifest.transfer_id,
+            index,
+            checksum(&chunk),
+            hex::encode(&chunk)
+        );
+
+        let mut attempt: usize = 1;
+        while let Err(err) = remote_command(settings, target, app_id, raw.clone()).await {
+            if attempt >= CHUNK_ATTEMPTS {
+                return Err(err);
+            }
+            log!(
+                LogLevel::Warn,
+                "Chunk {} of {} to {} failed, retrying: {}",
+                index,
+                app_id,
+                target,
+                err
+            );
+            attempt += 1;
+        }
+
+        update(app_id, |relocation| relocation.chunks_sent = index + 1);
+    }
+
+    Ok(manifest.transfer_id)
+}
+
+/// Takes everything of the app off this node once the target runs it
+async fn decommission(gs: &Arc<GlobalState>, app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let _ = Process::new("systemctl")
+        .arg("disable")
+        .arg(format!("{}.service", app_id))
+        .status()
+        .await;
+
+    for path in bundle_paths(gs, app_id) {
+        let path: PathBuf = Path::new("/").join(path);
+        match path.is_dir() {
+            true => fs::remove_dir_all(&path)?,
+            false => fs::remove_file(&path)?,
+        }
+    }
+
+    Process::new("systemctl")
+        .arg("daemon-reload")
+        .status()
+        .await?;
+    resolve_client_applications(gs).await?;
+    APP_STATUS_ARRAY.remove(app_id).await?;
+
+    Ok(())
+}
+
+async fn relocate(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    target: &str,
+) -> Result<(), ErrorArrayItem> {
+    let settings: &RelocationSettings = &gs.settings.relocation;
+
+    // Stopped first so the data directory isn't written to while it's packed
+    set_stage(app_id, RelocationStage::Stopping);
+    stop_application(app_id).await?;
+
+    let moved: Result<String, ErrorArrayItem> = async {
+        set_stage(app_id, RelocationStage::Packaging);
+        let bundle: PathBuf = package(gs, app_id).await?;
+
+        set_stage(app_id, RelocationStage::Transferring);
+        let uploaded: Result<Stringy, ErrorArrayItem> =
+            upload_bundle(settings, target, app_id, &bundle).await;
+        let _ = fs::remove_file(&bundle);
+        let transfer_id: Stringy = uploaded?;
+
+        let env: String = match fs::read(format!("/etc/{}/.env", app_id)) {
+            Ok(env) => hex::encode(env),
+            Err(_) => String::from("-"),
+        };
+
+        set_stage(app_id, RelocationStage::Starting);
+        remote_command(
+            settings,
+            target,
+            app_id,
+            format!("migrate receive {} {}", transfer_id, env),
+        )
+        .await
+    }
+    .await;
+
+    if let Err(err) = moved {
+        log!(
+            LogLevel::Error,
+            "Moving {} to {} failed, starting it here again: {}",
+            app_id,
+            target,
+            err
+        );
+        if let Err(err) = start_application(app_id).await {
+            log!(LogLevel::Error, "Failed to start {} again: {}", app_id, err);
+        }
+        return Err(err);
+    }
+
+    set_stage(app_id, RelocationStage::Decommissioning);
+    decommission(gs, app_id).await?;
+
+    log!(LogLevel::Info, "Moved {} to {}", app_id, target);
+    gs.events.publish(ManagerEvent::AppRelocated {
+        name: app_id.clone(),
+        target: target.to_owned(),
+    });
+
+    Ok(())
+}
+
+/// Moves a client app to the manager on `target`. The app is stopped here,
+/// packaged with its config and data, started on the target and only removed
+/// from this node once the target confirms it's running. Runs in the
+/// background, `migrate status` follows along.
+pub async fn start_relocation(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    target: &str,
+) -> Result<(), ErrorArrayItem> {
+    if !CLIENT_APPLICATION_ARRAY.contains(app_id).await? {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} isn't a client application", app_id),
+        ));
+    }
+
+    {
+        let mut relocations = RELOCATIONS
+            .lock()
+            .map_err(|err| relocation_error(err.to_string()))?;
+
+        let busy: bool = relocations
+            .get(&app_id.to_string())
+            .is_some_and(|relocation| {
+                !matches!(
+                    relocation.stage,
+                    RelocationStage::Done | RelocationStage::Failed
+                )
+            });
+        if busy {
+            return Err(relocation_error(format!("{} is already moving", app_id)));
+        }
+
+        let now: u64 = current_timestamp();
+        relocations.insert(
+            app_id.to_string(),
+            Relocation {
+                target: target.to_owned(),
+                stage: RelocationStage::Stopping,
+                chunks_sent: 0,
+                chunk_count: 0,
+                error: None,
+                started_at: now,
+                updated_at: now,
+            },
+        );
+    }
+
+    let (gs, app_id, target) = (gs.clone(), app_id.clone(), target.to_owned());
+    tokio::spawn(async move {
+        match relocate(&gs, &app_id, &target).await {
+            Ok(_) => set_stage(&app_id, RelocationStage::Done),
+            Err(err) => update(&app_id, |relocation| {
+                relocation.stage = RelocationStage::Failed;
+                relocation.error = Some(err.to_string());
+            }),
+        }
+    });
+
+    Ok(())
+}
+
+async fn wait_until_active(app_id: &Stringy, timeout_secs: u64) -> Result<(), ErrorArrayItem> {
+    for _ in 0..timeout_secs.max(1) {
+        let output = Process::new("systemctl")
+            .arg("is-active")
+            .arg(format!("{}.service", app_id))
+            .output()
+            .await?;
+
+        if String::from_utf8_lossy(&output.stdout).trim() == "active" {
+            return Ok(());
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    Err(relocation_error(format!(
+        "{} didn't come up within {}s",
+        app_id, timeout_secs
+    )))
+}
+
+async fn tar_listing(args: &[&str], bundle: &Path) -> Result<Vec<String>, ErrorArrayItem> {
+    let output = Process::new("tar").args(args).arg(bundle).output().await?;
+    if !output.status.success() {
+        return Err(relocation_error(format!(
+            "Failed to list {}",
+            bundle.display()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+/// Whether every entry of the bundle is a plain file or directory under
+/// one of the app's own paths. Links could point the unpacking anywhere.
+async fn bundle_contained(bundle: &Path, allowed: &[String]) -> Result<bool, ErrorArrayItem> {
+    let names: Vec<String> = tar_listing(&["-tzf"], bundle).await?;
+    let verbose: Vec<String> = tar_listing(&["-tvzf"], bundle).await?;
+    if names.len() != verbose.len() {
+        return Ok(false);
+    }
+
+    Ok(names.iter().zip(verbose.iter()).all(|(name, line)| {
+        let name: &str = name.trim_start_matches("./").trim_end_matches('/');
+        let plain: bool = line.starts_with('-') || line.starts_with('d');
+        let relative: bool = !name.starts_with('/')
+            && !Path::new(name)
+                .components()
+                .any(|component| component.as_os_str() == "..");
+        let inside: bool = allowed
+            .iter()
+            .any(|path| name == path || name.starts_with(&format!("{}/", path)));
+        plain && relative && inside
+    }))
+}
+
+/// Copies what was unpacked in staging over to where it belongs
+async fn install_staged(staging: &Path, allowed: &[String]) -> Result<(), ErrorArrayItem> {
+    for path in allowed {
+        let staged: PathBuf = staging.join(path);
+        if !staged.exists() {
+            continue;
+        }
+
+        let target: PathBuf = Path::new("/").join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let status = Process::new("cp")
+            .arg("-a")
+            .arg("-T")
+            .arg(&staged)
+            .arg(&target)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(relocation_error(format!(
+                "Failed to install {}",
+                target.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The target side of a relocation. Unpacks a finished upload, puts the env
+/// file back and starts the app, answering only once it's running.
+pub async fn receive_relocation(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+    transfer_id: &Stringy,
+    env: &str,
+) -> Result<(), ErrorArrayItem> {
+    // Nothing of the bundle is looked at before the app is known to belong
+    // on this node
+    let projects: Vec<Stringy> = git_project_ids(&gs.get_state_clone().await?).await?;
+    if !projects
+        .iter()
+        .any(|id| format!("ais_{}", id) == app_id.as_str())
+    {
+        return Err(relocation_error(format!(
+            "{} isn't one of this node's projects",
+            app_id
+        )));
+    }
+
+    let bundle: PathBuf = finish_transfer(app_id, transfer_id).await?;
+    let staging: PathBuf = Path::new(TRANSFER_DIR).join(format!("{}.relocate", app_id));
+
+    let unpacked: Result<(), ErrorArrayItem> = async {
+        if CLIENT_APPLICATION_ARRAY.contains(app_id).await? {
+            return Err(relocation_error(format!(
+                "{} already runs on this node",
+                app_id
+            )));
+        }
+
+        let allowed: Vec<String> = app_paths(gs, app_id);
+        if !is_tarball(&bundle)? || !bundle_contained(&bundle, &allowed).await? {
+            return Err(relocation_error(format!(
+                "Upload {} isn't a relocation bundle of {}",
+                transfer_id, app_id
+            )));
+        }
+
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging)?;
+        let status = Process::new("tar")
+            .arg("-xzf")
+            .arg(&bundle)
+            .arg("-C")
+            .arg(&staging)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(relocation_error(format!("Failed to unpack {}", app_id)));
+        }
+
+        install_staged(&staging, &allowed).await
+    }
+    .await;
+    let _ = fs::remove_file(&bundle);
+    let _ = fs::remove_dir_all(&staging);
+    unpacked?;
+
+    if env != "-" {
+        let env: Vec<u8> = hex::decode(env)
+            .map_err(|_| relocation_error(format!("The env file of {} isn't hex", app_id)))?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(format!("/etc/{}/.env", app_id))?
+            .write_all(&env)?;
+    }
+
+    Process::new("systemctl")
+        .arg("daemon-reload")
+        .status()
+        .await?;
+    resolve_client_applications(gs).await?;
+
+    // Only projects in our git credentials are picked up
+    if !CLIENT_APPLICATION_ARRAY.contains(app_id).await? {
+        return Err(relocation_error(format!(
+            "{} isn't one of this node's projects",
+            app_id
+        )));
+    }
+
+    let status = Process::new("systemctl")
+        .arg("start")
+        .arg(format!("{}.service", app_id))
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(relocation_error(format!("Failed to start {}", app_id)));
+    }
+
+    // The source starts it again when we fail, it mustn't run on both
+    if let Err(err) = wait_until_active(app_id, gs.settings.relocation.start_timeout_secs).await {
+        let _ = Process::new("systemctl")
+            .arg("stop")
+            .arg(format!("{}.service", app_id))
+            .status()
+            .await;
+        return Err(err);
+    }
+    log!(LogLevel::Info, "Took over {} from another node", app_id);
+
+    Ok(())
+}
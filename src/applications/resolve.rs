@@ -9,13 +9,17 @@ use artisan_middleware::enviornment::definitions::Enviornment;
 use artisan_middleware::git_actions::GitCredentials;
 use artisan_middleware::state_persistence::{AppState, StatePersistence};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::{fmt, fs};
 use tokio::task;
 
+use crate::system::cgroups::{cgroup_mode, CgroupMode};
 use crate::system::control::GlobalState;
+use crate::system::ebpf::BandwidthTracker;
 
 use super::child::{pids_in_cgroup, CLIENT_APPLICATION_ARRAY, SYSTEM_APPLICATION_ARRAY};
+use super::manifest::{load_manifest, AppManifest};
 
 // pub static SYSTEMAPPLICATIONS: [&'static str; 4] = ["gitmon", "ids", "self", "messenger"];
 pub static SYSTEMAPPLICATIONS: [&'static str; 3] = ["gitmon", "self", "mailler"];
@@ -49,6 +53,24 @@ pub struct ClientApplication {
     pub path: PathType,
     pub exists: bool,
     pub config: ApplicationConfig,
+    /// From /etc/{app}/manifest.toml, `None` without one or when it's invalid
+    #[serde(default)]
+    pub manifest: Option<AppManifest>,
+}
+
+impl ClientApplication {
+    /// Uid the application runs as, the same default the spawner uses
+    pub fn execution_uid(&self) -> u32 {
+        match self.config.get_enviornmentals() {
+            Some(Enviornment::V1(enviornment_v1)) => {
+                enviornment_v1.execution_uid.unwrap_or(33).into()
+            }
+            Some(Enviornment::V2(enviornment_v2)) => {
+                enviornment_v2.execution_uid.unwrap_or(33).into()
+            }
+            None => 33,
+        }
+    }
 }
 
 impl fmt::Display for ClientApplication {
@@ -67,9 +89,9 @@ impl fmt::Display for ClientApplication {
     }
 }
 
-#[allow(unused_assignments)]
-pub async fn resolve_system_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
-    let system_application_names: Vec<Stringy> = SYSTEMAPPLICATIONS
+/// Service names of the system applications we're expected to run
+pub fn system_application_names() -> Vec<Stringy> {
+    SYSTEMAPPLICATIONS
         .iter()
         .map(|app_name| {
             if *app_name == "self" {
@@ -79,7 +101,49 @@ pub async fn resolve_system_applications(gs: &Arc<GlobalState>) -> Result<(), Er
             }
         })
         .filter(|name: &Stringy| !SYSTEMAPPLICATIONSIGNORE.contains(&name.as_str()))
-        .collect();
+        .collect()
+}
+
+/// Ids of every project in the git credentials file, client applications are
+/// named `ais_{id}`
+pub async fn git_project_ids(app_state: &AppState) -> Result<Vec<Stringy>, ErrorArrayItem> {
+    // Pasring the git configuration
+    let git_credential_file_string = match &app_state.config.git {
+        Some(config) => config.credentials_file.clone(),
+        None => {
+            log!(LogLevel::Error, "FAILED TO PASRE CLIENT APPLICATIONS !!!");
+            log!(
+                LogLevel::Trace,
+                "Unable to validate what files to run, missing git credentials file"
+            );
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Failed to parse what applications to run",
+            ));
+        }
+    };
+
+    let git_credential_file: PathType = PathType::Content(git_credential_file_string);
+    let git_credentials_array = match GitCredentials::new_vec(Some(&git_credential_file)).await {
+        Ok(data) => data,
+        Err(err) => {
+            log!(LogLevel::Error, "{}", err);
+            return Err(err);
+        }
+    };
+
+    let mut git_project_hashes: Vec<Stringy> = Vec::new();
+
+    for project in git_credentials_array {
+        git_project_hashes.push(project.generate_id());
+    }
+
+    Ok(git_project_hashes)
+}
+
+#[allow(unused_assignments)]
+pub async fn resolve_system_applications(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let system_application_names: Vec<Stringy> = system_application_names();
 
     // assemble the Struct from the array
     let mut tasks: Vec<task::JoinHandle<Result<SystemApplication, ()>>> = Vec::new();
@@ -149,11 +213,6 @@ pub async fn resolve_system_applications(gs: &Arc<GlobalState>) -> Result<(), Er
     }
 
     // Writing to the system array
-    let mut system_application_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        std::collections::HashMap<Stringy, SystemApplication>,
-    > = SYSTEM_APPLICATION_ARRAY.try_write().await?;
-
     for app in results {
         if app.config.get_status() == Status::Running
             || app.config.get_status() == Status::Building
@@ -171,7 +230,9 @@ pub async fn resolve_system_applications(gs: &Arc<GlobalState>) -> Result<(), Er
             }
         }
 
-        system_application_array_write_lock.insert(app.clone().name, app);
+        SYSTEM_APPLICATION_ARRAY
+            .insert(app.clone().name, app)
+            .await?;
     }
 
     Ok(())
@@ -226,36 +287,7 @@ pub async fn resolve_client_applications(gs: &Arc<GlobalState>) -> Result<(), Er
         }
     }
 
-    // Pasring the git configuration
-    let git_credential_file_string = match &app_state.config.git {
-        Some(config) => config.credentials_file.clone(),
-        None => {
-            log!(LogLevel::Error, "FAILED TO PASRE CLIENT APPLICATIONS !!!");
-            log!(
-                LogLevel::Trace,
-                "Unable to validate what files to run, missing git credentials file"
-            );
-            return Err(ErrorArrayItem::new(
-                Errors::GeneralError,
-                "Failed to parse what applications to run",
-            ));
-        }
-    };
-
-    let git_credential_file: PathType = PathType::Content(git_credential_file_string);
-    let git_credentials_array = match GitCredentials::new_vec(Some(&git_credential_file)).await {
-        Ok(data) => data,
-        Err(err) => {
-            log!(LogLevel::Error, "{}", err);
-            return Err(err);
-        }
-    };
-
-    let mut git_project_hashes: Vec<Stringy> = Vec::new();
-
-    for project in git_credentials_array {
-        git_project_hashes.push(project.generate_id());
-    }
+    let git_project_hashes: Vec<Stringy> = git_project_ids(&app_state).await?;
 
     // filtering out system applications and files that dont match the git config file given to the manager
     let client_applications_names = application_list
@@ -314,11 +346,21 @@ pub async fn resolve_client_applications(gs: &Arc<GlobalState>) -> Result<(), Er
                 }
             };
 
+            // An invalid manifest is reported and the app managed without one
+            let manifest: Option<AppManifest> = match load_manifest(&name) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    log!(LogLevel::Error, "{}", err);
+                    None
+                }
+            };
+
             let client_application = ClientApplication {
                 name: state.clone().name.into(),
                 path: application_path.clone(),
                 exists: application_path.exists(),
                 config: ApplicationConfig::new(state, env, None),
+                manifest,
             };
 
             Ok(client_application)
@@ -342,33 +384,34 @@ pub async fn resolve_client_applications(gs: &Arc<GlobalState>) -> Result<(), Er
         }
     }
 
-    let mut client_application_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        std::collections::HashMap<Stringy, ClientApplication>,
-    > = CLIENT_APPLICATION_ARRAY.try_write().await?;
-
     for app in results {
-        client_application_array_write_lock.insert(app.clone().name, app);
+        CLIENT_APPLICATION_ARRAY
+            .insert(app.clone().name, app)
+            .await?;
     }
 
     Ok(())
 }
 
+/// Keeps the fork probe pointed at the services in artisan.slice. A service
+/// seen for the first time has its pids read from cgroup.procs once, the
+/// probe catches everything it forks after that. Without the probe every
+/// pass reads cgroup.procs.
 pub async fn track_pids(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
-    let entries = fs::read_dir("/sys/fs/cgroup/artisan.slice/").map_err(ErrorArrayItem::from)?;
-
-    for entry in entries {
-        let path = entry?.path();
-        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-            if name.starts_with("ais_") && name.ends_with(".service") {
-                // Extract service name
-                let service_name = name.trim_end_matches(".service");
-                // Read PIDs and track
-                if let Ok(pids) = pids_in_cgroup(service_name) {
-                    for pid in pids {
-                        gs.network_monitor.track_pid(pid).await?;
-                    }
-                }
+    let cgroups: HashMap<u64, String> = BandwidthTracker::service_cgroup_map()?;
+    let added: Vec<u64> = gs.network_monitor.sync_tracked_cgroups(&cgroups).await?;
+    // The probe matches v2 cgroup ids, on v1 only hosts it never sees ours
+    let tracks_forks: bool = gs.network_monitor.tracks_forks()
+        && !matches!(cgroup_mode(), CgroupMode::Legacy | CgroupMode::Unknown);
+
+    for (cgroup, service_name) in &cgroups {
+        if !service_name.starts_with("ais_") || (tracks_forks && !added.contains(cgroup)) {
+            continue;
+        }
+
+        if let Ok(pids) = pids_in_cgroup(service_name) {
+            for pid in pids {
+                gs.network_monitor.track_pid(pid).await?;
             }
         }
     }
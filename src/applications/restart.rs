@@ -0,0 +1,362 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::systemd_bus::unit_state;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+use super::health::is_tripped;
+use super::manifest::manifest_of;
+use super::start_stop::{restart_application, start_application};
+
+/// Apps stopped on purpose, they aren't restarted until started again
+static MANUAL_STOPS: Lazy<LockWithTimeout<HashSet<Stringy>>> =
+    Lazy::new(|| LockWithTimeout::new(HashSet::new()));
+
+static RESTART_HISTORY: Lazy<LockWithTimeout<HashMap<Stringy, RestartHistory>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// Declared as a `[restart]` table in /etc/{app}/Config.toml, or in the
+/// app's manifest which takes over from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    /// Restarts allowed before we give up and leave the app down
+    pub max_restarts: u32,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /// Running this long counts as recovered and resets the restart counter
+    pub stable_secs: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::OnFailure,
+            max_restarts: 5,
+            backoff_secs: 2,
+            max_backoff_secs: 300,
+            stable_secs: 600,
+        }
+    }
+}
+
+impl RestartConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let secs: u64 = self
+            .backoff_secs
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.max_backoff_secs);
+        Duration::from_secs(secs)
+    }
+}
+
+#[derive(Debug, Default)]
+struct RestartHistory {
+    restarts: u32,
+    started_at: u64,
+    pending: bool,
+    /// Set when the last death wasn't restarted, cleared on the next start
+    given_up: bool,
+}
+
+fn declared_restart_config(app_id: &str) -> RestartConfig {
+    if let Some(restart) = manifest_of(app_id).and_then(|manifest| manifest.restart) {
+        return restart;
+    }
+
+    let config: Option<toml::Value> = fs::read_to_string(format!("/etc/{}/Config.toml", app_id))
+        .ok()
+        .and_then(|data| toml::from_str(&data).ok());
+
+    match config.and_then(|config| config.get("restart").cloned()) {
+        Some(restart) => match restart.try_into::<RestartConfig>() {
+            Ok(restart) => restart,
+            Err(err) => {
+                log!(
+                    LogLevel::Error,
+                    "{} declares an invalid restart policy: {}",
+                    app_id,
+                    err
+                );
+                RestartConfig::default()
+            }
+        },
+        None => RestartConfig::default(),
+    }
+}
+
+pub async fn note_manual_stop(app_id: &Stringy) {
+    if let Ok(mut stops) = MANUAL_STOPS.try_write().await {
+        stops.insert(app_id.clone());
+    }
+}
+
+pub async fn clear_manual_stop(app_id: &Stringy) {
+    if let Ok(mut stops) = MANUAL_STOPS.try_write().await {
+        stops.remove(app_id);
+    }
+}
+
+/// Whether an app that's down is down on purpose: stopped by hand, waiting
+/// on a restart or left down by its restart policy. The reconciler doesn't
+/// start those.
+pub async fn left_down(app_id: &Stringy) -> bool {
+    if let Ok(stops) = MANUAL_STOPS.try_read().await {
+        if stops.contains(app_id) {
+            return true;
+        }
+    }
+
+    match RESTART_HISTORY.try_read().await {
+        Ok(history) => history
+            .get(app_id)
+            .map_or(false, |entry| entry.pending || entry.given_up),
+        // Rather skip a round than race the supervisor
+        Err(_) => true,
+    }
+}
+
+async fn give_up(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    RESTART_HISTORY
+        .try_write()
+        .await?
+        .entry(app_id.clone())
+        .or_default()
+        .given_up = true;
+    Ok(())
+}
+
+/// Whether systemd saw the service exit cleanly
+async fn exited_cleanly(app_id: &Stringy) -> bool {
+    match unit_state(app_id.as_str()).await {
+        Ok(state) => state.result == "success",
+        Err(_) => false,
+    }
+}
+
+async fn handle_death(gs: &Arc<GlobalState>, name: Stringy) -> Result<(), ErrorArrayItem> {
+    // System applications are left to systemd
+    if !CLIENT_APPLICATION_ARRAY.contains(&name).await? {
+        return Ok(());
+    }
+
+    // The crash loop breaker has already given up on it
+    if is_tripped(&name).await {
+        return Ok(());
+    }
+
+    if MANUAL_STOPS.try_read().await?.contains(&name) {
+        log!(LogLevel::Debug, "{} was stopped on purpose", name);
+        return Ok(());
+    }
+
+    let config: RestartConfig = declared_restart_config(&name);
+    let clean: bool = exited_cleanly(&name).await;
+    let restart: bool = match config.policy {
+        RestartPolicy::Always => true,
+        RestartPolicy::OnFailure => !clean,
+        RestartPolicy::Never => false,
+    };
+
+    if !restart {
+        Decision::new(
+            "restart",
+            Some(&name),
+            "leave down",
+            format!("its restart policy is {:?}", config.policy),
+        )
+        .input("policy", config.policy)
+        .input("exited_cleanly", clean)
+        .record();
+        return give_up(&name).await;
+    }
+
+    let reason: String = format!("it died and its restart policy is {:?}", config.policy);
+    schedule_restart(gs, name, config, reason, clean, false).await
+}
+
+/// A running app that keeps failing its liveness probe is as good as dead,
+/// it's restarted under its policy like one that died. Only a `never`
+/// policy leaves it running as it is.
+async fn handle_unhealthy(
+    gs: &Arc<GlobalState>,
+    name: Stringy,
+    failures: u32,
+) -> Result<(), ErrorArrayItem> {
+    if !CLIENT_APPLICATION_ARRAY.contains(&name).await? || is_tripped(&name).await {
+        return Ok(());
+    }
+    if MANUAL_STOPS.try_read().await?.contains(&name) {
+        return Ok(());
+    }
+
+    let config: RestartConfig = declared_restart_config(&name);
+    if config.policy == RestartPolicy::Never {
+        Decision::new(
+            "restart",
+            Some(&name),
+            "leave running",
+            String::from("it's unhealthy but its restart policy is Never"),
+        )
+        .input("liveness_failures", failures)
+        .record();
+        return Ok(());
+    }
+
+    let reason: String = format!(
+        "it failed its liveness probe {} times in a row and its restart policy is {:?}",
+        failures, config.policy
+    );
+    schedule_restart(gs, name, config, reason, false, true).await
+}
+
+/// Restarts the app after its backoff unless it used up its restarts. One
+/// that is still `running`, as an unhealthy one is, gets stopped first.
+async fn schedule_restart(
+    gs: &Arc<GlobalState>,
+    name: Stringy,
+    config: RestartConfig,
+    reason: String,
+    clean: bool,
+    running: bool,
+) -> Result<(), ErrorArrayItem> {
+    let attempt: u32 = {
+        let mut history = RESTART_HISTORY.try_write().await?;
+        let entry: &mut RestartHistory = history.entry(name.clone()).or_default();
+
+        if entry.pending {
+            return Ok(());
+        }
+
+        if entry.started_at > 0 && entry.started_at + config.stable_secs < current_timestamp() {
+            entry.restarts = 0;
+        }
+
+        if entry.restarts >= config.max_restarts {
+            log!(
+                LogLevel::Error,
+                "{} crashed {} times, not restarting it again",
+                name,
+                entry.restarts
+            );
+            gs.events.publish(ManagerEvent::RestartsExhausted {
+                name: name.clone(),
+                restarts: entry.restarts,
+            });
+            Decision::new(
+                "restart",
+                Some(&name),
+                "leave down",
+                String::from("it used up the restarts its policy allows"),
+            )
+            .input("restarts", entry.restarts)
+            .input("max_restarts", config.max_restarts)
+            .input("stable_secs", config.stable_secs)
+            .record();
+            entry.given_up = true;
+            return Ok(());
+        }
+
+        entry.pending = true;
+        entry.restarts += 1;
+        entry.restarts - 1
+    };
+
+    let delay: Duration = config.backoff(attempt);
+    log!(
+        LogLevel::Warn,
+        "Restarting {} in {}s (attempt {} of {})",
+        name,
+        delay.as_secs(),
+        attempt + 1,
+        config.max_restarts
+    );
+    Decision::new("restart", Some(&name), "restart", reason)
+        .input("policy", config.policy)
+        .input("exited_cleanly", clean)
+        .input("attempt", attempt + 1)
+        .input("max_restarts", config.max_restarts)
+        .input("delay_secs", delay.as_secs())
+        .record();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        // An unhealthy app is still up and has to go down first
+        let result: Result<(), ErrorArrayItem> = match running {
+            true => restart_application(&name).await,
+            false => start_application(&name).await,
+        };
+        if let Err(err) = result {
+            log!(LogLevel::Error, "Failed to restart {}: {}", name, err);
+        }
+
+        if let Ok(mut history) = RESTART_HISTORY.try_write().await {
+            if let Some(entry) = history.get_mut(&name) {
+                entry.pending = false;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Relaunches client applications that die or stop passing their liveness
+/// probe according to their restart policy
+pub async fn supervise_restarts(gs: &Arc<GlobalState>) {
+    let mut receiver = gs.events.subscribe();
+
+    loop {
+        match receiver.recv().await {
+            Ok(ManagerEvent::AppDied { name }) => {
+                if let Err(err) = handle_death(gs, name).await {
+                    log!(LogLevel::Error, "Restart supervisor error: {}", err);
+                }
+            }
+            Ok(ManagerEvent::LivenessFailed { name, failures, .. }) => {
+                if let Err(err) = handle_unhealthy(gs, name, failures).await {
+                    log!(LogLevel::Error, "Restart supervisor error: {}", err);
+                }
+            }
+            Ok(ManagerEvent::AppStarted { name, .. }) => {
+                if let Ok(mut history) = RESTART_HISTORY.try_write().await {
+                    let entry: &mut RestartHistory = history.entry(name).or_default();
+                    entry.started_at = current_timestamp();
+                    entry.given_up = false;
+                }
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                log!(
+                    LogLevel::Warn,
+                    "Restart supervisor missed {} events",
+                    skipped
+                );
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
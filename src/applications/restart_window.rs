@@ -0,0 +1,226 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use nix::libc::{self, kill};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::system::decisions::Decision;
+
+use super::child::APP_STATUS_ARRAY;
+
+/// Apps that were asked whether they can be restarted and haven't answered
+static PENDING: Lazy<Mutex<HashMap<String, oneshot::Sender<()>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How the last restart of every app that negotiates them went
+static NEGOTIATIONS: Lazy<Mutex<HashMap<String, RestartNegotiation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Declared as a `[restart_window]` table in /etc/{app}/Config.toml. Only
+/// apps that declare one are asked, the signal would kill any other.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestartWindow {
+    /// Sent to ask, the app answers with `restart_ready` on the admin socket
+    #[serde(default = "default_signal")]
+    pub signal: String,
+    /// Restarted anyway once this passes without an answer
+    #[serde(default = "default_deadline")]
+    pub deadline_secs: u64,
+}
+
+fn default_signal() -> String {
+    String::from("SIGUSR2")
+}
+
+fn default_deadline() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartOutcome {
+    /// The app said it was ready before the deadline
+    Cooperative,
+    /// The deadline passed or the app couldn't be asked
+    Forced,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestartNegotiation {
+    pub outcome: RestartOutcome,
+    pub asked_at: u64,
+    pub answered_at: Option<u64>,
+    pub deadline_secs: u64,
+    /// Why it was forced
+    pub reason: Option<String>,
+}
+
+fn window_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn declared_window(app_id: &str) -> Option<RestartWindow> {
+    let data: String = fs::read_to_string(format!("/etc/{}/Config.toml", app_id)).ok()?;
+    let config: toml::Value = toml::from_str(&data).ok()?;
+
+    match config
+        .get("restart_window")?
+        .clone()
+        .try_into::<RestartWindow>()
+    {
+        Ok(window) => Some(window),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "{} declares an invalid restart window: {}",
+                app_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn signal_number(name: &str) -> Result<i32, ErrorArrayItem> {
+    match name.to_uppercase().trim_start_matches("SIG") {
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "WINCH" => Ok(libc::SIGWINCH),
+        "URG" => Ok(libc::SIGURG),
+        other => Err(window_error(format!(
+            "SIG{} can't be used to ask for a restart window",
+            other
+        ))),
+    }
+}
+
+/// Signals the app, then waits for it to answer until the deadline
+async fn ask(app_id: &Stringy, window: &RestartWindow) -> Result<bool, ErrorArrayItem> {
+    let signal: i32 = signal_number(&window.signal)?;
+    let pid: u32 = APP_STATUS_ARRAY
+        .read(app_id, |app| app.app_data.get_pid())
+        .await?
+        .filter(|pid| *pid != 0)
+        .ok_or_else(|| window_error(format!("{} has no pid to signal", app_id)))?;
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    PENDING
+        .lock()
+        .map_err(|err| window_error(err.to_string()))?
+        .insert(app_id.to_string(), sender);
+
+    if unsafe { kill(pid as i32, signal) } != 0 {
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.remove(app_id.as_str());
+        }
+        return Err(ErrorArrayItem::from(io::Error::last_os_error()));
+    }
+
+    let deadline: Duration = Duration::from_secs(window.deadline_secs);
+    let answered: bool = matches!(tokio::time::timeout(deadline, receiver).await, Ok(Ok(_)));
+
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.remove(app_id.as_str());
+    }
+    Ok(answered)
+}
+
+/// Asks the app whether it can be restarted now and waits for the answer
+/// up to its deadline. Returns right away for apps that declare no restart
+/// window. The restart goes ahead either way, the outcome is only recorded.
+pub async fn negotiate_restart(app_id: &Stringy) -> Option<RestartNegotiation> {
+    let window: RestartWindow = declared_window(app_id.as_str())?;
+    let asked_at: u64 = current_timestamp();
+
+    log!(
+        LogLevel::Info,
+        "Asking {} for a restart window, waiting up to {}s",
+        app_id,
+        window.deadline_secs
+    );
+    let (outcome, reason) = match ask(app_id, &window).await {
+        Ok(true) => (RestartOutcome::Cooperative, None),
+        Ok(false) => (
+            RestartOutcome::Forced,
+            Some(format!("no answer within {}s", window.deadline_secs)),
+        ),
+        Err(err) => (RestartOutcome::Forced, Some(err.to_string())),
+    };
+
+    let negotiation: RestartNegotiation = RestartNegotiation {
+        outcome,
+        asked_at,
+        answered_at: match outcome {
+            RestartOutcome::Cooperative => Some(current_timestamp()),
+            RestartOutcome::Forced => None,
+        },
+        deadline_secs: window.deadline_secs,
+        reason: reason.clone(),
+    };
+
+    let decision: Decision = match reason {
+        None => {
+            log!(LogLevel::Info, "{} is ready to be restarted", app_id);
+            Decision::new(
+                "restart_window",
+                Some(app_id),
+                "restart cooperatively",
+                String::from("the app said it's ready"),
+            )
+        }
+        Some(reason) => {
+            log!(
+                LogLevel::Warn,
+                "Restarting {} without its consent, {}",
+                app_id,
+                reason
+            );
+            Decision::new("restart_window", Some(app_id), "force restart", reason)
+        }
+    };
+    decision
+        .input("signal", &window.signal)
+        .input("deadline_secs", window.deadline_secs)
+        .input("waited_secs", current_timestamp().saturating_sub(asked_at))
+        .record();
+
+    if let Ok(mut negotiations) = NEGOTIATIONS.lock() {
+        negotiations.insert(app_id.to_string(), negotiation.clone());
+    }
+    Some(negotiation)
+}
+
+/// The app's answer that now is a good time, sent over the admin socket
+pub fn acknowledge_restart(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let sender: oneshot::Sender<()> = PENDING
+        .lock()
+        .map_err(|err| window_error(err.to_string()))?
+        .remove(app_id.as_str())
+        .ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("{} wasn't asked for a restart window", app_id),
+            )
+        })?;
+
+    // The deadline passed in between, the restart is already forced
+    sender
+        .send(())
+        .map_err(|_| window_error(format!("The restart of {} was already forced", app_id)))
+}
+
+pub fn restart_negotiation_of(app_id: &str) -> Option<RestartNegotiation> {
+    NEGOTIATIONS
+        .lock()
+        .ok()
+        .and_then(|negotiations| negotiations.get(app_id).cloned())
+}
@@ -0,0 +1,130 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::pathtype::PathType;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::state_persistence::{AppState, StatePersistence};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::{retain_decisions, Decision};
+use crate::system::events::ManagerEvent;
+use crate::system::journal::JournalEntry;
+use crate::system::settings::{RetentionPolicy, RetentionSettings};
+use crate::system::storage::off_worker;
+
+use super::capture::clear_captures;
+use super::child::APP_STATUS_ARRAY;
+use super::coredump::{purge_core_dumps, registered_app};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// What was removed by a purge request
+#[derive(Debug, Serialize)]
+pub struct PurgeReport {
+    pub app: Stringy,
+    pub captured_lines: usize,
+    pub core_dumps: usize,
+    pub audit_entries: usize,
+    pub decisions: usize,
+}
+
+/// Oldest captured output line the policy allows to keep
+pub fn capture_cutoff(policy: &RetentionPolicy) -> u64 {
+    current_timestamp().saturating_sub(policy.capture_days * DAY)
+}
+
+/// Prunes event journal entries and decisions that are past the audit
+/// retention of the application they're about, those not tied to an app are
+/// kept
+pub async fn enforce_audit_retention(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let retention: &RetentionSettings = &gs.settings.retention;
+    let now: u64 = current_timestamp();
+
+    let unexpired = |entry: &JournalEntry| match entry.event.app_name() {
+        Some(name) => entry.timestamp + retention.policy_for(name).audit_days * DAY >= now,
+        None => true,
+    };
+
+    let dropped: usize = gs.journal.retain(unexpired)?;
+
+    if dropped > 0 {
+        log!(
+            LogLevel::Info,
+            "Pruned {} expired entries from the event journal",
+            dropped
+        );
+    }
+
+    let retention: RetentionSettings = retention.clone();
+    let dropped: usize = off_worker(move || {
+        retain_decisions(|decision: &Decision| match &decision.app_id {
+            Some(name) => decision.timestamp + retention.policy_for(name).audit_days * DAY >= now,
+            None => true,
+        })
+    })
+    .await?;
+
+    if dropped > 0 {
+        log!(
+            LogLevel::Info,
+            "Pruned {} expired decisions from the decision journal",
+            dropped
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes everything we hold about an application's output, crashes and
+/// history. The purge itself is recorded so there's proof it happened.
+pub async fn purge_tenant_data(
+    gs: &Arc<GlobalState>,
+    app_id: &Stringy,
+) -> Result<PurgeReport, ErrorArrayItem> {
+    // The id ends up in paths that get cleared or deleted
+    registered_app(app_id).await?;
+
+    let captured_lines: usize = clear_captures(app_id).await?;
+
+    APP_STATUS_ARRAY
+        .modify(app_id, |app| {
+            app.app_data.state.stdout.clear();
+            app.app_data.state.stderr.clear();
+        })
+        .await?;
+
+    // Otherwise the next state refresh would bring the captures right back
+    let state_path: PathType = PathType::Content(format!("/tmp/.{}.state", app_id));
+    if state_path.exists() {
+        let mut state: AppState = StatePersistence::load_state(&state_path).await?;
+        state.stdout.clear();
+        state.stderr.clear();
+        StatePersistence::save_state(&mut state, &state_path).await?;
+    }
+
+    let core_dumps: usize = purge_core_dumps(app_id).await?;
+    let audit_entries: usize = gs
+        .journal
+        .retain(|entry: &JournalEntry| entry.event.app_name() != Some(app_id))?;
+    let purged: Stringy = app_id.clone();
+    let decisions: usize = off_worker(move || {
+        retain_decisions(|decision: &Decision| decision.app_id.as_ref() != Some(&purged))
+    })
+    .await?;
+
+    log!(LogLevel::Info, "Purged retained data of {}", app_id);
+    gs.events.publish(ManagerEvent::DataPurged {
+        name: app_id.clone(),
+    });
+
+    Ok(PurgeReport {
+        app: app_id.clone(),
+        captured_lines,
+        core_dumps,
+        audit_entries,
+        decisions,
+    })
+}
@@ -0,0 +1,141 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::state_persistence::AppState;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Arc;
+use tokio::process::Command;
+
+use crate::system::cgroups::slice_dir;
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::events::ManagerEvent;
+use crate::system::self_test::SELF_TEST_UNIT_PREFIX;
+use crate::system::settings::{ServiceValidationMode, ServiceValidationSettings};
+
+use super::resolve::{git_project_ids, system_application_names, SYSTEMAPPLICATIONSIGNORE};
+
+/// Units whose names end in something generated, expected by their prefix
+const EXPECTED_PREFIXES: &[&str] = &[SELF_TEST_UNIT_PREFIX];
+
+/// Unexpected services we've already reported, so each is only raised once
+static REPORTED_SERVICES: Lazy<LockWithTimeout<HashSet<String>>> =
+    Lazy::new(|| LockWithTimeout::new(HashSet::new()));
+
+fn running_services() -> Result<Vec<String>, ErrorArrayItem> {
+    let mut services: Vec<String> = Vec::new();
+
+    for entry in fs::read_dir(slice_dir())? {
+        let path = entry?.path();
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            if let Some(service) = name.strip_suffix(".service") {
+                services.push(service.to_string());
+            }
+        }
+    }
+
+    Ok(services)
+}
+
+/// Every service name that's allowed to run in artisan.slice
+async fn expected_services(gs: &Arc<GlobalState>) -> Result<HashSet<String>, ErrorArrayItem> {
+    let app_state: AppState = gs.get_state_clone().await?;
+
+    let mut expected: HashSet<String> = system_application_names()
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    expected.extend(SYSTEMAPPLICATIONSIGNORE.iter().map(|name| name.to_string()));
+
+    for id in git_project_ids(&app_state).await? {
+        expected.insert(format!("ais_{}", id));
+    }
+
+    // Canaries run next to the app they're replacing
+    let canaries: Vec<String> = expected
+        .iter()
+        .map(|name| format!("{}-canary", name))
+        .collect();
+    expected.extend(canaries);
+
+    Ok(expected)
+}
+
+async fn systemctl(action: &str, unit: &str) -> Result<(), ErrorArrayItem> {
+    let status = Command::new("systemctl")
+        .arg(action)
+        .arg(unit)
+        .status()
+        .await?;
+
+    if !status.success() {
+        log!(LogLevel::Error, "systemctl {} {} failed", action, unit);
+    }
+
+    Ok(())
+}
+
+/// Compares the services running in artisan.slice with the git credentials
+/// file and system application list. Anything unexpected is reported, and
+/// stopped and masked when enforcing.
+pub async fn validate_services(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &ServiceValidationSettings = &gs.settings.service_validation;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let expected: HashSet<String> = expected_services(gs).await?;
+    let mut reported = REPORTED_SERVICES.try_write().await?;
+    let enforced: bool = settings.mode == ServiceValidationMode::Enforce;
+
+    let unexpected: Vec<String> = running_services()?
+        .into_iter()
+        .filter(|service| !expected.contains(service))
+        .filter(|service| {
+            !EXPECTED_PREFIXES
+                .iter()
+                .any(|prefix| service.starts_with(prefix))
+        })
+        .collect();
+
+    for service in &unexpected {
+        if enforced {
+            let unit: String = format!("{}.service", service);
+            systemctl("stop", &unit).await?;
+            systemctl("mask", &unit).await?;
+            Decision::new(
+                "service_validation",
+                Some(&Stringy::from(service.as_str())),
+                "stop and mask",
+                String::from("it runs in artisan.slice without being expected there"),
+            )
+            .input("mode", "enforce")
+            .record();
+        }
+
+        // Reporting mode only raises an alert the first time we see it
+        if !reported.insert(service.clone()) && !enforced {
+            continue;
+        }
+
+        log!(
+            LogLevel::Warn,
+            "{} is running in artisan.slice but isn't expected{}",
+            service,
+            if enforced { ", stopped and masked" } else { "" }
+        );
+        gs.events.publish(ManagerEvent::UnexpectedService {
+            name: Stringy::from(service.as_str()),
+            enforced,
+        });
+    }
+
+    // Forget services that went away so they're reported again if they return
+    reported.retain(|service| unexpected.contains(service));
+
+    Ok(())
+}
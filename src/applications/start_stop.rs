@@ -1,30 +1,53 @@
 use std::io;
+use std::sync::Arc;
 use std::time::Duration;
 
 use artisan_middleware::aggregator::AppStatus;
 use artisan_middleware::aggregator::Status;
 use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
-use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::logger::{set_log_level, LogLevel};
+use artisan_middleware::dusa_collection_utils::core::types::pathtype::PathType;
 use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
 use artisan_middleware::dusa_collection_utils::log;
-use artisan_middleware::systemd::SystemdService;
-use nix::libc::kill;
+use artisan_middleware::state_persistence::{AppState, StatePersistence};
+use nix::libc::{self, kill};
 
 use crate::applications::child::{
     SupervisedProcesses, APP_STATUS_ARRAY, CLIENT_APPLICATION_HANDLER, SYSTEM_APPLICATION_HANDLER,
 };
+use crate::applications::hibernation::release;
+use crate::applications::integrity::verify_binary;
+use crate::applications::limits::apply_limits;
+use crate::applications::pause::forget_pause;
+use crate::applications::quota::check_not_suspended;
+use crate::applications::registry::Registry;
+use crate::applications::restart::{clear_manual_stop, note_manual_stop};
+use crate::applications::restart_window::negotiate_restart;
+use crate::applications::unit_files::sync_unit_files;
+use crate::system::control::GLOBAL_STATE;
+use crate::system::systemd_bus::{self, UnitState};
 
-pub async fn stop_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
-    let app_status_array_write_lock: tokio::sync::RwLockReadGuard<
-        '_,
-        std::collections::HashMap<Stringy, artisan_middleware::aggregator::AppStatus>,
-    > = APP_STATUS_ARRAY.try_read().await?;
+/// How long systemd gets to bring a unit up or down before we stop waiting
+const UNIT_SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signals the portal may send to an app, the ones apps reload or shut down
+/// on. Anything else could crash or stop them behind the manager's back.
+const ALLOWED_SIGNALS: [(&str, i32); 4] = [
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+];
 
-    let app_status = app_status_array_write_lock.get(&app_id);
+pub async fn stop_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let app_status: Option<Arc<AppStatus>> = APP_STATUS_ARRAY.get(app_id).await?;
 
     match app_status {
         Some(app) => {
-            send_stop(&app)?;
+            note_manual_stop(app_id).await;
+            // systemd thaws a frozen unit on its way down
+            forget_pause(app_id);
+            send_stop(&app).await?;
             return Ok(());
         }
         None => {
@@ -37,95 +60,113 @@ pub async fn stop_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
     }
 }
 
-fn send_stop(app: &AppStatus) -> Result<(), ErrorArrayItem> {
-    let systemd_app = SystemdService::new(&app.app_data.get_name())?;
+async fn send_stop(app: &AppStatus) -> Result<(), ErrorArrayItem> {
+    let name: Stringy = app.app_data.get_name();
 
-    systemd_app.kill().map_err(|err| {
-        ErrorArrayItem::new(
-            Errors::GeneralError,
-            format!("Failed to kill application: {}", err.to_string()),
-        )
-    })?;
+    systemd_bus::stop_unit(&name).await?;
+    if systemd_bus::wait_for(&name, false, UNIT_SETTLE_TIMEOUT).await? {
+        return Ok(());
+    }
 
-    systemd_app
-        .is_active()
-        .map_err(|err| {
-            ErrorArrayItem::new(
-                Errors::GeneralError,
-                format!(
-                    "Error checking if system service is active: {}",
-                    err.to_string()
-                ),
-            )
-        })
-        .map(|active| match active {
-            true => {
-                let kill_result: i32 = unsafe { kill(app.app_data.get_pid() as i32, 9) };
-                if kill_result != 0 {
-                    let error: io::Error = io::Error::from_raw_os_error(kill_result);
-                    Err(ErrorArrayItem::from(error))
-                } else {
-                    Ok(())
-                }
-            }
-            false => Ok(()),
-        })?
-    // SIGUSR1 = 10
+    // Whatever is left of it after the stop timed out
+    log!(LogLevel::Warn, "{} didn't stop in time, killing it", name);
+    systemd_bus::kill_unit(&name, libc::SIGKILL).await
 }
 
-pub async fn reload_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
-    let mut app_status_array_write_lock: tokio::sync::RwLockWriteGuard<
-        '_,
-        std::collections::HashMap<Stringy, artisan_middleware::aggregator::AppStatus>,
-    > = APP_STATUS_ARRAY.try_write().await?;
+/// Pid of the process a handler holds for the app, if it holds one
+async fn handler_pid(
+    handler: &Registry<SupervisedProcesses>,
+    name: &Stringy,
+) -> Result<Option<i32>, ErrorArrayItem> {
+    let entry = match handler.entry(name).await? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
 
-    let app_status = match app_status_array_write_lock.get_mut(&app_id) {
-        Some(app) => {
-            app.app_data.set_status(Status::Stopping);
-            Some(app)
-        }
-        None => None, // couldn't find
+    let pid: i32 = match &*entry.try_read().await? {
+        SupervisedProcesses::Child(supervised_child) => supervised_child.get_pid().await? as i32,
+        SupervisedProcesses::Process(supervised_process) => supervised_process.get_pid(),
     };
 
+    Ok(Some(pid))
+}
+
+pub async fn reload_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let app_status: Option<(Stringy, Status)> = APP_STATUS_ARRAY
+        .modify(app_id, |app| {
+            let from: Status = app.app_data.get_status();
+            app.app_data.set_status(Status::Stopping);
+            (app.app_id.clone(), from)
+        })
+        .await?;
+
     match app_status {
-        Some(app) => {
-            let lock = CLIENT_APPLICATION_HANDLER.try_read().await?;
-            if let Some(child) = lock.get(&app.app_id) {
-                match child {
-                    SupervisedProcesses::Child(supervised_child) => {
-                        let pid = supervised_child.get_pid().await?;
-                        send_reload(pid as i32)?;
-                        return Ok(());
-                    }
-                    SupervisedProcesses::Process(supervised_process) => {
-                        let pid = supervised_process.get_pid();
-                        send_reload(pid)?;
-                        return Ok(());
-                    }
-                }
-            };
-
-            let lock = SYSTEM_APPLICATION_HANDLER.try_read().await?;
-            if let Some(child) = lock.get(&app.app_id) {
-                match child {
-                    SupervisedProcesses::Child(supervised_child) => {
-                        let pid = supervised_child.get_pid().await?;
-                        send_reload(pid as i32)?;
-                        return Ok(());
-                    }
-                    SupervisedProcesses::Process(supervised_process) => {
-                        let pid = supervised_process.get_pid();
-                        send_reload(pid)?;
-                        return Ok(());
-                    }
-                }
-            };
+        Some((status_id, from)) => {
+            if let Some(gs) = GLOBAL_STATE.get() {
+                gs.events.status_changed(app_id, from, Status::Stopping);
+            }
 
+            if let Some(pid) = handler_pid(&CLIENT_APPLICATION_HANDLER, &status_id).await? {
+                send_reload(pid)?;
+                return Ok(());
+            }
+
+            if let Some(pid) = handler_pid(&SYSTEM_APPLICATION_HANDLER, &status_id).await? {
+                send_reload(pid)?;
+                return Ok(());
+            }
+
+            return Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("{}, Not registered in the system", app_id),
+            ));
+        }
+        None => {
+            log!(LogLevel::Warn, "{}, Not registered in the system", app_id);
             return Err(ErrorArrayItem::new(
                 Errors::NotFound,
                 format!("{}, Not registered in the system", app_id),
             ));
         }
+    }
+}
+
+/// Parses the level names used in the config files, case doesn't matter
+pub fn parse_log_level(level: &str) -> Result<LogLevel, ErrorArrayItem> {
+    let mut chars = level.chars();
+    let normalized: String = match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(|c| c.to_lowercase()))
+            .collect(),
+        None => String::new(),
+    };
+
+    serde_json::from_str::<LogLevel>(&format!("\"{}\"", normalized)).map_err(|_| {
+        ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("{} is not a valid log level", level),
+        )
+    })
+}
+
+/// Writes the requested log level into the application's state and sends it
+/// a SIGHUP so it picks the change up without a restart
+pub async fn set_application_log_level(
+    app_id: &Stringy,
+    level: LogLevel,
+) -> Result<(), ErrorArrayItem> {
+    if *app_id == "ais_manager".into() {
+        set_log_level(level);
+        log!(LogLevel::Info, "Manager log level set to {:?}", level);
+        return Ok(());
+    }
+
+    let pid: u32 = match APP_STATUS_ARRAY
+        .read(app_id, |app| app.app_data.get_pid())
+        .await?
+    {
+        Some(pid) => pid,
         None => {
             log!(LogLevel::Warn, "{}, Not registered in the system", app_id);
             return Err(ErrorArrayItem::new(
@@ -133,7 +174,84 @@ pub async fn reload_application(app_id: &Stringy) -> Result<(), ErrorArrayItem>
                 format!("{}, Not registered in the system", app_id),
             ));
         }
+    };
+
+    let state_path: PathType = PathType::Content(format!("/tmp/.{}.state", app_id));
+    let mut state: AppState = StatePersistence::load_state(&state_path).await?;
+    state.config.log_level = level;
+    StatePersistence::save_state(&mut state, &state_path).await?;
+
+    log!(LogLevel::Info, "Set log level of {} to {:?}", app_id, level);
+    send_reload(pid as i32)
+}
+
+/// Name and number of `HUP`, `sighup` or `SIGHUP`, as long as it's on the
+/// allowlist
+fn allowed_signal(signal: &str) -> Result<(&'static str, i32), ErrorArrayItem> {
+    let normalized: String = format!("SIG{}", signal.to_uppercase().trim_start_matches("SIG"));
+
+    ALLOWED_SIGNALS
+        .iter()
+        .find(|(name, _)| *name == normalized)
+        .copied()
+        .ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::Unauthorized,
+                format!(
+                    "{} can't be sent, allowed are {}",
+                    signal,
+                    ALLOWED_SIGNALS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ),
+            )
+        })
+}
+
+pub fn parse_signal(signal: &str) -> Result<&'static str, ErrorArrayItem> {
+    allowed_signal(signal).map(|(name, _)| name)
+}
+
+/// Sends an allowlisted signal to the app's main process, or to every
+/// process in its cgroup
+pub async fn signal_application(
+    app_id: &Stringy,
+    signal: &str,
+    whole_cgroup: bool,
+) -> Result<(), ErrorArrayItem> {
+    if *app_id == "ais_manager".into() {
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            "The manager isn't signaled through the portal",
+        ));
+    }
+
+    let (name, number) = allowed_signal(signal)?;
+
+    if !systemd_bus::is_active(app_id.as_str()).await? {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} isn't running", app_id),
+        ));
+    }
+
+    match whole_cgroup {
+        true => systemd_bus::kill_unit(app_id.as_str(), number).await?,
+        false => systemd_bus::kill_main(app_id.as_str(), number).await?,
     }
+    log!(
+        LogLevel::Info,
+        "Sent {} to {} of {}",
+        name,
+        match whole_cgroup {
+            true => "every process",
+            false => "the main process",
+        },
+        app_id
+    );
+    Ok(())
 }
 
 fn send_reload(pid: i32) -> Result<(), ErrorArrayItem> {
@@ -149,12 +267,19 @@ fn send_reload(pid: i32) -> Result<(), ErrorArrayItem> {
 }
 
 pub async fn start_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
-    let app_status_array_read_lock = APP_STATUS_ARRAY
-        .try_read_with_timeout(Some(Duration::from_secs(20)))
-        .await?;
+    check_not_suspended(app_id)?;
+    if let Some(gs) = GLOBAL_STATE.get() {
+        gs.pressure.check_start_allowed()?;
+        verify_binary(gs, app_id).await?;
+    }
+
+    clear_manual_stop(app_id).await;
+    // A hibernated app binds its port itself again
+    release(app_id);
+    forget_pause(app_id);
 
     // Retrieve or initialize app status
-    let app: &AppStatus = match app_status_array_read_lock.get(app_id) {
+    let app: Arc<AppStatus> = match APP_STATUS_ARRAY.get(app_id).await? {
         Some(app) => app,
         None => {
             let error = ErrorArrayItem::new(
@@ -165,29 +290,151 @@ pub async fn start_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
         }
     };
 
-    let systemd_app = SystemdService::new(&app.app_data.get_name())?;
+    let name: Stringy = app.app_data.get_name();
+    let state: UnitState = systemd_bus::unit_state(&name).await?;
+    if state.is_active() {
+        return send_stop(&app).await;
+    }
 
-    systemd_app
-        .is_active()
-        .map_err(|err| {
-            ErrorArrayItem::new(
-                Errors::GeneralError,
-                format!(
-                    "Error checking if system service is active: {}",
-                    err.to_string()
-                ),
-            )
-        })
-        .map(|active| match active {
-            true => send_stop(app),
-            false => {
-                if let Err(err) = systemd_app.start() {
-                    Err(ErrorArrayItem::new(Errors::Unauthorized, err.to_string()))
-                } else {
-                    Ok(())
-                }
-            }
-        })?
+    if let Err(err) = systemd_bus::start_unit(&name).await {
+        return Err(ErrorArrayItem::new(Errors::Unauthorized, err.to_string()));
+    }
+
+    // The cgroup only exists once the unit is up
+    if let Err(err) = apply_limits(&name) {
+        let _ = systemd_bus::stop_unit(&name).await;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Has systemd restart the application and waits for the unit to come back
+pub async fn restart_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    check_not_suspended(app_id)?;
+
+    let name = match APP_STATUS_ARRAY
+        .read(app_id, |app| app.app_data.get_name())
+        .await?
+    {
+        Some(name) => name,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("State data for: {} not loaded", app_id),
+            ))
+        }
+    };
+
+    // Apps that negotiate restarts get their say first, a forced one is
+    // only recorded
+    negotiate_restart(app_id).await;
+
+    // The unit going down on the way isn't a crash
+    note_manual_stop(app_id).await;
+    let restarted: Result<(), ErrorArrayItem> = systemd_bus::restart_unit(&name)
+        .await
+        .map_err(|err| ErrorArrayItem::new(Errors::Unauthorized, err.to_string()));
+
+    // It's coming back up, so a crash from here on should be restarted
+    let up: Result<bool, ErrorArrayItem> = match restarted {
+        Ok(_) => systemd_bus::wait_for(&name, true, UNIT_SETTLE_TIMEOUT).await,
+        Err(err) => Err(err),
+    };
+    clear_manual_stop(app_id).await;
+
+    // Whatever was frozen went down with the old processes
+    let up: bool = up?;
+    forget_pause(app_id);
+
+    if !up {
+        log!(
+            LogLevel::Warn,
+            "{} isn't back up {}s after its restart",
+            name,
+            UNIT_SETTLE_TIMEOUT.as_secs()
+        );
+    }
+    Ok(())
+}
+
+async fn set_expected_status(app_id: &Stringy, expected: Status) -> Result<(), ErrorArrayItem> {
+    match APP_STATUS_ARRAY
+        .modify(app_id, |status| status.expected_status = expected)
+        .await?
+    {
+        Some(_) => Ok(()),
+        None => Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{}, Not registered in the system", app_id),
+        )),
+    }
+}
+
+/// Stops the app and masks its unit, nothing starts it again until it's
+/// unmasked
+pub async fn mask_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    if *app_id == "ais_manager".into() {
+        return Err(ErrorArrayItem::new(
+            Errors::Unauthorized,
+            "The manager can't mask itself",
+        ));
+    }
+
+    // A hibernated app would otherwise be woken into a masked unit
+    release(app_id);
+    if systemd_bus::is_active(app_id.as_str()).await? {
+        stop_application(app_id).await?;
+    }
+    systemd_bus::mask_unit(app_id.as_str()).await?;
+    set_expected_status(app_id, Status::Stopped).await?;
+    log!(LogLevel::Info, "Masked {}", app_id);
+    Ok(())
+}
+
+/// Lets the app be started again, it's expected to run when its unit is
+/// enabled and left to whoever starts it otherwise
+pub async fn unmask_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    systemd_bus::unmask_unit(app_id.as_str()).await?;
+
+    // Masking replaced the unit we rendered, it's written again right away
+    if let Some(gs) = GLOBAL_STATE.get() {
+        if let Err(err) = sync_unit_files(gs).await {
+            log!(
+                LogLevel::Warn,
+                "Couldn't render the unit of {} again: {}",
+                app_id,
+                err
+            );
+        }
+    }
+
+    let expected: Status = match systemd_bus::unit_file_state(app_id.as_str())
+        .await?
+        .as_str()
+    {
+        "enabled" => Status::Running,
+        _ => Status::Idle,
+    };
+    set_expected_status(app_id, expected).await?;
+    log!(LogLevel::Info, "Unmasked {}", app_id);
+    Ok(())
+}
+
+/// Has the app started at boot, it isn't started now
+pub async fn enable_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    systemd_bus::enable_unit(app_id.as_str()).await?;
+    set_expected_status(app_id, Status::Running).await?;
+    log!(LogLevel::Info, "Enabled {}", app_id);
+    Ok(())
+}
+
+/// Keeps the app from starting at boot, it isn't stopped now
+pub async fn disable_application(app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    systemd_bus::disable_unit(app_id.as_str()).await?;
+    set_expected_status(app_id, Status::Idle).await?;
+    log!(LogLevel::Info, "Disabled {}", app_id);
+    Ok(())
 }
 
 // /// Helper to start system applications
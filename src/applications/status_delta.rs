@@ -0,0 +1,61 @@
+use artisan_middleware::aggregator::AppStatus;
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::system::settings::TelemetrySettings;
+use crate::system::telemetry::outbound_status_value;
+
+use super::child::APP_STATUS_ARRAY;
+use super::registry::Changes;
+
+/// Statuses that changed since a generation the caller got before. Asking
+/// with the returned generation next time picks up from there.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusDelta {
+    pub generation: u64,
+    /// Set when every status was sent, the removals since the generation
+    /// asked about can't be told anymore
+    pub full: bool,
+    pub changed: Vec<Value>,
+    pub removed: Vec<Stringy>,
+}
+
+/// Only the entries changed after `since` are serialized, an unchanged app
+/// costs nothing
+pub async fn status_delta(
+    telemetry: &TelemetrySettings,
+    since: u64,
+) -> Result<StatusDelta, ErrorArrayItem> {
+    // Generations from before a reload are behind our horizon, or even ahead
+    // of ours when the clock went backwards or the previous instance made
+    // more changes than milliseconds passed since. Old enough ones have had
+    // their tombstones pruned. Whoever asks with one gets everything again.
+    let changes: Changes = APP_STATUS_ARRAY.changes_since(since);
+
+    let mut delta: StatusDelta = StatusDelta {
+        generation: changes.generation,
+        full: changes.full,
+        changed: Vec::new(),
+        removed: Vec::new(),
+    };
+
+    for (name, change) in changes.changes {
+        if change.removed {
+            delta.removed.push(name);
+            continue;
+        }
+
+        let status: AppStatus = match APP_STATUS_ARRAY.get(&name).await? {
+            Some(status) => (*status).clone(),
+            // Removed since the changes were read, the next delta says so
+            None => continue,
+        };
+        if let Some(value) = outbound_status_value(telemetry, &name, &status).await {
+            delta.changed.push(value);
+        }
+    }
+
+    Ok(delta)
+}
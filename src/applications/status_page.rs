@@ -0,0 +1,208 @@
+use artisan_middleware::aggregator::AppStatus;
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use gethostname::gethostname;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::journal::JournalEntry;
+use crate::system::settings::{StatusPageSettings, TelemetryLevel};
+use crate::system::timezone::format_timestamp;
+use crate::system::units::format_percent;
+
+use super::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use super::digest::{summarize, Digest};
+use super::hibernation::is_hibernating;
+use super::pause::is_paused;
+
+const DAY: u64 = 24 * 60 * 60;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / DAY, secs % DAY / 3600),
+    }
+}
+
+/// Outages customers can see, anything that says more about the node than
+/// that an app went down stays off the page
+fn public_incident(event: &ManagerEvent) -> Option<String> {
+    match event {
+        ManagerEvent::AppDied { name } => Some(format!("{} went down", name)),
+        ManagerEvent::CrashLoop { name, .. } => Some(format!("{} kept crashing", name)),
+        ManagerEvent::RestartsExhausted { name, .. } => {
+            Some(format!("{} is down and waiting for an operator", name))
+        }
+        ManagerEvent::CanaryRolledBack { name, .. } => {
+            Some(format!("An update of {} was rolled back", name))
+        }
+        _ => None,
+    }
+}
+
+fn render(
+    settings: &StatusPageSettings,
+    apps: &[(Stringy, AppStatus, Digest)],
+    incidents: &[(u64, String)],
+    now: u64,
+) -> String {
+    let mut page: String = String::new();
+    let title: String = escape(&settings.title);
+
+    let _ = writeln!(page, "<!DOCTYPE html>");
+    let _ = writeln!(page, "<html lang=\"en\">");
+    let _ = writeln!(page, "<head>");
+    let _ = writeln!(page, "<meta charset=\"utf-8\">");
+    let _ = writeln!(
+        page,
+        "<meta http-equiv=\"refresh\" content=\"{}\">",
+        settings.interval_secs.max(1)
+    );
+    let _ = writeln!(page, "<title>{}</title>", title);
+    let _ = writeln!(
+        page,
+        "<style>body{{font-family:sans-serif;max-width:48em;margin:2em auto}}\
+         table{{width:100%;border-collapse:collapse}}td,th{{text-align:left;padding:.3em}}\
+         .up{{color:#1a7f37}}.down{{color:#cf222e}}</style>"
+    );
+    let _ = writeln!(page, "</head>");
+    let _ = writeln!(page, "<body>");
+    let _ = writeln!(page, "<h1>{}</h1>", title);
+    let _ = writeln!(
+        page,
+        "<p>{} &middot; updated {}</p>",
+        escape(&gethostname().to_string_lossy()),
+        escape(&format_timestamp(now, None))
+    );
+
+    let _ = writeln!(page, "<h2>Applications</h2>");
+    let _ = writeln!(page, "<table>");
+    let _ = writeln!(
+        page,
+        "<tr><th>Application</th><th>Status</th><th>Up for</th><th>Uptime, last {} days</th></tr>",
+        settings.window_days.max(1)
+    );
+    for (name, status, digest) in apps {
+        // Hibernated apps are a connection away from being up
+        let hibernating: bool = is_hibernating(name.as_str());
+        let up: bool = status.uptime.is_some() || hibernating;
+        let _ = writeln!(
+            page,
+            "<tr><td>{}</td><td class=\"{}\">{}</td><td>{}</td><td>{}</td></tr>",
+            escape(name.as_str()),
+            if up { "up" } else { "down" },
+            match (is_paused(name.as_str()), hibernating) {
+                (true, _) => String::from("Paused"),
+                (false, true) => String::from("Hibernated"),
+                (false, false) => format!("{:?}", status.app_data.get_status()),
+            },
+            status.uptime.map(format_uptime).unwrap_or_default(),
+            escape(&format_percent(digest.uptime_percent))
+        );
+    }
+    let _ = writeln!(page, "</table>");
+
+    let _ = writeln!(page, "<h2>Incidents</h2>");
+    match incidents.is_empty() {
+        true => {
+            let _ = writeln!(
+                page,
+                "<p>No incidents in the last {} days.</p>",
+                settings.window_days.max(1)
+            );
+        }
+        false => {
+            let _ = writeln!(page, "<ul>");
+            for (timestamp, incident) in incidents {
+                let _ = writeln!(
+                    page,
+                    "<li>{} &middot; {}</li>",
+                    escape(&format_timestamp(*timestamp, None)),
+                    escape(incident)
+                );
+            }
+            let _ = writeln!(page, "</ul>");
+        }
+    }
+
+    let _ = writeln!(page, "</body>");
+    let _ = writeln!(page, "</html>");
+    page
+}
+
+/// Renders the status page of the client apps to where the reverse proxy
+/// serves it from. Apps that share no telemetry are left off it.
+pub async fn render_status_page(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &StatusPageSettings = &gs.settings.status_page;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let now: u64 = current_timestamp();
+    let since: u64 = now.saturating_sub(settings.window_days.max(1) * DAY);
+    let entries: Vec<JournalEntry> = gs.journal.since(0, usize::MAX)?;
+
+    let mut by_app: HashMap<Stringy, Vec<&JournalEntry>> = HashMap::new();
+    for entry in entries.iter().filter(|entry| entry.timestamp >= since) {
+        if let Some(name) = entry.event.app_name() {
+            by_app.entry(name.clone()).or_default().push(entry);
+        }
+    }
+
+    let mut apps: Vec<(Stringy, AppStatus, Digest)> = Vec::new();
+    let mut incidents: Vec<(u64, String)> = Vec::new();
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        if gs.settings.telemetry.level_for(name.as_str()) == TelemetryLevel::Nothing {
+            continue;
+        }
+        let status: AppStatus = match APP_STATUS_ARRAY.get(&name).await? {
+            Some(status) => (*status).clone(),
+            None => continue,
+        };
+
+        let journal: Vec<&JournalEntry> = by_app.remove(&name).unwrap_or_default();
+        incidents.extend(journal.iter().filter_map(|entry| {
+            public_incident(&entry.event).map(|incident| (entry.timestamp, incident))
+        }));
+        apps.push((name, status, summarize(&journal, since, now)));
+    }
+
+    apps.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+    incidents.sort_by(|a, b| b.0.cmp(&a.0));
+    incidents.truncate(settings.max_incidents);
+
+    let page: String = render(settings, &apps, &incidents, now);
+
+    // Written aside and renamed so the proxy never serves half a page
+    let path: &Path = Path::new(&settings.path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp: PathBuf = path.with_extension("tmp");
+    fs::write(&temp, page)?;
+    fs::rename(&temp, path)?;
+
+    log!(
+        LogLevel::Trace,
+        "Rendered the status page of {} apps to {}",
+        apps.len(),
+        settings.path
+    );
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+
+use super::child::APP_STATUS_ARRAY;
+
+/// Set while the store is over its cap, so the alert goes out once per
+/// crossing rather than on every check
+static OVER_CAPACITY: AtomicBool = AtomicBool::new(false);
+
+/// What the saved status store would hold under a cap
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreCapacity {
+    pub max_entries: usize,
+    pub entries: usize,
+    pub kept: usize,
+    /// Stopped and unknown apps go first, running ones last
+    pub evicted: Vec<Stringy>,
+}
+
+/// Lower goes first when the store is over its cap
+fn eviction_rank(status: &Status) -> u8 {
+    match status {
+        Status::Stopped | Status::Unknown => 0,
+        Status::Running | Status::Starting | Status::Building | Status::Warning => 2,
+        _ => 1,
+    }
+}
+
+/// Keeps the `max_entries` statuses that matter most, returns what was
+/// left out. Within a rank the order is by name, so the same apps lose out
+/// every time.
+pub fn fit_to_capacity(
+    mut statuses: Vec<AppStatus>,
+    max_entries: usize,
+) -> (Vec<AppStatus>, Vec<Stringy>) {
+    if statuses.len() <= max_entries {
+        return (statuses, Vec::new());
+    }
+
+    statuses.sort_by(|a, b| {
+        eviction_rank(&b.app_data.get_status())
+            .cmp(&eviction_rank(&a.app_data.get_status()))
+            .then_with(|| a.app_id.as_str().cmp(b.app_id.as_str()))
+    });
+
+    let evicted: Vec<Stringy> = statuses
+        .split_off(max_entries)
+        .into_iter()
+        .map(|status| status.app_id)
+        .collect();
+    (statuses, evicted)
+}
+
+/// What saving the store right now would keep and evict under `max_entries`
+pub async fn simulate_capacity(max_entries: usize) -> Result<StoreCapacity, ErrorArrayItem> {
+    let statuses: Vec<AppStatus> = APP_STATUS_ARRAY
+        .snapshot()
+        .await?
+        .into_values()
+        .map(|status| AppStatus::clone(&status))
+        .collect();
+    let entries: usize = statuses.len();
+    let (kept, evicted) = fit_to_capacity(statuses, max_entries);
+
+    Ok(StoreCapacity {
+        max_entries,
+        entries,
+        kept: kept.len(),
+        evicted,
+    })
+}
+
+/// Raises a capacity alert once the store holds more apps than it can
+/// save, before the eviction at shutdown drops any of them
+pub async fn check_status_capacity(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let capacity: StoreCapacity = simulate_capacity(gs.settings.status_store.max_entries).await?;
+
+    if capacity.evicted.is_empty() {
+        OVER_CAPACITY.store(false, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    if !OVER_CAPACITY.swap(true, Ordering::Relaxed) {
+        log!(
+            LogLevel::Warn,
+            "The status store holds {} apps, {} over its cap of {}",
+            capacity.entries,
+            capacity.evicted.len(),
+            capacity.max_entries
+        );
+        gs.events.publish(ManagerEvent::StatusStoreFull {
+            entries: capacity.entries,
+            max_entries: capacity.max_entries,
+            evicted: capacity.evicted,
+        });
+    }
+    Ok(())
+}
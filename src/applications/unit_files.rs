@@ -0,0 +1,238 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::system::control::GlobalState;
+use crate::system::decisions::Decision;
+use crate::system::settings::UnitFileSettings;
+use crate::system::systemd_bus::reload_daemon;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+use super::limits::declared_limits;
+use super::node_spec::LimitSpec;
+use super::resolve::ClientApplication;
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// First line of every unit we render, units without it were written by
+/// hand and are left alone
+const MANAGED_HEADER: &str = "# Managed by ais_manager, changes are overwritten";
+
+/// How the unit file of every client app looked on the last pass
+static UNIT_FILES: Lazy<Mutex<HashMap<String, UnitFileReport>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitFileReport {
+    pub path: String,
+    /// False for a hand written unit that isn't overwritten
+    pub managed: bool,
+    /// Whether the file on disk is what we'd render
+    pub in_sync: bool,
+    /// When the file was last written
+    pub written_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+fn unit_file_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn unit_path(app_id: &str) -> String {
+    format!("{}/{}.service", UNIT_DIR, app_id)
+}
+
+/// A masked unit is a link to /dev/null, it stays masked until it's
+/// unmasked
+fn masked(path: &str) -> bool {
+    fs::read_link(path).map_or(false, |target| target == Path::new("/dev/null"))
+}
+
+/// The app's unit: its user, artisan.slice, its environment file and the
+/// limits from its manifest or Config.toml. Restarts are left to the
+/// manager's own supervisor.
+pub fn render_unit(app: &ClientApplication) -> String {
+    let name: &str = app.name.as_str();
+    let mut unit: String = String::new();
+
+    let _ = writeln!(unit, "{}", MANAGED_HEADER);
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(unit, "Description=Artisan client application {}", name);
+    let _ = writeln!(unit, "Wants=network-online.target");
+    let _ = writeln!(unit, "After=network-online.target");
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Service]");
+    let _ = writeln!(unit, "Type=simple");
+    let _ = writeln!(unit, "ExecStart={}", app.path);
+    let _ = writeln!(unit, "User={}", app.execution_uid());
+    let _ = writeln!(unit, "WorkingDirectory=/etc/{}", name);
+    let _ = writeln!(unit, "EnvironmentFile=-/etc/{}/.env", name);
+    let _ = writeln!(unit, "Slice=artisan.slice");
+    let _ = writeln!(unit, "Restart=no");
+
+    match declared_limits(name) {
+        Ok(Some(LimitSpec {
+            cpu_quota_percent,
+            memory_max_bytes,
+            tasks_max,
+            ..
+        })) => {
+            if let Some(cpu) = cpu_quota_percent {
+                let _ = writeln!(unit, "CPUQuota={}%", cpu);
+            }
+            if let Some(memory) = memory_max_bytes {
+                let _ = writeln!(unit, "MemoryMax={}", memory);
+            }
+            if let Some(tasks) = tasks_max {
+                let _ = writeln!(unit, "TasksMax={}", tasks);
+            }
+        }
+        Ok(None) => (),
+        Err(err) => log!(
+            LogLevel::Warn,
+            "Rendering the unit of {} without limits: {}",
+            name,
+            err
+        ),
+    }
+
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Install]");
+    let _ = writeln!(unit, "WantedBy=multi-user.target");
+    unit
+}
+
+/// Writes the unit if it's missing or one of ours that went stale, returns
+/// whether it was written
+fn sync_unit(
+    app: &ClientApplication,
+    settings: &UnitFileSettings,
+    report: &mut UnitFileReport,
+) -> Result<bool, ErrorArrayItem> {
+    if masked(&report.path) {
+        report.managed = false;
+        report.in_sync = false;
+        return Ok(false);
+    }
+
+    let rendered: String = render_unit(app);
+    let current: Option<String> = fs::read_to_string(&report.path).ok();
+
+    report.managed = match &current {
+        Some(current) => current.starts_with(MANAGED_HEADER) || settings.take_over_existing,
+        None => true,
+    };
+    report.in_sync = current.as_deref() == Some(rendered.as_str());
+    if report.in_sync || !report.managed {
+        return Ok(false);
+    }
+
+    fs::write(&report.path, &rendered)
+        .map_err(|err| unit_file_error(format!("Couldn't write {}: {}", report.path, err)))?;
+    report.in_sync = true;
+    report.written_at = Some(current_timestamp());
+
+    Decision::new(
+        "unit_files",
+        Some(&app.name),
+        match current {
+            Some(_) => "rewrite unit",
+            None => "create unit",
+        },
+        match current {
+            Some(_) => String::from("its manifest or config changed"),
+            None => String::from("the app had no unit"),
+        },
+    )
+    .input("path", &report.path)
+    .record();
+    Ok(true)
+}
+
+/// Renders the unit of every client app and reloads systemd once if any of
+/// them changed. Units already running pick their new limits up with their
+/// next restart.
+pub async fn sync_unit_files(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &UnitFileSettings = &gs.settings.unit_files;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let previous: HashMap<String, UnitFileReport> = UNIT_FILES
+        .lock()
+        .map_err(|err| unit_file_error(err.to_string()))?
+        .clone();
+    let mut reports: HashMap<String, UnitFileReport> = HashMap::new();
+    let mut written: Vec<Stringy> = Vec::new();
+
+    for (name, app) in CLIENT_APPLICATION_ARRAY.snapshot().await? {
+        let mut report: UnitFileReport = UnitFileReport {
+            path: unit_path(name.as_str()),
+            managed: true,
+            in_sync: false,
+            written_at: previous
+                .get(name.as_str())
+                .and_then(|report| report.written_at),
+            error: None,
+        };
+
+        match sync_unit(&app, settings, &mut report) {
+            Ok(true) => {
+                log!(LogLevel::Info, "Wrote the unit of {}", name);
+                written.push(name.clone());
+            }
+            Ok(false) => (),
+            Err(err) => {
+                log!(LogLevel::Error, "{}", err);
+                report.error = Some(err.to_string());
+            }
+        }
+
+        if !report.managed
+            && !masked(&report.path)
+            && previous.get(name.as_str()).map_or(true, |old| old.managed)
+        {
+            log!(
+                LogLevel::Warn,
+                "{} has a hand written unit, it's left as it is",
+                name
+            );
+        }
+        reports.insert(name.to_string(), report);
+    }
+
+    *UNIT_FILES
+        .lock()
+        .map_err(|err| unit_file_error(err.to_string()))? = reports;
+
+    if !written.is_empty() {
+        reload_daemon().await?;
+        log!(
+            LogLevel::Info,
+            "Reloaded systemd for the units of {}",
+            written
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn unit_file_of(app_id: &str) -> Option<UnitFileReport> {
+    UNIT_FILES
+        .lock()
+        .ok()
+        .and_then(|reports| reports.get(app_id).cloned())
+}
@@ -0,0 +1,375 @@
+use artisan_middleware::aggregator::Metrics;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::sync::Mutex;
+
+use crate::system::settings::UsageHistorySettings;
+
+/// Where the series are kept across restarts
+const HISTORY_PATH: &str = "/opt/artisan/usage_history.json";
+
+/// Most buckets one query returns, enough for any graph the portal draws
+const MAX_BUCKETS: u64 = 2000;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Usage of every app over time, sampled alongside the ledger. The ledger
+/// only keeps what it needs for billing, this is what graphs are drawn from.
+static HISTORY: Lazy<Mutex<HashMap<String, Series>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Raw samples, rolled up into minutes and those into hours as they age.
+/// Every tier covers its whole retention, queries take the finest one that
+/// still reaches back far enough.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Series {
+    raw: VecDeque<UsagePoint>,
+    minutes: VecDeque<UsagePoint>,
+    hours: VecDeque<UsagePoint>,
+    /// rx and tx counters of the last sample, the traffic of the next one
+    /// is measured against them
+    counters: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct UsagePoint {
+    timestamp: u64,
+    cpu_usage: f64,
+    memory_usage: u64,
+    peak_memory: u64,
+    /// Bytes moved since the point before
+    rx_bytes: u64,
+    tx_bytes: u64,
+    samples: u64,
+    /// CPU and memory integrated over the time the point covers, what
+    /// usage reports bill on. Points written before they existed count 0.
+    #[serde(default)]
+    cpu_seconds: f64,
+    #[serde(default)]
+    memory_byte_seconds: f64,
+}
+
+impl UsagePoint {
+    /// Folds another point in, averages are weighted by their samples
+    fn absorb(&mut self, other: &UsagePoint) {
+        let samples: u64 = self.samples + other.samples;
+        if samples > 0 {
+            self.cpu_usage = (self.cpu_usage * self.samples as f64
+                + other.cpu_usage * other.samples as f64)
+                / samples as f64;
+            self.memory_usage =
+                (self.memory_usage * self.samples + other.memory_usage * other.samples) / samples;
+        }
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+        self.samples = samples;
+        self.cpu_seconds += other.cpu_seconds;
+        self.memory_byte_seconds += other.memory_byte_seconds;
+    }
+}
+
+/// An app's usage summed over a period
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct UsageTotals {
+    pub cpu_seconds: f64,
+    pub memory_byte_seconds: f64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageBucket {
+    pub start: u64,
+    pub cpu_usage: f64,
+    pub memory_usage: u64,
+    pub peak_memory: u64,
+    /// Bytes moved within the bucket
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageHistory {
+    pub app: String,
+    pub from: u64,
+    pub to: u64,
+    pub resolution: u64,
+    /// Buckets without samples are left out
+    pub buckets: Vec<UsageBucket>,
+}
+
+fn history_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Adds a sample unless the last one of the app is younger than the
+/// interval. Retention is left to `compact_usage_history`.
+pub fn record_usage(settings: &UsageHistorySettings, name: &str, metrics: &Metrics) {
+    if !settings.enabled {
+        return;
+    }
+
+    let mut history = match HISTORY.lock() {
+        Ok(history) => history,
+        Err(_) => return,
+    };
+
+    let now: u64 = current_timestamp();
+    let series: &mut Series = history.entry(name.to_owned()).or_default();
+    if matches!(series.raw.back(), Some(last) if last.timestamp + settings.interval_secs > now) {
+        return;
+    }
+
+    let rx: u64 = metrics.other.as_ref().map_or(0, |net| net.rx_bytes);
+    let tx: u64 = metrics.other.as_ref().map_or(0, |net| net.tx_bytes);
+    // Counters start over when the app restarts
+    let moved = |current: u64, last: Option<u64>| match last {
+        Some(last) if current >= last => current - last,
+        Some(_) => current,
+        None => 0,
+    };
+    let (rx_bytes, tx_bytes) = (
+        moved(rx, series.counters.map(|(rx, _)| rx)),
+        moved(tx, series.counters.map(|(_, tx)| tx)),
+    );
+    series.counters = Some((rx, tx));
+
+    // A sample stands for the time since the one before, but not for a
+    // gap where nothing was sampled
+    let seconds: u64 = series
+        .raw
+        .back()
+        .map_or(settings.interval_secs, |last| now - last.timestamp)
+        .min(settings.interval_secs * 3);
+
+    let memory_usage: u64 = metrics.memory_usage as u64;
+    let cpu_usage: f64 = metrics.cpu_usage as f64;
+    series.raw.push_back(UsagePoint {
+        timestamp: now,
+        cpu_usage,
+        memory_usage,
+        peak_memory: memory_usage,
+        rx_bytes,
+        tx_bytes,
+        samples: 1,
+        cpu_seconds: cpu_usage / 100.0 * seconds as f64,
+        memory_byte_seconds: memory_usage as f64 * seconds as f64,
+    });
+}
+
+/// Folds the points of `source` whose `width` wide bucket has closed and
+/// isn't in `target` yet into `target`
+fn roll_up(source: &VecDeque<UsagePoint>, target: &mut VecDeque<UsagePoint>, width: u64, now: u64) {
+    let rolled_until: u64 = target.back().map_or(0, |last| last.timestamp + width);
+    let mut buckets: BTreeMap<u64, UsagePoint> = BTreeMap::new();
+
+    for point in source {
+        let start: u64 = point.timestamp / width * width;
+        if start < rolled_until || start + width > now {
+            continue;
+        }
+
+        buckets
+            .entry(start)
+            .or_insert_with(|| UsagePoint {
+                timestamp: start,
+                ..Default::default()
+            })
+            .absorb(point);
+    }
+
+    target.extend(buckets.into_values());
+}
+
+fn trim(points: &mut VecDeque<UsagePoint>, cutoff: u64) {
+    while matches!(points.front(), Some(first) if first.timestamp < cutoff) {
+        points.pop_front();
+    }
+}
+
+/// Rolls closed minutes and hours up and drops every tier past its
+/// retention, keeping the file and our memory bounded. Apps without a
+/// single point left are forgotten.
+pub fn compact_usage_history(settings: &UsageHistorySettings) -> Result<(), ErrorArrayItem> {
+    let now: u64 = current_timestamp();
+    let mut history = HISTORY
+        .lock()
+        .map_err(|err| history_error(err.to_string()))?;
+
+    for series in history.values_mut() {
+        roll_up(&series.raw, &mut series.minutes, MINUTE, now);
+        roll_up(&series.minutes, &mut series.hours, HOUR, now);
+
+        trim(
+            &mut series.raw,
+            now.saturating_sub(settings.raw_retention_hours * HOUR),
+        );
+        trim(
+            &mut series.minutes,
+            now.saturating_sub(settings.minute_retention_days * DAY),
+        );
+        trim(
+            &mut series.hours,
+            now.saturating_sub(settings.hour_retention_days * DAY),
+        );
+    }
+
+    history.retain(|_, series| {
+        !(series.raw.is_empty() && series.minutes.is_empty() && series.hours.is_empty())
+    });
+    Ok(())
+}
+
+/// Every point of the series once, from the finest tier that covers its
+/// time. Coarser tiers only fill in what finer ones have already dropped.
+fn covering_points(series: &Series) -> impl Iterator<Item = &UsagePoint> {
+    let raw_from: u64 = series.raw.front().map_or(u64::MAX, |first| first.timestamp);
+    let minutes_from: u64 = series
+        .minutes
+        .front()
+        .map_or(raw_from, |first| first.timestamp.min(raw_from));
+
+    series
+        .hours
+        .iter()
+        .filter(move |point| point.timestamp + HOUR <= minutes_from)
+        .chain(
+            series
+                .minutes
+                .iter()
+                .filter(move |point| point.timestamp + MINUTE <= raw_from),
+        )
+        .chain(series.raw.iter())
+}
+
+/// The app's usage between `from` and `to`, averaged into buckets of
+/// `resolution` seconds
+pub fn usage_history(
+    name: &str,
+    from: u64,
+    to: u64,
+    resolution: u64,
+) -> Result<UsageHistory, ErrorArrayItem> {
+    if from > to || resolution == 0 {
+        return Err(history_error(String::from(
+            "The range must not end before it starts and the resolution can't be 0",
+        )));
+    }
+    if (to - from) / resolution > MAX_BUCKETS {
+        return Err(history_error(format!(
+            "That's more than {} buckets, use a coarser resolution",
+            MAX_BUCKETS
+        )));
+    }
+
+    let mut buckets: BTreeMap<u64, UsagePoint> = BTreeMap::new();
+    {
+        let history = HISTORY
+            .lock()
+            .map_err(|err| history_error(err.to_string()))?;
+
+        if let Some(series) = history.get(name) {
+            for point in covering_points(series)
+                .filter(|point| point.timestamp >= from && point.timestamp <= to)
+            {
+                let start: u64 = from + (point.timestamp - from) / resolution * resolution;
+                buckets
+                    .entry(start)
+                    .or_insert_with(|| UsagePoint {
+                        timestamp: start,
+                        ..Default::default()
+                    })
+                    .absorb(point);
+            }
+        }
+    }
+
+    Ok(UsageHistory {
+        app: name.to_owned(),
+        from,
+        to,
+        resolution,
+        buckets: buckets
+            .into_values()
+            .map(|point| UsageBucket {
+                start: point.timestamp,
+                cpu_usage: point.cpu_usage,
+                memory_usage: point.memory_usage,
+                peak_memory: point.peak_memory,
+                rx_bytes: point.rx_bytes,
+                tx_bytes: point.tx_bytes,
+                samples: point.samples,
+            })
+            .collect(),
+    })
+}
+
+/// Usage of every app with points between `from` and `to`, summed. Each
+/// point counts whole, ranges line up with the buckets of the tiers they
+/// reach back into.
+pub fn usage_totals(from: u64, to: u64) -> Result<BTreeMap<String, UsageTotals>, ErrorArrayItem> {
+    let history = HISTORY
+        .lock()
+        .map_err(|err| history_error(err.to_string()))?;
+
+    let mut totals: BTreeMap<String, UsageTotals> = BTreeMap::new();
+    for (name, series) in history.iter() {
+        for point in
+            covering_points(series).filter(|point| point.timestamp >= from && point.timestamp < to)
+        {
+            let total: &mut UsageTotals = totals.entry(name.clone()).or_default();
+            total.cpu_seconds += point.cpu_seconds;
+            total.memory_byte_seconds += point.memory_byte_seconds;
+            total.rx_bytes += point.rx_bytes;
+            total.tx_bytes += point.tx_bytes;
+        }
+    }
+    Ok(totals)
+}
+
+/// Picks up the series written by the last run, a missing or unreadable
+/// file starts them over
+pub fn load_usage_history() {
+    let loaded: HashMap<String, Series> = match fs::read_to_string(HISTORY_PATH) {
+        Ok(data) => match serde_json::from_str(&data) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                log!(
+                    LogLevel::Warn,
+                    "Couldn't read the usage history, starting it over: {}",
+                    err
+                );
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+
+    if let Ok(mut history) = HISTORY.lock() {
+        *history = loaded;
+    }
+}
+
+pub fn persist_usage_history() -> Result<(), ErrorArrayItem> {
+    let data: String = {
+        let history = HISTORY
+            .lock()
+            .map_err(|err| history_error(err.to_string()))?;
+        serde_json::to_string(&*history).map_err(|err| history_error(err.to_string()))?
+    };
+
+    // Written aside and renamed so a crash never leaves half a file
+    let temp: String = format!("{}.tmp", HISTORY_PATH);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, HISTORY_PATH)?;
+    Ok(())
+}
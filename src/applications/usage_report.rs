@@ -0,0 +1,302 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::system::control::GlobalState;
+use crate::system::settings::UsageReportSettings;
+use crate::system::timezone::{civil_from_days, days_from_civil, zone_for, Zone};
+
+use super::child::SYSTEM_APPLICATION_ARRAY;
+use super::usage_history::{usage_totals, UsageTotals};
+
+/// Billing counts decimal gigabytes
+const GB: f64 = 1_000_000_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportPeriod {
+    /// A calendar day in the node's timezone, labelled `2026-10-16`
+    Daily,
+    /// A calendar month in the node's timezone, labelled `2026-10`
+    Monthly,
+}
+
+impl ReportPeriod {
+    fn name(&self) -> &'static str {
+        match self {
+            ReportPeriod::Daily => "daily",
+            ReportPeriod::Monthly => "monthly",
+        }
+    }
+}
+
+impl FromStr for ReportPeriod {
+    type Err = ErrorArrayItem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(ReportPeriod::Daily),
+            "monthly" => Ok(ReportPeriod::Monthly),
+            _ => Err(report_error(format!("Unknown report period: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AppUsage {
+    pub cpu_seconds: f64,
+    pub ram_gb_hours: f64,
+    pub rx_gb: f64,
+    pub tx_gb: f64,
+    /// Both directions, what transfer is billed on
+    pub transfer_gb: f64,
+}
+
+impl From<&UsageTotals> for AppUsage {
+    fn from(totals: &UsageTotals) -> Self {
+        let (rx_gb, tx_gb) = (totals.rx_bytes as f64 / GB, totals.tx_bytes as f64 / GB);
+        Self {
+            cpu_seconds: totals.cpu_seconds,
+            ram_gb_hours: totals.memory_byte_seconds / GB / 3600.0,
+            rx_gb,
+            tx_gb,
+            transfer_gb: rx_gb + tx_gb,
+        }
+    }
+}
+
+/// Usage of every client app over one period, what Artisan Hosting bills on
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub period: ReportPeriod,
+    pub label: String,
+    pub from: u64,
+    pub to: u64,
+    pub timezone: String,
+    pub generated: u64,
+    /// Set when the period hadn't ended yet, such a report is never written
+    pub partial: bool,
+    pub apps: BTreeMap<String, AppUsage>,
+}
+
+fn report_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn parse_part<T: std::str::FromStr>(part: Option<&str>, label: &str) -> Result<T, ErrorArrayItem> {
+    part.and_then(|part| part.parse().ok())
+        .ok_or_else(|| report_error(format!("{} isn't a valid report date", label)))
+}
+
+/// Start and end of the period in the node's timezone
+pub fn period_bounds(
+    zone: &Zone,
+    period: ReportPeriod,
+    label: &str,
+) -> Result<(u64, u64), ErrorArrayItem> {
+    let mut parts = label.split('-');
+    let year: i64 = parse_part(parts.next(), label)?;
+    let month: u32 = parse_part(parts.next(), label)?;
+    if !(1..=12).contains(&month) {
+        return Err(report_error(format!("{} isn't a valid report date", label)));
+    }
+
+    let (first, next) = match period {
+        ReportPeriod::Daily => {
+            let day: u32 = parse_part(parts.next(), label)?;
+            let first: i64 = days_from_civil(year, month, day);
+            if civil_from_days(first) != (year, month, day) {
+                return Err(report_error(format!("{} isn't a valid report date", label)));
+            }
+            (first, first + 1)
+        }
+        ReportPeriod::Monthly => {
+            let next: i64 = match month {
+                12 => days_from_civil(year + 1, 1, 1),
+                _ => days_from_civil(year, month + 1, 1),
+            };
+            (days_from_civil(year, month, 1), next)
+        }
+    };
+
+    if parts.next().is_some() {
+        return Err(report_error(format!("{} isn't a valid report date", label)));
+    }
+    Ok((zone.day_start(first), zone.day_start(next)))
+}
+
+/// Label of the period `now` falls in
+pub fn current_label(zone: &Zone, period: ReportPeriod, now: u64) -> String {
+    match (period, civil_from_days(zone.local_day(now))) {
+        (ReportPeriod::Daily, (year, month, day)) => {
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        (ReportPeriod::Monthly, (year, month, _)) => format!("{:04}-{:02}", year, month),
+    }
+}
+
+/// Label of the last period that has fully passed
+fn last_completed(zone: &Zone, period: ReportPeriod, now: u64) -> String {
+    let today: i64 = zone.local_day(now);
+
+    match period {
+        ReportPeriod::Daily => {
+            let (year, month, day) = civil_from_days(today - 1);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        ReportPeriod::Monthly => match civil_from_days(today) {
+            (year, 1, _) => format!("{:04}-12", year - 1),
+            (year, month, _) => format!("{:04}-{:02}", year, month - 1),
+        },
+    }
+}
+
+fn report_path(
+    settings: &UsageReportSettings,
+    period: ReportPeriod,
+    label: &str,
+    extension: &str,
+) -> PathBuf {
+    Path::new(&settings.dir).join(format!("{}-{}.{}", period.name(), label, extension))
+}
+
+/// Sums the usage history of every client app over the period. System
+/// applications aren't billed and left out.
+pub async fn generate_report(
+    period: ReportPeriod,
+    label: &str,
+) -> Result<UsageReport, ErrorArrayItem> {
+    let zone: Zone = zone_for(None);
+    let (from, to) = period_bounds(&zone, period, label)?;
+    let system_apps = SYSTEM_APPLICATION_ARRAY.keys().await?;
+    let now: u64 = current_timestamp();
+
+    Ok(UsageReport {
+        period,
+        label: label.to_owned(),
+        from,
+        to,
+        timezone: zone.name.clone(),
+        generated: now,
+        partial: to > now,
+        apps: usage_totals(from, to)?
+            .iter()
+            .filter(|(name, _)| {
+                !system_apps
+                    .iter()
+                    .any(|system| system.as_str() == name.as_str())
+            })
+            .map(|(name, totals)| (name.clone(), AppUsage::from(totals)))
+            .collect(),
+    })
+}
+
+fn to_csv(report: &UsageReport) -> String {
+    let mut csv: String = String::from("app,cpu_seconds,ram_gb_hours,rx_gb,tx_gb,transfer_gb\n");
+    for (name, usage) in &report.apps {
+        let _ = writeln!(
+            csv,
+            "{},{:.3},{:.6},{:.6},{:.6},{:.6}",
+            name,
+            usage.cpu_seconds,
+            usage.ram_gb_hours,
+            usage.rx_gb,
+            usage.tx_gb,
+            usage.transfer_gb
+        );
+    }
+    csv
+}
+
+fn write_report(
+    settings: &UsageReportSettings,
+    report: &UsageReport,
+) -> Result<(), ErrorArrayItem> {
+    fs::create_dir_all(&settings.dir)?;
+
+    let json: String =
+        serde_json::to_string_pretty(report).map_err(|err| report_error(err.to_string()))?;
+    fs::write(
+        report_path(settings, report.period, &report.label, "json"),
+        json,
+    )?;
+    fs::write(
+        report_path(settings, report.period, &report.label, "csv"),
+        to_csv(report),
+    )?;
+    Ok(())
+}
+
+/// Writes the reports of the last finished day and month unless they're
+/// already there
+pub async fn generate_due_reports(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &UsageReportSettings = &gs.settings.usage_reports;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let zone: Zone = zone_for(None);
+    for period in [ReportPeriod::Daily, ReportPeriod::Monthly] {
+        let label: String = last_completed(&zone, period, current_timestamp());
+        if report_path(settings, period, &label, "json").exists() {
+            continue;
+        }
+
+        let report: UsageReport = generate_report(period, &label).await?;
+        write_report(settings, &report)?;
+        log!(
+            LogLevel::Info,
+            "Wrote the {} usage report for {}, {} apps",
+            period.name(),
+            label,
+            report.apps.len()
+        );
+    }
+    Ok(())
+}
+
+/// The written report of the period, or one generated on the spot when
+/// there's none yet. Periods that haven't ended come back partial.
+pub async fn usage_report(
+    settings: &UsageReportSettings,
+    period: ReportPeriod,
+    label: &str,
+    format: ReportFormat,
+) -> Result<String, ErrorArrayItem> {
+    // Checked first, the label ends up in a path
+    period_bounds(&zone_for(None), period, label)?;
+
+    let extension: &str = match format {
+        ReportFormat::Json => "json",
+        ReportFormat::Csv => "csv",
+    };
+    if let Ok(data) = fs::read_to_string(report_path(settings, period, label, extension)) {
+        return Ok(data);
+    }
+
+    let report: UsageReport = generate_report(period, label).await?;
+    if !report.partial {
+        write_report(settings, &report)?;
+    }
+
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_string(&report).map_err(|err| report_error(err.to_string()))
+        }
+        ReportFormat::Csv => Ok(to_csv(&report)),
+    }
+}
@@ -0,0 +1,263 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::VulnerabilityScanSettings;
+
+use super::child::CLIENT_APPLICATION_ARRAY;
+
+/// Latest dependency scan of every application
+pub static VULNERABILITY_REPORTS: Lazy<LockWithTimeout<HashMap<Stringy, VulnerabilityReport>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// Scanners we know how to run, keyed by the lockfile they audit
+const SCANNERS: [(&str, &str, &[&str]); 3] = [
+    ("package-lock.json", "npm", &["audit", "--json"]),
+    (
+        "composer.lock",
+        "composer",
+        &["audit", "--locked", "--format=json"],
+    ),
+    ("Cargo.lock", "cargo", &["audit", "--json"]),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VulnerabilityReport {
+    pub scanner: String,
+    /// Findings by severity as the scanner reports it
+    pub counts: BTreeMap<String, u64>,
+    /// Identifiers of the critical findings
+    pub critical: Vec<String>,
+    pub scanned_at: u64,
+}
+
+fn scan_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn summarize_npm(report: &Value, counts: &mut BTreeMap<String, u64>, critical: &mut Vec<String>) {
+    if let Some(totals) = report["metadata"]["vulnerabilities"].as_object() {
+        for (severity, count) in totals {
+            if severity != "total" {
+                counts.insert(severity.clone(), count.as_u64().unwrap_or(0));
+            }
+        }
+    }
+
+    if let Some(packages) = report["vulnerabilities"].as_object() {
+        for (package, details) in packages {
+            if details["severity"] == "critical" {
+                critical.push(package.clone());
+            }
+        }
+    }
+}
+
+fn summarize_composer(
+    report: &Value,
+    counts: &mut BTreeMap<String, u64>,
+    critical: &mut Vec<String>,
+) {
+    let advisories = match report["advisories"].as_object() {
+        Some(advisories) => advisories,
+        None => return,
+    };
+
+    for advisory in advisories
+        .values()
+        .flat_map(|list| list.as_array())
+        .flatten()
+    {
+        let severity: String = advisory["severity"]
+            .as_str()
+            .unwrap_or("unknown")
+            .to_lowercase();
+
+        if severity == "critical" {
+            let id: &str = advisory["cve"]
+                .as_str()
+                .or(advisory["advisoryId"].as_str())
+                .unwrap_or("unknown");
+            critical.push(format!(
+                "{} {}",
+                advisory["packageName"].as_str().unwrap_or(""),
+                id
+            ));
+        }
+
+        *counts.entry(severity).or_default() += 1;
+    }
+}
+
+fn summarize_cargo(report: &Value, counts: &mut BTreeMap<String, u64>) {
+    // cargo audit only carries a cvss vector, not a severity
+    if let Some(count) = report["vulnerabilities"]["count"].as_u64() {
+        counts.insert("unknown".to_owned(), count);
+    }
+}
+
+async fn scan(
+    settings: &VulnerabilityScanSettings,
+    app_id: &Stringy,
+    uid: u32,
+) -> Result<Option<VulnerabilityReport>, ErrorArrayItem> {
+    let app_dir: PathBuf = PathBuf::from(format!("/etc/{}", app_id));
+
+    let (scanner, args) = match SCANNERS
+        .iter()
+        .find(|(lockfile, _, _)| app_dir.join(lockfile).exists())
+    {
+        Some((_, scanner, args)) => (*scanner, *args),
+        None => return Ok(None),
+    };
+
+    let mut command: Command = Command::new(scanner);
+    command
+        .args(args)
+        .current_dir(&app_dir)
+        .uid(uid)
+        .gid(uid)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+
+    let timeout: Duration = Duration::from_secs(settings.timeout_secs);
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(scan_error(format!(
+                "{} audit timed out after {}s",
+                scanner, settings.timeout_secs
+            )))
+        }
+    };
+
+    // Scanners exit non zero when they find something, only bad output is an error
+    let report: Value = serde_json::from_slice(&output.stdout).map_err(|_| {
+        scan_error(format!(
+            "{} audit failed: {}",
+            scanner,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    })?;
+
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    let mut critical: Vec<String> = Vec::new();
+
+    match scanner {
+        "npm" => summarize_npm(&report, &mut counts, &mut critical),
+        "composer" => summarize_composer(&report, &mut counts, &mut critical),
+        _ => summarize_cargo(&report, &mut counts),
+    }
+
+    Ok(Some(VulnerabilityReport {
+        scanner: scanner.to_owned(),
+        counts,
+        critical,
+        scanned_at: current_timestamp(),
+    }))
+}
+
+async fn scan_application(gs: &Arc<GlobalState>, app_id: &Stringy) -> Result<(), ErrorArrayItem> {
+    let uid: u32 = match CLIENT_APPLICATION_ARRAY
+        .read(app_id, |client| client.execution_uid())
+        .await?
+    {
+        Some(uid) => uid,
+        None => return Ok(()),
+    };
+
+    let report: VulnerabilityReport =
+        match scan(&gs.settings.vulnerability_scan, app_id, uid).await? {
+            Some(report) => report,
+            None => return Ok(()),
+        };
+
+    let mut reports = VULNERABILITY_REPORTS.try_write().await?;
+    let known: Vec<String> = reports
+        .get(app_id)
+        .map(|previous| previous.critical.clone())
+        .unwrap_or_default();
+
+    // Only findings this deploy introduced are raised again
+    for finding in report
+        .critical
+        .iter()
+        .filter(|finding| !known.contains(finding))
+    {
+        log!(
+            LogLevel::Warn,
+            "{} ships a critical vulnerability: {}",
+            app_id,
+            finding
+        );
+        gs.events.publish(ManagerEvent::SecurityFinding {
+            name: app_id.clone(),
+            path: format!("/etc/{}", app_id),
+            issue: format!("critical vulnerability in {}", finding),
+        });
+    }
+
+    log!(
+        LogLevel::Info,
+        "{} audit of {}: {:?}",
+        report.scanner,
+        app_id,
+        report.counts
+    );
+    reports.insert(app_id.clone(), report);
+
+    Ok(())
+}
+
+/// Scans the dependencies of client applications after every deploy
+pub async fn run_vulnerability_scans(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    if !gs.settings.vulnerability_scan.enabled {
+        return Ok(());
+    }
+
+    let mut receiver = gs.events.subscribe();
+
+    loop {
+        let name: Stringy = match receiver.recv().await {
+            Ok(ManagerEvent::DeployFinished { name }) => name,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                log!(
+                    LogLevel::Warn,
+                    "Vulnerability scanner missed {} events",
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        if let Err(err) = scan_application(gs, &name).await {
+            log!(LogLevel::Warn, "Failed to scan {}: {}", name, err);
+        }
+    }
+}
+
+pub async fn vulnerability_report_of(app_name: &Stringy) -> Option<VulnerabilityReport> {
+    VULNERABILITY_REPORTS
+        .try_read()
+        .await
+        .ok()?
+        .get(app_name)
+        .cloned()
+}
@@ -0,0 +1,634 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::system::control::GlobalState;
+use crate::system::events::ManagerEvent;
+use crate::system::settings::{WebhookRoute, WebhookSettings};
+
+/// Webhooks tenants registered, keyed by application
+pub const WEBHOOKS_PATH: &str = "/opt/artisan/webhooks.json";
+
+/// Deliveries kept per application for the status command
+const DELIVERY_HISTORY: usize = 100;
+
+/// Recent deliveries of every application, newest last
+static DELIVERIES: Lazy<LockWithTimeout<HashMap<Stringy, VecDeque<Delivery>>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+/// Failed deliveries waiting for their next attempt, oldest first
+static RETRY_QUEUE: Lazy<LockWithTimeout<VecDeque<QueuedDelivery>>> =
+    Lazy::new(|| LockWithTimeout::new(VecDeque::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    Deployed,
+    Crashed,
+    Restarted,
+    QuotaWarning,
+    Warning,
+    Stopped,
+    Failed,
+    CrashLoop,
+    Suspended,
+    Quarantined,
+    Hibernated,
+}
+
+impl WebhookEvent {
+    fn of(event: &ManagerEvent) -> Option<Self> {
+        match event {
+            ManagerEvent::DeployFinished { .. } => Some(Self::Deployed),
+            ManagerEvent::AppDied { .. } => Some(Self::Crashed),
+            ManagerEvent::CrashLoop { .. } => Some(Self::CrashLoop),
+            ManagerEvent::AppStarted { .. } => Some(Self::Restarted),
+            ManagerEvent::PortExhaustion { .. } => Some(Self::QuotaWarning),
+            ManagerEvent::QuotaSuspended { .. } => Some(Self::Suspended),
+            ManagerEvent::BinaryQuarantined { .. } => Some(Self::Quarantined),
+            ManagerEvent::AppHibernated { .. } => Some(Self::Hibernated),
+            ManagerEvent::StatusChanged { to, .. } => match to {
+                Status::Warning => Some(Self::Warning),
+                Status::Stopped => Some(Self::Stopped),
+                Status::Failed => Some(Self::Failed),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a webhook subscribed to this event wants `kind` delivered
+    fn covers(&self, kind: WebhookEvent) -> bool {
+        // Crash loops were sent as crashes before they got their own event
+        *self == kind || (*self == Self::Crashed && kind == Self::CrashLoop)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Deployed => "deployed",
+            Self::Crashed => "crashed",
+            Self::Restarted => "restarted",
+            Self::QuotaWarning => "quota-warning",
+            Self::Warning => "warning",
+            Self::Stopped => "stopped",
+            Self::Failed => "failed",
+            Self::CrashLoop => "crash-loop",
+            Self::Suspended => "suspended",
+            Self::Quarantined => "quarantined",
+            Self::Hibernated => "hibernated",
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = ErrorArrayItem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deployed" => Ok(Self::Deployed),
+            "crashed" => Ok(Self::Crashed),
+            "restarted" => Ok(Self::Restarted),
+            "quota-warning" => Ok(Self::QuotaWarning),
+            "warning" => Ok(Self::Warning),
+            "stopped" => Ok(Self::Stopped),
+            "failed" => Ok(Self::Failed),
+            "crash-loop" => Ok(Self::CrashLoop),
+            "suspended" => Ok(Self::Suspended),
+            "quarantined" => Ok(Self::Quarantined),
+            "hibernated" => Ok(Self::Hibernated),
+            _ => Err(webhook_error(format!("Unknown webhook event: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Webhook {
+    id: String,
+    url: String,
+    events: Vec<WebhookEvent>,
+    /// Key the payloads are signed with, only shown once when registering
+    secret: String,
+    created_at: u64,
+}
+
+/// A registered webhook without its secret
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookSummary {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: u64,
+}
+
+/// Returned once on registration, the tenant needs the secret to verify
+/// signatures
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisteredWebhook {
+    pub id: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook: String,
+    pub event: WebhookEvent,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    /// Http status or curl error of the last attempt
+    pub last_response: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// A delivery waiting for its next attempt
+struct QueuedDelivery {
+    app_id: Stringy,
+    webhook: Webhook,
+    delivery: Delivery,
+    body: Vec<u8>,
+    next_attempt_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Payload {
+    delivery: String,
+    event: WebhookEvent,
+    app: Stringy,
+    detail: String,
+    timestamp: u64,
+}
+
+fn webhook_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn random_hex(len: usize) -> Result<String, ErrorArrayItem> {
+    let mut bytes: Vec<u8> = vec![0; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
+
+fn load_webhooks() -> Result<HashMap<Stringy, Vec<Webhook>>, ErrorArrayItem> {
+    match fs::read_to_string(WEBHOOKS_PATH) {
+        Ok(data) => serde_json::from_str(&data)
+            .map_err(|err| webhook_error(format!("Invalid {}: {}", WEBHOOKS_PATH, err))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn save_webhooks(webhooks: &HashMap<Stringy, Vec<Webhook>>) -> Result<(), ErrorArrayItem> {
+    let data: String =
+        serde_json::to_string(webhooks).map_err(|err| webhook_error(err.to_string()))?;
+
+    // Holds secrets, keep it away from the tenants
+    let temp: String = format!("{}.tmp", WEBHOOKS_PATH);
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp)?
+        .write_all(data.as_bytes())?;
+    fs::rename(&temp, WEBHOOKS_PATH)?;
+    Ok(())
+}
+
+/// Registers a webhook for an app. `events` is a comma separated list.
+pub fn add_webhook(
+    app_id: &Stringy,
+    url: &str,
+    event_list: &str,
+) -> Result<RegisteredWebhook, ErrorArrayItem> {
+    // Also keeps curl from reading the url as an option
+    if !url.starts_with("https://") {
+        return Err(webhook_error("Webhooks have to use https".to_owned()));
+    }
+
+    let mut events: Vec<WebhookEvent> = Vec::new();
+    for event in event_list.split(',') {
+        let event: WebhookEvent = event.parse()?;
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+
+    let webhook: Webhook = Webhook {
+        id: random_hex(8)?,
+        url: url.to_owned(),
+        events,
+        secret: random_hex(32)?,
+        created_at: current_timestamp(),
+    };
+
+    let registered: RegisteredWebhook = RegisteredWebhook {
+        id: webhook.id.clone(),
+        secret: webhook.secret.clone(),
+    };
+
+    let mut webhooks = load_webhooks()?;
+    webhooks.entry(app_id.clone()).or_default().push(webhook);
+    save_webhooks(&webhooks)?;
+
+    log!(
+        LogLevel::Info,
+        "Registered webhook {} for {}",
+        registered.id,
+        app_id
+    );
+    Ok(registered)
+}
+
+pub fn remove_webhook(app_id: &Stringy, id: &str) -> Result<(), ErrorArrayItem> {
+    let mut webhooks = load_webhooks()?;
+    let registered: &mut Vec<Webhook> = webhooks.entry(app_id.clone()).or_default();
+
+    let before: usize = registered.len();
+    registered.retain(|webhook| webhook.id != id);
+    if registered.len() == before {
+        return Err(ErrorArrayItem::new(
+            Errors::NotFound,
+            format!("{} has no webhook {}", app_id, id),
+        ));
+    }
+
+    if registered.is_empty() {
+        webhooks.remove(app_id);
+    }
+
+    save_webhooks(&webhooks)?;
+    log!(LogLevel::Info, "Removed webhook {} of {}", id, app_id);
+    Ok(())
+}
+
+pub fn list_webhooks(app_id: &Stringy) -> Result<Vec<WebhookSummary>, ErrorArrayItem> {
+    Ok(load_webhooks()?
+        .remove(app_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|webhook| WebhookSummary {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            created_at: webhook.created_at,
+        })
+        .collect())
+}
+
+pub async fn webhook_deliveries(app_id: &Stringy) -> Result<Vec<Delivery>, ErrorArrayItem> {
+    Ok(DELIVERIES
+        .try_read()
+        .await?
+        .get(app_id)
+        .map(|deliveries| deliveries.iter().cloned().collect())
+        .unwrap_or_default())
+}
+
+fn sign(secret: &str, body: &[u8]) -> Result<String, ErrorArrayItem> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|err| webhook_error(err.to_string()))?;
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts the payload once, returning the http status
+async fn post(
+    settings: &WebhookSettings,
+    webhook: &Webhook,
+    delivery: &Delivery,
+    body: &[u8],
+) -> Result<u16, ErrorArrayItem> {
+    let signature: String = sign(&webhook.secret, body)?;
+
+    let mut child = Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--proto",
+            "=https",
+            "--output",
+            "/dev/null",
+            "--write-out",
+            "%{http_code}",
+            "--max-time",
+            &settings.timeout_secs.to_string(),
+            "--request",
+            "POST",
+            "--header",
+            "Content-Type: application/json",
+            "--header",
+            &format!("X-Artisan-Event: {}", delivery.event.name()),
+            "--header",
+            &format!("X-Artisan-Delivery: {}", delivery.id),
+            "--header",
+            &format!("X-Artisan-Signature: sha256={}", signature),
+            "--data-binary",
+            "@-",
+            &webhook.url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(webhook_error(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<u16>()
+        .map_err(|_| webhook_error("curl returned no status".to_owned()))
+}
+
+async fn track(app_id: &Stringy, delivery: &Delivery) -> Result<(), ErrorArrayItem> {
+    let mut deliveries = DELIVERIES.try_write().await?;
+    let history: &mut VecDeque<Delivery> = deliveries.entry(app_id.clone()).or_default();
+
+    match history.iter_mut().find(|known| known.id == delivery.id) {
+        Some(known) => *known = delivery.clone(),
+        None => {
+            if history.len() == DELIVERY_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(delivery.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// Queues a delivery for another attempt, giving up on the oldest one when
+/// the queue is full
+async fn enqueue(settings: &WebhookSettings, queued: QueuedDelivery) -> Result<(), ErrorArrayItem> {
+    let mut dropped: Vec<QueuedDelivery> = Vec::new();
+    {
+        let mut queue = RETRY_QUEUE.try_write().await?;
+        while queue.len() >= settings.max_queued.max(1) {
+            match queue.pop_front() {
+                Some(oldest) => dropped.push(oldest),
+                None => break,
+            }
+        }
+        queue.push_back(queued);
+    }
+
+    for mut oldest in dropped {
+        oldest.delivery.state = DeliveryState::Failed;
+        oldest.delivery.updated_at = current_timestamp();
+        track(&oldest.app_id, &oldest.delivery).await?;
+        log!(
+            LogLevel::Warn,
+            "Retry queue is full, gave up on delivering {} of {} to webhook {}",
+            oldest.delivery.event.name(),
+            oldest.app_id,
+            oldest.webhook.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Makes one attempt at a delivery and queues it for a retry with a growing
+/// backoff when it fails
+async fn attempt(
+    settings: &WebhookSettings,
+    mut queued: QueuedDelivery,
+) -> Result<(), ErrorArrayItem> {
+    queued.delivery.attempts += 1;
+
+    let result: Result<u16, ErrorArrayItem> =
+        post(settings, &queued.webhook, &queued.delivery, &queued.body).await;
+    queued.delivery.updated_at = current_timestamp();
+
+    match result {
+        Ok(status) if (200..300).contains(&status) => {
+            queued.delivery.state = DeliveryState::Delivered;
+            queued.delivery.last_response = Some(status.to_string());
+            return track(&queued.app_id, &queued.delivery).await;
+        }
+        Ok(status) => queued.delivery.last_response = Some(status.to_string()),
+        Err(err) => queued.delivery.last_response = Some(err.to_string()),
+    }
+
+    if queued.delivery.attempts >= settings.max_attempts.max(1) {
+        queued.delivery.state = DeliveryState::Failed;
+        track(&queued.app_id, &queued.delivery).await?;
+
+        log!(
+            LogLevel::Warn,
+            "Gave up delivering {} of {} to webhook {} after {} attempts",
+            queued.delivery.event.name(),
+            queued.app_id,
+            queued.webhook.id,
+            queued.delivery.attempts
+        );
+        return Ok(());
+    }
+
+    track(&queued.app_id, &queued.delivery).await?;
+
+    let backoff: u64 = settings
+        .backoff_secs
+        .saturating_mul(2u64.saturating_pow(queued.delivery.attempts - 1));
+    queued.next_attempt_at = current_timestamp() + backoff;
+    enqueue(settings, queued).await
+}
+
+/// Delivers an event to one webhook, failed attempts go to the retry queue
+async fn deliver(
+    settings: WebhookSettings,
+    app_id: Stringy,
+    webhook: Webhook,
+    event: WebhookEvent,
+    detail: String,
+) -> Result<(), ErrorArrayItem> {
+    let now: u64 = current_timestamp();
+    let mut delivery: Delivery = Delivery {
+        id: random_hex(8)?,
+        webhook: webhook.id.clone(),
+        event,
+        state: DeliveryState::Pending,
+        attempts: 0,
+        last_response: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let body: Vec<u8> = serde_json::to_vec(&Payload {
+        delivery: delivery.id.clone(),
+        event,
+        app: app_id.clone(),
+        detail,
+        timestamp: now,
+    })
+    .map_err(|err| webhook_error(err.to_string()))?;
+
+    track(&app_id, &delivery).await?;
+
+    let queued: QueuedDelivery = QueuedDelivery {
+        app_id,
+        webhook,
+        delivery,
+        body,
+        next_attempt_at: now,
+    };
+    attempt(&settings, queued).await
+}
+
+/// Operator routes that want the event, as webhooks
+fn routed_webhooks(routes: &[WebhookRoute], app_id: &Stringy) -> Vec<Webhook> {
+    routes
+        .iter()
+        .enumerate()
+        .filter(|(_, route)| {
+            route.apps.is_empty() || route.apps.iter().any(|app| *app_id == app.as_str().into())
+        })
+        .filter_map(|(index, route)| {
+            // Same rule as tenant webhooks, also keeps curl from reading the
+            // url as an option
+            if !route.url.starts_with("https://") {
+                log!(
+                    LogLevel::Debug,
+                    "Skipping webhook route {}, it doesn't use https",
+                    index
+                );
+                return None;
+            }
+
+            Some(Webhook {
+                id: format!("route-{}", index),
+                url: route.url.clone(),
+                events: route
+                    .events
+                    .iter()
+                    .filter_map(|event| event.parse().ok())
+                    .collect(),
+                secret: route.secret.clone(),
+                created_at: 0,
+            })
+        })
+        .collect()
+}
+
+async fn dispatch(gs: &Arc<GlobalState>, event: &ManagerEvent) -> Result<(), ErrorArrayItem> {
+    let (app_id, kind) = match (event.app_name(), WebhookEvent::of(event)) {
+        (Some(app_id), Some(kind)) => (app_id.clone(), kind),
+        _ => return Ok(()),
+    };
+
+    let mut webhooks: Vec<Webhook> = load_webhooks()?.remove(&app_id).unwrap_or_default();
+    webhooks.extend(routed_webhooks(&gs.settings.webhooks.routes, &app_id));
+
+    for webhook in webhooks
+        .into_iter()
+        .filter(|webhook| webhook.events.iter().any(|event| event.covers(kind)))
+    {
+        let settings: WebhookSettings = gs.settings.webhooks.clone();
+        let app_id: Stringy = app_id.clone();
+        let detail: String = event.to_string();
+
+        // Retries of a slow endpoint shouldn't hold up the other deliveries
+        tokio::spawn(async move {
+            if let Err(err) = deliver(settings, app_id.clone(), webhook, kind, detail).await {
+                log!(
+                    LogLevel::Warn,
+                    "Failed to deliver a webhook of {}: {}",
+                    app_id,
+                    err
+                );
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Makes the next attempt of every queued delivery that's due
+pub async fn retry_webhooks(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let now: u64 = current_timestamp();
+    let due: Vec<QueuedDelivery> = {
+        let mut queue = RETRY_QUEUE.try_write().await?;
+        let (due, waiting): (Vec<QueuedDelivery>, VecDeque<QueuedDelivery>) = queue
+            .drain(..)
+            .partition(|queued| queued.next_attempt_at <= now);
+        *queue = waiting;
+        due
+    };
+
+    for queued in due {
+        let settings: WebhookSettings = gs.settings.webhooks.clone();
+        tokio::spawn(async move {
+            let app_id: Stringy = queued.app_id.clone();
+            if let Err(err) = attempt(&settings, queued).await {
+                log!(
+                    LogLevel::Warn,
+                    "Failed to retry a webhook of {}: {}",
+                    app_id,
+                    err
+                );
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Sends lifecycle events of client applications to their tenants' webhooks
+/// and the operator's routes
+pub async fn emit_webhooks(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    if !gs.settings.webhooks.enabled {
+        return Ok(());
+    }
+
+    let mut receiver = gs.events.subscribe();
+
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if let Err(err) = dispatch(gs, &event).await {
+                    log!(LogLevel::Warn, "Failed to dispatch webhooks: {}", err);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                log!(LogLevel::Warn, "Webhooks missed {} events", skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use artisan_middleware::aggregator::{AppMessage, CommandResponse, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::applications::adopt::{adopt_service, adopted_services, release_service};
+use crate::applications::canary::deploy_canary;
+use crate::applications::child::APP_STATUS_ARRAY;
+use crate::applications::config_files::{read_config_file, write_config_file};
+use crate::applications::coredump::{core_dump_path, list_core_dumps};
+use crate::applications::dependencies::dependencies_of;
+use crate::applications::deploy::deploy_upload;
+use crate::applications::deploy_queue::deploy_queue;
+use crate::applications::diagnostics::{fetch_diagnostics, DiagnosticArtifact};
+use crate::applications::drift::drift_reports;
+use crate::applications::egress::egress_of;
+use crate::applications::gitmon::repository_change_of;
+use crate::applications::hibernation::hibernation_of;
+use crate::applications::integrity::{integrity_report, reverify_binary};
+use crate::applications::inventory::{list_inventories, read_inventory};
+use crate::applications::limits::{enforce_bandwidth_limits, set_bandwidth_limit};
+use crate::applications::liveness::health_of;
+use crate::applications::logs::tail_logs;
+use crate::applications::lookup::{lookup_by_pid, lookup_by_port};
+use crate::applications::manifest::manifest_report;
+use crate::applications::node_spec::{last_reconciliation, ProbeSpec};
+use crate::applications::pause::{pause_application, resume_application};
+use crate::applications::ports::{all_port_usage, port_usage_of};
+use crate::applications::quota::{lift_suspension, quota_usage};
+use crate::applications::relocate::{receive_relocation, relocations, start_relocation};
+use crate::applications::restart_window::restart_negotiation_of;
+use crate::applications::retention::purge_tenant_data;
+use crate::applications::start_stop::{
+    disable_application, enable_application, mask_application, parse_log_level, parse_signal,
+    set_application_log_level, signal_application, unmask_application,
+};
+use crate::applications::status_delta::status_delta;
+use crate::applications::status_store::simulate_capacity;
+use crate::applications::unit_files::unit_file_of;
+use crate::applications::usage_history::usage_history;
+use crate::applications::usage_report::{usage_report, ReportFormat, ReportPeriod};
+use crate::applications::webhooks::{
+    add_webhook, list_webhooks, remove_webhook, webhook_deliveries,
+};
+use crate::pretty::{render_all_status, render_status};
+use crate::system::audit::audit_tail;
+use crate::system::cadence::cadence_report;
+use crate::system::cgroups::cgroup_report;
+use crate::system::control::{GlobalState, GLOBAL_STATE};
+use crate::system::decisions::{query_decisions, DecisionQuery};
+use crate::system::formats::format_report;
+use crate::system::jobs::{job_of, jobs_of};
+use crate::system::latency::latency_report;
+use crate::system::ledger_store::ledger_store;
+use crate::system::runtime::runtime_report;
+use crate::system::self_test::run_self_test;
+use crate::system::siblings::sibling_matrix;
+use crate::system::storage::off_worker;
+use crate::system::telemetry::telemetry_report;
+use crate::system::timezone::timezone_report;
+use crate::system::transfer::{
+    begin_upload, finish_transfer, open_download, read_chunk, transfer_status, write_chunk,
+};
+use crate::system::watchdog::manager_memory;
+
+/// Commands that ride on `CommandType::Custom`. The payload is a whitespace
+/// separated string with the verb first, e.g. `lookup pid 1234`.
+#[derive(Debug, Clone)]
+pub enum CustomCommand {
+    LookupPid(u32),
+    LookupPort(u16),
+    EventsSince(u64),
+    SetLogLevel(LogLevel),
+    /// An allowlisted signal for the main process, or the whole cgroup
+    Signal(&'static str, bool),
+    ListCoreDumps,
+    DownloadCoreDump(String),
+    TransferChunk(Stringy, u64),
+    TransferBegin(String, u64, String),
+    TransferPut(Stringy, u64, String, String),
+    TransferStatus(Stringy),
+    TransferFinish(Stringy),
+    DeployUpload(Stringy, String),
+    DeployCanary(Stringy, String, Option<u64>),
+    DeployQueue,
+    ListInventories,
+    ReadInventory(String),
+    ReadConfig(String),
+    WriteConfig(String, String),
+    DriftReport,
+    FetchDiagnostics(DiagnosticArtifact),
+    PurgeData,
+    /// Reachability of the external endpoints the app depends on
+    Dependencies,
+    /// The app's manifest and how the app measures up to it
+    Manifest,
+    /// The app's binary against what it's expected to be
+    Integrity,
+    /// Verifies a quarantined binary again and starts the app if it passes
+    VerifyIntegrity,
+    /// The app's liveness as its probe last saw it
+    Health,
+    /// Whether the app's last restart was cooperative or forced
+    RestartWindow,
+    /// The app's egress allowlist and what it had dropped
+    Egress,
+    /// Whether the app's systemd unit matches what it's rendered from
+    UnitFile,
+    /// Whether the app is hibernating and how its last wake went
+    Hibernation,
+    /// Stops the app and keeps anything from starting it
+    Mask,
+    Unmask,
+    /// Whether the app starts at boot
+    Enable,
+    Disable,
+    /// Freezes the app where it is, until it's resumed
+    Pause,
+    Resume,
+    /// Progress or result of a job queued with `async <command>`
+    Job(u64),
+    Jobs,
+    /// Keeps the connection open for more requests and the node's events
+    Session,
+    /// Turns the connection into a stream of status changes, alerts and
+    /// metric samples
+    Subscribe,
+    /// The last repository change ais_gitmon reported and what it set off
+    RepositoryChange,
+    /// Usage this month against the app's quota
+    Quota,
+    /// Lifts a quota suspension and starts the app again
+    LiftSuspension,
+    AuditTail(usize),
+    /// What the manager decided on its own about the app, or the whole node
+    Decisions(DecisionQuery),
+    Logs(usize, bool),
+    SpecReport,
+    Adopt(String, Option<ProbeSpec>),
+    Release(String),
+    ListAdopted,
+    Siblings,
+    ManagerMemory,
+    Formats,
+    SelfTest,
+    Latency,
+    Cadence,
+    Runtime,
+    Timezone,
+    /// The telemetry levels apps are held to off the node
+    Telemetry,
+    Cgroups,
+    /// Billing report of a day or month, generated on the spot when it
+    /// wasn't written yet
+    UsageReport(ReportPeriod, String, ReportFormat),
+    /// What the saved status store keeps and evicts, under the configured
+    /// cap unless one is given
+    StatusCapacity(Option<usize>),
+    /// Statuses changed after a generation, every status from 0
+    StatusDelta(u64),
+    /// From and to as unix timestamps, resolution in seconds
+    UsageHistory(u64, u64, u64),
+    /// From and to as unix timestamps, needs the sqlite ledger
+    LedgerSamples(u64, u64),
+    /// Aligned, colorized status text for ais-ctl, of every app when set
+    PrettyStatus(bool),
+    TopTalkers(usize),
+    /// Moves the app to the manager on another node
+    MigrateApp(String),
+    /// Sent by the manager an app is moving away from, never by hand
+    MigrateReceive(Stringy, String),
+    MigrateStatus,
+    /// Bytes per second, `Some(None)` lifts the cap and `None` goes back to
+    /// the declared one
+    SetBandwidthLimit(Option<Option<u64>>),
+    /// Connection counts and listening ports, of every app when set
+    Ports(bool),
+    AddWebhook(String, String),
+    RemoveWebhook(String),
+    ListWebhooks,
+    WebhookDeliveries,
+}
+
+impl CustomCommand {
+    pub fn parse(raw: &str) -> Result<Self, ErrorArrayItem> {
+        let args: Vec<&str> = raw.split_whitespace().collect();
+
+        match args.as_slice() {
+            ["lookup", "pid", pid] => Ok(Self::LookupPid(parse_arg(pid)?)),
+            ["lookup", "port", port] => Ok(Self::LookupPort(parse_arg(port)?)),
+            ["events", "since", seq] => Ok(Self::EventsSince(parse_arg(seq)?)),
+            ["loglevel", level] => Ok(Self::SetLogLevel(parse_log_level(level)?)),
+            ["signal", signal] => Ok(Self::Signal(parse_signal(signal)?, false)),
+            ["signal", signal, "all"] => Ok(Self::Signal(parse_signal(signal)?, true)),
+            ["coredumps", "list"] => Ok(Self::ListCoreDumps),
+            ["coredumps", "download", file] => Ok(Self::DownloadCoreDump(file.to_string())),
+            ["transfer", "chunk", id, index] => {
+                Ok(Self::TransferChunk((*id).into(), parse_arg(index)?))
+            }
+            ["transfer", "begin", name, size, checksum] => Ok(Self::TransferBegin(
+                name.to_string(),
+                parse_arg(size)?,
+                checksum.to_string(),
+            )),
+            ["transfer", "put", id, index, checksum, data] => Ok(Self::TransferPut(
+                (*id).into(),
+                parse_arg(index)?,
+                checksum.to_string(),
+                data.to_string(),
+            )),
+            ["transfer", "status", id] => Ok(Self::TransferStatus((*id).into())),
+            ["transfer", "finish", id] => Ok(Self::TransferFinish((*id).into())),
+            ["config", "read", file] => Ok(Self::ReadConfig(file.to_string())),
+            ["config", "write", file, data] => {
+                let bytes: Vec<u8> = hex::decode(data).map_err(|_| {
+                    ErrorArrayItem::new(Errors::GeneralError, "Config data must be hex encoded")
+                })?;
+                let data: String = String::from_utf8(bytes).map_err(|_| {
+                    ErrorArrayItem::new(Errors::GeneralError, "Config data must be utf-8")
+                })?;
+                Ok(Self::WriteConfig(file.to_string(), data))
+            }
+            ["drift", "report"] => Ok(Self::DriftReport),
+            ["deploy", "upload", id, signature] => {
+                Ok(Self::DeployUpload((*id).into(), signature.to_string()))
+            }
+            ["deploy", "canary", id, signature] => Ok(Self::DeployCanary(
+                (*id).into(),
+                signature.to_string(),
+                None,
+            )),
+            ["deploy", "canary", id, signature, bake_secs] => Ok(Self::DeployCanary(
+                (*id).into(),
+                signature.to_string(),
+                Some(parse_arg(bake_secs)?),
+            )),
+            ["deploy", "queue"] => Ok(Self::DeployQueue),
+            ["inventory", "list"] => Ok(Self::ListInventories),
+            ["inventory", "show", version] => Ok(Self::ReadInventory(version.to_string())),
+            ["diagnostics", artifact] => Ok(Self::FetchDiagnostics(artifact.parse()?)),
+            ["retention", "purge"] => Ok(Self::PurgeData),
+            ["dependencies"] => Ok(Self::Dependencies),
+            ["manifest"] => Ok(Self::Manifest),
+            ["integrity"] => Ok(Self::Integrity),
+            ["integrity", "verify"] => Ok(Self::VerifyIntegrity),
+            ["gitmon"] => Ok(Self::RepositoryChange),
+            ["health"] => Ok(Self::Health),
+            ["restart", "window"] => Ok(Self::RestartWindow),
+            ["egress"] => Ok(Self::Egress),
+            ["unit", "file"] => Ok(Self::UnitFile),
+            ["hibernation"] => Ok(Self::Hibernation),
+            ["mask"] => Ok(Self::Mask),
+            ["unmask"] => Ok(Self::Unmask),
+            ["enable"] => Ok(Self::Enable),
+            ["disable"] => Ok(Self::Disable),
+            ["pause"] => Ok(Self::Pause),
+            ["resume"] => Ok(Self::Resume),
+            ["job", id] => Ok(Self::Job(parse_arg(id)?)),
+            ["jobs"] => Ok(Self::Jobs),
+            ["session"] => Ok(Self::Session),
+            ["subscribe"] => Ok(Self::Subscribe),
+            ["quota"] => Ok(Self::Quota),
+            ["quota", "lift"] => Ok(Self::LiftSuspension),
+            ["audit", "tail"] => Ok(Self::AuditTail(50)),
+            ["audit", "tail", count] => Ok(Self::AuditTail(parse_arg(count)?)),
+            ["decisions", filters @ ..] => Ok(Self::Decisions(DecisionQuery::parse(filters)?)),
+            ["spec", "report"] => Ok(Self::SpecReport),
+            ["adopt", "list"] => Ok(Self::ListAdopted),
+            ["adopt", unit] => Ok(Self::Adopt(unit.to_string(), None)),
+            ["adopt", unit, "tcp", port] => Ok(Self::Adopt(
+                unit.to_string(),
+                Some(ProbeSpec::Tcp {
+                    port: parse_arg(port)?,
+                    timeout_secs: 5,
+                }),
+            )),
+            ["adopt", unit, "http", port, path] => Ok(Self::Adopt(
+                unit.to_string(),
+                Some(ProbeSpec::Http {
+                    port: parse_arg(port)?,
+                    path: path.to_string(),
+                    timeout_secs: 5,
+                }),
+            )),
+            ["siblings"] => Ok(Self::Siblings),
+            ["manager", "memory"] => Ok(Self::ManagerMemory),
+            ["formats"] => Ok(Self::Formats),
+            ["self-test"] => Ok(Self::SelfTest),
+            ["latency"] => Ok(Self::Latency),
+            ["cadence"] => Ok(Self::Cadence),
+            ["runtime"] => Ok(Self::Runtime),
+            ["timezone"] => Ok(Self::Timezone),
+            ["telemetry"] => Ok(Self::Telemetry),
+            ["cgroups"] => Ok(Self::Cgroups),
+            ["usage", "report", period, label] => Ok(Self::UsageReport(
+                parse_arg(period)?,
+                label.to_string(),
+                ReportFormat::Json,
+            )),
+            ["usage", "report", period, label, "csv"] => Ok(Self::UsageReport(
+                parse_arg(period)?,
+                label.to_string(),
+                ReportFormat::Csv,
+            )),
+            ["status", "capacity"] => Ok(Self::StatusCapacity(None)),
+            ["status", "capacity", cap] => Ok(Self::StatusCapacity(Some(parse_arg(cap)?))),
+            ["status", "delta"] => Ok(Self::StatusDelta(0)),
+            ["status", "delta", since] => Ok(Self::StatusDelta(parse_arg(since)?)),
+            ["usage", "history", from, to, resolution] => Ok(Self::UsageHistory(
+                parse_arg(from)?,
+                parse_arg(to)?,
+                parse_arg(resolution)?,
+            )),
+            ["ledger", "samples", from, to] => {
+                Ok(Self::LedgerSamples(parse_arg(from)?, parse_arg(to)?))
+            }
+            ["status", "pretty"] => Ok(Self::PrettyStatus(false)),
+            ["status", "pretty", "all"] => Ok(Self::PrettyStatus(true)),
+            ["top-talkers"] => Ok(Self::TopTalkers(10)),
+            ["top-talkers", count] => Ok(Self::TopTalkers(parse_arg(count)?)),
+            ["migrate", "status"] => Ok(Self::MigrateStatus),
+            ["migrate", "receive", id, env] => {
+                Ok(Self::MigrateReceive((*id).into(), env.to_string()))
+            }
+            ["migrate", target] => Ok(Self::MigrateApp(target.to_string())),
+            ["ports"] => Ok(Self::Ports(false)),
+            ["ports", "all"] => Ok(Self::Ports(true)),
+            ["bandwidth", "limit", "off"] => Ok(Self::SetBandwidthLimit(Some(None))),
+            ["bandwidth", "limit", "declared"] => Ok(Self::SetBandwidthLimit(None)),
+            ["bandwidth", "limit", bytes_per_sec] => Ok(Self::SetBandwidthLimit(Some(Some(
+                parse_arg(bytes_per_sec)?,
+            )))),
+            ["release", unit] => Ok(Self::Release(unit.to_string())),
+            ["logs", lines] => Ok(Self::Logs(parse_arg(lines)?, false)),
+            ["logs", lines, "follow"] => Ok(Self::Logs(parse_arg(lines)?, true)),
+            ["webhook", "add", url, events] => {
+                Ok(Self::AddWebhook(url.to_string(), events.to_string()))
+            }
+            ["webhook", "remove", id] => Ok(Self::RemoveWebhook(id.to_string())),
+            ["webhook", "list"] => Ok(Self::ListWebhooks),
+            ["webhook", "deliveries"] => Ok(Self::WebhookDeliveries),
+            _ => Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("Unknown command: {}", raw),
+            )),
+        }
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &str) -> Result<T, ErrorArrayItem> {
+    arg.parse::<T>().map_err(|_| {
+        ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("Invalid command argument: {}", arg),
+        )
+    })
+}
+
+fn to_json<T: Serialize>(data: &T) -> Result<String, ErrorArrayItem> {
+    serde_json::to_string(data)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+}
+
+fn respond(app_id: Stringy, raw: &str, success: bool, message: Option<String>) -> AppMessage {
+    AppMessage::Response(CommandResponse {
+        app_id,
+        command_type: CommandType::Custom(raw.to_string()),
+        success,
+        message,
+    })
+}
+
+pub async fn custom_command_processor(
+    app_id: Stringy,
+    raw: String,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let global_state: &Arc<GlobalState> = match GLOBAL_STATE.get() {
+        Some(gs) => gs,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::AppState,
+                "Failed to get the app state from the global state",
+            ));
+        }
+    };
+
+    let command: CustomCommand = match CustomCommand::parse(&raw) {
+        Ok(command) => command,
+        Err(err) => return Ok(respond(app_id, &raw, false, Some(err.to_string()))),
+    };
+
+    let result: Result<String, ErrorArrayItem> = match command {
+        CustomCommand::LookupPid(pid) => lookup_by_pid(pid).await.and_then(|found| to_json(&found)),
+        CustomCommand::LookupPort(port) => {
+            lookup_by_port(port).await.and_then(|found| to_json(&found))
+        }
+        CustomCommand::EventsSince(seq) => global_state
+            .journal
+            .replay(seq)
+            .and_then(|replay| to_json(&replay)),
+        CustomCommand::SetLogLevel(level) => set_application_log_level(&app_id, level)
+            .await
+            .map(|_| format!("{} log level set to {:?}", app_id, level)),
+        CustomCommand::Signal(signal, whole_cgroup) => {
+            signal_application(&app_id, signal, whole_cgroup)
+                .await
+                .map(|_| format!("Sent {} to {}", signal, app_id))
+        }
+        CustomCommand::ListCoreDumps => list_core_dumps(&app_id)
+            .await
+            .and_then(|dumps| to_json(&dumps)),
+        CustomCommand::DownloadCoreDump(file) => match core_dump_path(&app_id, &file).await {
+            Ok(path) => open_download(&app_id, path)
+                .await
+                .and_then(|manifest| to_json(&manifest)),
+            Err(err) => Err(err),
+        },
+        CustomCommand::TransferChunk(id, index) => read_chunk(&app_id, &id, index)
+            .await
+            .and_then(|chunk| to_json(&chunk)),
+        CustomCommand::TransferBegin(name, size, checksum) => {
+            begin_upload(&app_id, &name, size, &checksum)
+                .await
+                .and_then(|manifest| to_json(&manifest))
+        }
+        CustomCommand::TransferPut(id, index, checksum, data) => {
+            write_chunk(&app_id, &id, index, &checksum, &data)
+                .await
+                .and_then(|manifest| to_json(&manifest))
+        }
+        CustomCommand::TransferStatus(id) => transfer_status(&app_id, &id)
+            .await
+            .and_then(|manifest| to_json(&manifest)),
+        CustomCommand::TransferFinish(id) => finish_transfer(&app_id, &id)
+            .await
+            .map(|path| path.display().to_string()),
+        CustomCommand::DeployUpload(id, signature) => {
+            deploy_upload(global_state, &app_id, &id, &signature)
+                .await
+                .map(|_| format!("Deploying {} from upload {}", app_id, id))
+        }
+        CustomCommand::DeployCanary(id, signature, bake_secs) => {
+            deploy_canary(global_state, &app_id, &id, &signature, bake_secs)
+                .await
+                .map(|_| format!("Baking a canary of {} from upload {}", app_id, id))
+        }
+        CustomCommand::DeployQueue => deploy_queue(global_state)
+            .await
+            .and_then(|report| to_json(&report)),
+        CustomCommand::ListInventories => {
+            list_inventories(&app_id).and_then(|inventories| to_json(&inventories))
+        }
+        CustomCommand::ReadInventory(version) => {
+            read_inventory(&app_id, &version).and_then(|inventory| to_json(&inventory))
+        }
+        CustomCommand::ReadConfig(file) => read_config_file(&app_id, &file).await,
+        CustomCommand::WriteConfig(file, data) => {
+            write_config_file(global_state, &app_id, &file, &data)
+                .await
+                .map(|_| format!("Updated {} for {}", file, app_id))
+        }
+        CustomCommand::DriftReport => drift_reports().await.and_then(|reports| to_json(&reports)),
+        CustomCommand::FetchDiagnostics(artifact) => fetch_diagnostics(&app_id, artifact).await,
+        CustomCommand::PurgeData => purge_tenant_data(global_state, &app_id)
+            .await
+            .and_then(|report| to_json(&report)),
+        CustomCommand::Dependencies => to_json(&dependencies_of(&app_id)),
+        CustomCommand::Manifest => to_json(&manifest_report(&app_id)),
+        CustomCommand::Integrity => to_json(&integrity_report(&app_id)),
+        CustomCommand::VerifyIntegrity => reverify_binary(global_state, &app_id)
+            .await
+            .map(|_| format!("{} verified and started", app_id)),
+        CustomCommand::RepositoryChange => to_json(&repository_change_of(&app_id)),
+        CustomCommand::Health => to_json(&health_of(&app_id)),
+        CustomCommand::RestartWindow => to_json(&restart_negotiation_of(&app_id)),
+        CustomCommand::Egress => to_json(&egress_of(&app_id)),
+        CustomCommand::UnitFile => to_json(&unit_file_of(&app_id)),
+        CustomCommand::Hibernation => to_json(&hibernation_of(&app_id)),
+        CustomCommand::Mask => mask_application(&app_id)
+            .await
+            .map(|_| format!("{} stopped and masked", app_id)),
+        CustomCommand::Unmask => unmask_application(&app_id)
+            .await
+            .map(|_| format!("{} unmasked", app_id)),
+        CustomCommand::Enable => enable_application(&app_id)
+            .await
+            .map(|_| format!("{} starts at boot", app_id)),
+        CustomCommand::Disable => disable_application(&app_id)
+            .await
+            .map(|_| format!("{} no longer starts at boot", app_id)),
+        CustomCommand::Pause => pause_application(&app_id)
+            .await
+            .and_then(|pause| to_json(&pause)),
+        CustomCommand::Resume => resume_application(&app_id)
+            .await
+            .map(|_| format!("{} resumed", app_id)),
+        // Job ids are shared by every app, another app's jobs stay hidden
+        CustomCommand::Job(id) => match job_of(id).filter(|job| job.app_id == app_id.as_str()) {
+            Some(job) => to_json(&job),
+            None => Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("No job {} for {}", id, app_id),
+            )),
+        },
+        CustomCommand::Jobs => to_json(&jobs_of(app_id.as_str())),
+        // The listener takes the connection over once this is answered
+        CustomCommand::Session => match global_state.settings.command_sessions.enabled {
+            true => Ok(String::from("session open")),
+            false => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Sessions are disabled on this node",
+            )),
+        },
+        CustomCommand::Subscribe => match global_state.settings.subscriptions.enabled {
+            true => Ok(String::from("subscribed")),
+            false => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Subscriptions are disabled on this node",
+            )),
+        },
+        CustomCommand::Quota => {
+            quota_usage(&global_state.settings.quotas, &app_id).and_then(|usage| to_json(&usage))
+        }
+        CustomCommand::LiftSuspension => lift_suspension(global_state, &app_id)
+            .await
+            .map(|_| format!("{} is no longer suspended", app_id)),
+        CustomCommand::AuditTail(count) => off_worker(move || audit_tail(count))
+            .await
+            .and_then(|entries| to_json(&entries)),
+        CustomCommand::Decisions(mut query) => {
+            query.app_id = Some(app_id.clone());
+            off_worker(move || query_decisions(&query))
+                .await
+                .and_then(|decisions| to_json(&decisions))
+        }
+        // Following is done by the listener once this first batch went out
+        CustomCommand::Logs(count, _) => tail_logs(&app_id, count)
+            .await
+            .and_then(|(lines, _)| to_json(&lines)),
+        CustomCommand::SpecReport => last_reconciliation()
+            .await
+            .and_then(|report| to_json(&report)),
+        CustomCommand::Adopt(unit, probe) => adopt_service(global_state, &unit, probe)
+            .await
+            .map(|_| format!("Adopted {}", unit)),
+        CustomCommand::Release(unit) => release_service(&unit)
+            .await
+            .map(|_| format!("Released {}", unit)),
+        CustomCommand::ListAdopted => adopted_services().and_then(|services| to_json(&services)),
+        CustomCommand::Siblings => sibling_matrix()
+            .await
+            .and_then(|siblings| to_json(&siblings)),
+        CustomCommand::ManagerMemory => {
+            to_json(&manager_memory(&global_state.settings.memory_watchdog))
+        }
+        CustomCommand::Formats => to_json(&format_report()),
+        CustomCommand::SelfTest => to_json(&run_self_test(global_state).await),
+        CustomCommand::Latency => to_json(&latency_report()),
+        CustomCommand::Cadence => to_json(&cadence_report(&global_state.settings.adaptive_polling)),
+        CustomCommand::Runtime => to_json(&runtime_report()),
+        CustomCommand::Timezone => to_json(&timezone_report()),
+        CustomCommand::Telemetry => to_json(&telemetry_report(&global_state.settings.telemetry)),
+        CustomCommand::Cgroups => to_json(&cgroup_report()),
+        CustomCommand::UsageReport(period, label, format) => {
+            usage_report(&global_state.settings.usage_reports, period, &label, format).await
+        }
+        CustomCommand::StatusCapacity(cap) => {
+            simulate_capacity(cap.unwrap_or(global_state.settings.status_store.max_entries))
+                .await
+                .and_then(|capacity| to_json(&capacity))
+        }
+        CustomCommand::StatusDelta(since) => status_delta(&global_state.settings.telemetry, since)
+            .await
+            .and_then(|delta| to_json(&delta)),
+        CustomCommand::UsageHistory(from, to, resolution) => {
+            usage_history(&app_id, from, to, resolution).and_then(|history| to_json(&history))
+        }
+        CustomCommand::LedgerSamples(from, to) => ledger_store()
+            .samples(&app_id, from, to)
+            .and_then(|samples| to_json(&samples)),
+        CustomCommand::PrettyStatus(true) => render_all_status(true).await,
+        CustomCommand::TopTalkers(count) => global_state
+            .network_monitor
+            .top_talkers(count)
+            .await
+            .and_then(|talkers| to_json(&talkers)),
+        CustomCommand::MigrateApp(target) => start_relocation(global_state, &app_id, &target)
+            .await
+            .map(|_| format!("Moving {} to {}", app_id, target)),
+        CustomCommand::MigrateReceive(id, env) => {
+            receive_relocation(global_state, &app_id, &id, &env)
+                .await
+                .map(|_| format!("{} is running", app_id))
+        }
+        CustomCommand::MigrateStatus => to_json(&relocations()),
+        CustomCommand::Ports(false) => match port_usage_of(&app_id).await {
+            Some(usage) => to_json(&usage),
+            None => Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("No port usage sampled for {}", app_id),
+            )),
+        },
+        CustomCommand::Ports(true) => all_port_usage().await.and_then(|usage| to_json(&usage)),
+        CustomCommand::SetBandwidthLimit(bytes_per_sec) => {
+            match set_bandwidth_limit(&app_id, bytes_per_sec) {
+                Ok(_) => enforce_bandwidth_limits(global_state).await.map(|_| {
+                    match global_state.network_monitor.enforces_bandwidth() {
+                        true => format!("Bandwidth cap of {} updated", app_id),
+                        false => format!(
+                            "Bandwidth cap of {} updated, but caps aren't enforced on this node",
+                            app_id
+                        ),
+                    }
+                }),
+                Err(err) => Err(err),
+            }
+        }
+        CustomCommand::PrettyStatus(false) => match APP_STATUS_ARRAY.get(&app_id).await {
+            Ok(Some(app)) => Ok(render_status(&app_id, &app, true)),
+            Ok(None) => Err(ErrorArrayItem::new(
+                Errors::NotFound,
+                format!("The app: {}, wasn't in our store", app_id),
+            )),
+            Err(err) => Err(err),
+        },
+        CustomCommand::AddWebhook(url, events) => {
+            add_webhook(&app_id, &url, &events).and_then(|webhook| to_json(&webhook))
+        }
+        CustomCommand::RemoveWebhook(id) => {
+            remove_webhook(&app_id, &id).map(|_| format!("Removed webhook {}", id))
+        }
+        CustomCommand::ListWebhooks => list_webhooks(&app_id).and_then(|hooks| to_json(&hooks)),
+        CustomCommand::WebhookDeliveries => webhook_deliveries(&app_id)
+            .await
+            .and_then(|deliveries| to_json(&deliveries)),
+    };
+
+    match result {
+        Ok(data) => Ok(respond(app_id, &raw, true, Some(data))),
+        Err(err) => Ok(respond(app_id, &raw, false, Some(err.to_string()))),
+    }
+}
@@ -32,4 +32,15 @@
 #else
   #error Unsupported target architecture!
 #endif
-#endif
\ No newline at end of file
+#endif
+#ifndef PT_REGS_PARM1
+#if defined(__TARGET_ARCH_x86)
+  // For x86_64, the 1st parameter is in rdi.
+  #define PT_REGS_PARM1(ctx) ((ctx)->di)
+#elif defined(__TARGET_ARCH_arm64)
+  // For arm64, parameters are in the regs array; parameter 1 is at index 0.
+  #define PT_REGS_PARM1(ctx) ((ctx)->regs[0])
+#else
+  #error Unsupported target architecture!
+#endif
+#endif
@@ -10,9 +10,10 @@ struct traffic_stats {
 
 struct {
     __uint(type, BPF_MAP_TYPE_HASH);
-    __uint(max_entries, 1024);
+    __uint(max_entries, 8192);
     __type(key, __u32); // PID
     __type(value, struct traffic_stats);
+    __uint(pinning, LIBBPF_PIN_BY_NAME); // Kept under /sys/fs/bpf/ais across restarts
 } pid_traffic_map SEC(".maps");
 
 struct {
@@ -20,8 +21,117 @@ struct {
     __uint(max_entries, 1024);
     __type(key, __u64); // Cgroup ID
     __type(value, struct traffic_stats);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
 } cgroup_traffic_map SEC(".maps");
 
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, __u64); // Cgroup ID of a service in artisan.slice
+    __type(value, __u8);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} tracked_cgroup_map SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 8192);
+    __type(key, __u32); // PID
+    __type(value, __u64); // Cgroup ID it was forked in
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} pid_cgroup_map SEC(".maps");
+
+// Token buckets of the capped services, one per direction holding up to a
+// second of traffic. Caps are written by userspace, which resets the buckets.
+struct bandwidth_limit {
+    struct bpf_spin_lock lock;
+    __u64 rate; // Bytes per second
+    __u64 tx_tokens;
+    __u64 tx_last_ns;
+    __u64 rx_tokens;
+    __u64 rx_last_ns;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, __u64); // Cgroup ID of a service in artisan.slice
+    __type(value, struct bandwidth_limit);
+} bandwidth_limit_map SEC(".maps");
+
+#define AF_INET 2
+#define AF_INET6 10
+
+// Services with an egress allowlist, everything they send to an address
+// outside of it is dropped. Written by userspace along with the lists.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, __u64); // Cgroup ID of a service in artisan.slice
+    __type(value, __u8);
+} egress_policy_map SEC(".maps");
+
+// The cgroup ID is matched in full, the prefix length is 64 plus the one of
+// the allowed network
+struct egress_v4_key {
+    __u32 prefixlen;
+    __u64 cgroup_id;
+    __u8 addr[4];
+} __attribute__((packed));
+
+struct egress_v6_key {
+    __u32 prefixlen;
+    __u64 cgroup_id;
+    __u8 addr[16];
+} __attribute__((packed));
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __uint(max_entries, 16384);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+    __type(key, struct egress_v4_key);
+    __type(value, __u8);
+} egress_allow_v4_map SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __uint(max_entries, 16384);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+    __type(key, struct egress_v6_key);
+    __type(value, __u8);
+} egress_allow_v6_map SEC(".maps");
+
+// IPv4 addresses take the first 4 bytes of addr
+struct egress_blocked_key {
+    __u64 cgroup_id;
+    __u16 family;
+    __u16 pad;
+    __u8 addr[16];
+    __u32 pad2;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 8192);
+    __type(key, struct egress_blocked_key);
+    __type(value, __u64); // Packets dropped
+} egress_blocked_map SEC(".maps");
+
+// IPv4 addresses take the first 4 bytes of addr
+struct remote_key {
+    __u32 pid;
+    __u16 family;
+    __u16 pad;
+    __u8 addr[16];
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 16384);
+    __type(key, struct remote_key); // PID and remote address
+    __type(value, struct traffic_stats);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} remote_traffic_map SEC(".maps");
+
 // Common function to update stats
 static __always_inline void update_stats(__u32 pid, ssize_t bytes, bool is_tx) {
     if (bytes <= 0)
@@ -43,6 +153,43 @@ static __always_inline void update_stats(__u32 pid, ssize_t bytes, bool is_tx) {
     }
 }
 
+// Same as update_stats, keyed by the socket's peer as well. Unconnected UDP
+// sockets have no peer on the socket and are only counted per pid.
+static __always_inline void update_remote_stats(__u32 pid, struct sock *sk, ssize_t bytes, bool is_tx) {
+    if (bytes <= 0 || !sk)
+        return;
+
+    struct remote_key key = {};
+    key.pid = pid;
+    key.family = BPF_CORE_READ(sk, __sk_common.skc_family);
+
+    if (key.family == AF_INET) {
+        __be32 daddr = BPF_CORE_READ(sk, __sk_common.skc_daddr);
+        if (!daddr)
+            return;
+        __builtin_memcpy(key.addr, &daddr, sizeof(daddr));
+    } else if (key.family == AF_INET6) {
+        bpf_core_read(key.addr, sizeof(key.addr), &sk->__sk_common.skc_v6_daddr);
+    } else {
+        return;
+    }
+
+    struct traffic_stats zero = {};
+    struct traffic_stats *stats = bpf_map_lookup_elem(&remote_traffic_map, &key);
+    if (!stats) {
+        bpf_map_update_elem(&remote_traffic_map, &key, &zero, BPF_ANY);
+        stats = bpf_map_lookup_elem(&remote_traffic_map, &key);
+        if (!stats)
+            return;
+    }
+
+    if (is_tx) {
+        __sync_fetch_and_add(&stats->tx_bytes, bytes);
+    } else {
+        __sync_fetch_and_add(&stats->rx_bytes, bytes);
+    }
+}
+
 // TCP send
 SEC("kprobe/tcp_sendmsg")
 int bpf_tcp_sendmsg(struct pt_regs *ctx) {
@@ -50,6 +197,7 @@ int bpf_tcp_sendmsg(struct pt_regs *ctx) {
     ssize_t size = PT_REGS_PARM3(ctx);
     bpf_printk("tcp_sendmsg: pid=%d, size=%d\n", pid, size);
     update_stats(pid, size, true);
+    update_remote_stats(pid, (struct sock *)PT_REGS_PARM1(ctx), size, true);
     return 0;
 }
 
@@ -60,6 +208,7 @@ int bpf_tcp_recvmsg(struct pt_regs *ctx) {
     int copied = PT_REGS_PARM2(ctx);
     // bpf_printk("tcp_recvmsg: pid=%d, size=%d\n", pid, size);
     update_stats(pid, copied, false);
+    update_remote_stats(pid, (struct sock *)PT_REGS_PARM1(ctx), copied, false);
     return 0;
 }
 
@@ -70,6 +219,7 @@ int bpf_udp_sendmsg(struct pt_regs *ctx) {
     ssize_t size = PT_REGS_PARM3(ctx);
     bpf_printk("udp_sendmsg: pid=%d, size=%d\n", pid, size);
     update_stats(pid, size, true);
+    update_remote_stats(pid, (struct sock *)PT_REGS_PARM1(ctx), size, true);
     return 0;
 }
 
@@ -80,7 +230,151 @@ int bpf_udp_recvmsg(struct pt_regs *ctx) {
     int copied = PT_REGS_PARM4(ctx);
     // bpf_printk("upp_recvmsg: pid=%d, size=%d\n", pid, size);
     update_stats(pid, copied, false);
+    update_remote_stats(pid, (struct sock *)PT_REGS_PARM1(ctx), copied, false);
     return 0;
 }
 
+// Processes forked inside a tracked service are counted from their first
+// byte. Threads share the entry of their process and are skipped.
+SEC("tp_btf/sched_process_fork")
+int bpf_sched_process_fork(__u64 *ctx) {
+    struct task_struct *child = (struct task_struct *)ctx[1];
+    if (child->pid != child->tgid)
+        return 0;
+
+    __u64 cgroup_id = bpf_get_current_cgroup_id();
+    if (!bpf_map_lookup_elem(&tracked_cgroup_map, &cgroup_id))
+        return 0;
+
+    __u32 pid = child->pid;
+    struct traffic_stats zero = {};
+    bpf_map_update_elem(&pid_traffic_map, &pid, &zero, BPF_NOEXIST);
+    bpf_map_update_elem(&pid_cgroup_map, &pid, &cgroup_id, BPF_ANY);
+    return 0;
+}
+
+// 1 lets the packet through, 0 drops it. Dropped TCP segments are sent
+// again, which slows the sender down to the cap.
+static __always_inline int within_bandwidth_limit(struct __sk_buff *skb, bool is_tx) {
+    struct bpf_sock *sk = skb->sk;
+    if (!sk)
+        return 1;
+    sk = bpf_sk_fullsock(sk);
+    if (!sk)
+        return 1;
+
+    __u64 cgroup_id = bpf_sk_cgroup_id(sk);
+    struct bandwidth_limit *limit = bpf_map_lookup_elem(&bandwidth_limit_map, &cgroup_id);
+    if (!limit)
+        return 1;
+
+    __u64 now = bpf_ktime_get_ns();
+    int allowed = 1;
+
+    bpf_spin_lock(&limit->lock);
+    __u64 *tokens = is_tx ? &limit->tx_tokens : &limit->rx_tokens;
+    __u64 *last_ns = is_tx ? &limit->tx_last_ns : &limit->rx_last_ns;
+
+    __u64 elapsed = now - *last_ns;
+    if (elapsed > 1000000000ULL)
+        elapsed = 1000000000ULL;
+
+    // Time that didn't add a whole byte is kept for the next packet
+    __u64 refill = elapsed * limit->rate / 1000000000ULL;
+    if (refill) {
+        *tokens += refill;
+        if (*tokens > limit->rate)
+            *tokens = limit->rate;
+        *last_ns = now;
+    }
+
+    if (*tokens >= skb->len)
+        *tokens -= skb->len;
+    else
+        allowed = 0;
+    bpf_spin_unlock(&limit->lock);
+
+    return allowed;
+}
+
+static __always_inline void count_blocked(__u64 cgroup_id, __u16 family, void *addr, __u32 len) {
+    struct egress_blocked_key key = {};
+    key.cgroup_id = cgroup_id;
+    key.family = family;
+    __builtin_memcpy(key.addr, addr, len);
+
+    __u64 one = 1;
+    __u64 *count = bpf_map_lookup_elem(&egress_blocked_map, &key);
+    if (count)
+        __sync_fetch_and_add(count, 1);
+    else
+        bpf_map_update_elem(&egress_blocked_map, &key, &one, BPF_NOEXIST);
+}
+
+// 1 lets the packet through, 0 drops it. Services without an allowlist and
+// loopback traffic always go through. The packet starts at the IP header.
+static __always_inline int within_egress_allowlist(struct __sk_buff *skb) {
+    struct bpf_sock *sk = skb->sk;
+    if (!sk)
+        return 1;
+    sk = bpf_sk_fullsock(sk);
+    if (!sk)
+        return 1;
+
+    __u64 cgroup_id = bpf_sk_cgroup_id(sk);
+    if (!bpf_map_lookup_elem(&egress_policy_map, &cgroup_id))
+        return 1;
+
+    __u8 version;
+    if (bpf_skb_load_bytes(skb, 0, &version, sizeof(version)))
+        return 1;
+
+    if ((version >> 4) == 4) {
+        struct egress_v4_key key = {};
+        key.prefixlen = 64 + 32;
+        key.cgroup_id = cgroup_id;
+        if (bpf_skb_load_bytes(skb, 16, key.addr, sizeof(key.addr)))
+            return 1;
+        if (key.addr[0] == 127 || bpf_map_lookup_elem(&egress_allow_v4_map, &key))
+            return 1;
+
+        count_blocked(cgroup_id, AF_INET, key.addr, sizeof(key.addr));
+        return 0;
+    }
+
+    if ((version >> 4) == 6) {
+        struct egress_v6_key key = {};
+        key.prefixlen = 64 + 128;
+        key.cgroup_id = cgroup_id;
+        if (bpf_skb_load_bytes(skb, 24, key.addr, sizeof(key.addr)))
+            return 1;
+
+        // ::1
+        int loopback = key.addr[15] == 1;
+        for (int i = 0; i < 15; i++)
+            loopback = loopback && key.addr[i] == 0;
+        if (loopback || bpf_map_lookup_elem(&egress_allow_v6_map, &key))
+            return 1;
+
+        count_blocked(cgroup_id, AF_INET6, key.addr, sizeof(key.addr));
+        return 0;
+    }
+
+    return 1;
+}
+
+// Attached to artisan.slice, every service below it goes through these.
+// Traffic outside the allowlist never takes tokens from the bucket.
+SEC("cgroup_skb/egress")
+int bpf_bandwidth_egress(struct __sk_buff *skb) {
+    if (!within_egress_allowlist(skb))
+        return 0;
+    return within_bandwidth_limit(skb, true);
+}
+
+SEC("cgroup_skb/ingress")
+int bpf_bandwidth_ingress(struct __sk_buff *skb) {
+    return within_bandwidth_limit(skb, false);
+}
+
 char LICENSE[] SEC("license") = "GPL";
@@ -1,37 +1,97 @@
+use admin::serve_admin_socket;
+use mirror::serve_status_mirror;
 use applications::{
+    adopt::refresh_adopted_services,
+    build_cache::run_build_cache,
     child::{populate_initial_state_lock, CLIENT_APPLICATION_HANDLER, SYSTEM_APPLICATION_HANDLER},
+    coredump::collect_core_dumps,
+    dependencies::probe_dependencies,
+    digest::send_health_digests,
+    drift::detect_config_drift,
+    egress::enforce_egress_allowlists,
+    hibernation::hibernate_idle_apps,
+    limits::enforce_bandwidth_limits,
+    liveness::probe_liveness,
+    manifest::check_manifests,
     monitor::{
-        handle_dead_applications, handle_new_client_applications, handle_new_system_applications,
-        monitor_application_resource_usage, update_client_state, update_system_state,
+        handle_dead_applications, monitor_application_resource_usage, update_client_state,
+        update_system_state,
     },
+    node_spec::reconcile_node_spec,
+    permissions::audit_permissions,
+    ports::monitor_port_usage,
+    priority::refresh_priorities,
+    quota::enforce_quotas,
+    reconcile::{reconcile_client_applications, reconcile_system_applications},
+    redaction::refresh_redaction_rules,
+    registry::Registry,
     resolve::{resolve_client_applications, resolve_system_applications, track_pids},
+    restart::supervise_restarts,
+    retention::enforce_audit_retention,
+    service_audit::validate_services,
+    status_page::render_status_page,
+    status_store::check_status_capacity,
+    unit_files::sync_unit_files,
+    usage_history::{compact_usage_history, load_usage_history, persist_usage_history},
+    usage_report::generate_due_reports,
+    vulnerabilities::run_vulnerability_scans,
+    webhooks::{emit_webhooks, retry_webhooks},
 };
 use artisan_middleware::dusa_collection_utils::{
     core::errors::ErrorArrayItem,
+    core::functions::current_timestamp,
     core::logger::LogLevel,
-    core::types::{rwarc::LockWithTimeout, stringy::Stringy},
 };
 use artisan_middleware::{aggregator::AppStatus, state_persistence::AppState};
 use artisan_middleware::{dusa_collection_utils::log, identity::Identifier};
-use network::process_tcp;
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use network::{command_tls_acceptor, process_tcp};
+use subscription::subscribers;
+use std::{sync::Arc, time::Duration};
 use system::{
-    control::{GlobalState, GLOBAL_STATE, LEDGER_PATH},
+    alerts::send_alerts,
+    cadence::{pace, track_activity},
+    cgroups::log_cgroup_mode,
+    control::{GlobalState, GLOBAL_STATE},
+    journal::record_events,
+    legacy_state::migrate_legacy_state,
+    ledger_store::ledger_store,
+    pressure::check_memory_pressure,
+    siblings::discover_siblings,
     portal::connect_with_portal,
+    runtime::build_runtime,
+    settings::{ManagerSettings, UnitEventSettings},
+    storage::{off_worker, restore_file, storage, STATUS_SNAPSHOT_KEY},
     signals::{handle_signal, reload_callback, shutdown_callback},
+    systemd_bus::{follow_jobs, follows_jobs, next_unit_change},
+    watchdog::watch_manager_memory,
+};
+use tokio::{
+    net::TcpListener, signal::unix::SignalKind, sync::broadcast::error::RecvError, time::sleep,
 };
-use tokio::{net::TcpListener, signal::unix::SignalKind, time::sleep};
 
+mod admin;
 mod applications;
+mod commands;
+mod mirror;
 mod network;
+mod pretty;
+mod session;
+mod subscription;
 mod system;
 
-pub type AppStatusArray = LockWithTimeout<HashMap<Stringy, AppStatus>>;
+pub type AppStatusArray = Registry<AppStatus>;
+
+fn main() -> Result<(), ErrorArrayItem> {
+    // The runtime has to exist before the global state does, so its
+    // settings are read on their own
+    let settings: ManagerSettings = ManagerSettings::load();
+    build_runtime(&settings.runtime)?.block_on(run())
+}
 
-#[tokio::main]
-async fn main() -> Result<(), ErrorArrayItem> {
+async fn run() -> Result<(), ErrorArrayItem> {
     GlobalState::initialize_global_state().await?;
     let global_state: &Arc<GlobalState> = GLOBAL_STATE.get().unwrap();
+    log_cgroup_mode();
     let mut app_state: AppState = global_state.get_state_clone().await?;
 
     // loading configuration and state persistence
@@ -40,9 +100,29 @@ async fn main() -> Result<(), ErrorArrayItem> {
     }
     
     {
+        // A reprovisioned node gets back the statuses it saved off box
+        let snapshot_path: String = global_state.settings.legacy_state.registered_apps_path.clone();
+        match off_worker(move || restore_file(STATUS_SNAPSHOT_KEY, &snapshot_path)).await {
+            Ok(true) => log!(LogLevel::Info, "Restored the status snapshot from storage"),
+            Ok(false) => (),
+            Err(err) => log!(
+                LogLevel::Error,
+                "Failed to restore the status snapshot: {}",
+                err
+            ),
+        }
+
         resolve_client_applications(&global_state.clone()).await?;
         resolve_system_applications(&global_state.clone()).await?;
         populate_initial_state_lock(&mut app_state).await?;
+
+        if let Err(err) = migrate_legacy_state(&global_state.settings.legacy_state).await {
+            log!(
+                LogLevel::Error,
+                "Failed to migrate the legacy registered apps file: {}",
+                err
+            );
+        }
     }
 
     // seting up signal listeners
@@ -87,6 +167,26 @@ async fn main() -> Result<(), ErrorArrayItem> {
         }
     });
 
+    // Event bus consumer
+    tokio::spawn(async move {
+        let mut receiver = global_state.events.subscribe();
+
+        loop {
+            match receiver.recv().await {
+                Ok(event) => log!(LogLevel::Debug, "Event: {}", event),
+                Err(RecvError::Lagged(skipped)) => {
+                    log!(LogLevel::Warn, "Event logger skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Event journal
+    tokio::spawn(async move {
+        record_events(&global_state.events, &global_state.journal).await;
+    });
+
     // Network Monitor Maintenence
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
@@ -109,6 +209,306 @@ async fn main() -> Result<(), ErrorArrayItem> {
                     err.err_mesg
                 );
             }
+
+            if let Err(err) = enforce_bandwidth_limits(&global_state.clone()).await {
+                log!(
+                    LogLevel::Warn,
+                    "Skipping refresh of bandwidth caps: {}",
+                    err.err_mesg
+                );
+            }
+
+            if let Err(err) = enforce_egress_allowlists(&global_state.clone()).await {
+                log!(
+                    LogLevel::Warn,
+                    "Skipping refresh of egress allowlists: {}",
+                    err.err_mesg
+                );
+            }
+        }
+    });
+
+    // Core dump collection
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = collect_core_dumps(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to collect core dumps: {}", err);
+            }
+            sleep(Duration::from_secs(60)).await;
+        }
+    });
+
+    // Config drift detection
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = detect_config_drift(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to check config drift: {}", err);
+            }
+            sleep(Duration::from_secs(600)).await;
+        }
+    });
+
+    // Memory pressure protection
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = check_memory_pressure(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to check memory pressure: {}", err);
+            }
+            sleep(Duration::from_secs(10)).await;
+        }
+    });
+
+    // Output redaction rules
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = refresh_redaction_rules(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to refresh redaction rules: {}", err);
+            }
+            sleep(Duration::from_secs(60)).await;
+        }
+    });
+
+    // Declarative node spec from provisioning tooling
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = reconcile_node_spec(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to reconcile the node spec: {}", err);
+            }
+            sleep(Duration::from_secs(60)).await;
+        }
+    });
+
+    // Systemd services adopted into management
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = refresh_adopted_services(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to refresh adopted services: {}", err);
+            }
+            sleep(Duration::from_secs(15)).await;
+        }
+    });
+
+    // Sibling Artisan components and their compatibility
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = discover_siblings().await {
+                log!(LogLevel::Warn, "Failed to discover sibling components: {}", err);
+            }
+            sleep(Duration::from_secs(300)).await;
+        }
+    });
+
+    // Memory watchdog for the manager itself
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = watch_manager_memory(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to sample manager memory: {}", err);
+            }
+            sleep(Duration::from_secs(60)).await;
+        }
+    });
+
+    // Relaunch crashed client applications
+    tokio::spawn(async move {
+        supervise_restarts(&global_state.clone()).await;
+    });
+
+    // Build cache for on-node builds
+    tokio::spawn(async move {
+        if let Err(err) = run_build_cache(&global_state.clone()).await {
+            log!(LogLevel::Error, "Build cache stopped: {}", err);
+        }
+    });
+
+    // Dependency scans after deploys
+    tokio::spawn(async move {
+        if let Err(err) = run_vulnerability_scans(&global_state.clone()).await {
+            log!(LogLevel::Error, "Vulnerability scanner stopped: {}", err);
+        }
+    });
+
+    // Tenant webhooks for lifecycle events
+    tokio::spawn(async move {
+        if let Err(err) = emit_webhooks(&global_state.clone()).await {
+            log!(LogLevel::Error, "Webhook delivery stopped: {}", err);
+        }
+    });
+
+    // Retries of failed webhook deliveries
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = retry_webhooks(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to retry webhooks: {}", err);
+            }
+            sleep(Duration::from_secs(5)).await;
+        }
+    });
+
+    // Operator alerts through ais_mailler
+    tokio::spawn(async move {
+        if let Err(err) = send_alerts(&global_state.clone()).await {
+            log!(LogLevel::Error, "Alert bridge stopped: {}", err);
+        }
+    });
+
+    // Customer health digests
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = send_health_digests(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to send health digests: {}", err);
+            }
+            sleep(Duration::from_secs(3600)).await;
+        }
+    });
+
+    // Audit retention of the event journal
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = enforce_audit_retention(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to enforce audit retention: {}", err);
+            }
+            sleep(Duration::from_secs(3600)).await;
+        }
+    });
+
+    // Validation of the services running in artisan.slice
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = validate_services(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to validate services: {}", err);
+            }
+            sleep(Duration::from_secs(300)).await;
+        }
+    });
+
+    // Permission audit of application files
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = audit_permissions(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to audit permissions: {}", err);
+            }
+            sleep(Duration::from_secs(900)).await;
+        }
+    });
+
+    // Ephemeral port and conntrack usage
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = monitor_port_usage(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to sample port usage: {}", err);
+            }
+            sleep(Duration::from_secs(30)).await;
+        }
+    });
+
+    // Billing reports of the last finished day and month
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = generate_due_reports(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to write the usage reports: {}", err);
+            }
+            sleep(Duration::from_secs(3600)).await;
+        }
+    });
+
+    // External endpoints apps depend on, so upstream outages show up on them
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = probe_dependencies(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to probe app dependencies: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.dependencies.interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Client apps against what their manifests declare
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = check_manifests(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to check app manifests: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.manifests.interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Units of client apps against their manifests and configs
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = sync_unit_files(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to sync unit files: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.unit_files.interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Idle client apps hibernate until their next connection
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = hibernate_idle_apps(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to hibernate idle apps: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.hibernation.interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Status page for the reverse proxy to serve
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = render_status_page(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to render the status page: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.status_page.interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Liveness probes, each app's on its own interval
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = probe_liveness(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to probe app liveness: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.liveness.tick_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Suspends client apps over their monthly quotas
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = enforce_quotas(&global_state.clone()).await {
+                log!(LogLevel::Error, "Failed to enforce usage quotas: {}", err);
+            }
+            sleep(Duration::from_secs(
+                global_state.settings.quotas.check_interval_secs.max(1),
+            ))
+            .await;
+        }
+    });
+
+    // Alerts before the status store outgrows what shutdown can save
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = check_status_capacity(&global_state.clone()).await {
+                log!(LogLevel::Warn, "Failed to check the status store: {}", err);
+            }
+            sleep(Duration::from_secs(60)).await;
         }
     });
 
@@ -116,72 +516,135 @@ async fn main() -> Result<(), ErrorArrayItem> {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-            if let Err(e) = global_state
-                .ledger
-                .try_read()
-                .await
-                .unwrap()
-                .persist_to_disk(LEDGER_PATH)
-            {
+            if !global_state.ledger_ready() {
+                continue;
+            }
+            let identity: Identifier = global_state.get_identity().await;
+            if let Err(e) = ledger_store().persist(
+                &*global_state.ledger.try_read().await.unwrap(),
+                &identity,
+                false,
+            ) {
                 log!(LogLevel::Error, "Failed to persist usage ledger: {}", e);
             } else {
                 log!(LogLevel::Trace, "Persisted usage ledger to disk");
             }
+
+            // Uploads to an object store shouldn't hold up a worker thread
+            match tokio::task::spawn_blocking(|| storage()?.flush()).await {
+                Ok(Err(err)) => log!(LogLevel::Warn, "Failed to flush the storage: {}", err),
+                Err(err) => log!(LogLevel::Error, "The storage flush panicked: {}", err),
+                Ok(Ok(_)) => (),
+            }
         }
     });
 
+    // Usage history for graphs, written less often than the ledger as it's
+    // much bigger. Compacted first so neither the file nor memory keep
+    // growing.
     tokio::spawn(async move {
+        load_usage_history();
         loop {
-            if let Err(err) = handle_new_system_applications(&global_state.clone()).await {
+            sleep(Duration::from_secs(600)).await;
+            if let Err(err) = compact_usage_history(&global_state.settings.usage_history) {
+                log!(LogLevel::Error, "Failed to compact usage history: {}", err);
+            }
+            if let Err(err) = persist_usage_history() {
+                log!(LogLevel::Error, "Failed to persist usage history: {}", err);
+            }
+        }
+    });
+
+    // Tightens the monitor loop below whenever apps change
+    tokio::spawn(async move {
+        if let Err(err) = track_activity(&global_state.clone()).await {
+            log!(LogLevel::Error, "Cadence tracker stopped: {}", err);
+        }
+    });
+
+    // Brings apps to their desired state whenever systemd reports a change,
+    // polls like the monitor loop when its signals can't be followed
+    tokio::spawn(async move {
+        let settings: UnitEventSettings = global_state.settings.unit_events.clone();
+        if settings.enabled {
+            if let Err(err) = follow_jobs().await {
+                log!(LogLevel::Warn, "Polling for unit changes: {}", err);
+            }
+        }
+
+        loop {
+            if let Err(err) = refresh_priorities().await {
                 log!(LogLevel::Error, "{}", err);
             };
-            sleep(Duration::from_millis(150)).await;
 
-            if let Err(err) = handle_new_client_applications(&global_state.clone()).await {
+            if let Err(err) = reconcile_system_applications(&global_state.clone()).await {
                 log!(LogLevel::Error, "{}", err);
             };
-            sleep(Duration::from_millis(150)).await;
 
+            if let Err(err) = reconcile_client_applications(&global_state.clone()).await {
+                log!(LogLevel::Error, "{}", err);
+            };
+
+            if let Err(err) = handle_dead_applications(&global_state.clone()).await {
+                log!(LogLevel::Error, "{}", err);
+            }
+
+            match settings.enabled && follows_jobs() {
+                true => next_unit_change(Duration::from_secs(settings.fallback_secs.max(1))).await,
+                false => pace(&global_state.settings.adaptive_polling).await,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
             if let Err(err) = monitor_application_resource_usage(
-                SYSTEM_APPLICATION_HANDLER.clone(),
+                &SYSTEM_APPLICATION_HANDLER,
                 &global_state.clone(),
             )
             .await
             {
                 log!(LogLevel::Error, "{}", err);
             };
-            sleep(Duration::from_millis(150)).await;
+            pace(&global_state.settings.adaptive_polling).await;
 
             if let Err(err) = monitor_application_resource_usage(
-                CLIENT_APPLICATION_HANDLER.clone(),
+                &CLIENT_APPLICATION_HANDLER,
                 &global_state.clone(),
             )
             .await
             {
                 log!(LogLevel::Error, "{}", err);
             };
-            sleep(Duration::from_millis(150)).await;
-
-            if let Err(err) = handle_dead_applications().await {
-                log!(LogLevel::Error, "{}", err);
-            }
-            sleep(Duration::from_millis(150)).await;
+            pace(&global_state.settings.adaptive_polling).await;
 
             if let Err(err) = update_client_state(&global_state.clone()).await {
                 log!(LogLevel::Error, "{}", err);
             }
-            sleep(Duration::from_millis(150)).await;
+            pace(&global_state.settings.adaptive_polling).await;
 
             if let Err(err) = update_system_state(&global_state.clone()).await {
                 log!(LogLevel::Error, "{}", err);
             }
-            sleep(Duration::from_millis(150)).await;
+            pace(&global_state.settings.adaptive_polling).await;
         }
     });
 
     // Regiser with portal
     tokio::spawn(async move {
+        let mut registered: u64 = 0;
+
         loop {
+            // A subscribed portal already hears of every change as it happens
+            if subscribers() > 0
+                && current_timestamp().saturating_sub(registered)
+                    < global_state.settings.subscriptions.registration_secs
+            {
+                sleep(Duration::from_secs(30)).await;
+                continue;
+            }
+            registered = current_timestamp();
+
             let app_state: Result<AppState, ErrorArrayItem> = global_state.get_state_clone().await;
 
             match app_state {
@@ -199,16 +662,38 @@ async fn main() -> Result<(), ErrorArrayItem> {
         }
     });
 
+    // Local admin API
+    tokio::spawn(async move {
+        if let Err(err) = serve_admin_socket().await {
+            log!(LogLevel::Error, "Admin socket stopped: {}", err);
+        }
+    });
+
+    // Read only status for tools on the node
+    let mirror_settings = global_state.settings.status_mirror.clone();
+    tokio::spawn(async move {
+        if let Err(err) = serve_status_mirror(mirror_settings).await {
+            log!(LogLevel::Error, "Status mirror stopped: {}", err);
+        }
+    });
+
     // Initiating network stack
     let tcp_listener: TcpListener = TcpListener::bind(format!("0.0.0.0:9800"))
         .await
         .map_err(|err| ErrorArrayItem::from(err))?;
+    let tls_acceptor = command_tls_acceptor(&global_state.settings.command_tls)?;
+
+    // Heavy initialization waits until we can answer status requests
+    tokio::spawn(async move {
+        global_state.finish_initialization().await;
+    });
 
     loop {
         tokio::select! {
             Ok(conn) = tcp_listener.accept() => {
+                let tls_acceptor = tls_acceptor.clone();
                 tokio::spawn(async move {
-                    if let Err(err) = process_tcp(conn).await {
+                    if let Err(err) = process_tcp(conn, tls_acceptor).await {
                         log!(LogLevel::Error, "TCP connection handling panicked: {:?}", err);
                     }
                 });
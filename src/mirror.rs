@@ -0,0 +1,173 @@
+use artisan_middleware::aggregator::AppStatus;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::applications::child::APP_STATUS_ARRAY;
+use crate::system::settings::StatusMirrorSettings;
+
+/// Longest request we read, anything asking for more than a path is not
+/// one of ours
+const MAX_REQUEST: usize = 4096;
+
+/// What the mirror shows of an app. Error logs, env details and anything
+/// else that could carry a secret stay on the command port.
+#[derive(Debug, Clone, Serialize)]
+pub struct MirroredStatus {
+    pub name: String,
+    pub status: String,
+    pub pid: u32,
+    pub uptime: Option<u64>,
+    pub cpu_usage: Option<f64>,
+    pub memory_usage: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    pub tx_bytes: Option<u64>,
+    pub errors: usize,
+}
+
+impl MirroredStatus {
+    fn from_status(name: &Stringy, status: &AppStatus) -> Self {
+        Self {
+            name: name.to_string(),
+            status: format!("{:?}", status.app_data.get_status()),
+            pid: status.app_data.get_pid(),
+            uptime: status.uptime,
+            cpu_usage: status
+                .metrics
+                .as_ref()
+                .map(|metrics| metrics.cpu_usage as f64),
+            memory_usage: status
+                .metrics
+                .as_ref()
+                .map(|metrics| metrics.memory_usage as u64),
+            rx_bytes: status
+                .metrics
+                .as_ref()
+                .and_then(|metrics| metrics.other.as_ref())
+                .map(|net| net.rx_bytes),
+            tx_bytes: status
+                .metrics
+                .as_ref()
+                .and_then(|metrics| metrics.other.as_ref())
+                .map(|net| net.tx_bytes),
+            errors: status.app_data.state.error_log.len(),
+        }
+    }
+}
+
+async fn mirrored_statuses() -> Result<Vec<MirroredStatus>, ErrorArrayItem> {
+    let mut apps: Vec<(Stringy, Arc<AppStatus>)> =
+        APP_STATUS_ARRAY.snapshot().await?.into_iter().collect();
+    apps.sort_by_key(|(name, _)| name.to_string());
+
+    Ok(apps
+        .iter()
+        .map(|(name, status)| MirroredStatus::from_status(name, status))
+        .collect())
+}
+
+fn to_json<T: Serialize>(data: &T) -> Result<String, ErrorArrayItem> {
+    serde_json::to_string(data)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+}
+
+/// `(status line, body)` for the path of a GET request
+async fn route(path: &str) -> (&'static str, String) {
+    let result: Result<Option<String>, ErrorArrayItem> = match path.trim_end_matches('/') {
+        "" | "/status" => match mirrored_statuses().await {
+            Ok(statuses) => to_json(&statuses).map(Some),
+            Err(err) => Err(err),
+        },
+        path => match path.strip_prefix("/status/") {
+            Some(name) => match APP_STATUS_ARRAY.get(&Stringy::from(name)).await {
+                Ok(Some(status)) => {
+                    to_json(&MirroredStatus::from_status(&name.into(), &status)).map(Some)
+                }
+                Ok(None) => Ok(None),
+                Err(err) => Err(err),
+            },
+            None => Ok(None),
+        },
+    };
+
+    match result {
+        Ok(Some(body)) => ("200 OK", body),
+        Ok(None) => ("404 Not Found", String::from("{\"error\":\"not found\"}")),
+        Err(err) => {
+            log!(LogLevel::Warn, "Status mirror failed to answer: {}", err);
+            (
+                "500 Internal Server Error",
+                String::from("{\"error\":\"status unavailable\"}"),
+            )
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> Result<(), ErrorArrayItem> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut buffer: [u8; 1024] = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read: usize = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request: String = String::from_utf8_lossy(&request).into_owned();
+    let (status_line, body) = match request.lines().next().map(|line| {
+        let mut parts = line.split_whitespace();
+        (parts.next(), parts.next())
+    }) {
+        Some((Some("GET"), Some(path))) => route(path).await,
+        _ => (
+            "405 Method Not Allowed",
+            String::from("{\"error\":\"only GET is served\"}"),
+        ),
+    };
+
+    let response: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Serves sanitized status over plain HTTP for motd generators and
+/// dashboards on the node. Always bound to loopback and never routed to the
+/// command processor, it can't do anything but read.
+pub async fn serve_status_mirror(settings: StatusMirrorSettings) -> Result<(), ErrorArrayItem> {
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let addr: SocketAddr = SocketAddr::from((Ipv4Addr::LOCALHOST, settings.port));
+    let listener: TcpListener = TcpListener::bind(addr).await?;
+    log!(LogLevel::Info, "Status mirror listening on {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            // Slow clients don't get to hold a task forever
+            match timeout(Duration::from_secs(5), handle_connection(stream)).await {
+                Ok(Err(err)) => log!(LogLevel::Debug, "Status mirror connection failed: {}", err),
+                Ok(Ok(_)) | Err(_) => (),
+            }
+        });
+    }
+}
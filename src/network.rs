@@ -1,41 +1,204 @@
 use artisan_middleware::dusa_collection_utils::core::errors::Errors;
 use artisan_middleware::dusa_collection_utils::{core::errors::ErrorArrayItem, log};
 use artisan_middleware::{
-    aggregator::{AppMessage, Command, CommandResponse, CommandType},
+    aggregator::{AppMessage, AppStatus, Command, CommandResponse, CommandType},
     dusa_collection_utils::{core::logger::LogLevel, core::types::stringy::Stringy},
     portal::ManagerData,
     state_persistence::AppState,
 };
+use serde::Serialize;
 use simple_comms::{
     network::send_receive::{send_data, send_empty_err},
     protocol::{
         flags::Flags, header::EOL, io_helpers::read_until, message::ProtocolMessage, proto::Proto,
     },
 };
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    future::Future,
+    io::BufReader,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_rustls::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
 
+use crate::commands::{custom_command_processor, CustomCommand};
+use crate::session::run_session;
+use crate::subscription::stream_subscription;
+use crate::system::audit::{audited_command, record_command};
+use crate::system::auth::{authenticate, retarget, strip_token, Role};
+use crate::system::batch::{parse_batch, select_apps, BatchReport, BatchSelector};
+use crate::system::cadence::note_activity;
 use crate::system::control::{GlobalState, GLOBAL_STATE};
+use crate::system::federation::{fan_out, parse_fanout, FanoutOutcome, FanoutReport};
+use crate::system::jobs::{finish_job, parse_async, start_job, submit_job, Job};
+use crate::system::latency::{CommandTrace, Stage};
+use crate::system::settings::CommandTlsSettings;
+use crate::system::systemd_bus::watched_unit_state;
+use crate::system::telemetry::{outbound_status_json, outbound_status_value, withheld_command};
 use crate::{
     applications::{
         child::APP_STATUS_ARRAY,
+        dependencies::dependencies_of,
+        descriptors::process_counts_of,
+        disk::disk_usage_of,
+        egress::egress_of,
+        health::reset_breaker,
+        hibernation::hibernation_of,
+        integrity::quarantine_of,
+        limits::current_limits,
+        liveness::health_of,
+        logs::{follow_logs, tail_logs, LogCursor, LogLine},
+        maintenance::{begin_maintenance, end_maintenance_when_ready},
+        manifest::manifest_check_of,
+        oom::oom_kills_of,
+        pause::pause_of,
+        permissions::permission_report_of,
+        ports::port_usage_of,
+        priority::priority_of,
+        quota::suspension_of,
+        reconcile::reconciliation_of,
+        restart_window::restart_negotiation_of,
         start_stop::{reload_application, start_application, stop_application},
+        vulnerabilities::vulnerability_report_of,
     },
     system::manager::get_manager_data,
 };
 
-pub async fn process_tcp(mut connection: (TcpStream, SocketAddr)) -> Result<(), ErrorArrayItem> {
+fn tls_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, ErrorArrayItem> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<CertificateDer<'static>> =
+        rustls_pemfile::certs(&mut reader).collect::<Result<_, _>>()?;
+
+    match certs.is_empty() {
+        true => Err(tls_error(format!("No certificates in {}", path))),
+        false => Ok(certs),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, ErrorArrayItem> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| tls_error(format!("No private key in {}", path)))
+}
+
+/// Builds the acceptor for the command listener when TLS is enabled. Clients
+/// have to present a certificate signed by the portal's CA.
+pub fn command_tls_acceptor(
+    settings: &CommandTlsSettings,
+) -> Result<Option<TlsAcceptor>, ErrorArrayItem> {
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let mut roots: RootCertStore = RootCertStore::empty();
+    for cert in load_certs(&settings.client_ca_path)? {
+        roots
+            .add(cert)
+            .map_err(|err| tls_error(format!("Invalid client CA: {}", err)))?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|err| tls_error(format!("Invalid client CA: {}", err)))?;
+
+    let config: ServerConfig = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            load_certs(&settings.cert_path)?,
+            load_key(&settings.key_path)?,
+        )
+        .map_err(|err| tls_error(format!("Invalid server certificate: {}", err)))?;
+
+    log!(
+        LogLevel::Info,
+        "Command listener requires client certificates"
+    );
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(|name| name.to_owned())
+}
+
+pub async fn process_tcp(
+    connection: (TcpStream, SocketAddr),
+    tls: Option<TlsAcceptor>,
+) -> Result<(), ErrorArrayItem> {
+    let (stream, addr) = connection;
+
+    let acceptor: TlsAcceptor = match tls {
+        Some(acceptor) => acceptor,
+        None => return process_stream(stream, addr).await,
+    };
+
+    let stream = acceptor
+        .accept(stream)
+        .await
+        .map_err(|err| tls_error(format!("TLS handshake with {} failed: {}", addr, err)))?;
+
+    // The handshake already checked the CA, the allowlist narrows it down
+    if let Some(gs) = GLOBAL_STATE.get() {
+        let allowed: &Vec<String> = &gs.settings.command_tls.allowed_client_cns;
+        if !allowed.is_empty() {
+            let cn: Option<String> = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(common_name);
+
+            match cn {
+                Some(cn) if allowed.contains(&cn) => (),
+                cn => {
+                    return Err(ErrorArrayItem::new(
+                        Errors::Unauthorized,
+                        format!("{} presented a certificate for {:?}", addr, cn),
+                    ))
+                }
+            }
+        }
+    }
+
+    process_stream(stream, addr).await
+}
+
+async fn process_stream<S>(mut stream: S, source: SocketAddr) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     let proto: Proto = Proto::TCP;
 
-    let mut buffer = read_until(&mut connection.0, EOL.to_vec()).await?;
+    let mut buffer = read_until(&mut stream, EOL.to_vec()).await?;
     if let Some(pos) = buffer.windows(EOL.len()).rposition(|window| window == EOL) {
         buffer.truncate(pos);
     }
 
-    let recieved_message: ProtocolMessage<AppMessage> =
-        ProtocolMessage::<AppMessage>::from_bytes(&buffer).await?;
-
-    let recieved_payload = recieved_message.get_payload().await;
+    let mut trace: CommandTrace = CommandTrace::new();
+    let recieved_payload: AppMessage = trace
+        .time(Stage::Parse, async {
+            let recieved_message: ProtocolMessage<AppMessage> =
+                ProtocolMessage::<AppMessage>::from_bytes(&buffer).await?;
+            Ok::<AppMessage, ErrorArrayItem>(recieved_message.get_payload().await)
+        })
+        .await?;
 
     // TODO Security ?
     // let recieved_header = recieved_message.get_header().await;
@@ -50,19 +213,63 @@ pub async fn process_tcp(mut connection: (TcpStream, SocketAddr)) -> Result<(),
     // }
 
     match recieved_payload {
-        AppMessage::Command(command) => match command_processor(command).await {
-            Ok(data) => {
-                let message: ProtocolMessage<AppMessage> =
-                    ProtocolMessage::new(Flags::ENCRYPTED | Flags::COMPRESSED, data)?;
-                let message_bytes: Vec<u8> = message.format().await?;
-                send_data(&mut connection.0, message_bytes, proto).await?;
+        AppMessage::Command(command) => {
+            let presented: Stringy = command.app_id.clone();
+            let app_id: Stringy = strip_token(&command.app_id);
+            // Commands that keep the connection once they're answered
+            let upgrade: Option<CustomCommand> = match &command.command_type {
+                CommandType::Custom(raw) => CustomCommand::parse(raw).ok().filter(|custom| {
+                    matches!(
+                        custom,
+                        CustomCommand::Logs(_, true)
+                            | CustomCommand::Session
+                            | CustomCommand::Subscribe
+                    )
+                }),
+                _ => None,
+            };
+
+            let result: Result<AppMessage, ErrorArrayItem> =
+                handle_command(command, source, &mut trace).await;
+
+            match result {
+                Ok(data) => {
+                    let accepted: bool =
+                        matches!(&data, AppMessage::Response(response) if response.success);
+
+                    let message_bytes: Vec<u8> = trace
+                        .time(Stage::Serialize, async {
+                            let message: ProtocolMessage<AppMessage> =
+                                ProtocolMessage::new(Flags::ENCRYPTED | Flags::COMPRESSED, data)?;
+                            Ok::<Vec<u8>, ErrorArrayItem>(message.format().await?)
+                        })
+                        .await?;
+                    trace.finish();
+                    send_data(&mut stream, message_bytes, proto).await?;
+
+                    match upgrade {
+                        Some(CustomCommand::Logs(..)) if accepted => {
+                            return stream_logs(&mut stream, &app_id).await;
+                        }
+                        Some(CustomCommand::Session) if accepted => {
+                            return run_session(stream, source, presented).await;
+                        }
+                        Some(CustomCommand::Subscribe) if accepted => {
+                            return stream_subscription(&mut stream, source, presented).await;
+                        }
+                        _ => (),
+                    }
+                }
+                Err(err) => {
+                    trace.finish();
+                    return Err(err);
+                }
             }
-            Err(err) => return Err(err),
-        },
+        }
 
         _ => {
             // * illegal in this context
-            send_empty_err(&mut connection.0, proto).await?;
+            send_empty_err(&mut stream, proto).await?;
             return Ok(());
         }
     }
@@ -70,7 +277,431 @@ pub async fn process_tcp(mut connection: (TcpStream, SocketAddr)) -> Result<(),
     Ok(())
 }
 
-async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayItem> {
+/// Runs a command from the listener, noting the activity and auditing it.
+/// Every request of a session goes through here as well.
+pub(crate) async fn handle_command(
+    command: Command,
+    source: SocketAddr,
+    trace: &mut CommandTrace,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let audited: Option<&str> = audited_command(&command.command_type);
+    let app_id: Stringy = strip_token(&command.app_id);
+
+    // Whatever the command was, its effects show up sooner
+    note_activity();
+    trace.set_command(&command.command_type);
+    let started: Instant = Instant::now();
+    let result: Result<AppMessage, ErrorArrayItem> =
+        command_processor(command, source, trace).await;
+    trace.record(Stage::Handle, started.elapsed());
+
+    if let Some(name) = audited {
+        if let Err(err) = record_command(source, &app_id, name, &result).await {
+            log!(
+                LogLevel::Error,
+                "Failed to audit {} on {}: {}",
+                name,
+                app_id,
+                err
+            );
+        }
+    }
+
+    result
+}
+
+/// Keeps sending newly captured lines of an app until the client hangs up
+async fn stream_logs<S>(stream: &mut S, app_id: &Stringy) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (_, mut cursor): (Vec<LogLine>, LogCursor) = tail_logs(app_id, 0).await?;
+    let mut probe: [u8; 1] = [0; 1];
+
+    loop {
+        tokio::select! {
+            // Followers don't send anything, a read returning means they're gone
+            _ = stream.read(&mut probe) => {
+                log!(LogLevel::Debug, "Stopped streaming the logs of {}", app_id);
+                return Ok(());
+            }
+            _ = sleep(Duration::from_secs(1)) => (),
+        }
+
+        let lines: Vec<LogLine> = follow_logs(app_id, &mut cursor).await?;
+        if lines.is_empty() {
+            continue;
+        }
+
+        let data: AppMessage = AppMessage::Response(CommandResponse {
+            app_id: app_id.clone(),
+            command_type: CommandType::Custom("logs follow".to_owned()),
+            success: true,
+            message: Some(
+                serde_json::to_string(&lines)
+                    .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?,
+            ),
+        });
+
+        let message: ProtocolMessage<AppMessage> =
+            ProtocolMessage::new(Flags::ENCRYPTED | Flags::COMPRESSED, data)?;
+        let message_bytes: Vec<u8> = message.format().await?;
+        if send_data(stream, message_bytes, Proto::TCP).await.is_err() {
+            log!(LogLevel::Debug, "Stopped streaming the logs of {}", app_id);
+            return Ok(());
+        }
+    }
+}
+
+/// Version of the AllStatus response layout, bumped whenever it changes
+pub const ALL_STATUS_SCHEMA_VERSION: u32 = 2;
+
+/// Every app's status in one document. Before version 2 the response was a
+/// bare array of statuses.
+#[derive(Debug, Serialize)]
+struct AllStatusResponse {
+    schema_version: u32,
+    statuses: Vec<serde_json::Value>,
+}
+
+/// Serializes a status along with the manager side fields that aren't part of
+/// the middleware AppStatus
+pub(crate) async fn status_json(name: &Stringy, status: &AppStatus) -> Option<String> {
+    status_value(name, status)
+        .await
+        .map(|value| value.to_string())
+}
+
+/// `status_json` before it's written out, for responses that hold many
+/// statuses and serialize them together
+pub(crate) async fn status_value(name: &Stringy, status: &AppStatus) -> Option<serde_json::Value> {
+    let mut value: serde_json::Value = serde_json::to_value(status).ok()?;
+
+    if let Some(object) = value.as_object_mut() {
+        object.insert(
+            "priority".to_owned(),
+            serde_json::to_value(priority_of(name).await).ok()?,
+        );
+        object.insert(
+            "ports".to_owned(),
+            serde_json::to_value(port_usage_of(name).await).ok()?,
+        );
+        object.insert(
+            "compliance".to_owned(),
+            serde_json::to_value(permission_report_of(name).await).ok()?,
+        );
+        object.insert(
+            "vulnerabilities".to_owned(),
+            serde_json::to_value(vulnerability_report_of(name).await).ok()?,
+        );
+        object.insert(
+            "limits".to_owned(),
+            serde_json::to_value(current_limits(name)).ok()?,
+        );
+        object.insert(
+            "oom_kills".to_owned(),
+            serde_json::to_value(oom_kills_of(name)).ok()?,
+        );
+        object.insert(
+            "disk".to_owned(),
+            serde_json::to_value(disk_usage_of(name)).ok()?,
+        );
+        object.insert(
+            "process".to_owned(),
+            serde_json::to_value(process_counts_of(name)).ok()?,
+        );
+        object.insert(
+            "dependencies".to_owned(),
+            serde_json::to_value(dependencies_of(name)).ok()?,
+        );
+        object.insert(
+            "suspension".to_owned(),
+            serde_json::to_value(suspension_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "quarantine".to_owned(),
+            serde_json::to_value(quarantine_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "manifest".to_owned(),
+            serde_json::to_value(manifest_check_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "health".to_owned(),
+            serde_json::to_value(health_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "restart_window".to_owned(),
+            serde_json::to_value(restart_negotiation_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "egress".to_owned(),
+            serde_json::to_value(egress_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "hibernation".to_owned(),
+            serde_json::to_value(hibernation_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "pause".to_owned(),
+            serde_json::to_value(pause_of(name.as_str())).ok()?,
+        );
+        object.insert(
+            "unit".to_owned(),
+            serde_json::to_value(watched_unit_state(name.as_str())).ok()?,
+        );
+        object.insert(
+            "reconciliation".to_owned(),
+            serde_json::to_value(reconciliation_of(name.as_str())).ok()?,
+        );
+    }
+
+    Some(value)
+}
+
+/// Least privileged role allowed to run a command
+pub(crate) fn required_role(command_type: &CommandType, app_id: &Stringy) -> Role {
+    match command_type {
+        CommandType::Status | CommandType::AllStatus | CommandType::Info => Role::ReadOnly,
+        // Stopping or restarting the manager itself takes the whole node down
+        CommandType::Stop | CommandType::Restart if *app_id == "ais_manager".into() => Role::Admin,
+        // Without its unit enabled the node comes back without a manager
+        CommandType::Custom(raw) if raw == "disable" && *app_id == "ais_manager".into() => {
+            Role::Admin
+        }
+        // Adopting puts an arbitrary host service under our control
+        CommandType::Custom(raw) if raw.starts_with("adopt ") || raw.starts_with("release ") => {
+            Role::Admin
+        }
+        CommandType::Custom(raw) if raw.starts_with("migrate ") && raw != "migrate status" => {
+            Role::Admin
+        }
+        // Suspensions are billing decisions
+        CommandType::Custom(raw) if raw == "quota lift" => Role::Admin,
+        // So are quarantines, a binary is only trusted again by an admin
+        CommandType::Custom(raw) if raw == "integrity verify" => Role::Admin,
+        // Every item of a batch is checked on its own, like every request of
+        // a session
+        CommandType::Custom(raw) if raw.starts_with("batch ") || raw == "session" => Role::ReadOnly,
+        // They send nothing AllStatus wouldn't
+        CommandType::Custom(raw) if raw == "subscribe" || raw.starts_with("status delta") => {
+            Role::ReadOnly
+        }
+        // So is the command a job runs, once it starts
+        CommandType::Custom(raw) if raw.starts_with("async ") => Role::ReadOnly,
+        CommandType::Custom(raw) if raw.starts_with("job ") || raw == "jobs" => Role::ReadOnly,
+        // Whatever it relays lands on every node of the fleet
+        CommandType::Custom(raw) if raw.starts_with("fanout ") => Role::Admin,
+        _ => Role::Operator,
+    }
+}
+
+fn denied(command_type: CommandType, message: String) -> AppMessage {
+    AppMessage::Response(CommandResponse {
+        app_id: "".into(),
+        command_type,
+        success: false,
+        message: Some(message),
+    })
+}
+
+/// Runs the command here and relays it to every federation peer. The local
+/// run goes through the same checks as the command would on its own.
+async fn fanout_command(
+    global_state: &Arc<GlobalState>,
+    presented: Stringy,
+    app_id: &Stringy,
+    raw: &str,
+    inner: Result<CommandType, ErrorArrayItem>,
+    source: SocketAddr,
+    trace: &mut CommandTrace,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let command_type: CommandType = match inner {
+        Ok(_) if !global_state.settings.federation.enabled => {
+            return Ok(denied(
+                CommandType::Custom(raw.to_owned()),
+                "Federation isn't enabled on this node".to_owned(),
+            ))
+        }
+        Ok(command_type) => command_type,
+        Err(err) => return Ok(denied(CommandType::Custom(raw.to_owned()), err.to_string())),
+    };
+
+    if *app_id == "ais_manager".into()
+        && matches!(command_type, CommandType::Stop | CommandType::Restart)
+    {
+        return Ok(denied(
+            CommandType::Custom(raw.to_owned()),
+            "Managers aren't stopped across the fleet".to_owned(),
+        ));
+    }
+
+    log!(
+        LogLevel::Info,
+        "Fanning out {:?} on {} to {} peers",
+        command_type,
+        app_id,
+        global_state.settings.federation.peers.len()
+    );
+
+    let local: Result<AppMessage, ErrorArrayItem> = Box::pin(command_processor(
+        Command {
+            app_id: presented,
+            command_type: command_type.clone(),
+        },
+        source,
+        trace,
+    ))
+    .await;
+
+    let report: FanoutReport = FanoutReport {
+        local: FanoutOutcome::of(&local),
+        peers: fan_out(&global_state.settings.federation, app_id, command_type).await,
+    };
+    let success: bool = report.local.success && report.peers.values().all(|peer| peer.success);
+
+    Ok(AppMessage::Response(CommandResponse {
+        app_id: app_id.clone(),
+        command_type: CommandType::Custom(raw.to_owned()),
+        success,
+        message: serde_json::to_string(&report).ok(),
+    }))
+}
+
+/// Runs the command on every client app the selector picks, one after the
+/// other. Each goes through the same checks as it would on its own, with
+/// the caller's token, and is audited on its own.
+async fn batch_command(
+    presented: Stringy,
+    app_id: &Stringy,
+    raw: &str,
+    inner: Result<(BatchSelector, CommandType), ErrorArrayItem>,
+    source: SocketAddr,
+    trace: &mut CommandTrace,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let (selector, command_type) = match inner {
+        Ok(inner) => inner,
+        Err(err) => return Ok(denied(CommandType::Custom(raw.to_owned()), err.to_string())),
+    };
+
+    let targets: Vec<Stringy> = select_apps(&selector).await?;
+    if targets.is_empty() {
+        return Ok(denied(
+            CommandType::Custom(raw.to_owned()),
+            format!("{} matched no client apps", selector),
+        ));
+    }
+
+    log!(
+        LogLevel::Info,
+        "Running {:?} on {} apps for {}",
+        command_type,
+        targets.len(),
+        selector
+    );
+
+    let audited: Option<&str> = audited_command(&command_type);
+    let mut report: BatchReport = BatchReport {
+        selector: selector.to_string(),
+        items: BTreeMap::new(),
+    };
+    for target in targets {
+        let result: Result<AppMessage, ErrorArrayItem> = Box::pin(command_processor(
+            Command {
+                app_id: retarget(&presented, &target),
+                command_type: command_type.clone(),
+            },
+            source,
+            trace,
+        ))
+        .await;
+
+        if let Some(name) = audited {
+            if let Err(err) = record_command(source, &target, name, &result).await {
+                log!(
+                    LogLevel::Error,
+                    "Failed to audit {} on {}: {}",
+                    name,
+                    target,
+                    err
+                );
+            }
+        }
+        report
+            .items
+            .insert(target.to_string(), FanoutOutcome::of(&result));
+    }
+    let success: bool = report.items.values().all(|item| item.success);
+
+    Ok(AppMessage::Response(CommandResponse {
+        app_id: app_id.clone(),
+        command_type: CommandType::Custom(raw.to_owned()),
+        success,
+        message: serde_json::to_string(&report).ok(),
+    }))
+}
+
+/// Queues the command as a job and answers with its id right away. The job
+/// runs in the background and its progress is polled with `job <id>`.
+async fn async_command(
+    presented: Stringy,
+    app_id: &Stringy,
+    raw: &str,
+    inner: Result<CommandType, ErrorArrayItem>,
+    source: SocketAddr,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let command_type: CommandType = match inner {
+        Ok(inner) => inner,
+        Err(err) => return Ok(denied(CommandType::Custom(raw.to_owned()), err.to_string())),
+    };
+
+    let job: Job = submit_job(app_id, raw.trim_start_matches("async ").trim())?;
+    let id: u64 = job.id;
+    log!(
+        LogLevel::Info,
+        "Queued job {} to run {:?} on {}",
+        id,
+        command_type,
+        app_id
+    );
+
+    tokio::spawn(async move {
+        let mut trace: CommandTrace = CommandTrace::new();
+        trace.set_command(&command_type);
+        start_job(id);
+
+        // Boxed as a sendable future, the job runs the processor that
+        // spawned it
+        let run: Pin<Box<dyn Future<Output = Result<AppMessage, ErrorArrayItem>> + Send + '_>> =
+            Box::pin(command_processor(
+                Command {
+                    app_id: presented,
+                    command_type,
+                },
+                source,
+                &mut trace,
+            ));
+        let result: Result<AppMessage, ErrorArrayItem> = run.await;
+        if let Err(err) = &result {
+            log!(LogLevel::Warn, "Job {} failed: {}", id, err);
+        }
+        finish_job(id, &result);
+        trace.finish();
+    });
+
+    Ok(AppMessage::Response(CommandResponse {
+        app_id: app_id.clone(),
+        command_type: CommandType::Custom(raw.to_owned()),
+        success: true,
+        message: serde_json::to_string(&job).ok(),
+    }))
+}
+
+async fn command_processor(
+    command: Command,
+    source: SocketAddr,
+    trace: &mut CommandTrace,
+) -> Result<AppMessage, ErrorArrayItem> {
     let global_state: &Arc<GlobalState> = match GLOBAL_STATE.get() {
         Some(gs) => gs,
         None => {
@@ -81,13 +712,15 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
         }
     };
 
+    let lock_wait: Instant = Instant::now();
     let mut app_state: AppState = global_state.get_state_clone().await?;
-
-    if let Err(err) = global_state
+    let network_control: Result<(), &str> = global_state
         .locks
         .wait_for_network_control_with_timeout(Duration::from_secs(1))
-        .await
-    {
+        .await;
+    trace.record(Stage::LockWait, lock_wait.elapsed());
+
+    if let Err(err) = network_control {
         log!(LogLevel::Error, "{}", err);
         return Ok(AppMessage::Response(CommandResponse {
             app_id: "".into(),
@@ -97,17 +730,63 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
         }));
     }
 
-    let app_id: Stringy = command.app_id;
+    // Fanned out commands are checked again when they run here
+    let presented: Stringy = command.app_id.clone();
+    let (app_id, role) = match authenticate(&global_state.settings.command_auth, command.app_id) {
+        Ok(auth) => auth,
+        Err(err) => {
+            log!(LogLevel::Warn, "Rejected unauthenticated command: {}", err);
+            return Ok(denied(command.command_type, err.to_string()));
+        }
+    };
+
+    let required: Role = required_role(&command.command_type, &app_id);
+    if role < required {
+        log!(
+            LogLevel::Warn,
+            "Rejected {:?} on {} from a {:?} token",
+            command.command_type,
+            app_id,
+            role
+        );
+        return Ok(denied(
+            command.command_type,
+            format!("Permission denied, requires {:?}", required),
+        ));
+    }
+
+    if let CommandType::Custom(raw) = &command.command_type {
+        if let Some(inner) = parse_fanout(raw) {
+            return fanout_command(global_state, presented, &app_id, raw, inner, source, trace)
+                .await;
+        }
+        if let Some(inner) = parse_batch(raw) {
+            return batch_command(presented, &app_id, raw, inner, source, trace).await;
+        }
+        if let Some(inner) = parse_async(raw) {
+            return async_command(presented, &app_id, raw, inner, source).await;
+        }
+    }
+
     match command.command_type {
         artisan_middleware::aggregator::CommandType::Start => {
-            match start_application(&app_id).await {
+            // Starting by hand is how a tripped crash loop breaker gets reset
+            if let Err(err) = reset_breaker(&app_id).await {
+                log!(LogLevel::Warn, "{}", err);
+            }
+
+            match trace.time(Stage::Systemd, start_application(&app_id)).await {
                 Ok(_) => {
+                    if let Err(err) = end_maintenance_when_ready(&app_id).await {
+                        log!(LogLevel::Warn, "{}", err);
+                    }
+
                     return Ok(AppMessage::Response(CommandResponse {
                         app_id,
                         command_type: CommandType::Start,
                         success: true,
                         message: None,
-                    }))
+                    }));
                 }
                 Err(err) => {
                     return Ok(AppMessage::Response(CommandResponse {
@@ -130,7 +809,12 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
                 }));
             }
 
-            match stop_application(&app_id).await {
+            // Visitors get the maintenance page instead of a dead upstream
+            if let Err(err) = begin_maintenance(&app_id, "stopped").await {
+                log!(LogLevel::Warn, "{}", err);
+            }
+
+            match trace.time(Stage::Systemd, stop_application(&app_id)).await {
                 Ok(_) => {
                     return Ok(AppMessage::Response(CommandResponse {
                         app_id,
@@ -141,6 +825,9 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
                 }
                 Err(err) => {
                     log!(LogLevel::Error, "Failed to stop {}, {}", app_id, err);
+                    if let Err(err) = end_maintenance_when_ready(&app_id).await {
+                        log!(LogLevel::Warn, "{}", err);
+                    }
                     return Ok(AppMessage::Response(CommandResponse {
                         app_id,
                         command_type: CommandType::Stop,
@@ -162,7 +849,10 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
                 }));
             }
 
-            match reload_application(&app_id).await {
+            match trace
+                .time(Stage::Systemd, reload_application(&app_id))
+                .await
+            {
                 Ok(_) => {
                     return Ok(AppMessage::Response(CommandResponse {
                         app_id,
@@ -183,36 +873,19 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
             }
         }
         artisan_middleware::aggregator::CommandType::Status => {
-            let store_lock = APP_STATUS_ARRAY
-                .try_read_with_timeout(Some(Duration::from_secs(5)))
-                .await?;
-
-            if store_lock.contains_key(&app_id) {
-                match store_lock.get(&app_id) {
-                    Some(app) => {
-                        let mut app = app.clone();
-                        app.timestamp = 0;
-
-                        let response_data = AppMessage::Response(CommandResponse {
-                            app_id,
-                            command_type: CommandType::Status,
-                            success: true,
-                            message: app.to_json(),
-                        });
-                        return Ok(response_data);
-                    }
-                    None => {
-                        return Ok(AppMessage::Response(CommandResponse {
-                            app_id: app_id.clone(),
-                            command_type: CommandType::Status,
-                            success: false,
-                            message: Some(format!("The app: {}, wasn't in our store", app_id)),
-                        }))
-                    }
-                }
-            }
+            if let Some(mut app) = APP_STATUS_ARRAY.get(&app_id).await? {
+                Arc::make_mut(&mut app).timestamp = 0;
+                let message: Option<String> =
+                    outbound_status_json(&global_state.settings.telemetry, &app_id, &app).await;
 
-            drop(store_lock);
+                let response_data = AppMessage::Response(CommandResponse {
+                    app_id,
+                    command_type: CommandType::Status,
+                    success: true,
+                    message,
+                });
+                return Ok(response_data);
+            }
 
             return Ok(AppMessage::Response(CommandResponse {
                 app_id: app_id.clone(),
@@ -222,33 +895,32 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
             }));
         }
         artisan_middleware::aggregator::CommandType::AllStatus => {
-            let store_lock = APP_STATUS_ARRAY
-                .try_read_with_timeout(Some(Duration::from_secs(2)))
-                .await?;
-
-            let mut status_vec = Vec::new();
+            let mut response: AllStatusResponse = AllStatusResponse {
+                schema_version: ALL_STATUS_SCHEMA_VERSION,
+                statuses: Vec::new(),
+            };
 
-            for (id, status) in store_lock.iter() {
+            for (id, entry) in APP_STATUS_ARRAY.entries().await? {
                 log!(LogLevel::Debug, "Sending status of: {}", id);
-                status_vec.push(status.clone().to_json().unwrap());
+                // Only the snapshot is held while serializing, not the lock
+                let status = entry.try_read().await?.clone();
+                if let Some(value) =
+                    outbound_status_value(&global_state.settings.telemetry, &id, &status).await
+                {
+                    response.statuses.push(value);
+                }
             }
 
-            status_vec.shrink_to_fit();
-            let mut data = String::new();
-
-            for status in status_vec {
-                data.push_str(&format!("{},", status));
-            }
+            let message: String = serde_json::to_string(&response)
+                .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
 
             let response_data = AppMessage::Response(CommandResponse {
                 app_id,
                 command_type: CommandType::AllStatus,
                 success: true,
-                message: Some(format!("[{}]", data).replace(",]", "]")),
+                message: Some(message),
             });
 
-            drop(store_lock);
-
             return Ok(response_data);
         }
 
@@ -257,6 +929,22 @@ async fn command_processor(command: Command) -> Result<AppMessage, ErrorArrayIte
             return Ok(AppMessage::ManagerInfo(manager_data));
         }
 
+        artisan_middleware::aggregator::CommandType::Custom(raw) => {
+            if let Ok(command) = CustomCommand::parse(&raw) {
+                if let Some(reason) =
+                    withheld_command(&global_state.settings.telemetry, &app_id, &command)
+                {
+                    return Ok(AppMessage::Response(CommandResponse {
+                        app_id,
+                        command_type: CommandType::Custom(raw),
+                        success: false,
+                        message: Some(reason),
+                    }));
+                }
+            }
+            return custom_command_processor(app_id, raw).await;
+        }
+
         _ => {
             return Ok(AppMessage::Response(CommandResponse {
                 app_id,
@@ -0,0 +1,122 @@
+use artisan_middleware::aggregator::{AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use std::sync::Arc;
+
+use crate::applications::child::APP_STATUS_ARRAY;
+use crate::applications::hibernation::is_hibernating;
+use crate::applications::pause::is_paused;
+use crate::system::units::{format_bytes, format_percent};
+
+/// Errors shown under each app, the status keeps the last five anyway
+const RECENT_ERRORS: usize = 3;
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+const DIM: &str = "2";
+const BOLD: &str = "1";
+
+/// Escape codes are written by hand, the `colored` crate turns itself off
+/// when our stdout isn't a terminal, which under systemd it never is
+fn paint(text: &str, code: &str, color: bool) -> String {
+    match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text.to_owned(),
+    }
+}
+
+fn status_color(status: &Status) -> &'static str {
+    match status {
+        Status::Running => GREEN,
+        Status::Warning | Status::Starting | Status::Stopping => YELLOW,
+        Status::Failed | Status::Stopped => RED,
+        _ => DIM,
+    }
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+/// One block of aligned lines per app: status line, usage, recent errors
+pub fn render_status(name: &Stringy, status: &AppStatus, color: bool) -> String {
+    let state: Status = status.app_data.get_status();
+    let mut lines: Vec<String> = Vec::new();
+
+    // Statuses the middleware has no word for. A hibernated app is stopped
+    // as far as it knows, but comes back on its own.
+    let (label, code): (String, &str) = if is_paused(name.as_str()) {
+        (String::from("Paused"), YELLOW)
+    } else if is_hibernating(name.as_str()) {
+        (String::from("Hibernated"), DIM)
+    } else {
+        (format!("{:?}", state), status_color(&state))
+    };
+
+    // Padded before painting so the escape codes don't throw the columns off
+    lines.push(format!(
+        "{} {} {} {}",
+        paint("●", code, color),
+        paint(&format!("{:<24}", name.to_string()), BOLD, color),
+        paint(&format!("{:<10}", label), code, color),
+        match status.uptime {
+            Some(uptime) => format!(
+                "up {:<14} pid {}",
+                format_uptime(uptime),
+                status.app_data.get_pid()
+            ),
+            None => paint("down", DIM, color),
+        }
+    ));
+
+    if let Some(metrics) = &status.metrics {
+        let network: String = match &metrics.other {
+            Some(net) => format!(
+                "rx {:<10} tx {}",
+                format_bytes(net.rx_bytes),
+                format_bytes(net.tx_bytes)
+            ),
+            None => String::new(),
+        };
+
+        lines.push(format!(
+            "  cpu {:<8} mem {:<10} {}",
+            format_percent(metrics.cpu_usage as f64),
+            format_bytes(metrics.memory_usage as u64),
+            network
+        ));
+    }
+
+    let errors: &Vec<ErrorArrayItem> = &status.app_data.state.error_log;
+    for error in errors.iter().rev().take(RECENT_ERRORS) {
+        lines.push(format!("  {} {}", paint("!", RED, color), error.err_mesg));
+    }
+    if errors.len() > RECENT_ERRORS {
+        lines.push(paint(
+            &format!("  … {} more", errors.len() - RECENT_ERRORS),
+            DIM,
+            color,
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Every app, sorted by name so the output is stable between calls
+pub async fn render_all_status(color: bool) -> Result<String, ErrorArrayItem> {
+    let mut apps: Vec<(Stringy, Arc<AppStatus>)> =
+        APP_STATUS_ARRAY.snapshot().await?.into_iter().collect();
+    apps.sort_by_key(|(name, _)| name.to_string());
+
+    Ok(apps
+        .iter()
+        .map(|(name, status)| render_status(name, status, color))
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
@@ -0,0 +1,240 @@
+use artisan_middleware::aggregator::{AppMessage, Command, CommandResponse, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
+use simple_comms::network::send_receive::send_data;
+use simple_comms::protocol::{
+    flags::Flags, header::EOL, io_helpers::read_until, message::ProtocolMessage, proto::Proto,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{sleep, timeout};
+
+use crate::network::handle_command;
+use crate::system::auth::{authenticate, retarget, strip_token};
+use crate::system::control::{GlobalState, GLOBAL_STATE};
+use crate::system::events::ManagerEvent;
+use crate::system::latency::CommandTrace;
+use crate::system::settings::{CommandSessionSettings, TelemetryLevel};
+
+/// A request on an open session, the id comes back on its response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRequest {
+    pub id: u64,
+    /// Without a token, the one the session was opened with is used
+    pub command: Command,
+}
+
+/// What the manager sends on an open session. Responses come back in the
+/// order their commands finish, not the order they were sent in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SessionFrame {
+    Response {
+        id: u64,
+        message: AppMessage,
+    },
+    Event {
+        event: ManagerEvent,
+    },
+    /// Sent when the session was quiet for a while, needs no answer
+    Ping,
+}
+
+fn session_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+async fn send_frame<S>(stream: &mut WriteHalf<S>, frame: SessionFrame) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let message: ProtocolMessage<SessionFrame> =
+        ProtocolMessage::new(Flags::ENCRYPTED | Flags::COMPRESSED, frame)?;
+    let message_bytes: Vec<u8> = message.format().await?;
+    send_data(stream, message_bytes, Proto::TCP).await
+}
+
+/// Events of apps that share no telemetry don't leave the node
+fn pushed(gs: &GlobalState, event: &ManagerEvent) -> bool {
+    match event.app_name() {
+        Some(name) => gs.settings.telemetry.level_for(name.as_str()) != TelemetryLevel::Nothing,
+        None => true,
+    }
+}
+
+/// Reads requests until the portal hangs up or goes quiet, every request is
+/// handled on its own task and answered through `frames`
+async fn read_requests<S>(
+    mut stream: ReadHalf<S>,
+    source: SocketAddr,
+    presented: Stringy,
+    settings: &CommandSessionSettings,
+    frames: mpsc::Sender<SessionFrame>,
+) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let in_flight: Arc<Semaphore> = Arc::new(Semaphore::new(settings.max_in_flight.max(1)));
+
+    loop {
+        let read = timeout(
+            Duration::from_secs(settings.idle_secs.max(1)),
+            read_until(&mut stream, EOL.to_vec()),
+        )
+        .await;
+        let mut buffer: Vec<u8> = match read {
+            Ok(Ok(buffer)) if !buffer.is_empty() => buffer,
+            Ok(_) => return Ok(()),
+            Err(_) => {
+                log!(LogLevel::Debug, "Closing the idle session of {}", source);
+                return Ok(());
+            }
+        };
+        if let Some(pos) = buffer.windows(EOL.len()).rposition(|window| window == EOL) {
+            buffer.truncate(pos);
+        }
+
+        // Frames end at EOL, one that doesn't parse is skipped on its own
+        let request: SessionRequest =
+            match ProtocolMessage::<SessionRequest>::from_bytes(&buffer).await {
+                Ok(message) => message.get_payload().await,
+                Err(err) => {
+                    log!(
+                        LogLevel::Warn,
+                        "Skipped a bad frame from {}: {}",
+                        source,
+                        err
+                    );
+                    continue;
+                }
+            };
+
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|err| session_error(err.to_string()))?;
+        let frames: mpsc::Sender<SessionFrame> = frames.clone();
+        let command: Command = Command {
+            app_id: retarget(&presented, &strip_token(&request.command.app_id)),
+            command_type: request.command.command_type,
+        };
+
+        tokio::spawn(async move {
+            let message: AppMessage = match &command.command_type {
+                CommandType::Custom(raw) if raw == "session" => {
+                    AppMessage::Response(CommandResponse {
+                        app_id: strip_token(&command.app_id),
+                        command_type: command.command_type.clone(),
+                        success: false,
+                        message: Some("The session is already open".to_owned()),
+                    })
+                }
+                _ => {
+                    let mut trace: CommandTrace = CommandTrace::new();
+                    let result: Result<AppMessage, ErrorArrayItem> =
+                        handle_command(command, source, &mut trace).await;
+                    trace.finish();
+
+                    // A failed command only ends its own request, not the session
+                    result.unwrap_or_else(|err| {
+                        AppMessage::Response(CommandResponse {
+                            app_id: "".into(),
+                            command_type: CommandType::Custom("session".to_owned()),
+                            success: false,
+                            message: Some(err.to_string()),
+                        })
+                    })
+                }
+            };
+
+            let _ = frames
+                .send(SessionFrame::Response {
+                    id: request.id,
+                    message,
+                })
+                .await;
+            drop(permit);
+        });
+    }
+}
+
+/// Writes responses as they're ready and events as they're published,
+/// pinging the portal when there was nothing to send for a while
+async fn write_frames<S>(
+    mut stream: WriteHalf<S>,
+    gs: &GlobalState,
+    presented: Stringy,
+    mut frames: mpsc::Receiver<SessionFrame>,
+) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let settings: &CommandSessionSettings = &gs.settings.command_sessions;
+    let mut events: broadcast::Receiver<ManagerEvent> = gs.events.subscribe();
+
+    loop {
+        let frame: SessionFrame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+            event = events.recv(), if settings.push_events => match event {
+                Ok(event) if pushed(gs, &event) => SessionFrame::Event { event },
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    log!(LogLevel::Warn, "A session missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = sleep(Duration::from_secs(settings.keepalive_secs.max(1))) => {
+                // Tokens expire, the session goes with them
+                authenticate(&gs.settings.command_auth, presented.clone())?;
+                SessionFrame::Ping
+            }
+        };
+
+        send_frame(&mut stream, frame).await?;
+    }
+}
+
+/// Keeps the connection open after a `session` command was answered. The
+/// portal sends framed `SessionRequest`s on it and gets their responses,
+/// and the events of the node, back as `SessionFrame`s. Every request is
+/// authenticated and audited like a command on its own connection would be.
+pub async fn run_session<S>(
+    stream: S,
+    source: SocketAddr,
+    presented: Stringy,
+) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let gs: &Arc<GlobalState> = GLOBAL_STATE.get().ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::AppState,
+            "Failed to get the app state from the global state",
+        )
+    })?;
+    let settings: &CommandSessionSettings = &gs.settings.command_sessions;
+
+    log!(LogLevel::Info, "Opened a session for {}", source);
+    let (reader, writer) = tokio::io::split(stream);
+    let (frames, pending) = mpsc::channel::<SessionFrame>(settings.max_in_flight.max(1));
+
+    let result: Result<(), ErrorArrayItem> = tokio::select! {
+        result = read_requests(reader, source, presented.clone(), settings, frames) => result,
+        result = write_frames(writer, gs, presented, pending) => result,
+    };
+
+    log!(LogLevel::Info, "Closed the session of {}", source);
+    result
+}
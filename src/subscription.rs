@@ -0,0 +1,274 @@
+use artisan_middleware::aggregator::{AppStatus, Metrics};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use serde_json::Value;
+use simple_comms::network::send_receive::send_data;
+use simple_comms::protocol::{flags::Flags, message::ProtocolMessage, proto::Proto};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::applications::child::APP_STATUS_ARRAY;
+use crate::system::alerts::Alert;
+use crate::system::auth::authenticate;
+use crate::system::control::{GlobalState, GLOBAL_STATE};
+use crate::system::events::ManagerEvent;
+use crate::system::settings::{SubscriptionSettings, TelemetryLevel};
+use crate::system::telemetry::{outbound_metrics, outbound_status_value};
+
+/// Open subscriptions, the portal registration loop backs off while there
+/// are any
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+/// What the manager pushes on a subscribed connection
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PushFrame {
+    /// The app's status changed since it was last pushed, or this is the
+    /// first push of the subscription
+    Status {
+        app_id: String,
+        status: Value,
+    },
+    /// The app is no longer managed here
+    Removed {
+        app_id: String,
+    },
+    Alert {
+        alert: Alert,
+    },
+    Metrics {
+        app_id: String,
+        metrics: Metrics,
+    },
+    /// Sent on every keepalive, needs no answer
+    Ping,
+}
+
+/// Counts the subscription for as long as it's open
+struct Subscribed;
+
+impl Subscribed {
+    fn new() -> Self {
+        SUBSCRIBERS.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Subscribed {
+    fn drop(&mut self) {
+        SUBSCRIBERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn subscribers() -> usize {
+    SUBSCRIBERS.load(Ordering::Relaxed)
+}
+
+fn subscription_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Statuses without their timestamp and metrics, those change on every
+/// update and go out as samples instead
+async fn comparable_status(gs: &GlobalState, name: &Stringy, status: &AppStatus) -> Option<Value> {
+    let mut status: AppStatus = status.clone();
+    status.timestamp = 0;
+    status.metrics = None;
+    outbound_status_value(&gs.settings.telemetry, name, &status).await
+}
+
+fn fingerprint(status: &Value) -> u64 {
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    status.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The app's status when it differs from the one pushed last
+async fn status_frame(
+    gs: &GlobalState,
+    name: &Stringy,
+    status: &AppStatus,
+    pushed: &mut HashMap<String, u64>,
+) -> Option<PushFrame> {
+    let value: Value = comparable_status(gs, name, status).await?;
+    let print: u64 = fingerprint(&value);
+    match pushed.insert(name.to_string(), print) != Some(print) {
+        true => Some(PushFrame::Status {
+            app_id: name.to_string(),
+            status: value,
+        }),
+        false => None,
+    }
+}
+
+/// Statuses that changed since `pushed` was last updated, along with the
+/// apps that are gone
+async fn status_deltas(
+    gs: &GlobalState,
+    pushed: &mut HashMap<String, u64>,
+) -> Result<Vec<PushFrame>, ErrorArrayItem> {
+    let mut frames: Vec<PushFrame> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+
+    for (name, entry) in APP_STATUS_ARRAY.entries().await? {
+        let status: AppStatus = entry.try_read().await?.clone();
+        seen.push(name.to_string());
+        frames.extend(status_frame(gs, &name, &status, pushed).await);
+    }
+
+    pushed.retain(|name, _| {
+        let kept: bool = seen.contains(name);
+        if !kept {
+            frames.push(PushFrame::Removed {
+                app_id: name.clone(),
+            });
+        }
+        kept
+    });
+
+    Ok(frames)
+}
+
+/// The status of the app a lifecycle event is about, pushed right away
+/// instead of on the next sweep
+async fn lifecycle_frames(
+    gs: &GlobalState,
+    event: &ManagerEvent,
+    pushed: &mut HashMap<String, u64>,
+) -> Result<Vec<PushFrame>, ErrorArrayItem> {
+    let name: &Stringy = match event {
+        ManagerEvent::AppStarted { name, .. }
+        | ManagerEvent::AppDied { name }
+        | ManagerEvent::StatusChanged { name, .. } => name,
+        _ => return Ok(Vec::new()),
+    };
+
+    let frame: Option<PushFrame> = match APP_STATUS_ARRAY.get(name).await? {
+        Some(status) => status_frame(gs, name, &status, pushed).await,
+        None => pushed.remove(name.as_str()).map(|_| PushFrame::Removed {
+            app_id: name.to_string(),
+        }),
+    };
+    Ok(frame.into_iter().collect())
+}
+
+/// Current metrics of every app, as far as their telemetry policy lets them
+/// leave the node
+async fn metric_samples(gs: &GlobalState) -> Result<Vec<PushFrame>, ErrorArrayItem> {
+    let mut frames: Vec<PushFrame> = Vec::new();
+
+    for (name, entry) in APP_STATUS_ARRAY.entries().await? {
+        let metrics: Option<Metrics> = entry.try_read().await?.metrics.clone();
+        let level: TelemetryLevel = gs.settings.telemetry.level_for(name.as_str());
+        if let Some(metrics) = outbound_metrics(level, metrics) {
+            frames.push(PushFrame::Metrics {
+                app_id: name.to_string(),
+                metrics,
+            });
+        }
+    }
+
+    Ok(frames)
+}
+
+/// Alerts of apps that share no telemetry don't leave the node
+fn pushed_alert(gs: &GlobalState, event: &ManagerEvent) -> Option<Alert> {
+    if let Some(name) = event.app_name() {
+        if gs.settings.telemetry.level_for(name.as_str()) == TelemetryLevel::Nothing {
+            return None;
+        }
+    }
+    Alert::of(event)
+}
+
+fn ticker(secs: u64) -> Interval {
+    let mut ticker: Interval = interval(Duration::from_secs(secs.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// Keeps pushing to the connection after a `subscribe` command was
+/// answered, until the portal hangs up. Every status goes out once at
+/// first, then as lifecycle events report it changing. The periodic sweep
+/// only catches what no event covers, like errors and uptime.
+pub async fn stream_subscription<S>(
+    stream: &mut S,
+    source: SocketAddr,
+    presented: Stringy,
+) -> Result<(), ErrorArrayItem>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let gs: &Arc<GlobalState> = GLOBAL_STATE.get().ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::AppState,
+            "Failed to get the app state from the global state",
+        )
+    })?;
+    let settings: &SubscriptionSettings = &gs.settings.subscriptions;
+
+    let _subscribed: Subscribed = Subscribed::new();
+    log!(LogLevel::Info, "{} subscribed to the node", source);
+
+    let mut events: broadcast::Receiver<ManagerEvent> = gs.events.subscribe();
+    let mut pushed: HashMap<String, u64> = HashMap::new();
+    let mut statuses: Interval = ticker(settings.interval_secs);
+    let mut samples: Interval = ticker(settings.metrics_secs);
+    let mut keepalive: Interval = ticker(settings.keepalive_secs);
+    let mut probe: [u8; 1] = [0; 1];
+
+    loop {
+        let frames: Vec<PushFrame> = tokio::select! {
+            // Subscribers don't send anything, a read returning means they're gone
+            _ = stream.read(&mut probe) => {
+                log!(LogLevel::Info, "{} unsubscribed", source);
+                return Ok(());
+            }
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let mut frames: Vec<PushFrame> =
+                        lifecycle_frames(gs, &event, &mut pushed).await?;
+                    if let Some(alert) = pushed_alert(gs, &event) {
+                        frames.push(PushFrame::Alert { alert });
+                    }
+                    frames
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log!(LogLevel::Warn, "The subscription of {} missed {} events", source, skipped);
+                    Vec::new()
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = statuses.tick() => status_deltas(gs, &mut pushed).await?,
+            _ = samples.tick() => metric_samples(gs).await?,
+            _ = keepalive.tick() => {
+                // Tokens expire, the subscription goes with them
+                authenticate(&gs.settings.command_auth, presented.clone())?;
+                vec![PushFrame::Ping]
+            }
+        };
+
+        for frame in frames {
+            let data: String =
+                serde_json::to_string(&frame).map_err(|err| subscription_error(err.to_string()))?;
+            let message: ProtocolMessage<String> =
+                ProtocolMessage::new(Flags::ENCRYPTED | Flags::COMPRESSED, data)?;
+            let message_bytes: Vec<u8> = message.format().await?;
+            if send_data(stream, message_bytes, Proto::TCP).await.is_err() {
+                log!(LogLevel::Info, "{} unsubscribed", source);
+                return Ok(());
+            }
+        }
+    }
+}
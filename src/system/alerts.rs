@@ -0,0 +1,204 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use gethostname::gethostname;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::applications::digest::{queue_mail, OutgoingMail};
+
+use super::control::GlobalState;
+use super::events::ManagerEvent;
+use super::pressure::PressureLevel;
+use super::settings::AlertSettings;
+use super::timezone::format_timestamp;
+
+const HOUR: u64 = 60 * 60;
+
+/// An operator alert, `key` is what duplicates are recognized by
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Alert {
+    pub key: String,
+    pub severity: &'static str,
+    pub app: Option<String>,
+    pub summary: String,
+}
+
+impl Alert {
+    pub fn of(event: &ManagerEvent) -> Option<Self> {
+        let (key, severity) = match event {
+            ManagerEvent::SystemAppOutsideSystemd { name, .. } => {
+                (format!("outside-systemd:{}", name), "critical")
+            }
+            ManagerEvent::CrashLoop { name, .. } => (format!("crash-loop:{}", name), "critical"),
+            ManagerEvent::RestartsExhausted { name, .. } => {
+                (format!("restarts-exhausted:{}", name), "critical")
+            }
+            ManagerEvent::OomKilled { name, .. } => (format!("oom-killed:{}", name), "warning"),
+            ManagerEvent::DependencyUnreachable {
+                name, dependency, ..
+            } => (format!("dependency:{}:{}", name, dependency), "warning"),
+            ManagerEvent::QuotaSuspended { name, .. } => {
+                (format!("quota-suspended:{}", name), "warning")
+            }
+            ManagerEvent::BinaryQuarantined { name, .. } => {
+                (format!("quarantined:{}", name), "critical")
+            }
+            ManagerEvent::LivenessFailed { name, .. } => (format!("liveness:{}", name), "warning"),
+            ManagerEvent::EgressBlocked {
+                name, destination, ..
+            } => (format!("egress:{}:{}", name, destination), "warning"),
+            ManagerEvent::RepositoryChanged {
+                name,
+                commit,
+                alert: true,
+            } => (format!("repository:{}:{}", name, commit), "info"),
+            ManagerEvent::MemoryPressure {
+                level: PressureLevel::Critical,
+                ..
+            } => (String::from("memory-pressure"), "warning"),
+            ManagerEvent::StatusStoreFull { .. } => {
+                (String::from("status-store-capacity"), "warning")
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            key,
+            severity,
+            app: event.app_name().map(|name| name.to_string()),
+            summary: event.to_string(),
+        })
+    }
+}
+
+/// What's been mailed, kept for rate limiting and deduplication
+#[derive(Default)]
+struct Sent {
+    /// Alert keys with when they were last mailed and how many duplicates
+    /// were held back since
+    by_key: HashMap<String, (u64, usize)>,
+    /// When each alert of the last hour was mailed, oldest first
+    recent: VecDeque<u64>,
+}
+
+impl Sent {
+    /// Whether the alert should go out now, counts it as suppressed if not
+    fn admit(&mut self, settings: &AlertSettings, alert: &Alert, now: u64) -> Option<usize> {
+        while self
+            .recent
+            .front()
+            .map_or(false, |sent| now.saturating_sub(*sent) >= HOUR)
+        {
+            self.recent.pop_front();
+        }
+
+        if let Some((last_sent, suppressed)) = self.by_key.get_mut(&alert.key) {
+            if now.saturating_sub(*last_sent) < settings.dedup_window_secs {
+                *suppressed += 1;
+                return None;
+            }
+        }
+
+        if self.recent.len() >= settings.max_per_hour {
+            log!(
+                LogLevel::Warn,
+                "Alert rate limit reached, not mailing: {}",
+                alert.summary
+            );
+            return None;
+        }
+
+        self.recent.push_back(now);
+        let suppressed: usize = self
+            .by_key
+            .insert(alert.key.clone(), (now, 0))
+            .map_or(0, |(_, suppressed)| suppressed);
+        Some(suppressed)
+    }
+}
+
+fn compose(alert: &Alert, suppressed: usize, now: u64) -> String {
+    let mut body: String = String::new();
+
+    let _ = writeln!(body, "{}\n", alert.summary);
+    let _ = writeln!(body, "Node: {}", gethostname().to_string_lossy());
+    let _ = writeln!(body, "Severity: {}", alert.severity);
+    if let Some(app) = &alert.app {
+        let _ = writeln!(body, "Application: {}", app);
+    }
+    let _ = writeln!(body, "Alert: {}", alert.key);
+    let _ = writeln!(
+        body,
+        "Timestamp: {}",
+        format_timestamp(now, alert.app.as_deref())
+    );
+
+    if suppressed > 0 {
+        let _ = writeln!(
+            body,
+            "\n{} more of this alert were held back since the last mail",
+            suppressed
+        );
+    }
+
+    body
+}
+
+/// Mails operator alerts for events that need a human, through ais_mailler
+pub async fn send_alerts(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &AlertSettings = &gs.settings.alerts;
+    if !settings.enabled || settings.recipients.is_empty() {
+        return Ok(());
+    }
+
+    let mut receiver = gs.events.subscribe();
+    let mut sent: Sent = Sent::default();
+
+    loop {
+        let event: ManagerEvent = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                log!(LogLevel::Warn, "Alerts missed {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let alert: Alert = match Alert::of(&event) {
+            Some(alert) => alert,
+            None => continue,
+        };
+
+        let now: u64 = current_timestamp();
+        let suppressed: usize = match sent.admit(settings, &alert, now) {
+            Some(suppressed) => suppressed,
+            None => continue,
+        };
+
+        let mail: OutgoingMail = OutgoingMail {
+            to: settings.recipients.clone(),
+            subject: format!(
+                "[{}] {} on {}",
+                alert.severity,
+                alert.summary,
+                gethostname().to_string_lossy()
+            ),
+            body: compose(&alert, suppressed, now),
+        };
+
+        match queue_mail(&format!("alert-{}", alert.key.replace(':', "-")), &mail) {
+            Ok(_) => log!(LogLevel::Info, "Queued alert {}", alert.key),
+            Err(err) => log!(
+                LogLevel::Warn,
+                "Failed to queue alert {}: {}",
+                alert.key,
+                err
+            ),
+        }
+    }
+}
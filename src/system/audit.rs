@@ -0,0 +1,107 @@
+use artisan_middleware::aggregator::{AppMessage, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use super::jobs::parse_async;
+use super::storage::{off_worker, storage};
+
+/// Append only record of the commands the listener handled
+const AUDIT_LOG: &str = "audit/commands.jsonl";
+
+/// Most entries a single tail returns
+pub const MAX_AUDIT_TAIL: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub source: String,
+    pub app_id: Stringy,
+    pub command: String,
+    pub success: bool,
+    /// Only kept for failures, successful status replies are far too large
+    pub message: Option<String>,
+}
+
+/// Name of the command when it's one we audit
+pub fn audited_command(command_type: &CommandType) -> Option<&'static str> {
+    match command_type {
+        CommandType::Start => Some("start"),
+        CommandType::Stop => Some("stop"),
+        CommandType::Restart => Some("restart"),
+        CommandType::Status => Some("status"),
+        // Moving an app takes it off this node for good
+        CommandType::Custom(raw) if raw.starts_with("migrate ") && raw != "migrate status" => {
+            Some("migrate")
+        }
+        CommandType::Custom(raw) if raw.starts_with("fanout ") => Some("fanout"),
+        // Every item is audited as well, as the command it ran
+        CommandType::Custom(raw) if raw.starts_with("batch ") => Some("batch"),
+        // A job is audited as the command it runs, when it's queued
+        CommandType::Custom(raw) if raw.starts_with("async ") => match parse_async(raw) {
+            Some(Ok(inner)) => audited_command(&inner),
+            _ => None,
+        },
+        CommandType::Custom(raw) if raw == "quota lift" => Some("quota lift"),
+        CommandType::Custom(raw) if raw == "integrity verify" => Some("integrity verify"),
+        _ => None,
+    }
+}
+
+/// Appends the outcome of a command to the audit log
+pub async fn record_command(
+    source: SocketAddr,
+    app_id: &Stringy,
+    command: &str,
+    result: &Result<AppMessage, ErrorArrayItem>,
+) -> Result<(), ErrorArrayItem> {
+    let (success, message) = match result {
+        Ok(AppMessage::Response(response)) => match response.success {
+            true => (true, None),
+            false => (false, response.message.clone()),
+        },
+        Ok(_) => (true, None),
+        Err(err) => (false, Some(err.to_string())),
+    };
+
+    let entry: AuditEntry = AuditEntry {
+        timestamp: current_timestamp(),
+        source: source.to_string(),
+        app_id: app_id.clone(),
+        command: command.to_owned(),
+        success,
+        message,
+    };
+
+    let line: String = serde_json::to_string(&entry)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+    off_worker(move || storage()?.append_line(AUDIT_LOG, &line)).await
+}
+
+/// The last `count` entries of the audit log, oldest first
+pub fn audit_tail(count: usize) -> Result<Vec<AuditEntry>, ErrorArrayItem> {
+    let count: usize = count.min(MAX_AUDIT_TAIL);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut entries: VecDeque<AuditEntry> = VecDeque::with_capacity(count);
+
+    for line in storage()?.read_lines(AUDIT_LOG)? {
+        // A torn line from a crash mid write shouldn't hide the rest
+        let entry: AuditEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+
+        if entries.len() == count {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    Ok(entries.into())
+}
@@ -0,0 +1,113 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use super::settings::CommandAuthSettings;
+
+/// Separates the token from the app id, `<token>#<app_id>`. The middleware
+/// `Command` has no field of its own for it.
+const TOKEN_SEPARATOR: char = '#';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    ReadOnly,
+    Operator,
+    Admin,
+}
+
+/// What the portal signs, hex encoded json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClaims {
+    pub subject: String,
+    pub role: Role,
+    pub expires: u64,
+}
+
+fn unauthorized(message: &str) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::Unauthorized, message)
+}
+
+fn load_auth_key(path: &str) -> Result<VerifyingKey, ErrorArrayItem> {
+    let raw: String = fs::read_to_string(path)?;
+    let bytes: [u8; 32] = hex::decode(raw.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| {
+            ErrorArrayItem::new(Errors::GeneralError, format!("{} is not a valid key", path))
+        })?;
+
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+}
+
+/// Tokens are `hex(claims).hex(signature)`, signed with the portal's key
+fn verify_token(key: &VerifyingKey, token: &str) -> Result<TokenClaims, ErrorArrayItem> {
+    let (claims, signature) = token
+        .split_once('.')
+        .ok_or_else(|| unauthorized("Malformed token"))?;
+
+    let claims: Vec<u8> = hex::decode(claims).map_err(|_| unauthorized("Malformed token"))?;
+    let signature: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| unauthorized("Malformed token"))?;
+
+    key.verify(&claims, &Signature::from_bytes(&signature))
+        .map_err(|_| unauthorized("Token signature verification failed"))?;
+
+    let claims: TokenClaims =
+        serde_json::from_slice(&claims).map_err(|_| unauthorized("Malformed token claims"))?;
+
+    if claims.expires < current_timestamp() {
+        return Err(unauthorized("Token expired"));
+    }
+
+    Ok(claims)
+}
+
+/// Puts a token in front of an app id, for commands we send to other managers
+pub fn attach_token(token: &str, app_id: &Stringy) -> Stringy {
+    Stringy::from(format!("{}{}{}", token, TOKEN_SEPARATOR, app_id))
+}
+
+/// The token the caller presented, put in front of another app id. Every
+/// item of a batch runs with the caller's own token.
+pub fn retarget(presented: &Stringy, app_id: &Stringy) -> Stringy {
+    match presented.split_once(TOKEN_SEPARATOR) {
+        Some((token, _)) => attach_token(token, app_id),
+        None => app_id.clone(),
+    }
+}
+
+/// The app id of a command without its token, safe to log
+pub fn strip_token(app_id: &Stringy) -> Stringy {
+    match app_id.split_once(TOKEN_SEPARATOR) {
+        Some((_, app_id)) => Stringy::from(app_id),
+        None => app_id.clone(),
+    }
+}
+
+/// Splits the token off the app id of a command and works out the caller's
+/// role. With auth disabled every caller is an admin.
+pub fn authenticate(
+    settings: &CommandAuthSettings,
+    app_id: Stringy,
+) -> Result<(Stringy, Role), ErrorArrayItem> {
+    if !settings.enabled {
+        return Ok((app_id, Role::Admin));
+    }
+
+    let raw: String = app_id.to_string();
+    let (token, app_id) = raw
+        .split_once(TOKEN_SEPARATOR)
+        .ok_or_else(|| unauthorized("Command carries no token"))?;
+
+    let key: VerifyingKey = load_auth_key(&settings.key_path)?;
+    let claims: TokenClaims = verify_token(&key, token)?;
+
+    Ok((Stringy::from(app_id), claims.role))
+}
@@ -0,0 +1,132 @@
+use artisan_middleware::aggregator::CommandType;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::applications::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+
+use super::federation::FanoutOutcome;
+
+/// Which client apps a batch runs on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchSelector {
+    /// `*`, every client app on the node
+    All,
+    /// `git:<id>`, the apps deployed from one git project
+    GitProject(String),
+    /// App names, `*` matches any run of characters
+    Pattern(String),
+}
+
+impl BatchSelector {
+    fn parse(selector: &str) -> Self {
+        match selector {
+            "*" => Self::All,
+            selector => match selector.strip_prefix("git:") {
+                Some(id) => Self::GitProject(id.to_owned()),
+                None => Self::Pattern(selector.to_owned()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BatchSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchSelector::All => write!(f, "*"),
+            BatchSelector::GitProject(id) => write!(f, "git:{}", id),
+            BatchSelector::Pattern(pattern) => write!(f, "{}", pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchReport {
+    pub selector: String,
+    /// Keyed by app, they ran in that order
+    pub items: BTreeMap<String, FanoutOutcome>,
+}
+
+/// `*` against any run of characters, everything else literally
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !name.starts_with(first) || !name[first.len()..].ends_with(last) {
+        return false;
+    }
+
+    let mut rest: &str = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The command wrapped in `batch <selector> <command>`, `None` when it
+/// isn't one. The command is read the same way `fanout` reads its own.
+pub fn parse_batch(raw: &str) -> Option<Result<(BatchSelector, CommandType), ErrorArrayItem>> {
+    let inner: &str = raw.strip_prefix("batch ")?.trim();
+
+    let (selector, command) = match inner.split_once(' ') {
+        Some((selector, command)) => (selector, command.trim()),
+        None => {
+            return Some(Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "batch needs a selector and a command",
+            )))
+        }
+    };
+
+    Some(match command {
+        command
+            if command.starts_with("batch")
+                || command.starts_with("fanout")
+                || command.starts_with("async") =>
+        {
+            Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "batch can't wrap batch, fanout or async",
+            ))
+        }
+        "start" => Ok((BatchSelector::parse(selector), CommandType::Start)),
+        "stop" => Ok((BatchSelector::parse(selector), CommandType::Stop)),
+        "restart" => Ok((BatchSelector::parse(selector), CommandType::Restart)),
+        "status" => Ok((BatchSelector::parse(selector), CommandType::Status)),
+        command => Ok((
+            BatchSelector::parse(selector),
+            CommandType::Custom(command.to_owned()),
+        )),
+    })
+}
+
+/// Client apps the selector picks, sorted by name. System applications and
+/// the manager are never part of a batch.
+pub async fn select_apps(selector: &BatchSelector) -> Result<Vec<Stringy>, ErrorArrayItem> {
+    let mut selected: Vec<Stringy> = Vec::new();
+
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        let picked: bool = match selector {
+            BatchSelector::All => true,
+            BatchSelector::GitProject(id) => APP_STATUS_ARRAY
+                .read(&name, |status| status.git_id == id.as_str().into())
+                .await?
+                .unwrap_or(false),
+            BatchSelector::Pattern(pattern) => matches_pattern(pattern, name.as_str()),
+        };
+        if picked {
+            selected.push(name);
+        }
+    }
+
+    selected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    Ok(selected)
+}
@@ -0,0 +1,113 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+use super::control::GlobalState;
+use super::events::ManagerEvent;
+use super::settings::AdaptivePollingSettings;
+
+/// When something last happened that the monitor loop should keep up with
+static LAST_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+
+/// Cuts a stretched out pause short as soon as there's activity
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CadenceReport {
+    pub enabled: bool,
+    pub step_ms: u64,
+    pub min_step_ms: u64,
+    pub max_step_ms: u64,
+    pub quiet_secs: u64,
+    pub last_activity: u64,
+}
+
+/// Tightens the monitor loop, called on status changes, deploys and commands
+pub fn note_activity() {
+    LAST_ACTIVITY.store(current_timestamp(), Ordering::Relaxed);
+    WAKE.notify_waiters();
+}
+
+fn quiet_secs() -> u64 {
+    current_timestamp().saturating_sub(LAST_ACTIVITY.load(Ordering::Relaxed))
+}
+
+/// Pause between the steps of the monitor loop. It stays at the minimum
+/// while the node is busy and doubles for every quiet period after that.
+fn step_delay(settings: &AdaptivePollingSettings) -> Duration {
+    let min: u64 = settings.min_step_ms;
+    if !settings.enabled || settings.quiet_after_secs == 0 {
+        return Duration::from_millis(min);
+    }
+
+    let quiet: u64 = quiet_secs();
+    if quiet < settings.quiet_after_secs {
+        return Duration::from_millis(min);
+    }
+
+    let periods: u32 = (quiet / settings.quiet_after_secs).min(16) as u32;
+    let stretched: u64 = min.saturating_mul(2u64.pow(periods));
+    Duration::from_millis(stretched.min(settings.max_step_ms.max(min)))
+}
+
+/// Sleeps for the current step delay, or until there's activity
+pub async fn pace(settings: &AdaptivePollingSettings) {
+    let delay: Duration = step_delay(settings);
+    tokio::select! {
+        _ = sleep(delay) => (),
+        _ = WAKE.notified() => (),
+    }
+}
+
+pub fn cadence_report(settings: &AdaptivePollingSettings) -> CadenceReport {
+    CadenceReport {
+        enabled: settings.enabled,
+        step_ms: step_delay(settings).as_millis() as u64,
+        min_step_ms: settings.min_step_ms,
+        max_step_ms: settings.max_step_ms,
+        quiet_secs: quiet_secs(),
+        last_activity: LAST_ACTIVITY.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts application lifecycle events as activity
+pub async fn track_activity(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let mut receiver = gs.events.subscribe();
+    note_activity();
+
+    loop {
+        match receiver.recv().await {
+            Ok(
+                ManagerEvent::StatusChanged { .. }
+                | ManagerEvent::UnitStateChanged { .. }
+                | ManagerEvent::AppStarted { .. }
+                | ManagerEvent::AppDied { .. }
+                | ManagerEvent::AppHibernated { .. }
+                | ManagerEvent::AppWoken { .. }
+                | ManagerEvent::DeployQueued { .. }
+                | ManagerEvent::DeployFinished { .. }
+                | ManagerEvent::DeployFailed { .. }
+                | ManagerEvent::CrashLoop { .. },
+            ) => note_activity(),
+            Ok(_) => (),
+            Err(RecvError::Lagged(skipped)) => {
+                log!(
+                    LogLevel::Debug,
+                    "Cadence tracker skipped {} events",
+                    skipped
+                );
+                note_activity();
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::process::Command;
+
+/// Read once, the mount layout doesn't change under a running manager
+static MODE: Lazy<CgroupMode> = Lazy::new(detect_mode);
+
+/// What `provision_slice` found and did at startup
+static PROVISIONING: Lazy<Mutex<Option<SliceProvisioning>>> = Lazy::new(|| Mutex::new(None));
+
+const SLICE_UNIT_PATH: &str = "/etc/systemd/system/artisan.slice";
+
+/// Accounting on makes systemd enable the controllers for everything under
+/// the slice
+const SLICE_UNIT: &str = "[Unit]
+Description=Applications hosted by the artisan manager
+Before=slices.target
+
+[Slice]
+CPUAccounting=yes
+MemoryAccounting=yes
+IOAccounting=yes
+";
+
+/// Controllers limits, weights and usage accounting rely on
+const REQUIRED_CONTROLLERS: [&str; 3] = ["cpu", "memory", "io"];
+
+/// How the host mounts cgroups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CgroupMode {
+    /// cgroup v2 only, everything works
+    Unified,
+    /// v1 controllers with systemd's tree mirrored on a v2 mount under
+    /// /sys/fs/cgroup/unified
+    Hybrid,
+    /// v1 only, systemd's tree lives in the name=systemd hierarchy
+    Legacy,
+    /// None of the above are mounted where systemd puts them
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CgroupReport {
+    pub mode: CgroupMode,
+    pub slice: String,
+    /// Features this host can't back, they stay off rather than failing
+    /// quietly
+    pub unsupported: Vec<&'static str>,
+    /// `None` until startup got to it
+    pub provisioning: Option<SliceProvisioning>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SliceProvisioning {
+    /// Whether the slice's cgroup exists now
+    pub exists: bool,
+    pub unit_file: String,
+    /// Controllers the slice hands to its services
+    pub controllers: Vec<String>,
+    /// Required ones still missing after the repair, limits relying on
+    /// them won't apply
+    pub missing: Vec<String>,
+    /// What was fixed on the way, empty on a healthy node
+    pub repaired: Vec<String>,
+}
+
+fn detect_mode() -> CgroupMode {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        CgroupMode::Unified
+    } else if Path::new("/sys/fs/cgroup/unified/cgroup.controllers").exists() {
+        CgroupMode::Hybrid
+    } else if Path::new("/sys/fs/cgroup/systemd/cgroup.procs").exists() {
+        CgroupMode::Legacy
+    } else {
+        CgroupMode::Unknown
+    }
+}
+
+pub fn cgroup_mode() -> CgroupMode {
+    *MODE
+}
+
+/// Root of the hierarchy systemd keeps its units in, the one with a
+/// cgroup.procs for every service
+pub fn cgroup_root() -> &'static str {
+    match cgroup_mode() {
+        CgroupMode::Unified | CgroupMode::Unknown => "/sys/fs/cgroup",
+        CgroupMode::Hybrid => "/sys/fs/cgroup/unified",
+        CgroupMode::Legacy => "/sys/fs/cgroup/systemd",
+    }
+}
+
+pub fn slice_dir() -> PathBuf {
+    Path::new(cgroup_root()).join("artisan.slice")
+}
+
+pub fn service_dir(service_name: &str) -> PathBuf {
+    slice_dir().join(format!("{}.service", service_name))
+}
+
+/// A file of the service's cgroup. Controller files like memory.max only
+/// exist when `controllers_available`.
+pub fn service_file(service_name: &str, file: &str) -> PathBuf {
+    service_dir(service_name).join(file)
+}
+
+/// Whether the controllers sit on the same hierarchy as systemd's tree, so
+/// the v2 files we read and write exist
+pub fn controllers_available() -> bool {
+    cgroup_mode() == CgroupMode::Unified
+}
+
+pub fn cgroup_report() -> CgroupReport {
+    let mode: CgroupMode = cgroup_mode();
+    let mut unsupported: Vec<&'static str> = Vec::new();
+
+    if mode == CgroupMode::Unknown {
+        unsupported.push("pid tracking");
+    }
+    // Probes see cgroup ids of the v2 hierarchy, there's none on these
+    if matches!(mode, CgroupMode::Legacy | CgroupMode::Unknown) {
+        unsupported.extend(["fork tracking", "bandwidth caps"]);
+    }
+    if !controllers_available() {
+        unsupported.extend([
+            "resource limits",
+            "priority weights",
+            "memory pressure throttling",
+            "oom kill counts",
+            "canary cpu comparison",
+            "build usage accounting",
+        ]);
+    }
+
+    CgroupReport {
+        mode,
+        slice: slice_dir().display().to_string(),
+        unsupported,
+        provisioning: PROVISIONING.lock().ok().and_then(|report| report.clone()),
+    }
+}
+
+fn read_controllers(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|data| data.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+fn provisioning_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+async fn systemctl(args: &[&str]) -> Result<(), ErrorArrayItem> {
+    let status = Command::new("systemctl").args(args).status().await?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(provisioning_error(format!(
+            "systemctl {} exited with {}",
+            args.join(" "),
+            status
+        ))),
+    }
+}
+
+/// Turns a controller on for the children of `dir`, each level above has
+/// to pass it down first
+fn enable_controller(dir: &Path, controller: &str) -> Result<(), ErrorArrayItem> {
+    if read_controllers(&dir.join("cgroup.subtree_control"))
+        .iter()
+        .any(|enabled| enabled == controller)
+    {
+        return Ok(());
+    }
+
+    if !read_controllers(&dir.join("cgroup.controllers"))
+        .iter()
+        .any(|available| available == controller)
+    {
+        match dir.parent() {
+            Some(parent) if dir != Path::new(cgroup_root()) => {
+                enable_controller(parent, controller)?
+            }
+            _ => {
+                return Err(provisioning_error(format!(
+                    "the kernel offers no {} controller",
+                    controller
+                )))
+            }
+        }
+    }
+
+    fs::write(
+        dir.join("cgroup.subtree_control"),
+        format!("+{}", controller),
+    )?;
+    Ok(())
+}
+
+/// Makes sure artisan.slice exists and passes cpu, memory and io down to
+/// its services, creating the unit and enabling controllers where they're
+/// missing. Without it limits and usage accounting come back empty.
+/// Controllers can only be repaired on the unified hierarchy.
+pub async fn provision_slice() -> Result<SliceProvisioning, ErrorArrayItem> {
+    let mut report: SliceProvisioning = SliceProvisioning {
+        unit_file: SLICE_UNIT_PATH.to_owned(),
+        ..Default::default()
+    };
+
+    if !Path::new(SLICE_UNIT_PATH).exists() {
+        fs::write(SLICE_UNIT_PATH, SLICE_UNIT)?;
+        systemctl(&["daemon-reload"]).await?;
+        report.repaired.push(format!("created {}", SLICE_UNIT_PATH));
+    }
+
+    if !slice_dir().exists() {
+        systemctl(&["start", "artisan.slice"]).await?;
+        report.repaired.push(String::from("started artisan.slice"));
+    }
+    report.exists = slice_dir().exists();
+
+    if report.exists && controllers_available() {
+        for controller in REQUIRED_CONTROLLERS {
+            let enabled: bool = read_controllers(&slice_dir().join("cgroup.subtree_control"))
+                .iter()
+                .any(|enabled| enabled == controller);
+            if enabled {
+                continue;
+            }
+
+            match enable_controller(&slice_dir(), controller) {
+                Ok(()) => report
+                    .repaired
+                    .push(format!("enabled the {} controller", controller)),
+                Err(err) => log!(
+                    LogLevel::Warn,
+                    "Couldn't enable the {} controller for artisan.slice: {}",
+                    controller,
+                    err
+                ),
+            }
+        }
+    }
+
+    report.controllers = read_controllers(&slice_dir().join("cgroup.subtree_control"));
+    report.missing = REQUIRED_CONTROLLERS
+        .iter()
+        .filter(|required| {
+            !report
+                .controllers
+                .iter()
+                .any(|enabled| enabled == *required)
+        })
+        .map(|required| required.to_string())
+        .collect();
+
+    for repair in &report.repaired {
+        log!(LogLevel::Info, "Provisioning artisan.slice: {}", repair);
+    }
+    if !report.missing.is_empty() {
+        log!(
+            LogLevel::Warn,
+            "artisan.slice is missing the {} controllers",
+            report.missing.join(", ")
+        );
+    }
+
+    if let Ok(mut provisioning) = PROVISIONING.lock() {
+        *provisioning = Some(report.clone());
+    }
+    Ok(report)
+}
+
+/// Logs what this host's cgroup layout leaves out, once at startup
+pub fn log_cgroup_mode() {
+    let report: CgroupReport = cgroup_report();
+
+    match report.unsupported.is_empty() {
+        true => log!(LogLevel::Debug, "cgroup mode: {:?}", report.mode),
+        false => log!(
+            LogLevel::Warn,
+            "cgroup mode is {:?}, unsupported here: {}",
+            report.mode,
+            report.unsupported.join(", ")
+        ),
+    }
+}
@@ -15,6 +15,7 @@ use artisan_middleware::{
 
 use crate::system::state::save_state;
 
+use super::formats::{guard_format, read_lenient, Encoding};
 use super::state::get_state_path;
 
 const VERSIONCODE: VersionCode = VersionCode::Patched;
@@ -35,10 +36,50 @@ pub fn get_config() -> AppConfig {
     }
 }
 
+fn fresh_state(config: &AppConfig) -> AppState {
+    AppState {
+        name: env!("CARGO_PKG_NAME").to_owned(),
+        version: {
+            let library_version: Version = aml_version();
+            let software_version: Version =
+                str_to_version(env!("CARGO_PKG_VERSION"), Some(VERSIONCODE));
+
+            SoftwareVersion {
+                application: software_version,
+                library: library_version,
+            }
+        },
+        data: String::new(),
+        last_updated: current_timestamp(),
+        event_counter: 0,
+        pid: std::process::id(),
+        error_log: vec![],
+        config: config.clone(),
+        system_application: true,
+        stared_at: current_timestamp(),
+        status: Status::Running,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    }
+}
+
 pub async fn generate_state(config: &AppConfig) -> Result<AppState, ErrorArrayItem> {
     let state_path: PathType = get_state_path(&config);
+    guard_format(&state_path.to_string());
+
+    let loaded: Result<AppState, ErrorArrayItem> =
+        match StatePersistence::load_state(&state_path).await {
+            Ok(state) => Ok(state),
+            // Likely written by a newer manager before a rollback
+            Err(err) => read_lenient(
+                &state_path.to_string(),
+                Encoding::Toml,
+                &fresh_state(config),
+            )
+            .map_err(|_| err),
+        };
 
-    match StatePersistence::load_state(&state_path).await {
+    match loaded {
         Ok(mut loaded_data) => {
             log!(LogLevel::Info, "Loaded previous state data");
             // log!(LogLevel::Trace, "Previous state data: {:#?}", loaded_data);
@@ -73,30 +114,7 @@ pub async fn generate_state(config: &AppConfig) -> Result<AppState, ErrorArrayIt
         Err(e) => {
             log!(LogLevel::Warn, "No previous state loaded, creating new one");
             log!(LogLevel::Debug, "Error loading previous state: {}", e);
-            let mut state = AppState {
-                name: env!("CARGO_PKG_NAME").to_owned(),
-                version: {
-                    let library_version: Version = aml_version();
-                    let software_version: Version =
-                        str_to_version(env!("CARGO_PKG_VERSION"), Some(VERSIONCODE));
-
-                    SoftwareVersion {
-                        application: software_version,
-                        library: library_version,
-                    }
-                },
-                data: String::new(),
-                last_updated: current_timestamp(),
-                event_counter: 0,
-                pid: std::process::id(),
-                error_log: vec![],
-                config: config.clone(),
-                system_application: true,
-                stared_at: current_timestamp(),
-                status: Status::Running,
-                stdout: Vec::new(),
-                stderr: Vec::new(),
-            };
+            let mut state = fresh_state(config);
             state.data = String::from("Initializing");
             state.config.debug_mode = true;
             state.last_updated = current_timestamp();
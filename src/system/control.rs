@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 // Application control locks
 use std::{sync::Arc, time::Duration};
@@ -17,20 +18,33 @@ use artisan_middleware::{dusa_collection_utils::log, identity::Identifier};
 use tokio::net::TcpStream;
 use tokio::sync::{Notify, OnceCell};
 
+use super::cgroups::provision_slice;
 use super::config::{generate_state, get_config};
 use super::ebpf::BandwidthTracker;
+use super::events::EventBus;
+use super::journal::{EventJournal, JOURNAL_PATH};
+use super::ledger_store::ledger_store;
 use super::portal::PortalAddr;
+use super::pressure::PressureGuard;
+use super::settings::ManagerSettings;
 use super::state::get_state_path;
+use super::storage::off_worker;
 
 pub static GLOBAL_STATE: OnceCell<Arc<GlobalState>> = OnceCell::const_new();
-pub const LEDGER_PATH: &str = "/opt/artisan/ledger.json"; // make this encrypted at some point
 
 pub struct GlobalState {
+    pub settings: Arc<ManagerSettings>,
     pub signals: Arc<Signals>,
     pub locks: Arc<Locks>,
     pub portal_state: PortalState,
     pub network_monitor: Arc<BandwidthTracker>,
+    pub events: Arc<EventBus>,
+    pub journal: Arc<EventJournal>,
+    pub pressure: Arc<PressureGuard>,
     pub ledger: LockWithTimeout<UsageLedger>,
+    /// Cleared until the ledger on disk has been loaded, nothing may persist
+    /// the ledger before then or it would overwrite the history with nothing
+    pub ledger_ready: AtomicBool,
     pub app_state: Arc<RwLock<AppState>>,
     pub app_state_path: PathType,
 }
@@ -38,6 +52,7 @@ pub struct GlobalState {
 #[allow(dead_code)]
 impl GlobalState {
     pub async fn initialize_global_state() -> Result<(), ErrorArrayItem> {
+        let settings: Arc<ManagerSettings> = Arc::new(ManagerSettings::load());
         let signals: Arc<Signals> = Arc::new(Signals::new());
         let locks: Arc<Locks> = Arc::new(Locks::new());
         
@@ -56,8 +71,9 @@ impl GlobalState {
 
         let portal_state: PortalState = PortalState::new()?;
         let network_monitor: Arc<BandwidthTracker> = Arc::new(BandwidthTracker::new().await?);
-        let ledger: UsageLedger =
-            UsageLedger::load_from_disk(LEDGER_PATH).unwrap_or_else(|_| UsageLedger::new());
+        let events: Arc<EventBus> = Arc::new(EventBus::new());
+        let journal: Arc<EventJournal> = Arc::new(EventJournal::open(JOURNAL_PATH)?);
+        let pressure: Arc<PressureGuard> = Arc::new(PressureGuard::new());
 
         let app_state_data: (Arc<RwLock<AppState>>, PathType) = {
             let config: AppConfig = get_config();
@@ -83,11 +99,16 @@ impl GlobalState {
         let state: GlobalState = GlobalState {
             portal_state,
             network_monitor,
+            events,
+            journal,
+            pressure,
+            settings,
             signals,
             locks,
             app_state: app_state_data.0,
             app_state_path: app_state_data.1,
-            ledger: LockWithTimeout::new(ledger),
+            ledger: LockWithTimeout::new(UsageLedger::new()),
+            ledger_ready: AtomicBool::new(false),
         };
 
         if let Err(err) = GLOBAL_STATE.set(Arc::new(state)) {
@@ -110,6 +131,57 @@ impl GlobalState {
         Ok(())
     }
 
+    /// The parts of startup that portal status requests don't depend on,
+    /// run once the listener is up so a restarted node answers right away
+    pub async fn finish_initialization(&self) {
+        // The cgroup programs attach to the slice, it has to exist first
+        if let Err(err) = provision_slice().await {
+            log!(
+                LogLevel::Error,
+                "Failed to provision artisan.slice: {}",
+                err
+            );
+        }
+
+        if let Err(err) = self
+            .network_monitor
+            .attach(
+                self.settings.bandwidth_limits.enforce,
+                self.settings.egress.enforce,
+            )
+            .await
+        {
+            log!(
+                LogLevel::Error,
+                "Failed to attach the bandwidth probes: {}",
+                err
+            );
+            self.signals.signal_shutdown();
+            return;
+        }
+        log!(LogLevel::Info, "Bandwidth probes attached");
+
+        if let Err(err) = self.load_ledger().await {
+            log!(LogLevel::Error, "Failed to load the usage ledger: {}", err);
+        }
+    }
+
+    /// Replaces the empty ledger we started with by the one on disk. Usage
+    /// recorded in between is dropped, it's a few seconds at most.
+    async fn load_ledger(&self) -> Result<(), ErrorArrayItem> {
+        let identity: Identifier = self.get_identity().await;
+        let ledger: UsageLedger = off_worker(move || ledger_store().load(&identity)).await?;
+
+        *self.ledger.try_write().await? = ledger;
+        self.ledger_ready.store(true, Ordering::Relaxed);
+        log!(LogLevel::Info, "Usage ledger loaded");
+        Ok(())
+    }
+
+    pub fn ledger_ready(&self) -> bool {
+        self.ledger_ready.load(Ordering::Relaxed)
+    }
+
     pub async fn initialized(&self) -> bool {
         if let None = GLOBAL_STATE.get() {
             false
@@ -0,0 +1,212 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use tokio::runtime::Handle;
+
+use super::storage::storage;
+
+/// Append only record of what the manager decided on its own, next to the
+/// command audit log
+const DECISIONS_LOG: &str = "audit/decisions.jsonl";
+
+/// Most decisions a single query returns
+pub const MAX_DECISIONS: usize = 1000;
+
+/// Held while writing so pruning never drops a line being appended
+static WRITE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Something the manager did without being asked, with what it was based on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Decision {
+    pub timestamp: u64,
+    /// The subsystem that decided, `restart`, `quota`, `reconcile` and so on
+    pub source: String,
+    pub app_id: Option<Stringy>,
+    pub action: String,
+    /// The policy or rule that called for it
+    pub reason: String,
+    pub inputs: BTreeMap<String, Value>,
+}
+
+/// Which decisions a query returns, newest last
+#[derive(Debug, Clone)]
+pub struct DecisionQuery {
+    /// Every app's decisions and the node wide ones, not just the asking app's
+    pub node_wide: bool,
+    pub app_id: Option<Stringy>,
+    pub source: Option<String>,
+    pub since: Option<u64>,
+    pub count: usize,
+}
+
+impl Default for DecisionQuery {
+    fn default() -> Self {
+        Self {
+            node_wide: false,
+            app_id: None,
+            source: None,
+            since: None,
+            count: 50,
+        }
+    }
+}
+
+impl DecisionQuery {
+    /// `[all] [source <source>] [since <timestamp>] [count <n>]`
+    pub fn parse(args: &[&str]) -> Result<Self, ErrorArrayItem> {
+        let mut query: DecisionQuery = DecisionQuery::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().ok_or_else(|| {
+                    ErrorArrayItem::new(Errors::GeneralError, format!("{} needs a value", arg))
+                })
+            };
+            match *arg {
+                "all" => query.node_wide = true,
+                "source" => query.source = Some(value()?.to_string()),
+                "since" => query.since = Some(parse_number(value()?)?),
+                "count" => query.count = parse_number(value()?)? as usize,
+                other => {
+                    return Err(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        format!("Unknown decision filter: {}", other),
+                    ))
+                }
+            }
+        }
+
+        Ok(query)
+    }
+
+    fn matches(&self, decision: &Decision) -> bool {
+        (self.node_wide || decision.app_id == self.app_id)
+            && self
+                .source
+                .as_ref()
+                .map_or(true, |source| decision.source == *source)
+            && self.since.map_or(true, |since| decision.timestamp >= since)
+    }
+}
+
+fn parse_number(arg: &str) -> Result<u64, ErrorArrayItem> {
+    arg.parse::<u64>().map_err(|_| {
+        ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("Invalid command argument: {}", arg),
+        )
+    })
+}
+
+impl Decision {
+    pub fn new(source: &str, app_id: Option<&Stringy>, action: &str, reason: String) -> Self {
+        Self {
+            timestamp: current_timestamp(),
+            source: source.to_owned(),
+            app_id: app_id.cloned(),
+            action: action.to_owned(),
+            reason,
+            inputs: BTreeMap::new(),
+        }
+    }
+
+    pub fn input<T: Serialize>(mut self, key: &str, value: T) -> Self {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.inputs.insert(key.to_owned(), value);
+        }
+        self
+    }
+
+    fn append(&self) -> Result<(), ErrorArrayItem> {
+        let line: String = serde_json::to_string(self)
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+        let _guard = WRITE_LOCK
+            .lock()
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        storage()?.append_line(DECISIONS_LOG, &line)
+    }
+
+    /// Writes the decision to the journal. The manager acts either way, a
+    /// journal that can't be written is only logged. On the runtime the
+    /// write goes to the blocking pool, the caller doesn't wait for it.
+    pub fn record(self) {
+        let write = move || {
+            if let Err(err) = self.append() {
+                log!(
+                    LogLevel::Error,
+                    "Couldn't journal the {} decision to {}: {}",
+                    self.source,
+                    self.action,
+                    err
+                );
+            }
+        };
+
+        match Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+fn read_decisions() -> Result<Vec<Decision>, ErrorArrayItem> {
+    // A torn line from a crash mid write shouldn't hide the rest
+    Ok(storage()?
+        .read_lines(DECISIONS_LOG)?
+        .into_iter()
+        .filter_map(|line| serde_json::from_str::<Decision>(&line).ok())
+        .collect())
+}
+
+/// The last decisions the query matches, oldest first
+pub fn query_decisions(query: &DecisionQuery) -> Result<Vec<Decision>, ErrorArrayItem> {
+    let count: usize = query.count.min(MAX_DECISIONS);
+    let mut decisions: VecDeque<Decision> = VecDeque::with_capacity(count);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    for decision in read_decisions()? {
+        if !query.matches(&decision) {
+            continue;
+        }
+        if decisions.len() == count {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision);
+    }
+
+    Ok(decisions.into())
+}
+
+/// Keeps the decisions `keep` holds true for, returns how many were dropped
+pub fn retain_decisions(keep: impl Fn(&Decision) -> bool) -> Result<usize, ErrorArrayItem> {
+    let _guard = WRITE_LOCK
+        .lock()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+    let decisions: Vec<Decision> = read_decisions()?;
+    let total: usize = decisions.len();
+    let kept: Vec<Decision> = decisions.into_iter().filter(|d| keep(d)).collect();
+    if kept.len() == total {
+        return Ok(0);
+    }
+
+    let lines: Vec<String> = kept
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<String>, serde_json::Error>>()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+    storage()?.replace_lines(DECISIONS_LOG, &lines)?;
+
+    Ok(total - kept.len())
+}
@@ -3,16 +3,35 @@ use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Er
 use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
 use artisan_middleware::dusa_collection_utils::log;
 use artisan_middleware::process_manager::is_pid_active;
-use aya::programs::Program;
-use aya::{include_bytes_aligned, programs::KProbe, Bpf};
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::programs::{BtfTracePoint, CgroupSkb, CgroupSkbAttachType, Program};
+use aya::{include_bytes_aligned, programs::KProbe, Bpf, BpfLoader, Btf};
 use bytemuck::Zeroable;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 // Only derive Zeroable.
 use std::convert::TryInto;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 
-use super::control::GLOBAL_STATE;
+use super::cgroups::slice_dir;
+use super::units::format_bytes;
+
+/// bpffs directory the maps are pinned in, see `pinning` in network.c
+const PIN_PATH: &str = "/sys/fs/bpf/ais";
+
+/// Every map network.c pins
+const PINNED_MAPS: [&str; 5] = [
+    "pid_traffic_map",
+    "cgroup_traffic_map",
+    "remote_traffic_map",
+    "tracked_cgroup_map",
+    "pid_cgroup_map",
+];
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, Zeroable)]
@@ -24,6 +43,114 @@ pub struct TrafficStats {
 
 unsafe impl aya::Pod for TrafficStats {}
 
+/// Value of bandwidth_limit_map, the lock belongs to the probes and is
+/// skipped by the kernel when we write the entry
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Zeroable)]
+#[repr(C)]
+pub struct BandwidthLimit {
+    lock: u32,
+    pad: u32,
+    rate: u64,
+    tx_tokens: u64,
+    tx_last_ns: u64,
+    rx_tokens: u64,
+    rx_last_ns: u64,
+}
+
+unsafe impl aya::Pod for BandwidthLimit {}
+
+/// Key of remote_traffic_map, IPv4 addresses take the first 4 bytes
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Zeroable)]
+#[repr(C)]
+pub struct RemoteKey {
+    pid: u32,
+    family: u16,
+    pad: u16,
+    addr: [u8; 16],
+}
+
+unsafe impl aya::Pod for RemoteKey {}
+
+impl RemoteKey {
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+
+    fn address(&self) -> Option<IpAddr> {
+        match self.family {
+            Self::AF_INET => {
+                let octets: [u8; 4] = self.addr[..4].try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            Self::AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(self.addr))),
+            _ => None,
+        }
+    }
+}
+
+/// Data of the egress_allow_v4_map keys, the prefix length covers the whole
+/// cgroup ID plus the allowed network
+#[derive(Clone, Copy, Debug, Zeroable, PartialEq, Eq, Hash)]
+#[repr(C, packed)]
+pub struct EgressV4Key {
+    cgroup_id: u64,
+    addr: [u8; 4],
+}
+
+unsafe impl aya::Pod for EgressV4Key {}
+
+#[derive(Clone, Copy, Debug, Zeroable, PartialEq, Eq, Hash)]
+#[repr(C, packed)]
+pub struct EgressV6Key {
+    cgroup_id: u64,
+    addr: [u8; 16],
+}
+
+unsafe impl aya::Pod for EgressV6Key {}
+
+/// Key of egress_blocked_map, IPv4 addresses take the first 4 bytes
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Zeroable)]
+#[repr(C)]
+pub struct EgressBlockedKey {
+    cgroup_id: u64,
+    family: u16,
+    pad: u16,
+    addr: [u8; 16],
+    pad2: u32,
+}
+
+unsafe impl aya::Pod for EgressBlockedKey {}
+
+impl EgressBlockedKey {
+    fn address(&self) -> Option<IpAddr> {
+        RemoteKey {
+            pid: 0,
+            family: self.family,
+            pad: 0,
+            addr: self.addr,
+        }
+        .address()
+    }
+}
+
+/// An allowed destination network, the address has its host bits cleared
+pub type EgressNetwork = (IpAddr, u8);
+
+/// The two passes of `sync_trie`
+enum TrieStep {
+    Insert,
+    Remove,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteTalker {
+    pub address: IpAddr,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
 // to avoid adding aya depends to the shared lib
 impl TrafficStats {
     pub fn to_network_usage(&self) -> NetworkUsage {
@@ -32,25 +159,106 @@ impl TrafficStats {
             tx_bytes: self.tx_bytes,
         }
     }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
 }
 
 #[allow(dead_code)]
 pub struct BandwidthTracker {
-    bpf: RwLock<Bpf>,
+    /// `None` until `attach` runs, loading the probes is the slowest part of
+    /// starting up so it waits until we're answering status requests
+    bpf: RwLock<Option<Bpf>>,
+    /// Whether the fork probe is attached, without it `track_pids` has to
+    /// read cgroup.procs on every pass
+    fork_tracking: AtomicBool,
+    /// Whether the cgroup programs enforcing bandwidth caps are attached,
+    /// caps are only measured against without them
+    bandwidth_enforcement: AtomicBool,
+    /// Whether the egress program drops traffic outside of the allowlists,
+    /// blocked attempts aren't counted without it
+    egress_enforcement: AtomicBool,
+}
+
+fn bpf_error<E: ToString>(err: E) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, err.to_string())
 }
 
 impl BandwidthTracker {
     pub async fn new() -> Result<Self, ErrorArrayItem> {
-        if GLOBAL_STATE.initialized() {
+        Ok(Self {
+            bpf: RwLock::new(None),
+            fork_tracking: AtomicBool::new(false),
+            bandwidth_enforcement: AtomicBool::new(false),
+            egress_enforcement: AtomicBool::new(false),
+        })
+    }
+
+    pub fn tracks_forks(&self) -> bool {
+        self.fork_tracking.load(Ordering::Relaxed)
+    }
+
+    pub fn enforces_bandwidth(&self) -> bool {
+        self.bandwidth_enforcement.load(Ordering::Relaxed)
+    }
+
+    pub fn enforces_egress(&self) -> bool {
+        self.egress_enforcement.load(Ordering::Relaxed)
+    }
+
+    pub fn attached(&self) -> bool {
+        self.bpf
+            .try_read()
+            .map(|bpf| bpf.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Loads the program against the maps pinned by the last run, so the
+    /// counters carry over a restart. Pins left by an incompatible build are
+    /// dropped and the maps start over.
+    fn load_pinned() -> Result<Bpf, ErrorArrayItem> {
+        let bpf_data = include_bytes_aligned!("../ebpf/network.o");
+        fs::create_dir_all(PIN_PATH)?;
+
+        match BpfLoader::new().map_pin_path(PIN_PATH).load(bpf_data) {
+            Ok(bpf) => Ok(bpf),
+            Err(err) => {
+                log!(
+                    LogLevel::Warn,
+                    "Couldn't reuse the maps pinned in {}, starting the counters over: {}",
+                    PIN_PATH,
+                    err
+                );
+                for map in PINNED_MAPS {
+                    let _ = fs::remove_file(Path::new(PIN_PATH).join(map));
+                }
+
+                BpfLoader::new()
+                    .map_pin_path(PIN_PATH)
+                    .load(bpf_data)
+                    .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
+            }
+        }
+    }
+
+    /// Loads the probes and attaches them to the kernel. Pids tracked before
+    /// this were dropped, `track_pids` picks them up again on its next pass.
+    /// Bandwidth caps and egress allowlists are only enforced when asked to,
+    /// they share the cgroup programs.
+    pub async fn attach(
+        &self,
+        enforce_bandwidth: bool,
+        enforce_egress: bool,
+    ) -> Result<(), ErrorArrayItem> {
+        if self.attached() {
             return Err(ErrorArrayItem::new(
                 Errors::AppState,
                 "Attemping to double initialize ebpf",
             ));
         }
 
-        let bpf_data = include_bytes_aligned!("../ebpf/network.o");
-        let mut bpf = Bpf::load(bpf_data)
-            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        let mut bpf = Self::load_pinned()?;
 
         let probes = [
             ("bpf_tcp_sendmsg", "tcp_sendmsg"),
@@ -95,9 +303,357 @@ impl BandwidthTracker {
             );
         }
 
-        Ok(Self {
-            bpf: RwLock::new(bpf),
-        })
+        // Kernels without BTF can't take the tracepoint, we keep polling there
+        match Self::attach_fork_probe(&mut bpf) {
+            Ok(_) => self.fork_tracking.store(true, Ordering::Relaxed),
+            Err(err) => log!(
+                LogLevel::Warn,
+                "Not following forks, polling cgroup.procs instead: {}",
+                err
+            ),
+        }
+
+        if enforce_bandwidth || enforce_egress {
+            match Self::attach_bandwidth_limits(&mut bpf) {
+                Ok(_) => {
+                    self.bandwidth_enforcement
+                        .store(enforce_bandwidth, Ordering::Relaxed);
+                    self.egress_enforcement
+                        .store(enforce_egress, Ordering::Relaxed);
+                }
+                Err(err) => log!(
+                    LogLevel::Error,
+                    "Bandwidth caps and egress allowlists won't be enforced, the cgroup programs can't be attached: {}",
+                    err
+                ),
+            }
+        }
+
+        let mut attached = self.bpf.try_write().map_err(|err| {
+            ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Can't lock bpf handle: {}", err),
+            )
+        })?;
+        *attached = Some(bpf);
+
+        Ok(())
+    }
+
+    fn attach_fork_probe(bpf: &mut Bpf) -> Result<(), ErrorArrayItem> {
+        let btf: Btf = Btf::from_sys_fs().map_err(bpf_error)?;
+
+        let program: &mut BtfTracePoint = bpf
+            .program_mut("bpf_sched_process_fork")
+            .ok_or_else(|| bpf_error("Error getting bpf application"))?
+            .try_into()
+            .map_err(|err: aya::programs::ProgramError| bpf_error(err))?;
+
+        program
+            .load("sched_process_fork", &btf)
+            .map_err(bpf_error)?;
+        program.attach().map_err(bpf_error)?;
+
+        log!(
+            LogLevel::Debug,
+            "✅ Successfully attached probe bpf_sched_process_fork to sched_process_fork"
+        );
+        Ok(())
+    }
+
+    /// Attaches both directions to artisan.slice, the programs look the
+    /// service up by the cgroup of the socket
+    fn attach_bandwidth_limits(bpf: &mut Bpf) -> Result<(), ErrorArrayItem> {
+        let slice: fs::File = fs::File::open(slice_dir())?;
+
+        for (prog_name, attach_type) in [
+            ("bpf_bandwidth_egress", CgroupSkbAttachType::Egress),
+            ("bpf_bandwidth_ingress", CgroupSkbAttachType::Ingress),
+        ] {
+            let program: &mut CgroupSkb = bpf
+                .program_mut(prog_name)
+                .ok_or_else(|| bpf_error("Error getting bpf application"))?
+                .try_into()
+                .map_err(|err: aya::programs::ProgramError| bpf_error(err))?;
+
+            program.load().map_err(bpf_error)?;
+            program.attach(&slice, attach_type).map_err(bpf_error)?;
+
+            log!(
+                LogLevel::Debug,
+                "✅ Successfully attached {} to artisan.slice",
+                prog_name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes the caps, in bytes per second, of the given cgroups and lifts
+    /// every other one. A cap that didn't change keeps its buckets. Does
+    /// nothing unless the cgroup programs are attached.
+    pub async fn sync_bandwidth_limits(
+        &self,
+        caps: &HashMap<u64, u64>,
+    ) -> Result<(), ErrorArrayItem> {
+        if !self.enforces_bandwidth() {
+            return Ok(());
+        }
+
+        let mut bpf = self
+            .bpf
+            .try_write()
+            .map_err(|err| bpf_error(format!("Can't lock bpf handle: {}", err)))?;
+
+        let bpf: &mut Bpf = match bpf.as_mut() {
+            Some(bpf) => bpf,
+            None => return Ok(()),
+        };
+
+        let mut limits: aya::maps::HashMap<_, u64, BandwidthLimit> = aya::maps::HashMap::try_from(
+            bpf.map_mut("bandwidth_limit_map")
+                .ok_or_else(|| bpf_error("failed to find bandwidth_limit_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        let lifted: Vec<u64> = limits
+            .keys()
+            .filter_map(|key| key.ok())
+            .filter(|cgroup| !caps.contains_key(cgroup))
+            .collect();
+        for cgroup in lifted {
+            let _ = limits.remove(&cgroup);
+        }
+
+        for (cgroup, rate) in caps {
+            if matches!(limits.get(cgroup, 0), Ok(limit) if limit.rate == *rate) {
+                continue;
+            }
+
+            let limit: BandwidthLimit = BandwidthLimit {
+                rate: *rate,
+                tx_tokens: *rate,
+                rx_tokens: *rate,
+                ..BandwidthLimit::zeroed()
+            };
+            limits.insert(cgroup, limit, 0).map_err(bpf_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the allowlists of the given cgroups and lifts the ones of every
+    /// other. Allowed networks go in before a cgroup is restricted and come
+    /// out after it's lifted, so no allowed packet is dropped in between.
+    /// Does nothing unless the cgroup programs are attached.
+    pub async fn sync_egress_allowlists(
+        &self,
+        allowlists: &HashMap<u64, Vec<EgressNetwork>>,
+    ) -> Result<(), ErrorArrayItem> {
+        if !self.enforces_egress() {
+            return Ok(());
+        }
+
+        let mut bpf = self
+            .bpf
+            .try_write()
+            .map_err(|err| bpf_error(format!("Can't lock bpf handle: {}", err)))?;
+
+        let bpf: &mut Bpf = match bpf.as_mut() {
+            Some(bpf) => bpf,
+            None => return Ok(()),
+        };
+
+        let mut v4: HashSet<(u32, EgressV4Key)> = HashSet::new();
+        let mut v6: HashSet<(u32, EgressV6Key)> = HashSet::new();
+        for (cgroup_id, networks) in allowlists {
+            for (address, prefix) in networks {
+                match address {
+                    IpAddr::V4(address) => v4.insert((
+                        64 + *prefix as u32,
+                        EgressV4Key {
+                            cgroup_id: *cgroup_id,
+                            addr: address.octets(),
+                        },
+                    )),
+                    IpAddr::V6(address) => v6.insert((
+                        64 + *prefix as u32,
+                        EgressV6Key {
+                            cgroup_id: *cgroup_id,
+                            addr: address.octets(),
+                        },
+                    )),
+                };
+            }
+        }
+
+        Self::sync_trie(bpf, "egress_allow_v4_map", &v4, TrieStep::Insert)?;
+        Self::sync_trie(bpf, "egress_allow_v6_map", &v6, TrieStep::Insert)?;
+
+        let mut policies: aya::maps::HashMap<_, u64, u8> = aya::maps::HashMap::try_from(
+            bpf.map_mut("egress_policy_map")
+                .ok_or_else(|| bpf_error("failed to find egress_policy_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        let lifted: Vec<u64> = policies
+            .keys()
+            .filter_map(|key| key.ok())
+            .filter(|cgroup| !allowlists.contains_key(cgroup))
+            .collect();
+        for cgroup in lifted {
+            let _ = policies.remove(&cgroup);
+        }
+        for cgroup in allowlists.keys() {
+            policies.insert(cgroup, 1, 0).map_err(bpf_error)?;
+        }
+
+        Self::sync_trie(bpf, "egress_allow_v4_map", &v4, TrieStep::Remove)?;
+        Self::sync_trie(bpf, "egress_allow_v6_map", &v6, TrieStep::Remove)
+    }
+
+    /// Adds the missing entries of an allowlist trie, or drops the ones that
+    /// aren't wanted anymore
+    fn sync_trie<K: aya::Pod + Eq + std::hash::Hash>(
+        bpf: &mut Bpf,
+        map: &str,
+        wanted: &HashSet<(u32, K)>,
+        step: TrieStep,
+    ) -> Result<(), ErrorArrayItem> {
+        let mut trie: LpmTrie<_, K, u8> = LpmTrie::try_from(
+            bpf.map_mut(map)
+                .ok_or_else(|| bpf_error(format!("failed to find {}", map)))?,
+        )
+        .map_err(bpf_error)?;
+
+        let present: HashSet<(u32, K)> = trie
+            .keys()
+            .filter_map(|key| key.ok())
+            .map(|key| (key.prefix_len(), key.data()))
+            .collect();
+
+        match step {
+            TrieStep::Insert => {
+                for (prefix_len, data) in wanted.difference(&present) {
+                    trie.insert(&Key::new(*prefix_len, *data), 1, 0)
+                        .map_err(bpf_error)?;
+                }
+            }
+            TrieStep::Remove => {
+                for (prefix_len, data) in present.difference(wanted) {
+                    let _ = trie.remove(&Key::new(*prefix_len, *data));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Packets the egress program dropped so far, by cgroup and destination
+    pub async fn blocked_egress(&self) -> Result<HashMap<u64, Vec<(IpAddr, u64)>>, ErrorArrayItem> {
+        let mut blocked: HashMap<u64, Vec<(IpAddr, u64)>> = HashMap::new();
+
+        let bpf = self
+            .bpf
+            .try_read()
+            .map_err(|err| bpf_error(format!("Can't lock bpf handle: {}", err)))?;
+
+        let bpf: &Bpf = match bpf.as_ref() {
+            Some(bpf) => bpf,
+            None => return Ok(blocked),
+        };
+
+        let map: aya::maps::HashMap<_, EgressBlockedKey, u64> = aya::maps::HashMap::try_from(
+            bpf.map("egress_blocked_map")
+                .ok_or_else(|| bpf_error("failed to find egress_blocked_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        for (key, count) in map.iter().filter_map(|entry| entry.ok()) {
+            if let Some(address) = key.address() {
+                blocked
+                    .entry(key.cgroup_id)
+                    .or_default()
+                    .push((address, count));
+            }
+        }
+
+        Ok(blocked)
+    }
+
+    /// Cgroup ID -> Service of everything in artisan.slice, the ID of a
+    /// cgroup is the inode of its directory
+    pub fn service_cgroup_map() -> Result<HashMap<u64, String>, ErrorArrayItem> {
+        let mut service_cgroup_map: HashMap<u64, String> = HashMap::new();
+
+        for entry in fs::read_dir(slice_dir())? {
+            let entry = entry?;
+            if let Some(service_name) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".service"))
+            {
+                service_cgroup_map.insert(entry.metadata()?.ino(), service_name.to_string());
+            }
+        }
+
+        Ok(service_cgroup_map)
+    }
+
+    /// Points the fork probe at the given cgroups and forgets the ones that
+    /// are gone, along with what their dead children sent. Returns the
+    /// cgroups that weren't tracked before.
+    pub async fn sync_tracked_cgroups(
+        &self,
+        cgroups: &HashMap<u64, String>,
+    ) -> Result<Vec<u64>, ErrorArrayItem> {
+        let mut bpf = self
+            .bpf
+            .try_write()
+            .map_err(|err| bpf_error(format!("Can't lock bpf handle: {}", err)))?;
+
+        // Nothing is counted until we're attached, every cgroup is new then
+        let bpf: &mut Bpf = match bpf.as_mut() {
+            Some(bpf) => bpf,
+            None => return Ok(cgroups.keys().copied().collect()),
+        };
+
+        let mut tracked: aya::maps::HashMap<_, u64, u8> = aya::maps::HashMap::try_from(
+            bpf.map_mut("tracked_cgroup_map")
+                .ok_or_else(|| bpf_error("failed to find tracked_cgroup_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        let known: Vec<u64> = tracked.keys().filter_map(|key| key.ok()).collect();
+        let added: Vec<u64> = cgroups
+            .keys()
+            .filter(|cgroup| !known.contains(cgroup))
+            .copied()
+            .collect();
+
+        for cgroup in &added {
+            tracked.insert(cgroup, 1, 0).map_err(bpf_error)?;
+        }
+        for cgroup in known.iter().filter(|cgroup| !cgroups.contains_key(cgroup)) {
+            let _ = tracked.remove(cgroup);
+        }
+
+        let mut folded: aya::maps::HashMap<_, u64, TrafficStats> = aya::maps::HashMap::try_from(
+            bpf.map_mut("cgroup_traffic_map")
+                .ok_or_else(|| bpf_error("failed to find cgroup_traffic_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        let gone: Vec<u64> = folded
+            .keys()
+            .filter_map(|key| key.ok())
+            .filter(|cgroup| !cgroups.contains_key(cgroup))
+            .collect();
+        for cgroup in gone {
+            let _ = folded.remove(&cgroup);
+        }
+
+        Ok(added)
     }
 
     #[allow(dead_code)]
@@ -109,6 +665,11 @@ impl BandwidthTracker {
             )
         })?;
 
+        let bpf: &Bpf = match bpf.as_ref() {
+            Some(bpf) => bpf,
+            None => return Ok((format_bytes(0), format_bytes(0))),
+        };
+
         let map_data = bpf.map("pid_traffic_map").ok_or_else(|| {
             ErrorArrayItem::new(
                 Errors::GeneralError,
@@ -127,33 +688,11 @@ impl BandwidthTracker {
                     "ℹ️ PID {} not found in map, returning zeroed stats",
                     pid
                 );
-                return Ok((Self::format_bytes(0), Self::format_bytes(0)));
+                return Ok((format_bytes(0), format_bytes(0)));
             }
         };
 
-        Ok((
-            Self::format_bytes(stats.rx_bytes),
-            Self::format_bytes(stats.tx_bytes),
-        ))
-    }
-
-    /// Helper function to format byte counts into human-readable strings.
-    fn format_bytes(bytes: u64) -> String {
-        const KB: f64 = 1024.0;
-        const MB: f64 = KB * 1024.0;
-        const GB: f64 = MB * 1024.0;
-
-        let bytes_f64 = bytes as f64;
-
-        if bytes_f64 >= GB {
-            format!("{:.2} GB", bytes_f64 / GB)
-        } else if bytes_f64 >= MB {
-            format!("{:.2} MB", bytes_f64 / MB)
-        } else if bytes_f64 >= KB {
-            format!("{:.2} KB", bytes_f64 / KB)
-        } else {
-            format!("{} B", bytes)
-        }
+        Ok((format_bytes(stats.rx_bytes), format_bytes(stats.tx_bytes)))
     }
 
     pub async fn track_pid(&self, pid: u32) -> Result<(), ErrorArrayItem> {
@@ -164,6 +703,12 @@ impl BandwidthTracker {
             )
         })?;
 
+        // Not attached yet, nothing is counted until then anyway
+        let bpf: &mut Bpf = match bpf.as_mut() {
+            Some(bpf) => bpf,
+            None => return Ok(()),
+        };
+
         let map_data = bpf.map_mut("pid_traffic_map").ok_or_else(|| {
             ErrorArrayItem::new(Errors::GeneralError, "failed to find pid_traffic_map")
         })?;
@@ -199,14 +744,32 @@ impl BandwidthTracker {
         Ok(())
     }
 
-    pub async fn aggregate_bandwidth_by_service(
-        &self,
-    ) -> Result<HashMap<String, TrafficStats>, ErrorArrayItem> {
-        let mut service_pid_map: HashMap<u32, String> = HashMap::new();
-        let artisan_slice = Path::new("/sys/fs/cgroup/artisan.slice/");
+    pub async fn is_tracked(&self, pid: u32) -> Result<bool, ErrorArrayItem> {
+        let bpf = self.bpf.try_read().map_err(|err| {
+            ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Can't lock bpf handle: {}", err),
+            )
+        })?;
 
-        // Step 1: Build PID -> Service map
-        for entry in std::fs::read_dir(artisan_slice)? {
+        let bpf: &Bpf = bpf.as_ref().ok_or_else(|| {
+            ErrorArrayItem::new(Errors::AppState, "The bandwidth probes aren't attached yet")
+        })?;
+
+        let map_data = bpf.map("pid_traffic_map").ok_or_else(|| {
+            ErrorArrayItem::new(Errors::GeneralError, "failed to find pid_traffic_map")
+        })?;
+
+        let map: aya::maps::HashMap<_, u32, TrafficStats> = aya::maps::HashMap::try_from(map_data)
+            .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+        Ok(map.get(&pid, 0).is_ok())
+    }
+
+    /// PID -> Service map of everything in artisan.slice
+    fn service_pid_map() -> Result<HashMap<u32, String>, ErrorArrayItem> {
+        let mut service_pid_map: HashMap<u32, String> = HashMap::new();
+        for entry in std::fs::read_dir(slice_dir())? {
             let path = entry?.path();
             let service_name = match path.file_name().and_then(|n| n.to_str()) {
                 Some(name) if name.ends_with(".service") => {
@@ -225,6 +788,15 @@ impl BandwidthTracker {
             }
         }
 
+        Ok(service_pid_map)
+    }
+
+    pub async fn aggregate_bandwidth_by_service(
+        &self,
+    ) -> Result<HashMap<String, TrafficStats>, ErrorArrayItem> {
+        // Step 1: Build PID -> Service map
+        let service_pid_map: HashMap<u32, String> = Self::service_pid_map()?;
+
         // Step 2: Prepare aggregated map
         let mut service_traffic: HashMap<String, TrafficStats> = HashMap::new();
 
@@ -236,6 +808,11 @@ impl BandwidthTracker {
             )
         })?;
 
+        let bpf: &Bpf = match bpf.as_ref() {
+            Some(bpf) => bpf,
+            None => return Ok(service_traffic),
+        };
+
         let map_data = bpf.map("pid_traffic_map").ok_or_else(|| {
             ErrorArrayItem::new(Errors::GeneralError, "failed to find pid_traffic_map")
         })?;
@@ -243,10 +820,28 @@ impl BandwidthTracker {
         let map: aya::maps::HashMap<_, u32, TrafficStats> = aya::maps::HashMap::try_from(map_data)
             .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
 
+        // Children the fork probe caught are attributed by the cgroup they
+        // were forked in, they may be gone from cgroup.procs already
+        let service_cgroup_map: HashMap<u64, String> = Self::service_cgroup_map()?;
+        let forked: HashMap<u32, u64> = match bpf.map("pid_cgroup_map") {
+            Some(map_data) => aya::maps::HashMap::<_, u32, u64>::try_from(map_data)
+                .map_err(bpf_error)?
+                .iter()
+                .filter_map(|entry| entry.ok())
+                .collect(),
+            None => HashMap::new(),
+        };
+
         let mut iter = map.iter();
 
         while let Some(Ok((pid, stats))) = iter.next() {
-            if let Some(service_name) = service_pid_map.get(&pid) {
+            let service_name: Option<&String> = service_pid_map.get(&pid).or_else(|| {
+                forked
+                    .get(&pid)
+                    .and_then(|cgroup| service_cgroup_map.get(cgroup))
+            });
+
+            if let Some(service_name) = service_name {
                 let entry = service_traffic
                     .entry(service_name.clone())
                     .or_insert(TrafficStats {
@@ -258,9 +853,98 @@ impl BandwidthTracker {
             }
         }
 
+        // What children sent before they exited, see `cleanup_dead_pids`
+        if let Some(map_data) = bpf.map("cgroup_traffic_map") {
+            let folded: aya::maps::HashMap<_, u64, TrafficStats> =
+                aya::maps::HashMap::try_from(map_data).map_err(bpf_error)?;
+
+            for (cgroup, stats) in folded.iter().filter_map(|entry| entry.ok()) {
+                if let Some(service_name) = service_cgroup_map.get(&cgroup) {
+                    let entry = service_traffic
+                        .entry(service_name.clone())
+                        .or_insert(TrafficStats::zeroed());
+                    entry.rx_bytes += stats.rx_bytes;
+                    entry.tx_bytes += stats.tx_bytes;
+                }
+            }
+        }
+
         Ok(service_traffic)
     }
 
+    /// Traffic of every service split up by the remote address it went to
+    pub async fn aggregate_bandwidth_by_remote(
+        &self,
+    ) -> Result<HashMap<String, HashMap<IpAddr, TrafficStats>>, ErrorArrayItem> {
+        let service_pid_map: HashMap<u32, String> = Self::service_pid_map()?;
+        let mut remote_traffic: HashMap<String, HashMap<IpAddr, TrafficStats>> = HashMap::new();
+
+        let bpf = self.bpf.try_read().map_err(|err| {
+            ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Can't lock bpf handle: {}", err),
+            )
+        })?;
+
+        let bpf: &Bpf = match bpf.as_ref() {
+            Some(bpf) => bpf,
+            None => return Ok(remote_traffic),
+        };
+
+        let map_data = bpf.map("remote_traffic_map").ok_or_else(|| {
+            ErrorArrayItem::new(Errors::GeneralError, "failed to find remote_traffic_map")
+        })?;
+
+        let map: aya::maps::HashMap<_, RemoteKey, TrafficStats> =
+            aya::maps::HashMap::try_from(map_data)
+                .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+        let mut iter = map.iter();
+
+        while let Some(Ok((key, stats))) = iter.next() {
+            let (service_name, address) = match (service_pid_map.get(&key.pid), key.address()) {
+                (Some(service_name), Some(address)) => (service_name, address),
+                _ => continue,
+            };
+
+            let entry = remote_traffic
+                .entry(service_name.clone())
+                .or_default()
+                .entry(address)
+                .or_insert(TrafficStats::zeroed());
+            entry.rx_bytes += stats.rx_bytes;
+            entry.tx_bytes += stats.tx_bytes;
+        }
+
+        Ok(remote_traffic)
+    }
+
+    /// The `count` remote addresses every service exchanged the most bytes
+    /// with
+    pub async fn top_talkers(
+        &self,
+        count: usize,
+    ) -> Result<BTreeMap<String, Vec<RemoteTalker>>, ErrorArrayItem> {
+        Ok(self
+            .aggregate_bandwidth_by_remote()
+            .await?
+            .into_iter()
+            .map(|(service, remotes)| {
+                let mut talkers: Vec<RemoteTalker> = remotes
+                    .into_iter()
+                    .map(|(address, stats)| RemoteTalker {
+                        address,
+                        rx_bytes: stats.rx_bytes,
+                        tx_bytes: stats.tx_bytes,
+                    })
+                    .collect();
+                talkers.sort_by_key(|talker| std::cmp::Reverse(talker.rx_bytes + talker.tx_bytes));
+                talkers.truncate(count);
+                (service, talkers)
+            })
+            .collect())
+    }
+
     pub async fn cleanup_dead_pids(&self) -> Result<(), ErrorArrayItem> {
         let mut bpf = self.bpf.try_write().map_err(|err| {
             ErrorArrayItem::new(
@@ -269,6 +953,11 @@ impl BandwidthTracker {
             )
         })?;
 
+        let bpf: &mut Bpf = match bpf.as_mut() {
+            Some(bpf) => bpf,
+            None => return Ok(()),
+        };
+
         let map_data = bpf.map_mut("pid_traffic_map").ok_or_else(|| {
             ErrorArrayItem::new(
                 Errors::GeneralError,
@@ -286,13 +975,13 @@ impl BandwidthTracker {
 
         let mut iter = map.iter();
 
-        while let Some(Ok((pid, _stats))) = iter.next() {
+        while let Some(Ok((pid, stats))) = iter.next() {
             if !is_pid_active(pid as i32)? {
-                to_remove.push(pid);
+                to_remove.push((pid, stats));
             }
         }
 
-        for pid in to_remove {
+        for (pid, _) in &to_remove {
             map.remove(&pid).map_err(|e| {
                 ErrorArrayItem::new(
                     Errors::GeneralError,
@@ -307,6 +996,42 @@ impl BandwidthTracker {
             );
         }
 
+        Self::fold_dead_children(bpf, &to_remove)
+    }
+
+    /// Children of a service keep counting towards it after they exit, their
+    /// totals move to the cgroup they were forked in
+    fn fold_dead_children(
+        bpf: &mut Bpf,
+        dead: &[(u32, TrafficStats)],
+    ) -> Result<(), ErrorArrayItem> {
+        let mut forked: aya::maps::HashMap<_, u32, u64> = aya::maps::HashMap::try_from(
+            bpf.map_mut("pid_cgroup_map")
+                .ok_or_else(|| bpf_error("failed to find pid_cgroup_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        let mut exited: Vec<(u64, TrafficStats)> = Vec::new();
+        for (pid, stats) in dead {
+            if let Ok(cgroup) = forked.get(pid, 0) {
+                let _ = forked.remove(pid);
+                exited.push((cgroup, *stats));
+            }
+        }
+
+        let mut folded: aya::maps::HashMap<_, u64, TrafficStats> = aya::maps::HashMap::try_from(
+            bpf.map_mut("cgroup_traffic_map")
+                .ok_or_else(|| bpf_error("failed to find cgroup_traffic_map"))?,
+        )
+        .map_err(bpf_error)?;
+
+        for (cgroup, stats) in exited {
+            let mut total: TrafficStats = folded.get(&cgroup, 0).unwrap_or(TrafficStats::zeroed());
+            total.rx_bytes += stats.rx_bytes;
+            total.tx_bytes += stats.tx_bytes;
+            folded.insert(cgroup, total, 0).map_err(bpf_error)?;
+        }
+
         Ok(())
     }
 }
@@ -314,15 +1039,7 @@ impl BandwidthTracker {
 pub fn debug_print_aggregated(service_traffic: HashMap<String, TrafficStats>) {
     for (service, stats) in service_traffic {
         log!(LogLevel::Debug, "Service: {}", service);
-        log!(
-            LogLevel::Debug,
-            "  RX: {}",
-            BandwidthTracker::format_bytes(stats.rx_bytes)
-        );
-        log!(
-            LogLevel::Debug,
-            "  TX: {}",
-            BandwidthTracker::format_bytes(stats.tx_bytes)
-        );
+        log!(LogLevel::Debug, "  RX: {}", format_bytes(stats.rx_bytes));
+        log!(LogLevel::Debug, "  TX: {}", format_bytes(stats.tx_bytes));
     }
 }
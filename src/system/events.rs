@@ -0,0 +1,453 @@
+use artisan_middleware::aggregator::Status;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::sync::broadcast;
+
+use super::portal::PortalAddr;
+use super::pressure::PressureLevel;
+use super::units::format_percent;
+
+/// How many events a slow subscriber can fall behind before it starts lagging
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Things that happen inside the manager that other subsystems care about
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ManagerEvent {
+    AppStarted {
+        name: Stringy,
+        pid: u32,
+    },
+    AppDied {
+        name: Stringy,
+    },
+    StatusChanged {
+        name: Stringy,
+        from: Status,
+        to: Status,
+    },
+    /// systemd reported the app's unit going up or down
+    UnitStateChanged {
+        name: Stringy,
+        active_state: String,
+        sub_state: String,
+    },
+    DeployFinished {
+        name: Stringy,
+    },
+    DeployFailed {
+        name: Stringy,
+        reason: String,
+    },
+    DeployQueued {
+        name: Stringy,
+        position: usize,
+    },
+    InventoryRecorded {
+        name: Stringy,
+        version: String,
+        packages: usize,
+    },
+    PortalConnected {
+        address: String,
+    },
+    ConfigChanged {
+        name: Stringy,
+        file: String,
+    },
+    ConfigDrift {
+        name: Stringy,
+        files: Vec<String>,
+    },
+    MemoryPressure {
+        level: PressureLevel,
+        available_percent: f64,
+    },
+    PortExhaustion {
+        name: Stringy,
+        ephemeral_ports: usize,
+        conntrack_entries: usize,
+    },
+    SecurityFinding {
+        name: Stringy,
+        path: String,
+        issue: String,
+    },
+    DataPurged {
+        name: Stringy,
+    },
+    CanaryStarted {
+        name: Stringy,
+        bake_secs: u64,
+    },
+    CanaryRolledBack {
+        name: Stringy,
+        reason: String,
+    },
+    UnexpectedService {
+        name: Stringy,
+        enforced: bool,
+    },
+    RestartsExhausted {
+        name: Stringy,
+        restarts: u32,
+    },
+    CrashLoop {
+        name: Stringy,
+        restarts: usize,
+        window_secs: u64,
+    },
+    NodeReconciled {
+        actions: usize,
+        problems: usize,
+    },
+    SystemAppOutsideSystemd {
+        name: Stringy,
+        pid: u32,
+    },
+    OomKilled {
+        name: Stringy,
+        kills: u64,
+    },
+    AppRelocated {
+        name: Stringy,
+        target: String,
+    },
+    QuotaSuspended {
+        name: Stringy,
+        quota: String,
+        used: f64,
+        limit: f64,
+    },
+    SuspensionLifted {
+        name: Stringy,
+    },
+    BinaryQuarantined {
+        name: Stringy,
+        reason: String,
+    },
+    QuarantineLifted {
+        name: Stringy,
+    },
+    /// Failed its liveness probe `failures` times in a row
+    LivenessFailed {
+        name: Stringy,
+        failures: u32,
+        reason: String,
+    },
+    /// The egress program dropped what the app sent outside its allowlist
+    EgressBlocked {
+        name: Stringy,
+        destination: String,
+        /// Dropped since the last report
+        packets: u64,
+    },
+    /// Stopped for lack of traffic, the manager holds its port
+    AppHibernated {
+        name: Stringy,
+        idle_secs: u64,
+    },
+    /// A connection woke the app up
+    AppWoken {
+        name: Stringy,
+        wake_ms: u64,
+    },
+    /// ais_gitmon saw new commits in the app's repository
+    RepositoryChanged {
+        name: Stringy,
+        commit: String,
+        /// The app asked for an alert on changes
+        alert: bool,
+    },
+    DependencyUnreachable {
+        name: Stringy,
+        dependency: String,
+        /// The app's errors went up with the outage
+        error_spike: bool,
+    },
+    StatusStoreFull {
+        entries: usize,
+        max_entries: usize,
+        /// Apps that won't be saved
+        evicted: Vec<Stringy>,
+    },
+}
+
+impl ManagerEvent {
+    /// The application the event is about, if it's about one
+    pub fn app_name(&self) -> Option<&Stringy> {
+        match self {
+            ManagerEvent::AppStarted { name, .. }
+            | ManagerEvent::AppDied { name }
+            | ManagerEvent::StatusChanged { name, .. }
+            | ManagerEvent::UnitStateChanged { name, .. }
+            | ManagerEvent::DeployFinished { name }
+            | ManagerEvent::DeployFailed { name, .. }
+            | ManagerEvent::DeployQueued { name, .. }
+            | ManagerEvent::InventoryRecorded { name, .. }
+            | ManagerEvent::ConfigChanged { name, .. }
+            | ManagerEvent::ConfigDrift { name, .. }
+            | ManagerEvent::PortExhaustion { name, .. }
+            | ManagerEvent::SecurityFinding { name, .. }
+            | ManagerEvent::DataPurged { name }
+            | ManagerEvent::CanaryStarted { name, .. }
+            | ManagerEvent::CanaryRolledBack { name, .. }
+            | ManagerEvent::UnexpectedService { name, .. }
+            | ManagerEvent::RestartsExhausted { name, .. }
+            | ManagerEvent::CrashLoop { name, .. }
+            | ManagerEvent::SystemAppOutsideSystemd { name, .. }
+            | ManagerEvent::OomKilled { name, .. }
+            | ManagerEvent::AppRelocated { name, .. }
+            | ManagerEvent::QuotaSuspended { name, .. }
+            | ManagerEvent::SuspensionLifted { name }
+            | ManagerEvent::BinaryQuarantined { name, .. }
+            | ManagerEvent::QuarantineLifted { name }
+            | ManagerEvent::RepositoryChanged { name, .. }
+            | ManagerEvent::LivenessFailed { name, .. }
+            | ManagerEvent::EgressBlocked { name, .. }
+            | ManagerEvent::AppHibernated { name, .. }
+            | ManagerEvent::AppWoken { name, .. }
+            | ManagerEvent::DependencyUnreachable { name, .. } => Some(name),
+            ManagerEvent::PortalConnected { .. }
+            | ManagerEvent::MemoryPressure { .. }
+            | ManagerEvent::NodeReconciled { .. }
+            | ManagerEvent::StatusStoreFull { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ManagerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagerEvent::AppStarted { name, pid } => {
+                write!(f, "{} started with pid {}", name, pid)
+            }
+            ManagerEvent::AppDied { name } => write!(f, "{} died", name),
+            ManagerEvent::StatusChanged { name, from, to } => {
+                write!(f, "{} changed from {:?} to {:?}", name, from, to)
+            }
+            ManagerEvent::UnitStateChanged {
+                name,
+                active_state,
+                sub_state,
+            } => write!(
+                f,
+                "The unit of {} is {} ({})",
+                name, active_state, sub_state
+            ),
+            ManagerEvent::DeployFinished { name } => write!(f, "{} finished deploying", name),
+            ManagerEvent::DeployFailed { name, reason } => {
+                write!(f, "{} failed to deploy: {}", name, reason)
+            }
+            ManagerEvent::DeployQueued { name, position } => {
+                write!(f, "Deploy of {} queued at position {}", name, position)
+            }
+            ManagerEvent::InventoryRecorded {
+                name,
+                version,
+                packages,
+            } => write!(f, "Recorded {} packages for {} {}", packages, name, version),
+            ManagerEvent::PortalConnected { address } => {
+                write!(f, "Registered with portal @ {}", address)
+            }
+            ManagerEvent::ConfigChanged { name, file } => {
+                write!(f, "{} of {} was edited", file, name)
+            }
+            ManagerEvent::MemoryPressure {
+                level,
+                available_percent,
+            } => write!(
+                f,
+                "Memory pressure is {:?}, {} available",
+                level,
+                format_percent(*available_percent)
+            ),
+            ManagerEvent::ConfigDrift { name, files } => {
+                write!(
+                    f,
+                    "{} drifted from its template: {}",
+                    name,
+                    files.join(", ")
+                )
+            }
+            ManagerEvent::PortExhaustion {
+                name,
+                ephemeral_ports,
+                conntrack_entries,
+            } => write!(
+                f,
+                "{} is exhausting ports, {} ephemeral ports and {} conntrack entries",
+                name, ephemeral_ports, conntrack_entries
+            ),
+            ManagerEvent::DataPurged { name } => write!(f, "Purged retained data of {}", name),
+            ManagerEvent::CanaryStarted { name, bake_secs } => {
+                write!(f, "Baking a canary of {} for {}s", name, bake_secs)
+            }
+            ManagerEvent::CanaryRolledBack { name, reason } => {
+                write!(f, "Rolled back the canary of {}: {}", name, reason)
+            }
+            ManagerEvent::UnexpectedService { name, enforced } => match enforced {
+                true => write!(f, "Stopped and masked unexpected service {}", name),
+                false => write!(f, "Unexpected service {} is running", name),
+            },
+            ManagerEvent::RestartsExhausted { name, restarts } => {
+                write!(f, "Gave up restarting {} after {} attempts", name, restarts)
+            }
+            ManagerEvent::CrashLoop {
+                name,
+                restarts,
+                window_secs,
+            } => write!(
+                f,
+                "{} is crash looping, restarted {} times in {}s",
+                name, restarts, window_secs
+            ),
+            ManagerEvent::NodeReconciled { actions, problems } => write!(
+                f,
+                "Reconciled the node spec with {} changes and {} problems",
+                actions, problems
+            ),
+            ManagerEvent::SystemAppOutsideSystemd { name, pid } => write!(
+                f,
+                "System application {} is running outside systemd with pid {}",
+                name, pid
+            ),
+            ManagerEvent::OomKilled { name, kills } => write!(
+                f,
+                "{} was OOM killed, {} kills since the manager started",
+                name, kills
+            ),
+            ManagerEvent::StatusStoreFull {
+                entries,
+                max_entries,
+                evicted,
+            } => write!(
+                f,
+                "The status store holds {} apps but keeps {}, {} won't be saved",
+                entries,
+                max_entries,
+                evicted
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            ManagerEvent::AppRelocated { name, target } => {
+                write!(f, "Moved {} to {}", name, target)
+            }
+            ManagerEvent::QuotaSuspended {
+                name,
+                quota,
+                used,
+                limit,
+            } => write!(
+                f,
+                "{} was suspended, it used {:.2} of its {:.2} {} this month",
+                name, used, limit, quota
+            ),
+            ManagerEvent::SuspensionLifted { name } => {
+                write!(f, "The quota suspension of {} was lifted", name)
+            }
+            ManagerEvent::BinaryQuarantined { name, reason } => {
+                write!(f, "{} was quarantined, {}", name, reason)
+            }
+            ManagerEvent::QuarantineLifted { name } => {
+                write!(f, "The quarantine of {} was lifted", name)
+            }
+            ManagerEvent::LivenessFailed {
+                name,
+                failures,
+                reason,
+            } => write!(
+                f,
+                "{} failed its liveness probe {} times in a row: {}",
+                name, failures, reason
+            ),
+            ManagerEvent::EgressBlocked {
+                name,
+                destination,
+                packets,
+            } => write!(
+                f,
+                "{} tried to reach {} outside its egress allowlist, {} packets dropped",
+                name, destination, packets
+            ),
+            ManagerEvent::AppHibernated { name, idle_secs } => {
+                write!(
+                    f,
+                    "{} hibernated after {}s without traffic",
+                    name, idle_secs
+                )
+            }
+            ManagerEvent::AppWoken { name, wake_ms } => {
+                write!(f, "{} woke up for a connection in {}ms", name, wake_ms)
+            }
+            ManagerEvent::RepositoryChanged { name, commit, .. } => {
+                write!(f, "The repository of {} changed to {}", name, commit)
+            }
+            ManagerEvent::DependencyUnreachable {
+                name,
+                dependency,
+                error_spike,
+            } => match error_spike {
+                true => write!(
+                    f,
+                    "{} is degraded, upstream {} is unreachable and its errors went up",
+                    name, dependency
+                ),
+                false => write!(
+                    f,
+                    "{} is degraded, upstream {} is unreachable",
+                    name, dependency
+                ),
+            },
+            ManagerEvent::SecurityFinding { name, path, issue } => {
+                write!(
+                    f,
+                    "{} has a permission problem: {} is {}",
+                    name, path, issue
+                )
+            }
+        }
+    }
+}
+
+/// Broadcast channel that decouples the subsystems producing events from
+/// the ones reacting to them
+pub struct EventBus {
+    sender: broadcast::Sender<ManagerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Sends an event to every subscriber, it's fine if no one is listening
+    pub fn publish(&self, event: ManagerEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ManagerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes a status transition, setting an app to the status it
+    /// already has isn't one
+    pub fn status_changed(&self, name: &Stringy, from: Status, to: Status) {
+        if from != to {
+            self.publish(ManagerEvent::StatusChanged {
+                name: name.clone(),
+                from,
+                to,
+            });
+        }
+    }
+
+    pub fn portal_connected(&self, address: &PortalAddr) {
+        self.publish(ManagerEvent::PortalConnected {
+            address: format!("{}:{}", address.addr, address.port),
+        });
+    }
+}
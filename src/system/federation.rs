@@ -0,0 +1,176 @@
+use artisan_middleware::aggregator::{AppMessage, Command, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use simple_comms::{
+    network::send_receive::send_message,
+    protocol::{flags::Flags, proto::Proto},
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::auth::attach_token;
+use super::settings::FederationSettings;
+
+/// What one manager answered to a fanned out command
+#[derive(Debug, Clone, Serialize)]
+pub struct FanoutOutcome {
+    pub success: bool,
+    pub message: Option<String>,
+}
+
+impl FanoutOutcome {
+    fn failed(message: String) -> Self {
+        Self {
+            success: false,
+            message: Some(message),
+        }
+    }
+
+    pub fn of(result: &Result<AppMessage, ErrorArrayItem>) -> Self {
+        match result {
+            Ok(AppMessage::Response(response)) => Self {
+                success: response.success,
+                message: response.message.clone(),
+            },
+            Ok(_) => Self {
+                success: true,
+                message: None,
+            },
+            Err(err) => Self::failed(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FanoutReport {
+    pub local: FanoutOutcome,
+    /// Keyed by peer address
+    pub peers: BTreeMap<String, FanoutOutcome>,
+}
+
+/// The command wrapped in `fanout <command>`, `None` when it isn't one.
+/// Start, stop, restart and status are relayed as themselves, anything
+/// else as a custom command.
+pub fn parse_fanout(raw: &str) -> Option<Result<CommandType, ErrorArrayItem>> {
+    let inner: &str = raw.strip_prefix("fanout ")?.trim();
+
+    Some(match inner {
+        "" => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            "fanout needs a command to relay",
+        )),
+        // Peers answer plain commands only, so a relay never loops back.
+        // async and batch could carry a fanout past this check.
+        inner
+            if inner.starts_with("fanout")
+                || inner.starts_with("async")
+                || inner.starts_with("batch") =>
+        {
+            Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "fanout can't wrap fanout, async or batch",
+            ))
+        }
+        "start" => Ok(CommandType::Start),
+        "stop" => Ok(CommandType::Stop),
+        "restart" => Ok(CommandType::Restart),
+        "status" => Ok(CommandType::Status),
+        inner => Ok(CommandType::Custom(inner.to_owned())),
+    })
+}
+
+/// Sends one command to the manager of a peer and waits for its answer
+async fn relay(
+    settings: &FederationSettings,
+    peer: &str,
+    app_id: &Stringy,
+    command_type: CommandType,
+) -> Result<AppMessage, ErrorArrayItem> {
+    let mut stream: TcpStream = TcpStream::connect(format!("{}:{}", peer, settings.port))
+        .await
+        .map_err(|err| ErrorArrayItem::new(Errors::ConnectionError, err.to_string()))?;
+
+    let app_id: Stringy = match &settings.token {
+        Some(token) => attach_token(token, app_id),
+        None => app_id.clone(),
+    };
+    let message: AppMessage = AppMessage::Command(Command {
+        app_id,
+        command_type,
+    });
+
+    match send_message::<TcpStream, AppMessage, AppMessage>(
+        &mut stream,
+        Flags::ENCRYPTED | Flags::COMPRESSED,
+        message,
+        Proto::TCP,
+        false,
+    )
+    .await?
+    {
+        Ok(response) => Ok(response.get_payload().await),
+        Err(status) => Err(ErrorArrayItem::new(
+            Errors::ConnectionError,
+            format!("{} failed the command: {}", peer, status),
+        )),
+    }
+}
+
+/// Relays the command to every peer at once. A peer that doesn't answer in
+/// time counts as failed, the others aren't held up by it.
+pub async fn fan_out(
+    settings: &FederationSettings,
+    app_id: &Stringy,
+    command_type: CommandType,
+) -> BTreeMap<String, FanoutOutcome> {
+    let mut handles = Vec::new();
+
+    for peer in &settings.peers {
+        let (settings, peer, app_id, command_type) = (
+            settings.clone(),
+            peer.clone(),
+            app_id.clone(),
+            command_type.clone(),
+        );
+
+        handles.push(tokio::spawn(async move {
+            let outcome: FanoutOutcome = match timeout(
+                Duration::from_secs(settings.timeout_secs),
+                relay(&settings, &peer, &app_id, command_type),
+            )
+            .await
+            {
+                Ok(result) => FanoutOutcome::of(&result),
+                Err(_) => {
+                    FanoutOutcome::failed(format!("no answer within {}s", settings.timeout_secs))
+                }
+            };
+            (peer, outcome)
+        }));
+    }
+
+    let mut outcomes: BTreeMap<String, FanoutOutcome> = BTreeMap::new();
+    for handle in handles {
+        match handle.await {
+            Ok((peer, outcome)) => {
+                if !outcome.success {
+                    log!(
+                        LogLevel::Warn,
+                        "Fanned out command failed on {}: {}",
+                        peer,
+                        outcome.message.clone().unwrap_or_default()
+                    );
+                }
+                outcomes.insert(peer, outcome);
+            }
+            Err(err) => log!(LogLevel::Error, "Fan out task died: {}", err),
+        }
+    }
+
+    outcomes
+}
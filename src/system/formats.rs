@@ -0,0 +1,316 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::version::aml_version;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::siblings::{compatibility, major_minor, Compatibility};
+
+/// Which format every persisted file was last written in. The files
+/// themselves are serialized by the middleware, so the stamps live here.
+pub const FORMATS_PATH: &str = "/opt/artisan/formats.json";
+
+/// Bumped whenever the manager changes what it persists on top of the
+/// middleware formats
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+/// Outcome of checking every guarded file at startup
+static FORMAT_REPORT: Lazy<Mutex<Vec<FormatCheck>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Stamps already written this run, so saving state doesn't rewrite the
+/// manifest every time
+static STAMPED: Lazy<Mutex<HashMap<String, FormatStamp>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatStamp {
+    pub library: String,
+    pub format: u32,
+}
+
+impl FormatStamp {
+    fn current() -> Self {
+        Self {
+            library: aml_version().to_string(),
+            format: STATE_FORMAT_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatStatus {
+    Current,
+    /// Written before stamps existed, loaded as is
+    Unstamped,
+    /// Older but compatible, rewritten in the current format on next save
+    Migrated,
+    /// Written by a newer manager, read leniently and rewritten on next save
+    Newer,
+    /// Moved aside and started fresh
+    Refused,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatCheck {
+    pub path: String,
+    pub written_with: Option<FormatStamp>,
+    pub status: FormatStatus,
+    /// Where a refused file was moved to
+    pub moved_to: Option<String>,
+    /// Fields we couldn't read and left at their defaults
+    pub defaulted: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatReport {
+    pub current: FormatStamp,
+    pub files: Vec<FormatCheck>,
+}
+
+fn format_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn load_manifest() -> HashMap<String, FormatStamp> {
+    fs::read_to_string(FORMATS_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(manifest: &HashMap<String, FormatStamp>) -> Result<(), ErrorArrayItem> {
+    let data: String =
+        serde_json::to_string_pretty(manifest).map_err(|err| format_error(err.to_string()))?;
+    fs::write(FORMATS_PATH, data)?;
+    Ok(())
+}
+
+fn status_of(stamp: &FormatStamp, current: &FormatStamp) -> FormatStatus {
+    if stamp == current {
+        return FormatStatus::Current;
+    }
+
+    // After a rollback; unknown fields are dropped and anything we can't
+    // parse keeps its default, see read_lenient
+    let newer_library: bool = matches!(
+        (major_minor(&stamp.library), major_minor(&current.library)),
+        (Some(theirs), Some(ours)) if theirs > ours
+    );
+    if stamp.format > current.format || newer_library {
+        return FormatStatus::Newer;
+    }
+
+    match compatibility(&current.library, &stamp.library) {
+        Compatibility::Compatible | Compatibility::Drifted => FormatStatus::Migrated,
+        Compatibility::Incompatible | Compatibility::Unknown => FormatStatus::Refused,
+    }
+}
+
+/// Checks a persisted file against the format we write before it's loaded.
+/// A file we can't safely read is moved aside so loading starts fresh
+/// instead of misreading it.
+pub fn guard_format(path: &str) {
+    let current: FormatStamp = FormatStamp::current();
+    let written_with: Option<FormatStamp> = load_manifest().remove(path);
+
+    let mut check: FormatCheck = FormatCheck {
+        path: path.to_owned(),
+        status: match (&written_with, Path::new(path).exists()) {
+            (_, false) => FormatStatus::Current,
+            (None, true) => FormatStatus::Unstamped,
+            (Some(stamp), true) => status_of(stamp, &current),
+        },
+        written_with,
+        moved_to: None,
+        defaulted: Vec::new(),
+    };
+
+    match check.status {
+        FormatStatus::Current => (),
+        FormatStatus::Unstamped => log!(
+            LogLevel::Info,
+            "{} has no format stamp, loading it as format {}",
+            path,
+            STATE_FORMAT_VERSION
+        ),
+        FormatStatus::Migrated => log!(
+            LogLevel::Warn,
+            "{} was written with middleware {}, migrating to {}",
+            path,
+            check
+                .written_with
+                .as_ref()
+                .map(|stamp| stamp.library.as_str())
+                .unwrap_or_default(),
+            current.library
+        ),
+        FormatStatus::Newer => log!(
+            LogLevel::Warn,
+            "{} was written by a newer manager ({:?}, we write {:?}), reading it leniently",
+            path,
+            check.written_with,
+            current
+        ),
+        FormatStatus::Refused => {
+            let moved_to: String = format!("{}.skew-{}", path, current_timestamp());
+            match fs::rename(path, &moved_to) {
+                Ok(_) => log!(
+                    LogLevel::Error,
+                    "{} was written in a format we can't read ({:?}, we write {:?}), moved it to {}",
+                    path,
+                    check.written_with,
+                    current,
+                    moved_to
+                ),
+                Err(err) => log!(
+                    LogLevel::Error,
+                    "{} was written in a format we can't read and couldn't be moved aside: {}",
+                    path,
+                    err
+                ),
+            }
+            check.moved_to = Some(moved_to);
+        }
+    }
+
+    if let Ok(mut report) = FORMAT_REPORT.lock() {
+        report.retain(|existing| existing.path != path);
+        report.push(check);
+    }
+}
+
+pub enum Encoding {
+    Json,
+    Toml,
+}
+
+/// Reads a file the strict loader rejected, usually because a newer manager
+/// wrote it. Fields we don't know are dropped and fields we can't parse or
+/// didn't get keep the value from `template`.
+pub fn read_lenient<T: Serialize + DeserializeOwned>(
+    path: &str,
+    encoding: Encoding,
+    template: &T,
+) -> Result<T, ErrorArrayItem> {
+    parse_lenient(path, &fs::read_to_string(path)?, encoding, template)
+}
+
+/// `read_lenient` over contents already read, for files that have to be
+/// decrypted first. `path` is what the result is reported under.
+pub fn parse_lenient<T: Serialize + DeserializeOwned>(
+    path: &str,
+    data: &str,
+    encoding: Encoding,
+    template: &T,
+) -> Result<T, ErrorArrayItem> {
+    let read: Value = match encoding {
+        Encoding::Json => {
+            serde_json::from_str(data).map_err(|err| format_error(err.to_string()))?
+        }
+        Encoding::Toml => {
+            let value: toml::Value =
+                toml::from_str(data).map_err(|err| format_error(err.to_string()))?;
+            serde_json::to_value(value).map_err(|err| format_error(err.to_string()))?
+        }
+    };
+
+    let mut merged: Value =
+        serde_json::to_value(template).map_err(|err| format_error(err.to_string()))?;
+    let mut defaulted: Vec<String> = Vec::new();
+
+    match (&mut merged, read) {
+        (Value::Object(fields), Value::Object(read_fields)) => {
+            for (key, value) in read_fields {
+                if !fields.contains_key(&key) {
+                    continue;
+                }
+
+                // Taken one field at a time so a single bad field doesn't
+                // cost us the rest of the file
+                let kept: Option<Value> = fields.insert(key.clone(), value);
+                if serde_json::from_value::<T>(Value::Object(fields.clone())).is_err() {
+                    if let Some(kept) = kept {
+                        fields.insert(key.clone(), kept);
+                    }
+                    defaulted.push(key);
+                }
+            }
+        }
+        _ => {
+            return Err(format_error(format!(
+                "{} doesn't hold a structure we can read",
+                path
+            )))
+        }
+    }
+
+    let value: T = serde_json::from_value(merged).map_err(|err| format_error(err.to_string()))?;
+
+    if !defaulted.is_empty() {
+        log!(
+            LogLevel::Warn,
+            "Read {} leniently, left {} at their defaults",
+            path,
+            defaulted.join(", ")
+        );
+    }
+
+    if let Ok(mut report) = FORMAT_REPORT.lock() {
+        if let Some(check) = report.iter_mut().find(|check| check.path == path) {
+            check.defaulted = defaulted;
+        }
+    }
+
+    Ok(value)
+}
+
+/// Records that a file was just written in the current format
+pub fn stamp_format(path: &str) -> Result<(), ErrorArrayItem> {
+    let current: FormatStamp = FormatStamp::current();
+    let mut stamped = STAMPED
+        .lock()
+        .map_err(|err| format_error(err.to_string()))?;
+
+    if stamped.get(path) == Some(&current) {
+        return Ok(());
+    }
+
+    let mut manifest: HashMap<String, FormatStamp> = load_manifest();
+    manifest.insert(path.to_owned(), current.clone());
+    save_manifest(&manifest)?;
+
+    stamped.insert(path.to_owned(), current);
+    Ok(())
+}
+
+pub fn format_report() -> FormatReport {
+    FormatReport {
+        current: FormatStamp::current(),
+        files: FORMAT_REPORT
+            .lock()
+            .map(|report| report.clone())
+            .unwrap_or_default(),
+    }
+}
+
+/// Files that had to be refused at startup, counted as warnings in Info
+pub fn refused_formats() -> usize {
+    FORMAT_REPORT
+        .lock()
+        .map(|report| {
+            report
+                .iter()
+                .filter(|check| check.status == FormatStatus::Refused)
+                .count()
+        })
+        .unwrap_or(0)
+}
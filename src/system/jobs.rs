@@ -0,0 +1,209 @@
+use artisan_middleware::aggregator::{AppMessage, CommandType};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+
+use super::federation::FanoutOutcome;
+
+/// Jobs and how they ended, kept across reloads so a job id handed out
+/// before one can still be asked about
+const JOBS_PATH: &str = "/opt/artisan/jobs.json";
+
+/// Finished jobs kept for polling, the oldest go first
+const MAX_FINISHED_JOBS: usize = 500;
+
+static JOBS: Lazy<Mutex<BTreeMap<u64, Job>>> = Lazy::new(|| Mutex::new(load_jobs()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    /// The manager went down while the job ran, whether it got through is
+    /// unknown
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub app_id: String,
+    pub command: String,
+    pub state: JobState,
+    /// What the job is doing right now
+    pub progress: String,
+    pub submitted_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// The command's own response once it finished
+    pub success: Option<bool>,
+    pub message: Option<String>,
+}
+
+fn job_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Jobs a reload cut short can't be picked up again, they're marked
+/// interrupted instead
+fn load_jobs() -> BTreeMap<u64, Job> {
+    let mut jobs: BTreeMap<u64, Job> = fs::read_to_string(JOBS_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default();
+
+    for job in jobs.values_mut() {
+        if matches!(job.state, JobState::Queued | JobState::Running) {
+            log!(
+                LogLevel::Warn,
+                "Job {} ({} on {}) was interrupted by a reload",
+                job.id,
+                job.command,
+                job.app_id
+            );
+            job.state = JobState::Interrupted;
+            job.progress = String::from("interrupted by a manager reload");
+            job.finished_at = Some(current_timestamp());
+        }
+    }
+    jobs
+}
+
+fn save_jobs(jobs: &BTreeMap<u64, Job>) -> Result<(), ErrorArrayItem> {
+    let data: String = serde_json::to_string(jobs).map_err(|err| job_error(err.to_string()))?;
+    let temp: String = format!("{}.tmp", JOBS_PATH);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, JOBS_PATH)?;
+    Ok(())
+}
+
+fn with_jobs<T>(f: impl FnOnce(&mut BTreeMap<u64, Job>) -> T) -> Result<T, ErrorArrayItem> {
+    let mut jobs = JOBS.lock().map_err(|err| job_error(err.to_string()))?;
+    let output: T = f(&mut jobs);
+
+    // Finished jobs past the limit make room, running ones are never dropped
+    let finished: Vec<u64> = jobs
+        .values()
+        .filter(|job| matches!(job.state, JobState::Finished | JobState::Interrupted))
+        .map(|job| job.id)
+        .collect();
+    for id in finished
+        .iter()
+        .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+    {
+        jobs.remove(id);
+    }
+
+    save_jobs(&jobs)?;
+    Ok(output)
+}
+
+fn update(id: u64, f: impl FnOnce(&mut Job)) {
+    let result: Result<(), ErrorArrayItem> = with_jobs(|jobs| {
+        if let Some(job) = jobs.get_mut(&id) {
+            f(job);
+        }
+    });
+    if let Err(err) = result {
+        log!(LogLevel::Error, "Failed to update job {}: {}", id, err);
+    }
+}
+
+/// The command wrapped in `async <command>`, `None` when it isn't one.
+/// Start, stop, restart and status run as themselves, anything else as a
+/// custom command.
+pub fn parse_async(raw: &str) -> Option<Result<CommandType, ErrorArrayItem>> {
+    let inner: &str = raw.strip_prefix("async ")?.trim();
+
+    Some(match inner {
+        "" => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            "async needs a command to run",
+        )),
+        // A wrapped fanout would hide from fanout's own nesting check, so
+        // the peers could relay it back here
+        inner
+            if inner.starts_with("async")
+                || inner.starts_with("fanout")
+                || inner.starts_with("batch") =>
+        {
+            Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "async can't wrap async, fanout or batch",
+            ))
+        }
+        "start" => Ok(CommandType::Start),
+        "stop" => Ok(CommandType::Stop),
+        "restart" => Ok(CommandType::Restart),
+        "status" => Ok(CommandType::Status),
+        inner => Ok(CommandType::Custom(inner.to_owned())),
+    })
+}
+
+/// Records a job for the command before it runs, its id goes back to the
+/// caller right away
+pub fn submit_job(app_id: &Stringy, command: &str) -> Result<Job, ErrorArrayItem> {
+    with_jobs(|jobs| {
+        let id: u64 = jobs.keys().next_back().map_or(1, |last| last + 1);
+        let job: Job = Job {
+            id,
+            app_id: app_id.to_string(),
+            command: command.to_owned(),
+            state: JobState::Queued,
+            progress: String::from("queued"),
+            submitted_at: current_timestamp(),
+            started_at: None,
+            finished_at: None,
+            success: None,
+            message: None,
+        };
+        jobs.insert(id, job.clone());
+        job
+    })
+}
+
+pub fn start_job(id: u64) {
+    update(id, |job| {
+        job.state = JobState::Running;
+        job.progress = format!("running {}", job.command);
+        job.started_at = Some(current_timestamp());
+    });
+}
+
+pub fn finish_job(id: u64, result: &Result<AppMessage, ErrorArrayItem>) {
+    let outcome: FanoutOutcome = FanoutOutcome::of(result);
+    update(id, |job| {
+        job.state = JobState::Finished;
+        job.progress = match outcome.success {
+            true => String::from("done"),
+            false => String::from("failed"),
+        };
+        job.finished_at = Some(current_timestamp());
+        job.success = Some(outcome.success);
+        job.message = outcome.message;
+    });
+}
+
+pub fn job_of(id: u64) -> Option<Job> {
+    with_jobs(|jobs| jobs.get(&id).cloned()).ok().flatten()
+}
+
+/// The app's jobs, newest first
+pub fn jobs_of(app_id: &str) -> Vec<Job> {
+    with_jobs(|jobs| {
+        jobs.values()
+            .rev()
+            .filter(|job| job.app_id == app_id)
+            .cloned()
+            .collect()
+    })
+    .unwrap_or_default()
+}
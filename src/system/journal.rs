@@ -0,0 +1,179 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::events::{EventBus, ManagerEvent};
+
+pub const JOURNAL_PATH: &str = "/opt/artisan/events.journal";
+
+/// Most entries handed back for a single replay request
+pub const JOURNAL_REPLAY_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub event: ManagerEvent,
+}
+
+/// Response to a replay request, `last_seq` lets the portal tell if it needs to ask again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalReplay {
+    pub last_seq: u64,
+    pub entries: Vec<JournalEntry>,
+}
+
+/// Append only, sequence numbered record of everything published on the event bus
+pub struct EventJournal {
+    path: PathBuf,
+    // Holds the next sequence number, locking it also serializes writes
+    next_seq: Mutex<u64>,
+}
+
+impl EventJournal {
+    pub fn open(path: &str) -> Result<Self, ErrorArrayItem> {
+        let path: PathBuf = PathBuf::from(path);
+
+        let last_seq: u64 = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .filter_map(|line| line.ok())
+                .filter_map(|line| serde_json::from_str::<JournalEntry>(&line).ok())
+                .map(|entry| entry.seq)
+                .max()
+                .unwrap_or(0),
+            Err(_) => {
+                log!(LogLevel::Info, "Starting a new event journal");
+                0
+            }
+        };
+
+        Ok(Self {
+            path,
+            next_seq: Mutex::new(last_seq + 1),
+        })
+    }
+
+    pub fn append(&self, event: ManagerEvent) -> Result<u64, ErrorArrayItem> {
+        let mut next_seq = self
+            .next_seq
+            .lock()
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+        let entry: JournalEntry = JournalEntry {
+            seq: *next_seq,
+            timestamp: current_timestamp(),
+            event,
+        };
+
+        let line: String = serde_json::to_string(&entry)
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+        let mut file: File = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+
+        *next_seq += 1;
+        Ok(entry.seq)
+    }
+
+    /// Returns every entry with a sequence number greater than `seq`
+    pub fn since(&self, seq: u64, limit: usize) -> Result<Vec<JournalEntry>, ErrorArrayItem> {
+        let file: File = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        Ok(BufReader::new(file)
+            .lines()
+            .filter_map(|line| line.ok())
+            .filter_map(|line| serde_json::from_str::<JournalEntry>(&line).ok())
+            .filter(|entry| entry.seq > seq)
+            .take(limit)
+            .collect())
+    }
+
+    pub fn replay(&self, seq: u64) -> Result<JournalReplay, ErrorArrayItem> {
+        Ok(JournalReplay {
+            last_seq: self.last_seq(),
+            entries: self.since(seq, JOURNAL_REPLAY_LIMIT)?,
+        })
+    }
+
+    /// Rewrites the journal keeping only the entries `keep` returns true for,
+    /// returns how many were dropped. Sequence numbers are never reused.
+    pub fn retain<F>(&self, keep: F) -> Result<usize, ErrorArrayItem>
+    where
+        F: Fn(&JournalEntry) -> bool,
+    {
+        // Hold the sequence lock so nothing is appended mid rewrite
+        let _next_seq = self
+            .next_seq
+            .lock()
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+        let file: File = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(0),
+        };
+
+        let mut kept: Vec<String> = Vec::new();
+        let mut dropped: usize = 0;
+
+        for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+            match serde_json::from_str::<JournalEntry>(&line) {
+                Ok(entry) if !keep(&entry) => dropped += 1,
+                _ => kept.push(line),
+            }
+        }
+
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        let temp_path: PathBuf = self.path.with_extension("tmp");
+        let mut temp: File = File::create(&temp_path)?;
+        for line in kept {
+            writeln!(temp, "{}", line)?;
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        Ok(dropped)
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        match self.next_seq.lock() {
+            Ok(next_seq) => *next_seq - 1,
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Writes everything published on the bus into the journal until the bus closes
+pub async fn record_events(bus: &EventBus, journal: &EventJournal) {
+    let mut receiver = bus.subscribe();
+
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if let Err(err) = journal.append(event) {
+                    log!(LogLevel::Error, "Failed to journal event: {}", err);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                log!(LogLevel::Warn, "Event journal missed {} events", skipped);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use artisan_middleware::aggregator::CommandType;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::commands::CustomCommand;
+
+/// Upper bounds of the histogram buckets in milliseconds, one more bucket
+/// takes everything slower
+const BUCKETS_MS: [u64; 11] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+/// Histograms of every stage, keyed by command
+static HISTOGRAMS: Lazy<Mutex<HashMap<String, HashMap<Stage, Histogram>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Decoding the protocol message
+    Parse,
+    /// The whole handler, including the lock wait and systemd stages
+    Handle,
+    /// Waiting on the state and network control locks
+    LockWait,
+    /// Starting, stopping or restarting units
+    Systemd,
+    /// Encoding the response
+    Serialize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Histogram {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    /// One count per bucket of `buckets_ms` plus the overflow bucket
+    pub buckets: Vec<u64>,
+    #[serde(skip)]
+    total: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            mean_ms: 0.0,
+            max_ms: 0.0,
+            buckets: vec![0; BUCKETS_MS.len() + 1],
+            total: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms: f64 = elapsed.as_secs_f64() * 1000.0;
+        let bucket: usize = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += elapsed;
+        self.mean_ms = self.total.as_secs_f64() * 1000.0 / self.count as f64;
+        self.max_ms = self.max_ms.max(ms);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    pub buckets_ms: Vec<u64>,
+    pub commands: BTreeMap<String, BTreeMap<Stage, Histogram>>,
+}
+
+/// Histogram key of a command. Custom commands are split up by verb, the
+/// arguments would make the keys unbounded.
+fn command_name(command_type: &CommandType) -> String {
+    match command_type {
+        CommandType::Custom(raw) => match CustomCommand::parse(raw) {
+            Ok(_) => format!(
+                "Custom {}",
+                raw.split_whitespace().next().unwrap_or_default()
+            ),
+            Err(_) => String::from("Custom (invalid)"),
+        },
+        other => format!("{:?}", other),
+    }
+}
+
+/// Stage timings of a single command, folded into the histograms once the
+/// command is answered
+pub struct CommandTrace {
+    command: String,
+    stages: Vec<(Stage, Duration)>,
+}
+
+impl CommandTrace {
+    pub fn new() -> Self {
+        Self {
+            command: String::from("Unknown"),
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn set_command(&mut self, command_type: &CommandType) {
+        self.command = command_name(command_type);
+    }
+
+    pub fn record(&mut self, stage: Stage, elapsed: Duration) {
+        self.stages.push((stage, elapsed));
+    }
+
+    pub async fn time<F: Future>(&mut self, stage: Stage, future: F) -> F::Output {
+        let started: Instant = Instant::now();
+        let output: F::Output = future.await;
+        self.record(stage, started.elapsed());
+        output
+    }
+
+    pub fn finish(self) {
+        if let Ok(mut histograms) = HISTOGRAMS.lock() {
+            let stages: &mut HashMap<Stage, Histogram> =
+                histograms.entry(self.command).or_default();
+            for (stage, elapsed) in self.stages {
+                stages.entry(stage).or_default().record(elapsed);
+            }
+        }
+    }
+}
+
+impl Default for CommandTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn latency_report() -> LatencyReport {
+    let commands: BTreeMap<String, BTreeMap<Stage, Histogram>> = HISTOGRAMS
+        .lock()
+        .map(|histograms| {
+            histograms
+                .iter()
+                .map(|(command, stages)| {
+                    (
+                        command.clone(),
+                        stages
+                            .iter()
+                            .map(|(stage, histogram)| (*stage, histogram.clone()))
+                            .collect(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    LatencyReport {
+        buckets_ms: BUCKETS_MS.to_vec(),
+        commands,
+    }
+}
@@ -0,0 +1,525 @@
+use artisan_middleware::aggregator::Metrics;
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::historics::UsageLedger;
+use artisan_middleware::identity::Identifier;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use once_cell::sync::OnceCell;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use super::control::GLOBAL_STATE;
+use super::formats::{guard_format, parse_lenient, stamp_format, Encoding};
+use super::settings::{LedgerBackend, LedgerSettings};
+use super::storage::{storage, LEDGER_KEY};
+
+/// Starts every encrypted ledger, a file without it is the plaintext JSON
+/// older managers wrote
+const MAGIC: &[u8; 8] = b"AISLEDGR";
+
+/// Bumped when the cipher or key derivation changes, older versions stay
+/// readable so the ledger can be migrated
+const SEAL_VERSION: u8 = 2;
+
+/// Sealed with a key derived from the identity, which isn't secret
+const LEGACY_SEAL_VERSION: u8 = 1;
+
+/// Random key the ledger is sealed with, made on the first start
+const LEDGER_KEY_PATH: &str = "/opt/artisan/ledger.key";
+
+static SEALING_KEY: OnceCell<Key> = OnceCell::new();
+
+const NONCE_LEN: usize = 12;
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+fn ledger_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// The ledger key of this machine, read from its key file or created there
+/// readable by root alone
+fn ledger_key() -> Result<Key, ErrorArrayItem> {
+    SEALING_KEY
+        .get_or_try_init(|| match fs::read_to_string(LEDGER_KEY_PATH) {
+            Ok(raw) => hex::decode(raw.trim())
+                .ok()
+                .filter(|bytes| bytes.len() == 32)
+                .map(|bytes| Key::clone_from_slice(&bytes))
+                .ok_or_else(|| {
+                    ledger_error(format!("{} isn't a 32 byte hex key", LEDGER_KEY_PATH))
+                }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key: Key = ChaCha20Poly1305::generate_key(&mut OsRng);
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(LEDGER_KEY_PATH)?
+                    .write_all(hex::encode(key).as_bytes())?;
+                log!(LogLevel::Info, "Created the ledger key {}", LEDGER_KEY_PATH);
+                Ok(key)
+            }
+            Err(err) => Err(err.into()),
+        })
+        .cloned()
+}
+
+/// The key ledgers of the legacy version were sealed with, only read so
+/// they can be migrated
+fn legacy_ledger_key(identity: &Identifier) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(b"ais_manager ledger key v1\0");
+    hasher.update(format!("{}", identity.id).as_bytes());
+    Key::clone_from_slice(&hasher.finalize())
+}
+
+/// The ledger encrypted as magic, version, nonce and ciphertext. The
+/// header is authenticated with it, so a flipped version byte fails to
+/// decrypt rather than being read as another version.
+fn seal(ledger: &UsageLedger, identity: &Identifier) -> Result<Vec<u8>, ErrorArrayItem> {
+    let plaintext: Vec<u8> =
+        serde_json::to_vec(ledger).map_err(|err| ledger_error(err.to_string()))?;
+
+    let cipher: ChaCha20Poly1305 = ChaCha20Poly1305::new(&ledger_key()?);
+    let nonce: Nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut sealed: Vec<u8> = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    sealed.extend_from_slice(MAGIC);
+    sealed.push(SEAL_VERSION);
+    sealed.extend_from_slice(&nonce);
+
+    let ciphertext: Vec<u8> = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| ledger_error(String::from("Failed to encrypt the ledger")))?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Writes the ledger sealed with the machine's ledger key
+pub fn seal_ledger(
+    ledger: &UsageLedger,
+    identity: &Identifier,
+    path: &str,
+) -> Result<(), ErrorArrayItem> {
+    let sealed: Vec<u8> = seal(ledger, identity)?;
+
+    // Written aside and renamed so a crash never leaves half a ledger
+    let temp: String = format!("{}.tmp", path);
+    fs::write(&temp, sealed)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn unseal(data: &[u8], identity: &Identifier, path: &str) -> Result<Vec<u8>, ErrorArrayItem> {
+    if data.len() < HEADER_LEN {
+        return Err(ledger_error(format!("{} is cut short", path)));
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let key: Key = match header[MAGIC.len()] {
+        SEAL_VERSION => ledger_key()?,
+        LEGACY_SEAL_VERSION => legacy_ledger_key(identity),
+        version => {
+            return Err(ledger_error(format!(
+                "{} is sealed with version {}, we read {}",
+                path, version, SEAL_VERSION
+            )))
+        }
+    };
+
+    let cipher: ChaCha20Poly1305 = ChaCha20Poly1305::new(&key);
+    cipher
+        .decrypt(
+            Nonce::from_slice(&header[MAGIC.len() + 1..]),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| {
+            ledger_error(format!(
+                "{} doesn't decrypt with this machine's ledger key",
+                path
+            ))
+        })
+}
+
+/// Reads the ledger, decrypting it when it's sealed
+pub fn open_ledger(identity: &Identifier, path: &str) -> Result<UsageLedger, ErrorArrayItem> {
+    parse_ledger(&fs::read(path)?, identity, path)
+}
+
+/// A plaintext ledger from before encryption is loaded as is and sealed on
+/// the next save, `name` is only used in messages
+fn parse_ledger(
+    data: &[u8],
+    identity: &Identifier,
+    name: &str,
+) -> Result<UsageLedger, ErrorArrayItem> {
+    if !data.starts_with(MAGIC) {
+        log!(
+            LogLevel::Info,
+            "{} is still plaintext, it's encrypted on the next save",
+            name
+        );
+        return serde_json::from_slice(data).or_else(|_| {
+            parse_lenient(
+                name,
+                &String::from_utf8_lossy(data),
+                Encoding::Json,
+                &UsageLedger::new(),
+            )
+        });
+    }
+
+    open_sealed(data, identity, name)
+}
+
+/// Decrypts and parses a sealed ledger, `path` is only used in errors
+fn open_sealed(
+    data: &[u8],
+    identity: &Identifier,
+    path: &str,
+) -> Result<UsageLedger, ErrorArrayItem> {
+    let plaintext: Vec<u8> = unseal(data, identity, path)?;
+    let plaintext: String =
+        String::from_utf8(plaintext).map_err(|err| ledger_error(err.to_string()))?;
+
+    serde_json::from_str(&plaintext)
+        .or_else(|_| parse_lenient(path, &plaintext, Encoding::Json, &UsageLedger::new()))
+}
+
+/// Loads the stored ledger for startup. One that can't be parsed is moved
+/// aside rather than overwritten, it may still be recovered by hand. When
+/// the storage itself can't be reached that's an error, starting over would
+/// overwrite the ledger on the next persist.
+fn load_stored(identity: &Identifier) -> Result<UsageLedger, ErrorArrayItem> {
+    let data: Vec<u8> = match storage()?.read(LEDGER_KEY)? {
+        Some(data) => data,
+        None => return Ok(UsageLedger::new()),
+    };
+
+    match parse_ledger(&data, identity, LEDGER_KEY) {
+        Ok(ledger) => Ok(ledger),
+        Err(err) => {
+            let moved_to: String = format!("{}.unreadable-{}", LEDGER_KEY, current_timestamp());
+            match storage().and_then(|store| store.write(&moved_to, &data)) {
+                Ok(_) => log!(
+                    LogLevel::Error,
+                    "Couldn't read the ledger, moved it to {} and started a new one: {}",
+                    moved_to,
+                    err
+                ),
+                Err(move_err) => log!(
+                    LogLevel::Error,
+                    "Couldn't read the ledger ({}) nor move it aside: {}",
+                    err,
+                    move_err
+                ),
+            }
+            Ok(UsageLedger::new())
+        }
+    }
+}
+
+/// The ledger's file on this node, for the format stamps. A ledger in a
+/// database has none.
+fn stored_path() -> Option<String> {
+    storage()
+        .ok()?
+        .local_path(LEDGER_KEY)
+        .map(|path| path.display().to_string())
+}
+
+/// One sample as the ledger took it
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerSample {
+    pub timestamp: u64,
+    pub cpu_usage: f64,
+    pub memory_usage: u64,
+    /// Counters of the app's traffic, `None` when it had none tracked
+    pub rx_bytes: Option<u64>,
+    pub tx_bytes: Option<u64>,
+}
+
+/// Where the ledger is persisted, picked by `[ledger] backend`
+pub trait LedgerStore: Send + Sync {
+    /// The ledger as last persisted, empty when there's none
+    fn load(&self, identity: &Identifier) -> Result<UsageLedger, ErrorArrayItem>;
+
+    /// Runs every 30 seconds, stores that are expensive to write may skip
+    /// some unless `force` is set as it is on shutdown
+    fn persist(
+        &self,
+        ledger: &UsageLedger,
+        identity: &Identifier,
+        force: bool,
+    ) -> Result<(), ErrorArrayItem>;
+
+    /// Runs for every sample the ledger takes
+    fn append(&self, app: &str, timestamp: u64, metrics: &Metrics) -> Result<(), ErrorArrayItem>;
+
+    /// The app's samples between `from` and `to`, oldest first
+    fn samples(&self, app: &str, from: u64, to: u64) -> Result<Vec<LedgerSample>, ErrorArrayItem>;
+}
+
+/// The whole ledger sealed under one key of the configured storage,
+/// rewritten on every persist
+pub struct JsonStore;
+
+impl LedgerStore for JsonStore {
+    fn load(&self, identity: &Identifier) -> Result<UsageLedger, ErrorArrayItem> {
+        if let Some(path) = stored_path() {
+            guard_format(&path);
+        }
+        load_stored(identity)
+    }
+
+    fn persist(
+        &self,
+        ledger: &UsageLedger,
+        identity: &Identifier,
+        _force: bool,
+    ) -> Result<(), ErrorArrayItem> {
+        storage()?.write(LEDGER_KEY, &seal(ledger, identity)?)?;
+        match stored_path() {
+            Some(path) => stamp_format(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn append(
+        &self,
+        _app: &str,
+        _timestamp: u64,
+        _metrics: &Metrics,
+    ) -> Result<(), ErrorArrayItem> {
+        Ok(())
+    }
+
+    fn samples(
+        &self,
+        _app: &str,
+        _from: u64,
+        _to: u64,
+    ) -> Result<Vec<LedgerSample>, ErrorArrayItem> {
+        Err(ledger_error(String::from(
+            "The json ledger keeps no samples, set [ledger] backend to sqlite",
+        )))
+    }
+}
+
+/// Samples appended to an indexed table as they're taken. The ledger itself
+/// is sealed into the database on the snapshot interval, so persisting no
+/// longer costs the whole history every 30 seconds.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+    settings: LedgerSettings,
+    last_snapshot: AtomicU64,
+}
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS samples (
+    app TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    cpu_usage REAL NOT NULL,
+    memory_usage INTEGER NOT NULL,
+    rx_bytes INTEGER,
+    tx_bytes INTEGER
+);
+CREATE INDEX IF NOT EXISTS samples_by_app_time ON samples (app, timestamp);
+CREATE TABLE IF NOT EXISTS snapshot (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    taken INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+";
+
+/// Most samples one query returns
+const MAX_SAMPLES: i64 = 10_000;
+
+fn sql_error(err: rusqlite::Error) -> ErrorArrayItem {
+    ledger_error(err.to_string())
+}
+
+impl SqliteStore {
+    pub fn open(settings: &LedgerSettings) -> Result<Self, ErrorArrayItem> {
+        let connection: Connection = Connection::open(&settings.sqlite_path).map_err(sql_error)?;
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            settings: settings.clone(),
+            last_snapshot: AtomicU64::new(0),
+        })
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, ErrorArrayItem> {
+        self.connection
+            .lock()
+            .map_err(|err| ledger_error(err.to_string()))
+    }
+}
+
+impl LedgerStore for SqliteStore {
+    /// Falls back to the json ledger until the first snapshot, so switching
+    /// backends keeps the history
+    fn load(&self, identity: &Identifier) -> Result<UsageLedger, ErrorArrayItem> {
+        let snapshot: Option<Vec<u8>> = self
+            .connection()?
+            .query_row("SELECT data FROM snapshot WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_error)?;
+
+        if let Some(data) = snapshot {
+            return open_sealed(&data, identity, &self.settings.sqlite_path);
+        }
+
+        match storage()?.read(LEDGER_KEY)? {
+            Some(data) => {
+                log!(
+                    LogLevel::Info,
+                    "Moving the ledger from {} into {}",
+                    LEDGER_KEY,
+                    self.settings.sqlite_path
+                );
+                let ledger: UsageLedger = parse_ledger(&data, identity, LEDGER_KEY)?;
+                self.persist(&ledger, identity, true)?;
+                Ok(ledger)
+            }
+            None => Ok(UsageLedger::new()),
+        }
+    }
+
+    fn persist(
+        &self,
+        ledger: &UsageLedger,
+        identity: &Identifier,
+        force: bool,
+    ) -> Result<(), ErrorArrayItem> {
+        let now: u64 = current_timestamp();
+        if !force
+            && now
+                < self.last_snapshot.load(Ordering::Relaxed) + self.settings.snapshot_interval_secs
+        {
+            return Ok(());
+        }
+
+        let sealed: Vec<u8> = seal(ledger, identity)?;
+        let cutoff: u64 = now.saturating_sub(self.settings.sample_retention_days * 24 * 60 * 60);
+
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO snapshot (id, taken, data) VALUES (1, ?1, ?2)",
+                params![now as i64, sealed],
+            )
+            .map_err(sql_error)?;
+        transaction
+            .execute(
+                "DELETE FROM samples WHERE timestamp < ?1",
+                params![cutoff as i64],
+            )
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+
+        self.last_snapshot.store(now, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn append(&self, app: &str, timestamp: u64, metrics: &Metrics) -> Result<(), ErrorArrayItem> {
+        self.connection()?
+            .execute(
+                "INSERT INTO samples (app, timestamp, cpu_usage, memory_usage, rx_bytes, tx_bytes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    app,
+                    timestamp as i64,
+                    metrics.cpu_usage as f64,
+                    metrics.memory_usage as i64,
+                    metrics.other.as_ref().map(|net| net.rx_bytes as i64),
+                    metrics.other.as_ref().map(|net| net.tx_bytes as i64),
+                ],
+            )
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn samples(&self, app: &str, from: u64, to: u64) -> Result<Vec<LedgerSample>, ErrorArrayItem> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare(
+                "SELECT timestamp, cpu_usage, memory_usage, rx_bytes, tx_bytes FROM samples
+                 WHERE app = ?1 AND timestamp BETWEEN ?2 AND ?3
+                 ORDER BY timestamp LIMIT ?4",
+            )
+            .map_err(sql_error)?;
+
+        let samples = statement
+            .query_map(params![app, from as i64, to as i64, MAX_SAMPLES], |row| {
+                Ok(LedgerSample {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    cpu_usage: row.get(1)?,
+                    memory_usage: row.get::<_, i64>(2)? as u64,
+                    rx_bytes: row.get::<_, Option<i64>>(3)?.map(|bytes| bytes as u64),
+                    tx_bytes: row.get::<_, Option<i64>>(4)?.map(|bytes| bytes as u64),
+                })
+            })
+            .map_err(sql_error)?
+            .collect::<Result<Vec<LedgerSample>, rusqlite::Error>>()
+            .map_err(sql_error)?;
+        Ok(samples)
+    }
+}
+
+static JSON_STORE: JsonStore = JsonStore;
+
+static STORE: OnceCell<Box<dyn LedgerStore>> = OnceCell::new();
+
+/// The configured store, opened on first use. Until the global state exists
+/// and whenever the database can't be opened, that's the json ledger.
+pub fn ledger_store() -> &'static dyn LedgerStore {
+    let settings: LedgerSettings = match GLOBAL_STATE.get() {
+        Some(gs) => gs.settings.ledger.clone(),
+        None => return &JSON_STORE,
+    };
+
+    STORE
+        .get_or_init(|| match settings.backend {
+            LedgerBackend::Json => Box::new(JsonStore),
+            LedgerBackend::Sqlite => match SqliteStore::open(&settings) {
+                Ok(store) => Box::new(store),
+                Err(err) => {
+                    log!(
+                        LogLevel::Error,
+                        "Couldn't open {}, keeping the json ledger: {}",
+                        settings.sqlite_path,
+                        err
+                    );
+                    Box::new(JsonStore)
+                }
+            },
+        })
+        .as_ref()
+}
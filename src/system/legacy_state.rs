@@ -0,0 +1,189 @@
+use artisan_middleware::aggregator::{save_registered_apps, AppStatus, Status};
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::process_manager::is_pid_active;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::applications::child::APP_STATUS_ARRAY;
+
+use super::settings::LegacyStateSettings;
+
+/// One app as the aggregator era saved it. Those files were written by
+/// several releases, so every field is looked up under each name it had.
+#[derive(Debug, Clone)]
+struct LegacyEntry {
+    name: String,
+    expected_status: Option<Status>,
+    /// When the app was started, from the uptime it had when saved
+    started_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LegacyMigration {
+    pub path: String,
+    pub moved_to: String,
+    pub migrated: Vec<String>,
+    /// Apps in the old file this node doesn't run anymore
+    pub unmatched: Vec<String>,
+}
+
+fn legacy_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn field<'a>(entry: &'a Value, names: &[&str]) -> Option<&'a Value> {
+    names.iter().find_map(|name| entry.get(*name))
+}
+
+fn parse_status(value: Option<&Value>) -> Option<Status> {
+    serde_json::from_value(value?.clone()).ok()
+}
+
+fn parse_entry(key: Option<&str>, entry: &Value) -> Option<LegacyEntry> {
+    let name: String = match field(entry, &["name", "app_name", "app_id"]).and_then(Value::as_str) {
+        Some(name) => name.to_owned(),
+        None => key?.to_owned(),
+    };
+
+    let started_at: Option<u64> = match (
+        field(entry, &["uptime"]).and_then(Value::as_u64),
+        field(entry, &["timestamp", "last_updated", "saved_at"]).and_then(Value::as_u64),
+    ) {
+        (Some(uptime), Some(saved_at)) => Some(saved_at.saturating_sub(uptime)),
+        _ => None,
+    };
+
+    Some(LegacyEntry {
+        name,
+        // Releases before expected statuses existed only saved what the
+        // app was doing, which is what it was expected to keep doing
+        expected_status: parse_status(field(entry, &["expected_status", "expected"]))
+            .or_else(|| parse_status(field(entry, &["status"]))),
+        started_at,
+    })
+}
+
+/// The entries of a file the current middleware can't read, `None` when it
+/// can and there's nothing to migrate
+fn legacy_entries(data: &str) -> Result<Option<Vec<LegacyEntry>>, ErrorArrayItem> {
+    let value: Value = serde_json::from_str(data).map_err(|err| legacy_error(err.to_string()))?;
+    if serde_json::from_value::<Vec<AppStatus>>(value.clone()).is_ok() {
+        return Ok(None);
+    }
+
+    let entries: Vec<LegacyEntry> = match &value {
+        Value::Array(entries) => entries
+            .iter()
+            .filter_map(|entry| parse_entry(None, entry))
+            .collect(),
+        // The oldest layout keyed the apps by name
+        Value::Object(entries) => entries
+            .iter()
+            .filter_map(|(key, entry)| parse_entry(Some(key), entry))
+            .collect(),
+        _ => {
+            return Err(legacy_error(String::from(
+                "the registered apps file holds neither a list nor a map",
+            )))
+        }
+    };
+    Ok(Some(entries))
+}
+
+/// Carries the expected status and uptime baseline of a legacy entry over
+/// to the app's status. The baseline only holds while the process that
+/// had it is still up.
+async fn apply(entry: &LegacyEntry) -> Result<bool, ErrorArrayItem> {
+    let key: Stringy = match APP_STATUS_ARRAY
+        .contains(&entry.name.as_str().into())
+        .await?
+    {
+        true => entry.name.as_str().into(),
+        false => match APP_STATUS_ARRAY
+            .snapshot()
+            .await?
+            .into_iter()
+            .find(|(_, status)| status.app_id.as_str() == entry.name)
+        {
+            Some((key, _)) => key,
+            None => return Ok(false),
+        },
+    };
+
+    APP_STATUS_ARRAY
+        .modify(&key, |status| {
+            if let Some(expected) = &entry.expected_status {
+                status.expected_status = expected.clone();
+            }
+            if let Some(started_at) = entry.started_at {
+                let pid: i32 = status.app_data.get_pid() as i32;
+                if matches!(is_pid_active(pid), Ok(true)) {
+                    status.timestamp = started_at;
+                }
+            }
+        })
+        .await?;
+    Ok(true)
+}
+
+/// Converts a registered apps file from the aggregator era into today's
+/// format. Runs once the status array is populated, the old file is kept
+/// next to the new one.
+pub async fn migrate_legacy_state(
+    settings: &LegacyStateSettings,
+) -> Result<Option<LegacyMigration>, ErrorArrayItem> {
+    let path: &str = &settings.registered_apps_path;
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+
+    let entries: Vec<LegacyEntry> = match legacy_entries(&fs::read_to_string(path)?)? {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    let mut migration: LegacyMigration = LegacyMigration {
+        path: path.to_owned(),
+        moved_to: format!("{}.legacy-{}", path, current_timestamp()),
+        migrated: Vec::new(),
+        unmatched: Vec::new(),
+    };
+
+    for entry in &entries {
+        match apply(entry).await? {
+            true => migration.migrated.push(entry.name.clone()),
+            false => migration.unmatched.push(entry.name.clone()),
+        }
+    }
+
+    fs::rename(path, &migration.moved_to)?;
+    let statuses: Vec<AppStatus> = APP_STATUS_ARRAY
+        .snapshot()
+        .await?
+        .into_values()
+        .map(|status| AppStatus::clone(&status))
+        .collect();
+    save_registered_apps(&statuses).await?;
+
+    log!(
+        LogLevel::Info,
+        "Migrated {} apps from the legacy registered apps file, kept it as {}",
+        migration.migrated.len(),
+        migration.moved_to
+    );
+    if !migration.unmatched.is_empty() {
+        log!(
+            LogLevel::Warn,
+            "The legacy registered apps file lists apps this node doesn't run: {}",
+            migration.unmatched.join(", ")
+        );
+    }
+
+    Ok(Some(migration))
+}
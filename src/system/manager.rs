@@ -16,7 +16,11 @@ use crate::applications::child::{
 
 use gethostname::gethostname;
 
+use super::control::GLOBAL_STATE;
+use super::formats::refused_formats;
 use super::portal::load_identifier;
+use super::siblings::incompatible_siblings;
+use super::watchdog::memory_warning;
 
 pub async fn get_manager_data(state: &mut AppState) -> Result<ManagerData, ErrorArrayItem> {
     let manager_version = state.version.clone();
@@ -36,37 +40,48 @@ pub async fn get_manager_data(state: &mut AppState) -> Result<ManagerData, Error
         ));
     };
 
-    let system_array = SYSTEM_APPLICATION_ARRAY.try_read().await?;
-    let client_array = CLIENT_APPLICATION_ARRAY.try_read().await?;
-    let status_array = APP_STATUS_ARRAY.try_read().await?;
     let mut uptime = None;
 
-    status_array.clone().into_iter().for_each(|status| {
-        if status.1.app_id == "ais_manager".into() {
-            uptime = status.1.uptime
+    for (_, status) in APP_STATUS_ARRAY.entries().await? {
+        let status = status.try_read().await?;
+        if status.app_id == "ais_manager".into() {
+            uptime = status.uptime
         }
-    });
+    }
 
+    let system_array = SYSTEM_APPLICATION_ARRAY.entries().await?;
     let system_warning_count = {
         let mut num = 0;
-        for system in system_array.clone() {
-            let state = system.1.config.get_state();
-            let count = state.error_log.len();
+        for (_, system) in &system_array {
+            let count = system.try_read().await?.config.state.error_log.len();
             num += count
         }
         num
     };
 
+    let client_array = CLIENT_APPLICATION_ARRAY.entries().await?;
     let client_warning_count = {
         let mut num = 0;
-        for client in client_array.clone() {
-            let state = client.1.config.get_state();
-            let count = state.error_log.len();
+        for (_, client) in &client_array {
+            let count = client.try_read().await?.config.state.error_log.len();
             num += count
         }
         num
     };
 
+    // ManagerData has no room for the matrix itself, the siblings command
+    // has the details
+    let sibling_warning_count: usize = incompatible_siblings().await;
+
+    // Same for the manager's own RSS, see the manager memory command
+    let memory_warning_count: usize = match GLOBAL_STATE.get() {
+        Some(gs) => memory_warning(&gs.settings.memory_watchdog) as usize,
+        None => 0,
+    };
+
+    // And for files refused over a format mismatch, see the formats command
+    let format_warning_count: usize = refused_formats();
+
     let identity = if let Some(id) = load_identifier().await {
         id
     } else {
@@ -81,7 +96,11 @@ pub async fn get_manager_data(state: &mut AppState) -> Result<ManagerData, Error
         git_config: git_credentials,
         system_apps: system_array.len() as u32,
         client_apps: client_array.len() as u32,
-        warning: (client_warning_count + system_warning_count) as u32,
+        warning: (client_warning_count
+            + system_warning_count
+            + sibling_warning_count
+            + memory_warning_count
+            + format_warning_count) as u32,
         hostname: match gethostname().into_string() {
             Ok(data) => data.into(),
             Err(_) => "Failed to resolve hostname".into(),
@@ -7,6 +7,18 @@ pub mod control;
 // save, load, and manipulating state data
 pub mod state;
 
+// format versions of persisted files and the guard against skew
+pub mod formats;
+
+// registered apps files from the aggregator era, converted at startup
+pub mod legacy_state;
+
+// where the usage ledger is persisted, encrypted at rest
+pub mod ledger_store;
+
+// local, sqlite or s3 backed persistence for the ledger, snapshot and audit
+pub mod storage;
+
 // portal logic
 pub mod portal;
 
@@ -16,5 +28,74 @@ pub mod manager;
 //driver for interacting with ebpf system
 pub mod ebpf;
 
+// internal event bus between subsystems
+pub mod events;
+
+// on disk journal backing the event bus
+pub mod journal;
+
+// chunked, checksummed file transfers over the command protocol
+pub mod transfer;
+
+// manager specific settings file
+pub mod settings;
+
+// cgroup v1, v2 and hybrid layouts and what each can't do
+pub mod cgroups;
+
+// signed tokens and roles for the command listener
+pub mod auth;
+
+// append only audit log of commands
+pub mod audit;
+
+// journal of what the manager decided on its own and why
+pub mod decisions;
+
+// other artisan components on the node and their compatibility
+pub mod siblings;
+
+// host memory pressure protection
+pub mod pressure;
+
+// memory watchdog for the manager process itself
+pub mod watchdog;
+
+// operator alerts mailed through ais_mailler
+pub mod alerts;
+
+// commands relayed to the managers of peer nodes
+pub mod federation;
+
+// one command run on every client app a selector picks
+pub mod batch;
+
+// long running commands queued as jobs and polled by id
+pub mod jobs;
+
+// per command latency histograms split up by handling stage
+pub mod latency;
+
+// monitor loop cadence that follows node activity
+pub mod cadence;
+
+// tokio runtime tuning from the settings file
+pub mod runtime;
+
+// byte and percentage formatting shared by logs, alerts and status text
+pub mod units;
+
+// what telemetry leaves the node, per tenant
+pub mod telemetry;
+
+// node and tenant timezones for rollovers and human readable timestamps
+pub mod timezone;
+
+// end to end checks of every subsystem for post install verification
+pub mod self_test;
+
 // signalling system for  shutdowns and reloads
 pub mod signals;
+
+// native systemd client over D-Bus, follows unit state from its signals
+pub mod systemd_bus;
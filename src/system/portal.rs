@@ -39,7 +39,7 @@ impl fmt::Display for PortalAddr {
     }
 }
 
-async fn portal_discovery(stream: &mut TcpStream) -> Result<(), ErrorArrayItem> {
+pub async fn portal_discovery(stream: &mut TcpStream) -> Result<(), ErrorArrayItem> {
     let discovery = PortalMessage::Discover;
     match send_message::<TcpStream, PortalMessage, PortalMessage>(
         stream,
@@ -276,6 +276,7 @@ pub async fn connect_with_portal(state:&mut AppState ) -> Result<(), ErrorArrayI
             } else {
                 log!(LogLevel::Debug, "Registered with portal @ {} !", portal.get_address());
                 global_state.portal_state.set_time(portal.get_address(), true).await?;
+                global_state.events.portal_connected(&portal.get_address());
             }
             },
             Err(err) => {
@@ -0,0 +1,245 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::applications::child::{APP_STATUS_ARRAY, CLIENT_APPLICATION_ARRAY};
+use crate::applications::priority::{PriorityClass, APP_PRIORITIES};
+
+use super::cgroups::service_file;
+use super::control::GlobalState;
+use super::decisions::Decision;
+use super::events::ManagerEvent;
+use super::settings::MemoryPressureSettings;
+use super::units::format_percent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PressureLevel {
+    Normal,
+    Elevated,
+    Critical,
+}
+
+/// Tracks the protective actions taken while the host is short on memory
+pub struct PressureGuard {
+    starts_paused: AtomicBool,
+    level: Mutex<PressureLevel>,
+    throttled: Mutex<HashSet<Stringy>>,
+}
+
+impl PressureGuard {
+    pub fn new() -> Self {
+        Self {
+            starts_paused: AtomicBool::new(false),
+            level: Mutex::new(PressureLevel::Normal),
+            throttled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn starts_paused(&self) -> bool {
+        self.starts_paused.load(Ordering::Relaxed)
+    }
+
+    /// Returns an error while new application starts are on hold
+    pub fn check_start_allowed(&self) -> Result<(), ErrorArrayItem> {
+        match self.starts_paused() {
+            true => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                "Application starts are paused while the host is under memory pressure",
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Percentage of memory still available according to /proc/meminfo
+fn available_memory_percent() -> Result<f64, ErrorArrayItem> {
+    let meminfo: String = fs::read_to_string("/proc/meminfo")?;
+
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|value| value.parse::<f64>().ok())
+    };
+
+    match (field("MemTotal:"), field("MemAvailable:")) {
+        (Some(total), Some(available)) if total > 0.0 => Ok(available / total * 100.0),
+        _ => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            "Failed to read memory totals from /proc/meminfo",
+        )),
+    }
+}
+
+/// Caps a service at the memory it's using right now so the kernel reclaims
+/// from it first
+fn throttle(app: &str) -> Result<(), ErrorArrayItem> {
+    let current: String = fs::read_to_string(service_file(app, "memory.current"))?;
+    fs::write(service_file(app, "memory.high"), current.trim())?;
+    Ok(())
+}
+
+fn release(app: &str) -> Result<(), ErrorArrayItem> {
+    fs::write(service_file(app, "memory.high"), "max")?;
+    Ok(())
+}
+
+/// The lowest priority client application using the most memory that isn't
+/// throttled yet, critical applications are never picked
+async fn pick_victim(throttled: &HashSet<Stringy>) -> Result<Option<Stringy>, ErrorArrayItem> {
+    let mut memory: Vec<(Stringy, f64)> = Vec::new();
+    for name in CLIENT_APPLICATION_ARRAY.keys().await? {
+        if throttled.contains(&name) {
+            continue;
+        }
+
+        if let Some(Some(usage)) = APP_STATUS_ARRAY
+            .read(&name, |status| {
+                status
+                    .metrics
+                    .as_ref()
+                    .map(|metrics| metrics.memory_usage as f64)
+            })
+            .await?
+        {
+            memory.push((name, usage));
+        }
+    }
+
+    let priorities = APP_PRIORITIES.try_read().await?;
+
+    let victim = memory
+        .iter()
+        .filter_map(|(name, usage)| {
+            let priority: PriorityClass = priorities
+                .get(name)
+                .copied()
+                .unwrap_or(PriorityClass::Standard);
+
+            match priority {
+                PriorityClass::Critical => None,
+                priority => Some((name, priority, *usage)),
+            }
+        })
+        .max_by(|a, b| {
+            a.1.cmp(&b.1)
+                .then(a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+        })
+        .map(|(name, _, _)| name.clone());
+
+    Ok(victim)
+}
+
+/// Samples host memory and applies or lifts the configured protective actions
+pub async fn check_memory_pressure(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &MemoryPressureSettings = &gs.settings.memory_pressure;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let available: f64 = available_memory_percent()?;
+    let level: PressureLevel = if available <= settings.critical_available_percent {
+        PressureLevel::Critical
+    } else if available <= settings.elevated_available_percent {
+        PressureLevel::Elevated
+    } else {
+        PressureLevel::Normal
+    };
+
+    let guard: &PressureGuard = &gs.pressure;
+    let previous: PressureLevel = {
+        let mut current = guard
+            .level
+            .lock()
+            .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+        std::mem::replace(&mut *current, level)
+    };
+
+    if level != previous {
+        log!(
+            LogLevel::Warn,
+            "Memory pressure {:?} -> {:?} ({} available)",
+            previous,
+            level,
+            format_percent(available)
+        );
+
+        if settings.alert {
+            gs.events.publish(ManagerEvent::MemoryPressure {
+                level,
+                available_percent: available,
+            });
+        }
+    }
+
+    guard.starts_paused.store(
+        settings.pause_starts && level != PressureLevel::Normal,
+        Ordering::Relaxed,
+    );
+
+    if level == PressureLevel::Normal {
+        let throttled: Vec<Stringy> = match guard.throttled.lock() {
+            Ok(mut throttled) => throttled.drain().collect(),
+            Err(_) => Vec::new(),
+        };
+
+        for app in throttled {
+            match release(&app) {
+                Ok(_) => {
+                    log!(LogLevel::Info, "Released memory throttle on {}", app);
+                    Decision::new(
+                        "pressure",
+                        Some(&app),
+                        "release throttle",
+                        String::from("host memory pressure is back to normal"),
+                    )
+                    .input("available_percent", available)
+                    .record();
+                }
+                Err(err) => log!(LogLevel::Error, "Failed to release {}: {}", app, err),
+            }
+        }
+    }
+
+    // One more victim per check while we stay critical
+    if level == PressureLevel::Critical && settings.throttle_clients {
+        let throttled: HashSet<Stringy> = guard
+            .throttled
+            .lock()
+            .map(|throttled| throttled.clone())
+            .unwrap_or_default();
+
+        if let Some(victim) = pick_victim(&throttled).await? {
+            throttle(&victim)?;
+            log!(
+                LogLevel::Warn,
+                "Throttled {} due to memory pressure",
+                victim
+            );
+            Decision::new(
+                "pressure",
+                Some(&victim),
+                "throttle",
+                String::from("host memory is critical and it's the largest non critical app"),
+            )
+            .input("available_percent", available)
+            .input(
+                "critical_available_percent",
+                settings.critical_available_percent,
+            )
+            .record();
+            if let Ok(mut throttled) = guard.throttled.lock() {
+                throttled.insert(victim);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::thread;
+use tokio::runtime::{Builder, Runtime};
+
+use super::settings::RuntimeSettings;
+
+/// What the runtime was actually built with, settings left at 0 resolved
+static EFFECTIVE: OnceCell<RuntimeReport> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeReport {
+    pub worker_threads: usize,
+    pub max_blocking_threads: usize,
+    pub thread_name: String,
+    pub available_cores: usize,
+}
+
+/// Builds the multi threaded runtime the manager runs on
+pub fn build_runtime(settings: &RuntimeSettings) -> Result<Runtime, ErrorArrayItem> {
+    let available_cores: usize = thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1);
+
+    let report: RuntimeReport = RuntimeReport {
+        worker_threads: match settings.worker_threads {
+            0 => available_cores,
+            workers => workers,
+        },
+        max_blocking_threads: settings.max_blocking_threads.max(1),
+        thread_name: settings.thread_name.clone(),
+        available_cores,
+    };
+
+    let runtime: Runtime = Builder::new_multi_thread()
+        .worker_threads(report.worker_threads)
+        .max_blocking_threads(report.max_blocking_threads)
+        .thread_name(report.thread_name.clone())
+        .enable_all()
+        .build()?;
+
+    log!(
+        LogLevel::Info,
+        "Runtime started with {} workers and up to {} blocking threads on {} cores",
+        report.worker_threads,
+        report.max_blocking_threads,
+        report.available_cores
+    );
+
+    let _ = EFFECTIVE.set(report);
+    Ok(runtime)
+}
+
+pub fn runtime_report() -> Option<RuntimeReport> {
+    EFFECTIVE.get().cloned()
+}
@@ -0,0 +1,257 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::identity::Identifier;
+use serde::Serialize;
+use std::fs;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use super::cgroups::service_file;
+use super::control::{GlobalState, PortalIntance};
+use super::ledger_store::{open_ledger, seal_ledger};
+use super::portal::portal_discovery;
+
+/// No single check gets to hold up the report for longer than this
+const CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Written and read back by the ledger check, never the real ledger
+const SCRATCH_LEDGER_PATH: &str = "/tmp/.ais_manager_self_test_ledger.json";
+
+/// The dummy unit is this followed by the time it was started
+pub const SELF_TEST_UNIT_PREFIX: &str = "ais_self_test_";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemCheck {
+    pub subsystem: &'static str,
+    pub passed: bool,
+    pub detail: String,
+    pub took_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub ran_at: u64,
+    pub checks: Vec<SubsystemCheck>,
+}
+
+fn self_test_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+async fn check<F>(subsystem: &'static str, test: F) -> SubsystemCheck
+where
+    F: Future<Output = Result<String, ErrorArrayItem>>,
+{
+    let started: Instant = Instant::now();
+    let result: Result<String, ErrorArrayItem> = match timeout(CHECK_TIMEOUT, test).await {
+        Ok(result) => result,
+        Err(_) => Err(self_test_error(format!(
+            "Timed out after {}s",
+            CHECK_TIMEOUT.as_secs()
+        ))),
+    };
+
+    let (passed, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(err) => {
+            log!(LogLevel::Warn, "Self test of {} failed: {}", subsystem, err);
+            (false, err.err_mesg.to_string())
+        }
+    };
+
+    SubsystemCheck {
+        subsystem,
+        passed,
+        detail,
+        took_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// A short lived unit in the slice our apps run in
+struct DummyUnit {
+    unit: String,
+}
+
+impl DummyUnit {
+    async fn spawn() -> Result<Self, ErrorArrayItem> {
+        let unit: String = format!("{}{}", SELF_TEST_UNIT_PREFIX, current_timestamp());
+        let output = Command::new("systemd-run")
+            .arg(format!("--unit={}", unit))
+            .arg("--slice=artisan.slice")
+            .arg("--property=RuntimeMaxSec=60")
+            .arg("/bin/sleep")
+            .arg("60")
+            .output()
+            .await?;
+
+        match output.status.success() {
+            true => Ok(Self { unit }),
+            false => Err(self_test_error(format!(
+                "systemd-run failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+
+    async fn main_pid(&self) -> Result<u32, ErrorArrayItem> {
+        let output = Command::new("systemctl")
+            .args(["show", "--property=MainPID", "--value"])
+            .arg(format!("{}.service", self.unit))
+            .output()
+            .await?;
+
+        match String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<u32>()
+        {
+            Ok(pid) if pid != 0 => Ok(pid),
+            _ => Err(self_test_error(format!("{} has no main pid", self.unit))),
+        }
+    }
+
+    fn cgroup_pids(&self) -> Result<Vec<u32>, ErrorArrayItem> {
+        let procs: String = fs::read_to_string(service_file(&self.unit, "cgroup.procs"))?;
+        Ok(procs.lines().filter_map(|line| line.parse().ok()).collect())
+    }
+
+    async fn stop(self) {
+        if let Err(err) = Command::new("systemctl")
+            .arg("stop")
+            .arg(format!("{}.service", self.unit))
+            .status()
+            .await
+        {
+            log!(LogLevel::Error, "Failed to stop {}: {}", self.unit, err);
+        }
+    }
+}
+
+async fn check_cgroup(unit: &DummyUnit, pid: u32) -> Result<String, ErrorArrayItem> {
+    let pids: Vec<u32> = unit.cgroup_pids()?;
+    match pids.contains(&pid) {
+        true => Ok(format!("pid {} found in the cgroup of {}", pid, unit.unit)),
+        false => Err(self_test_error(format!(
+            "pid {} missing from the cgroup of {}",
+            pid, unit.unit
+        ))),
+    }
+}
+
+async fn check_ebpf(gs: &Arc<GlobalState>, pid: u32) -> Result<String, ErrorArrayItem> {
+    gs.network_monitor.track_pid(pid).await?;
+
+    // The entry is dropped by cleanup_dead_pids once the unit stops
+    match gs.network_monitor.is_tracked(pid).await? {
+        true => Ok(format!("pid {} round tripped through pid_traffic_map", pid)),
+        false => Err(self_test_error(format!(
+            "pid {} was inserted but isn't in pid_traffic_map",
+            pid
+        ))),
+    }
+}
+
+async fn check_ledger(gs: &Arc<GlobalState>) -> Result<String, ErrorArrayItem> {
+    let identity: Identifier = gs.get_identity().await;
+    seal_ledger(
+        &*gs.ledger.try_read().await?,
+        &identity,
+        SCRATCH_LEDGER_PATH,
+    )?;
+    let result = open_ledger(&identity, SCRATCH_LEDGER_PATH);
+    let _ = fs::remove_file(SCRATCH_LEDGER_PATH);
+    result?;
+
+    Ok(String::from("Ledger encrypted, written and read back"))
+}
+
+async fn check_portal(gs: &Arc<GlobalState>) -> Result<String, ErrorArrayItem> {
+    let portals: Vec<PortalIntance> = gs.portal_state.get_portals().await?;
+    if portals.is_empty() {
+        return Err(self_test_error(String::from(
+            "No portal has been located yet",
+        )));
+    }
+
+    let mut failures: Vec<String> = Vec::new();
+    for portal in portals {
+        let result: Result<(), ErrorArrayItem> = async {
+            let mut stream: TcpStream = portal.connect().await?;
+            portal_discovery(&mut stream).await
+        }
+        .await;
+
+        match result {
+            Ok(_) => return Ok(format!("Discovered by portal @ {}", portal.get_address())),
+            Err(err) => failures.push(format!("{}: {}", portal.get_address(), err)),
+        }
+    }
+
+    Err(self_test_error(failures.join("; ")))
+}
+
+/// Runs a quick end to end check of every subsystem, meant for verifying a
+/// fresh install
+pub async fn run_self_test(gs: &Arc<GlobalState>) -> SelfTestReport {
+    let mut checks: Vec<SubsystemCheck> = Vec::new();
+
+    let mut unit: Option<DummyUnit> = None;
+    let mut pid: Option<u32> = None;
+    checks.push(
+        check("systemd", async {
+            let spawned: DummyUnit = DummyUnit::spawn().await?;
+            let main_pid: Result<u32, ErrorArrayItem> = spawned.main_pid().await;
+            let name: String = spawned.unit.clone();
+            unit = Some(spawned);
+            pid = Some(main_pid?);
+            Ok(format!("Spawned {}", name))
+        })
+        .await,
+    );
+
+    match (&unit, pid) {
+        (Some(unit), Some(pid)) => {
+            checks.push(check("cgroup", check_cgroup(unit, pid)).await);
+            checks.push(check("ebpf", check_ebpf(gs, pid)).await);
+        }
+        _ => {
+            for subsystem in ["cgroup", "ebpf"] {
+                checks.push(SubsystemCheck {
+                    subsystem,
+                    passed: false,
+                    detail: String::from("Skipped, no dummy unit to check against"),
+                    took_ms: 0,
+                });
+            }
+        }
+    }
+
+    if let Some(unit) = unit {
+        unit.stop().await;
+    }
+
+    checks.push(check("ledger", check_ledger(gs)).await);
+    checks.push(check("portal", check_portal(gs)).await);
+
+    let passed: bool = checks.iter().all(|check| check.passed);
+    log!(
+        LogLevel::Info,
+        "Self test {}",
+        match passed {
+            true => "passed",
+            false => "failed",
+        }
+    );
+
+    SelfTestReport {
+        passed,
+        ran_at: current_timestamp(),
+        checks,
+    }
+}
@@ -0,0 +1,1332 @@
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// Manager specific settings that don't belong in the shared middleware config
+pub const SETTINGS_PATH: &str = "/opt/artisan/manager.toml";
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct ManagerSettings {
+    pub memory_pressure: MemoryPressureSettings,
+    pub port_usage: PortUsageSettings,
+    pub redaction: RedactionSettings,
+    pub retention: RetentionSettings,
+    pub capture: CaptureSettings,
+    pub canary: CanarySettings,
+    pub build_cache: BuildCacheSettings,
+    pub build_sandbox: BuildSandboxSettings,
+    pub deploy_scheduler: DeploySchedulerSettings,
+    pub crash_loop: CrashLoopSettings,
+    pub vulnerability_scan: VulnerabilityScanSettings,
+    pub command_tls: CommandTlsSettings,
+    pub digest: DigestSettings,
+    pub command_auth: CommandAuthSettings,
+    pub webhooks: WebhookSettings,
+    pub service_validation: ServiceValidationSettings,
+    pub memory_watchdog: MemoryWatchdogSettings,
+    pub alerts: AlertSettings,
+    pub adaptive_polling: AdaptivePollingSettings,
+    pub disk_usage: DiskUsageSettings,
+    pub runtime: RuntimeSettings,
+    pub descriptors: DescriptorSettings,
+    pub units: UnitSettings,
+    pub relocation: RelocationSettings,
+    pub status_mirror: StatusMirrorSettings,
+    pub maintenance: MaintenanceSettings,
+    pub bandwidth_limits: BandwidthLimitSettings,
+    pub timezone: TimezoneSettings,
+    pub federation: FederationSettings,
+    pub usage_history: UsageHistorySettings,
+    pub ledger: LedgerSettings,
+    pub status_store: StatusStoreSettings,
+    pub usage_reports: UsageReportSettings,
+    pub legacy_state: LegacyStateSettings,
+    pub telemetry: TelemetrySettings,
+    pub quotas: QuotaSettings,
+    pub dependencies: DependencySettings,
+    pub reconcile: ReconcileSettings,
+    pub manifests: ManifestSettings,
+    pub integrity: IntegritySettings,
+    pub storage: StorageSettings,
+    pub gitmon: GitmonSettings,
+    pub liveness: LivenessSettings,
+    pub egress: EgressSettings,
+    pub unit_events: UnitEventSettings,
+    pub unit_files: UnitFileSettings,
+    pub status_page: StatusPageSettings,
+    pub hibernation: HibernationSettings,
+    pub command_sessions: CommandSessionSettings,
+    pub subscriptions: SubscriptionSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemoryPressureSettings {
+    pub enabled: bool,
+    /// Percentage of MemAvailable at or below which we start reacting
+    pub elevated_available_percent: f64,
+    /// Percentage of MemAvailable at or below which we throttle applications
+    pub critical_available_percent: f64,
+    pub pause_starts: bool,
+    pub throttle_clients: bool,
+    pub alert: bool,
+}
+
+impl Default for MemoryPressureSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            elevated_available_percent: 15.0,
+            critical_available_percent: 5.0,
+            pause_starts: true,
+            throttle_clients: true,
+            alert: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PortUsageSettings {
+    pub enabled: bool,
+    /// Percentage of the ephemeral port range or conntrack table a single
+    /// application can hold before we raise an alert
+    pub alert_percent: f64,
+}
+
+impl Default for PortUsageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            alert_percent: 50.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedactionSettings {
+    pub enabled: bool,
+    /// Regexes applied to every application's captured output, apps can add
+    /// their own with `redact_patterns` in their Config.toml
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            patterns: vec![
+                r"(?i)bearer\s+[a-z0-9._~+/=-]+".to_owned(),
+                r"eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+".to_owned(),
+                r"AKIA[0-9A-Z]{16}".to_owned(),
+                r#"(?i)(password|passwd|secret|token|api_?key)["']?\s*[:=]\s*["']?[^\s"',]+"#
+                    .to_owned(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct RetentionSettings {
+    pub default: RetentionPolicy,
+    /// Overrides keyed by application name, fields left out fall back to the
+    /// built in defaults rather than `default`
+    pub tenants: HashMap<String, RetentionPolicy>,
+}
+
+impl RetentionSettings {
+    pub fn policy_for(&self, app_name: &str) -> &RetentionPolicy {
+        self.tenants.get(app_name).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    /// stdout and stderr lines kept per application, for each stream
+    pub lines: usize,
+    /// Overrides keyed by application name
+    pub apps: HashMap<String, usize>,
+}
+
+impl CaptureSettings {
+    pub fn capacity_for(&self, app_name: &str) -> usize {
+        self.apps.get(app_name).copied().unwrap_or(self.lines)
+    }
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            lines: 500,
+            apps: HashMap::new(),
+        }
+    }
+}
+
+/// How many days each kind of tenant data is kept
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Captured stdout and stderr
+    pub capture_days: u64,
+    /// Core dumps
+    pub post_mortem_days: u64,
+    /// Event journal entries about the application
+    pub audit_days: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            capture_days: 7,
+            post_mortem_days: 7,
+            audit_days: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CanarySettings {
+    /// Seconds the canary runs next to the current version when the deploy
+    /// request doesn't ask for a specific bake period
+    pub bake_secs: u64,
+    pub sample_interval_secs: u64,
+    /// How much worse than the current version the canary may do, in percent
+    pub max_cpu_increase_percent: f64,
+    pub max_memory_increase_percent: f64,
+    pub max_error_increase_percent: f64,
+}
+
+impl Default for CanarySettings {
+    fn default() -> Self {
+        Self {
+            bake_secs: 300,
+            sample_interval_secs: 10,
+            max_cpu_increase_percent: 50.0,
+            max_memory_increase_percent: 50.0,
+            max_error_increase_percent: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BuildCacheSettings {
+    pub enabled: bool,
+    /// Size of the object store before the least recently used layers go
+    pub max_bytes: u64,
+}
+
+impl Default for BuildCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_bytes: 10 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BuildSandboxSettings {
+    /// 100 is one full core
+    pub cpu_quota_percent: u32,
+    pub memory_max_bytes: u64,
+    pub tasks_max: u32,
+    pub sample_interval_secs: u64,
+}
+
+impl Default for BuildSandboxSettings {
+    fn default() -> Self {
+        Self {
+            cpu_quota_percent: 100,
+            memory_max_bytes: 2 * 1024 * 1024 * 1024,
+            tasks_max: 512,
+            sample_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeploySchedulerSettings {
+    /// Builds and deploys allowed to run at once, the rest wait in a queue
+    pub max_concurrent: usize,
+}
+
+impl Default for DeploySchedulerSettings {
+    fn default() -> Self {
+        Self { max_concurrent: 2 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CrashLoopSettings {
+    /// Restarts tolerated within the window before the breaker trips
+    pub max_restarts: usize,
+    pub window_secs: u64,
+}
+
+impl Default for CrashLoopSettings {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            window_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VulnerabilityScanSettings {
+    /// Needs npm, composer or cargo-audit installed on the node
+    pub enabled: bool,
+    pub timeout_secs: u64,
+}
+
+impl Default for VulnerabilityScanSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandTlsSettings {
+    /// Requires a client certificate on every connection to the command listener
+    pub enabled: bool,
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA the portal signs client certificates with
+    pub client_ca_path: String,
+    /// Common names allowed to connect, empty allows anything the CA signed
+    pub allowed_client_cns: Vec<String>,
+}
+
+impl Default for CommandTlsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: "/opt/artisan/tls/manager.crt".to_owned(),
+            key_path: "/opt/artisan/tls/manager.key".to_owned(),
+            client_ca_path: "/opt/artisan/tls/portal_ca.crt".to_owned(),
+            allowed_client_cns: Vec::new(),
+        }
+    }
+}
+
+/// Connections the portal keeps open after a `session` command, carrying
+/// many requests and the events of the node
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandSessionSettings {
+    pub enabled: bool,
+    /// Quiet sessions are pinged this often, the token is checked again too
+    pub keepalive_secs: u64,
+    /// Sessions the portal sent nothing on for this long are closed
+    pub idle_secs: u64,
+    /// Requests of one session handled at once, the rest wait their turn
+    pub max_in_flight: usize,
+    /// Pushes events from the bus to sessions as they're published
+    pub push_events: bool,
+}
+
+impl Default for CommandSessionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keepalive_secs: 30,
+            idle_secs: 300,
+            max_in_flight: 16,
+            push_events: true,
+        }
+    }
+}
+
+/// Connections upgraded with a `subscribe` command, the manager pushes
+/// status changes, alerts and metric samples on them as they happen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SubscriptionSettings {
+    pub enabled: bool,
+    /// How often statuses are compared against what was pushed last
+    pub interval_secs: u64,
+    pub metrics_secs: u64,
+    /// Subscriptions are pinged this often, the token is checked again too
+    pub keepalive_secs: u64,
+    /// Portal registration while a portal is subscribed, it only refreshes
+    /// what the subscription already sent
+    pub registration_secs: u64,
+}
+
+impl Default for SubscriptionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 2,
+            metrics_secs: 10,
+            keepalive_secs: 30,
+            registration_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct DigestSettings {
+    /// Keyed by application name, tenants not listed get no digest
+    pub tenants: HashMap<String, TenantDigest>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TenantDigest {
+    pub enabled: bool,
+    pub recipients: Vec<String>,
+    pub interval_days: u64,
+}
+
+impl Default for TenantDigest {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            recipients: Vec::new(),
+            interval_days: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandAuthSettings {
+    /// Requires a signed token on every command
+    pub enabled: bool,
+    /// Hex encoded ed25519 key the portal signs tokens with
+    pub key_path: String,
+}
+
+impl Default for CommandAuthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key_path: "/opt/artisan/auth.pub".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// Delivers tenant webhooks and routes, needs curl on the node
+    pub enabled: bool,
+    pub max_attempts: u32,
+    /// Doubled after every failed attempt
+    pub backoff_secs: u64,
+    pub timeout_secs: u64,
+    /// Deliveries waiting for a retry, the oldest is given up on past this
+    pub max_queued: usize,
+    pub routes: Vec<WebhookRoute>,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 5,
+            backoff_secs: 10,
+            timeout_secs: 10,
+            max_queued: 500,
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// Operator webhook for the whole node, next to the ones tenants register
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct WebhookRoute {
+    pub url: String,
+    /// Signs the payloads the same way tenant webhooks are signed
+    pub secret: String,
+    /// Applications routed here, every application when empty
+    pub apps: Vec<String>,
+    /// Same names the webhook command takes, e.g. `failed` or `crash-loop`
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogAction {
+    /// Only log that the ceiling was crossed
+    None,
+    /// Reload the manager, dropping and rebuilding application handles
+    Reload,
+    /// Shut down cleanly, needs `Restart=always` on the manager's unit
+    Restart,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemoryWatchdogSettings {
+    pub enabled: bool,
+    /// RSS of the manager itself above which we log a warning, 0 disables
+    pub warn_bytes: u64,
+    /// RSS at which `action` is taken, 0 disables
+    pub ceiling_bytes: u64,
+    pub action: WatchdogAction,
+}
+
+impl Default for MemoryWatchdogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            warn_bytes: 256 * 1024 * 1024,
+            ceiling_bytes: 1024 * 1024 * 1024,
+            action: WatchdogAction::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertSettings {
+    /// Mails operator alerts through ais_mailler, nothing is sent without
+    /// recipients
+    pub enabled: bool,
+    pub recipients: Vec<String>,
+    /// The same alert isn't mailed again within this window
+    pub dedup_window_secs: u64,
+    /// Alerts mailed per hour across the node, the rest are dropped
+    pub max_per_hour: usize,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            recipients: Vec::new(),
+            dedup_window_secs: 3600,
+            max_per_hour: 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdaptivePollingSettings {
+    /// Stretches the monitor loop while nothing is happening on the node
+    pub enabled: bool,
+    /// Pause between the loop's steps while the node is busy
+    pub min_step_ms: u64,
+    /// Longest the pause gets on a quiet node
+    pub max_step_ms: u64,
+    /// The pause doubles every time this passes without activity
+    pub quiet_after_secs: u64,
+}
+
+impl Default for AdaptivePollingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_step_ms: 150,
+            max_step_ms: 1200,
+            quiet_after_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiskUsageSettings {
+    pub enabled: bool,
+    /// Where applications keep their data, `{app}` is the application name
+    pub data_dir: String,
+    /// Bytes an application may use across its config, binary and data
+    /// directory, 0 for no quota
+    pub quota_bytes: u64,
+    /// How long a sample is reused before the directories are walked again
+    pub refresh_secs: u64,
+    /// Overrides keyed by application name
+    pub apps: HashMap<String, DiskQuota>,
+}
+
+impl DiskUsageSettings {
+    pub fn data_dir_for(&self, app_name: &str) -> String {
+        self.apps
+            .get(app_name)
+            .and_then(|quota| quota.data_dir.as_ref())
+            .unwrap_or(&self.data_dir)
+            .replace("{app}", app_name)
+    }
+
+    pub fn quota_for(&self, app_name: &str) -> Option<u64> {
+        let quota: u64 = self
+            .apps
+            .get(app_name)
+            .and_then(|quota| quota.quota_bytes)
+            .unwrap_or(self.quota_bytes);
+
+        match quota {
+            0 => None,
+            quota => Some(quota),
+        }
+    }
+}
+
+impl Default for DiskUsageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            data_dir: String::from("/var/lib/{app}"),
+            quota_bytes: 0,
+            refresh_secs: 300,
+            apps: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct DiskQuota {
+    pub data_dir: Option<String>,
+    pub quota_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RuntimeSettings {
+    /// Async worker threads, 0 for one per core
+    pub worker_threads: usize,
+    /// Ceiling of the pool blocking filesystem and process calls run on
+    pub max_blocking_threads: usize,
+    pub thread_name: String,
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        Self {
+            worker_threads: 0,
+            max_blocking_threads: 512,
+            thread_name: String::from("ais-manager-worker"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DescriptorSettings {
+    pub enabled: bool,
+    /// Share of the process' open file limit at which we warn
+    pub fd_warn_percent: f64,
+    /// Threads at which we warn, 0 to never warn
+    pub thread_warn: usize,
+}
+
+impl Default for DescriptorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fd_warn_percent: 80.0,
+            thread_warn: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitSystem {
+    /// Powers of 1024, KiB, MiB, GiB
+    Iec,
+    /// Powers of 1000, kB, MB, GB
+    Si,
+}
+
+/// How sizes and percentages read in logs, alerts and pretty status
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UnitSettings {
+    pub system: UnitSystem,
+    /// Digits after the decimal separator
+    pub precision: usize,
+    pub decimal_separator: char,
+}
+
+impl Default for UnitSettings {
+    fn default() -> Self {
+        Self {
+            system: UnitSystem::Iec,
+            precision: 2,
+            decimal_separator: '.',
+        }
+    }
+}
+
+/// Moving an app to the manager of another node
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelocationSettings {
+    /// Port the target managers take commands on
+    pub port: u16,
+    /// Admin token presented to targets that have command auth enabled
+    pub token: Option<String>,
+    /// How long the target gets to bring the app up before we give up and
+    /// start it here again
+    pub start_timeout_secs: u64,
+}
+
+impl Default for RelocationSettings {
+    fn default() -> Self {
+        Self {
+            port: 9800,
+            token: None,
+            start_timeout_secs: 60,
+        }
+    }
+}
+
+/// Unauthenticated, read only status on localhost for node local tools
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusMirrorSettings {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for StatusMirrorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 9802,
+        }
+    }
+}
+
+/// Maintenance pages of web apps, the domains are declared per app in
+/// `[maintenance]` of its Config.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceSettings {
+    pub enabled: bool,
+    /// One file per domain under maintenance, the proxy serves the page for
+    /// a host whose file exists
+    pub flag_dir: String,
+    /// Run with `sh -c` after the flags change, for proxies that need it
+    pub reload_command: Option<String>,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            flag_dir: String::from("/run/artisan/maintenance"),
+            reload_command: None,
+        }
+    }
+}
+
+/// Apps declare their cap as `bandwidth_bytes_per_sec` under `[limits]` of
+/// their Config.toml, `bandwidth limit` changes it until the next restart of
+/// the manager
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimitSettings {
+    /// Attach the cgroup programs that drop traffic over the cap, caps are
+    /// only reported without them
+    pub enforce: bool,
+}
+
+/// Usage series behind `usage history`, kept next to the ledger. Raw
+/// samples are rolled up into minutes and hours as they age, each tier is
+/// kept for its own window. These live here rather than in the app config,
+/// which is shared with every app.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageHistorySettings {
+    pub enabled: bool,
+    /// Seconds between two samples of the same app
+    pub interval_secs: u64,
+    pub raw_retention_hours: u64,
+    pub minute_retention_days: u64,
+    pub hour_retention_days: u64,
+}
+
+impl Default for UsageHistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 10,
+            raw_retention_hours: 24,
+            minute_retention_days: 7,
+            hour_retention_days: 90,
+        }
+    }
+}
+
+/// Peer managers that `fanout <command>` relays to, for acting on the whole
+/// fleet when the portal can't
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FederationSettings {
+    pub enabled: bool,
+    /// Hosts of the peer managers, not including this one
+    pub peers: Vec<String>,
+    /// Port the peers take commands on
+    pub port: u16,
+    /// Token presented to peers that have command auth enabled
+    pub token: Option<String>,
+    /// How long a peer gets to answer before it counts as failed
+    pub timeout_secs: u64,
+}
+
+impl Default for FederationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            peers: Vec::new(),
+            port: 9800,
+            token: None,
+            timeout_secs: 15,
+        }
+    }
+}
+
+/// Zones are `UTC`, a fixed offset like `+02:00`, `localtime` or an IANA
+/// name from /usr/share/zoneinfo
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimezoneSettings {
+    pub node: String,
+    /// Keyed by application name, tenants not listed use the node's zone
+    pub tenants: HashMap<String, String>,
+}
+
+impl Default for TimezoneSettings {
+    fn default() -> Self {
+        Self {
+            node: String::from("UTC"),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerBackend {
+    /// The whole ledger rewritten to `[storage]` every 30s
+    Json,
+    /// Samples appended to an SQLite database as they're taken, the ledger
+    /// itself only snapshotted every `snapshot_interval_secs`
+    Sqlite,
+}
+
+/// Where the usage ledger is kept
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LedgerSettings {
+    pub backend: LedgerBackend,
+    pub sqlite_path: String,
+    pub snapshot_interval_secs: u64,
+    /// Samples older than this are deleted with the next snapshot
+    pub sample_retention_days: u64,
+}
+
+impl Default for LedgerSettings {
+    fn default() -> Self {
+        Self {
+            backend: LedgerBackend::Json,
+            sqlite_path: String::from("/opt/artisan/ledger.db"),
+            snapshot_interval_secs: 600,
+            sample_retention_days: 90,
+        }
+    }
+}
+
+/// Where nodes upgraded from the aggregator era keep their registered apps,
+/// migrated to the current format once at startup
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LegacyStateSettings {
+    pub registered_apps_path: String,
+}
+
+impl Default for LegacyStateSettings {
+    fn default() -> Self {
+        Self {
+            registered_apps_path: String::from("/opt/artisan/registered_apps.json"),
+        }
+    }
+}
+
+/// Daily and monthly usage reports, as JSON and CSV
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageReportSettings {
+    pub enabled: bool,
+    pub dir: String,
+}
+
+impl Default for UsageReportSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: String::from("/opt/artisan/reports"),
+        }
+    }
+}
+
+/// The statuses saved on shutdown for the next run
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusStoreSettings {
+    /// Past this, stopped and unknown apps are evicted before running ones
+    pub max_entries: usize,
+}
+
+impl Default for StatusStoreSettings {
+    fn default() -> Self {
+        Self { max_entries: 600 }
+    }
+}
+
+/// Liveness probes apps declare in their Config.toml or manifest
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LivenessSettings {
+    pub enabled: bool,
+    /// How often due probes are looked for, each runs on its own interval
+    pub tick_secs: u64,
+}
+
+impl Default for LivenessSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tick_secs: 2,
+        }
+    }
+}
+
+/// Apps declare the destinations they may reach as `allow` under `[egress]`
+/// of their Config.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EgressSettings {
+    /// Attach the cgroup program that drops traffic outside of the
+    /// allowlists, they're only reported without it
+    pub enforce: bool,
+    /// How long the addresses an allowed domain resolved to are trusted
+    pub resolve_secs: u64,
+}
+
+impl Default for EgressSettings {
+    fn default() -> Self {
+        Self {
+            enforce: false,
+            resolve_secs: 300,
+        }
+    }
+}
+
+/// Changes ais_gitmon reports over the admin socket
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GitmonSettings {
+    pub enabled: bool,
+    /// What a change sets off, apps can pick their own with `[gitmon]
+    /// actions` in their Config.toml
+    pub actions: Vec<ChangeAction>,
+}
+
+impl Default for GitmonSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            actions: vec![
+                ChangeAction::RevalidateCredentials,
+                ChangeAction::QueueDeploy,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    /// Checks the project is still in the git credentials before anything
+    /// else is done for it
+    RevalidateCredentials,
+    /// Rebuilds the app from the pulled sources and restarts it
+    QueueDeploy,
+    /// Mails the change to the operators
+    Alert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// Files under `local_dir`, as the manager always kept them
+    Local,
+    /// One SQLite database at `sqlite_path`
+    Sqlite,
+    /// An S3 compatible bucket, for nodes whose disks are thrown away
+    S3,
+}
+
+/// Where the ledger, the status snapshot and the audit logs are kept
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    pub local_dir: String,
+    pub sqlite_path: String,
+    pub s3: S3Settings,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Local,
+            local_dir: String::from("/opt/artisan"),
+            sqlite_path: String::from("/opt/artisan/storage.db"),
+            s3: S3Settings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct S3Settings {
+    /// Path style, `https://s3.example.com` puts objects at
+    /// `https://s3.example.com/{bucket}/{prefix}/{key}`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Keeps nodes sharing a bucket apart, give every node its own
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Written here first and uploaded on flush, a tmpfs is enough
+    pub cache_dir: String,
+    pub timeout_secs: u64,
+}
+
+impl Default for S3Settings {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: String::from("us-east-1"),
+            prefix: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            cache_dir: String::from("/run/artisan/storage"),
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// Verification of client app binaries before they're started or reclaimed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IntegritySettings {
+    pub enabled: bool,
+    /// Quarantine binaries the portal hasn't signed, not only the ones that
+    /// differ from what was deployed
+    pub require_signature: bool,
+}
+
+impl Default for IntegritySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            require_signature: false,
+        }
+    }
+}
+
+/// Checks of client apps against their /etc/{app}/manifest.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ManifestSettings {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for ManifestSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 60,
+        }
+    }
+}
+
+/// The loop that brings every app to its desired state: running, or
+/// stopped when the node spec or a suspension says so
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReconcileSettings {
+    pub start_missing: bool,
+    pub stop_unexpected: bool,
+    /// Wait before a start or stop that failed is tried again
+    pub retry_secs: u64,
+}
+
+impl Default for ReconcileSettings {
+    fn default() -> Self {
+        Self {
+            start_missing: true,
+            stop_unexpected: true,
+            retry_secs: 60,
+        }
+    }
+}
+
+/// Stopping idle client apps and waking them on their next connection, apps
+/// opt in with a `[hibernation]` table in their Config.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HibernationSettings {
+    pub enabled: bool,
+    /// How often the apps' traffic counters are checked
+    pub interval_secs: u64,
+}
+
+impl Default for HibernationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// A static HTML page of the apps on the node, their uptime and recent
+/// incidents, left for the reverse proxy to serve
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusPageSettings {
+    pub enabled: bool,
+    /// Where the page is written, the proxy has to be able to read it
+    pub path: String,
+    /// How often the page is rendered, browsers reload it as often
+    pub interval_secs: u64,
+    /// Days the uptime and incidents are taken over
+    pub window_days: u64,
+    pub title: String,
+    pub max_incidents: usize,
+}
+
+impl Default for StatusPageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("/var/www/status/index.html"),
+            interval_secs: 60,
+            window_days: 7,
+            title: String::from("Service status"),
+            max_incidents: 20,
+        }
+    }
+}
+
+/// systemd units the manager renders for client apps into
+/// /etc/systemd/system
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UnitFileSettings {
+    pub enabled: bool,
+    /// How often units are compared with the apps' manifests and configs
+    pub interval_secs: u64,
+    /// Overwrite units that were written by hand, they're left alone
+    /// otherwise
+    pub take_over_existing: bool,
+}
+
+impl Default for UnitFileSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 60,
+            take_over_existing: false,
+        }
+    }
+}
+
+/// Reconciling apps when systemd reports a unit change rather than on every
+/// pass of the monitor loop
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UnitEventSettings {
+    pub enabled: bool,
+    /// Longest wait for a change before reconciling anyway, catches what no
+    /// signal announced
+    pub fallback_secs: u64,
+}
+
+impl Default for UnitEventSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fallback_secs: 30,
+        }
+    }
+}
+
+/// Probes of the external endpoints apps declare they depend on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DependencySettings {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Failed probes in a row before a dependency counts as down, so one
+    /// dropped connection doesn't mark every app using it degraded
+    pub failures_before_down: u32,
+    /// Errors the app logs while a dependency is down, at this many times
+    /// the rate from before, are put down to the outage
+    pub error_spike_factor: f64,
+}
+
+impl Default for DependencySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 60,
+            failures_before_down: 2,
+            error_spike_factor: 2.0,
+        }
+    }
+}
+
+/// Monthly usage quotas of client apps, an app over one is suspended until
+/// the suspension is lifted by hand
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuotaSettings {
+    pub enabled: bool,
+    pub check_interval_secs: u64,
+    /// Keyed by application name, apps left out have no quota
+    pub apps: HashMap<String, AppQuota>,
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            check_interval_secs: 300,
+            apps: HashMap::new(),
+        }
+    }
+}
+
+/// Limits over a calendar month in the node's timezone, as the usage
+/// reports count them
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AppQuota {
+    /// Received and sent together, in decimal gigabytes
+    pub transfer_gb: Option<f64>,
+    pub cpu_core_hours: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryLevel {
+    /// Metrics as sampled
+    Full,
+    /// Metrics rounded to coarse buckets, per process and per port detail
+    /// left out
+    Aggregates,
+    /// No metrics at all, only what the portal needs to manage the app
+    Nothing,
+}
+
+/// What telemetry leaves the node through the command port and outgoing
+/// mail. The status mirror and admin socket stay on the node and aren't
+/// affected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetrySettings {
+    pub default: TelemetryLevel,
+    /// Overrides keyed by application name, for tenants with data residency
+    /// requirements
+    pub tenants: HashMap<String, TelemetryLevel>,
+}
+
+impl TelemetrySettings {
+    pub fn level_for(&self, app_name: &str) -> TelemetryLevel {
+        self.tenants.get(app_name).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        Self {
+            default: TelemetryLevel::Full,
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceValidationMode {
+    /// Only raise an alert for unexpected services
+    ReportOnly,
+    /// Stop and mask unexpected services
+    Enforce,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServiceValidationSettings {
+    pub enabled: bool,
+    pub mode: ServiceValidationMode,
+}
+
+impl Default for ServiceValidationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: ServiceValidationMode::ReportOnly,
+        }
+    }
+}
+
+impl ManagerSettings {
+    pub fn load() -> Self {
+        match fs::read_to_string(SETTINGS_PATH) {
+            Ok(data) => match toml::from_str::<ManagerSettings>(&data) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    log!(
+                        LogLevel::Error,
+                        "Failed to parse {}, using defaults: {}",
+                        SETTINGS_PATH,
+                        err
+                    );
+                    ManagerSettings::default()
+                }
+            },
+            Err(_) => {
+                log!(
+                    LogLevel::Debug,
+                    "No manager settings at {}, using defaults",
+                    SETTINGS_PATH
+                );
+                ManagerSettings::default()
+            }
+        }
+    }
+}
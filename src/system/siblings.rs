@@ -0,0 +1,157 @@
+use artisan_middleware::dusa_collection_utils::core::errors::ErrorArrayItem;
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::pathtype::PathType;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::state_persistence::{AppState, StatePersistence};
+use artisan_middleware::version::aml_version;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs;
+
+use crate::applications::child::CLIENT_APPLICATION_ARRAY;
+
+/// A sibling that hasn't touched its state file in this long is likely down
+const STALE_AFTER_SECS: u64 = 600;
+
+/// Compatibility of every Artisan component found on the node, refreshed in
+/// the background so Info doesn't have to walk /tmp
+static SIBLINGS: Lazy<LockWithTimeout<Vec<Sibling>>> =
+    Lazy::new(|| LockWithTimeout::new(Vec::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compatibility {
+    Compatible,
+    /// Same major middleware version, different minor
+    Drifted,
+    Incompatible,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Sibling {
+    pub name: String,
+    pub version: String,
+    pub library: String,
+    pub compatibility: Compatibility,
+    pub stale: bool,
+    pub last_updated: u64,
+}
+
+/// `(major, minor)` from the front of a version string
+pub fn major_minor(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<u64>().ok());
+
+    Some((parts.next()??, parts.next().flatten().unwrap_or(0)))
+}
+
+pub fn compatibility(ours: &str, theirs: &str) -> Compatibility {
+    match (major_minor(ours), major_minor(theirs)) {
+        (Some(ours), Some(theirs)) if ours == theirs => Compatibility::Compatible,
+        (Some(ours), Some(theirs)) if ours.0 == theirs.0 => Compatibility::Drifted,
+        (Some(_), Some(_)) => Compatibility::Incompatible,
+        _ => Compatibility::Unknown,
+    }
+}
+
+/// State files of Artisan components that aren't client applications
+async fn sibling_state_files() -> Result<Vec<(String, PathType)>, ErrorArrayItem> {
+    let clients: Vec<Stringy> = CLIENT_APPLICATION_ARRAY.keys().await?;
+    let mut found: Vec<(String, PathType)> = Vec::new();
+
+    for entry in fs::read_dir("/tmp")? {
+        let file_name: String = entry?.file_name().to_string_lossy().into_owned();
+
+        let name: &str = match file_name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(".state"))
+        {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if !name.starts_with("ais_")
+            || name == env!("CARGO_PKG_NAME")
+            || clients.contains(&Stringy::from(name))
+        {
+            continue;
+        }
+
+        found.push((
+            name.to_owned(),
+            PathType::Content(format!("/tmp/{}", file_name)),
+        ));
+    }
+
+    Ok(found)
+}
+
+/// Finds the other Artisan components on the node and checks their
+/// middleware version against ours
+pub async fn discover_siblings() -> Result<(), ErrorArrayItem> {
+    let ours: String = aml_version().to_string();
+    let now: u64 = current_timestamp();
+    let mut siblings: Vec<Sibling> = Vec::new();
+
+    for (name, path) in sibling_state_files().await? {
+        let state: AppState = match StatePersistence::load_state(&path).await {
+            Ok(state) => state,
+            Err(err) => {
+                log!(
+                    LogLevel::Debug,
+                    "Couldn't read the state of {}: {}",
+                    name,
+                    err
+                );
+                continue;
+            }
+        };
+
+        let library: String = state.version.library.to_string();
+        let sibling: Sibling = Sibling {
+            name,
+            version: state.version.application.to_string(),
+            compatibility: compatibility(&ours, &library),
+            library,
+            stale: now.saturating_sub(state.last_updated) > STALE_AFTER_SECS,
+            last_updated: state.last_updated,
+        };
+
+        if sibling.compatibility == Compatibility::Incompatible {
+            log!(
+                LogLevel::Warn,
+                "{} runs middleware {}, we run {}",
+                sibling.name,
+                sibling.library,
+                ours
+            );
+        }
+
+        siblings.push(sibling);
+    }
+
+    siblings.sort_by(|a, b| a.name.cmp(&b.name));
+    *SIBLINGS.try_write().await? = siblings;
+    Ok(())
+}
+
+pub async fn sibling_matrix() -> Result<Vec<Sibling>, ErrorArrayItem> {
+    Ok(SIBLINGS.try_read().await?.clone())
+}
+
+/// Siblings that would break talking to us, counted as warnings in Info
+pub async fn incompatible_siblings() -> usize {
+    match SIBLINGS.try_read().await {
+        Ok(siblings) => siblings
+            .iter()
+            .filter(|sibling| sibling.compatibility == Compatibility::Incompatible)
+            .count(),
+        Err(_) => 0,
+    }
+}
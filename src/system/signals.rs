@@ -5,15 +5,17 @@ use artisan_middleware::aggregator::{save_registered_apps, AppStatus};
 use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
 use artisan_middleware::dusa_collection_utils::core::types::pathtype::PathType;
 use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::identity::Identifier;
 use artisan_middleware::state_persistence::AppState;
 use tokio::signal::unix::SignalKind;
 
-use crate::applications::child::{
-    APP_STATUS_ARRAY, CLIENT_APPLICATION_HANDLER, SYSTEM_APPLICATION_HANDLER,
-};
+use crate::applications::child::APP_STATUS_ARRAY;
 use crate::applications::resolve::{resolve_client_applications, resolve_system_applications};
-use crate::system::control::LEDGER_PATH;
+use crate::applications::status_store::fit_to_capacity;
+use crate::applications::usage_history::persist_usage_history;
+use crate::system::ledger_store::ledger_store;
 use crate::system::state::wind_down_state;
+use crate::system::storage::{keep_file, off_worker, storage, STATUS_SNAPSHOT_KEY};
 
 use super::control::GlobalState;
 
@@ -38,25 +40,6 @@ pub async fn reload_callback(gs: &Arc<GlobalState>) {
     gs.locks.pause_network().await;
     tokio::time::sleep(Duration::from_millis(300)).await;
 
-    // Clearing handlers
-    let client_handler = &CLIENT_APPLICATION_HANDLER.clone();
-    let system_handler = &SYSTEM_APPLICATION_HANDLER.clone();
-
-    if let Err(err) = client_handler.try_write().await {
-        log!(
-            LogLevel::Error,
-            "Failed to lock client handler, dumping: {}",
-            err
-        );
-    }
-    if let Err(err) = system_handler.try_write().await {
-        log!(
-            LogLevel::Error,
-            "Failed to lock system handler, dumping: {}",
-            err
-        );
-    }
-
     if let Err(err) = resolve_client_applications(&gs.clone()).await {
         log!(LogLevel::Error, "{}", err);
     }
@@ -75,50 +58,47 @@ pub async fn shutdown_callback(gs: &Arc<GlobalState>) {
 
     gs.locks.pause_network().await;
 
-    // Clearing the handlers
-    let client_handler = &CLIENT_APPLICATION_HANDLER.clone();
-    let system_handler = &SYSTEM_APPLICATION_HANDLER.clone();
-
-    let app_status_array = &APP_STATUS_ARRAY.clone();
-
-    if let Err(err) = client_handler.try_write().await {
-        log!(
-            LogLevel::Error,
-            "Failed to lock client handler, dumping: {}",
-            err
-        );
-    }
+    let app_array: Vec<AppStatus> = match APP_STATUS_ARRAY.snapshot().await {
+        Ok(snapshot) => snapshot
+            .into_values()
+            .map(|app| AppStatus::clone(&app))
+            .collect(),
+        Err(err) => {
+            log!(LogLevel::Error, "Failed to read the status array: {}", err);
+            Vec::new()
+        }
+    };
 
-    if let Err(err) = system_handler.try_write().await {
-        log!(
-            LogLevel::Error,
-            "Failed to lock system handler, dumping: {}",
-            err
-        );
+    for app in app_array.clone() {
+        log!(LogLevel::Debug, "Status: {}", app);
     }
 
-    // let app_status_array_read_lock = gs.get_state_clone().await.app_status_array();
-    let mut app_array: Vec<AppStatus> = Vec::new();
-
-    if let Ok(array_read) = app_status_array.try_read().await {
-        _ = array_read
-            .clone()
-            .into_iter()
-            .map(|app| app_array.push(app.1));
+    // Shutting down before the ledger was loaded leaves the file alone
+    if gs.ledger_ready() {
+        let identity: Identifier = gs.get_identity().await;
+        if let Err(e) =
+            ledger_store().persist(&*gs.ledger.try_read().await.unwrap(), &identity, true)
+        {
+            log!(LogLevel::Error, "Failed to persist usage ledger: {}", e);
+        }
     }
 
-    for app in app_array.clone() {
-        log!(LogLevel::Debug, "Status: {}", app);
+    if let Err(err) = persist_usage_history() {
+        log!(LogLevel::Error, "Failed to persist usage history: {}", err);
     }
 
-    if let Err(e) = gs
-        .ledger
-        .try_read()
-        .await
-        .unwrap()
-        .persist_to_disk(LEDGER_PATH)
-    {
-        log!(LogLevel::Error, "Failed to persist usage ledger: {}", e);
+    let (app_array, evicted) = fit_to_capacity(app_array, gs.settings.status_store.max_entries);
+    if !evicted.is_empty() {
+        log!(
+            LogLevel::Error,
+            "The status store is over its cap of {}, not saving {}",
+            gs.settings.status_store.max_entries,
+            evicted
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
     }
 
     if let Err(err) = save_registered_apps(&app_array).await {
@@ -126,6 +106,18 @@ pub async fn shutdown_callback(gs: &Arc<GlobalState>) {
         std::process::exit(1)
     }
 
+    let snapshot_path: String = gs.settings.legacy_state.registered_apps_path.clone();
+    if let Err(err) = off_worker(move || keep_file(STATUS_SNAPSHOT_KEY, &snapshot_path)).await {
+        log!(
+            LogLevel::Error,
+            "Failed to store the status snapshot: {}",
+            err
+        );
+    }
+    if let Err(err) = off_worker(|| storage()?.flush()).await {
+        log!(LogLevel::Error, "Failed to flush the storage: {}", err);
+    }
+
     let mut app_state: AppState = gs.get_state_clone().await.unwrap();
     let app_state_path: &PathType = &gs.app_state_path;
 
@@ -13,6 +13,7 @@ use artisan_middleware::{
 };
 
 use super::control::{GlobalState, GLOBAL_STATE};
+use super::formats::stamp_format;
 
 pub fn get_state_path(config: &AppConfig) -> PathType {
     state_persistence::StatePersistence::get_state_path(&config)
@@ -41,6 +42,8 @@ pub async fn save_state(state: &mut AppState, path: &PathType) -> Result<(), Err
             Errors::GeneralError,
             format!("{}", err),
         ));
+    } else if let Err(err) = stamp_format(&path.to_string()) {
+        log!(LogLevel::Warn, "Failed to stamp the state format: {}", err);
     }
 
     Ok(())
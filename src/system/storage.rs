@@ -0,0 +1,554 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::OnceCell;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+
+use super::control::GLOBAL_STATE;
+use super::settings::{S3Settings, StorageBackend, StorageSettings};
+
+/// The sealed usage ledger, the json ledger store keeps it here
+pub const LEDGER_KEY: &str = "ledger.json";
+
+/// The statuses saved on shutdown
+pub const STATUS_SNAPSHOT_KEY: &str = "registered_apps.json";
+
+fn storage_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// Where the manager keeps what has to outlive it. Keys are relative paths
+/// like `audit/commands.jsonl`, each store maps them its own way.
+pub trait Storage: Send + Sync {
+    /// The value under `key`, `None` when nothing was written there
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, ErrorArrayItem>;
+
+    /// Replaces the value whole, a crash leaves either the old or the new one
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), ErrorArrayItem>;
+
+    /// Adds one line to a log, `line` has no trailing newline
+    fn append_line(&self, key: &str, line: &str) -> Result<(), ErrorArrayItem>;
+
+    /// Every line of a log, oldest first
+    fn read_lines(&self, key: &str) -> Result<Vec<String>, ErrorArrayItem>;
+
+    /// Rewrites a log with only `lines`, used when pruning
+    fn replace_lines(&self, key: &str, lines: &[String]) -> Result<(), ErrorArrayItem>;
+
+    /// The file the key lives in on this node, `None` when it's not a file
+    fn local_path(&self, key: &str) -> Option<PathBuf>;
+
+    /// Pushes out whatever is only held locally so far. Runs with the
+    /// ledger every 30 seconds and on shutdown.
+    fn flush(&self) -> Result<(), ErrorArrayItem> {
+        Ok(())
+    }
+}
+
+/// Plain files under one directory, what the manager always wrote
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    fn create_parent(path: &Path) -> Result<(), ErrorArrayItem> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for LocalStore {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, ErrorArrayItem> {
+        let path: PathBuf = self.path(key);
+        match path.exists() {
+            true => Ok(Some(fs::read(path)?)),
+            false => Ok(None),
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), ErrorArrayItem> {
+        let path: PathBuf = self.path(key);
+        Self::create_parent(&path)?;
+
+        let temp: PathBuf = PathBuf::from(format!("{}.tmp", path.display()));
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    fn append_line(&self, key: &str, line: &str) -> Result<(), ErrorArrayItem> {
+        let path: PathBuf = self.path(key);
+        Self::create_parent(&path)?;
+
+        // One write per line so concurrent writers never interleave
+        let mut file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(format!("{}\n", line).as_bytes())?;
+        Ok(())
+    }
+
+    fn read_lines(&self, key: &str) -> Result<Vec<String>, ErrorArrayItem> {
+        let path: PathBuf = self.path(key);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        Ok(BufReader::new(File::open(path)?)
+            .lines()
+            .filter_map(|line| line.ok())
+            .collect())
+    }
+
+    fn replace_lines(&self, key: &str, lines: &[String]) -> Result<(), ErrorArrayItem> {
+        let mut data: String = String::new();
+        for line in lines {
+            data.push_str(line);
+            data.push('\n');
+        }
+        self.write(key, data.as_bytes())
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Values and log lines in one SQLite database, for nodes that would rather
+/// keep a single file than a directory of them
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    path: String,
+}
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS blobs (
+    key TEXT PRIMARY KEY,
+    written INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS lines (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    key TEXT NOT NULL,
+    line TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS lines_by_key ON lines (key, seq);
+";
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, ErrorArrayItem> {
+        let connection: Connection = Connection::open(path).map_err(|err| sql_error(path, err))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|err| sql_error(path, err))?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            path: path.to_owned(),
+        })
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, ErrorArrayItem> {
+        self.connection
+            .lock()
+            .map_err(|err| storage_error(err.to_string()))
+    }
+
+    fn error(&self, err: rusqlite::Error) -> ErrorArrayItem {
+        sql_error(&self.path, err)
+    }
+}
+
+fn sql_error(path: &str, err: rusqlite::Error) -> ErrorArrayItem {
+    storage_error(format!("{}: {}", path, err))
+}
+
+impl Storage for SqliteStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, ErrorArrayItem> {
+        self.connection()?
+            .query_row(
+                "SELECT data FROM blobs WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|err| self.error(err))
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), ErrorArrayItem> {
+        self.connection()?
+            .execute(
+                "INSERT OR REPLACE INTO blobs (key, written, data) VALUES (?1, ?2, ?3)",
+                params![key, current_timestamp() as i64, data],
+            )
+            .map_err(|err| self.error(err))?;
+        Ok(())
+    }
+
+    fn append_line(&self, key: &str, line: &str) -> Result<(), ErrorArrayItem> {
+        self.connection()?
+            .execute(
+                "INSERT INTO lines (key, line) VALUES (?1, ?2)",
+                params![key, line],
+            )
+            .map_err(|err| self.error(err))?;
+        Ok(())
+    }
+
+    fn read_lines(&self, key: &str) -> Result<Vec<String>, ErrorArrayItem> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT line FROM lines WHERE key = ?1 ORDER BY seq")
+            .map_err(|err| self.error(err))?;
+
+        let lines = statement
+            .query_map(params![key], |row| row.get(0))
+            .map_err(|err| self.error(err))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(|err| self.error(err))?;
+        Ok(lines)
+    }
+
+    fn replace_lines(&self, key: &str, lines: &[String]) -> Result<(), ErrorArrayItem> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction().map_err(|err| self.error(err))?;
+        transaction
+            .execute("DELETE FROM lines WHERE key = ?1", params![key])
+            .map_err(|err| self.error(err))?;
+        for line in lines {
+            transaction
+                .execute(
+                    "INSERT INTO lines (key, line) VALUES (?1, ?2)",
+                    params![key, line],
+                )
+                .map_err(|err| self.error(err))?;
+        }
+        transaction.commit().map_err(|err| self.error(err))?;
+        Ok(())
+    }
+
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// An S3 compatible bucket, for nodes whose disks don't survive being
+/// reprovisioned. Everything is written to a local cache first and uploaded
+/// on flush, a key missing from the cache is downloaded the first time it's
+/// used. Needs curl 7.75 or newer on the node for the request signing.
+pub struct S3Store {
+    settings: S3Settings,
+    cache: LocalStore,
+    /// Keys already looked up in the bucket since the manager started
+    restored: Mutex<HashSet<String>>,
+    /// Keys changed in the cache and not uploaded yet
+    dirty: Mutex<HashSet<String>>,
+}
+
+impl S3Store {
+    pub fn new(settings: &S3Settings) -> Result<Self, ErrorArrayItem> {
+        if settings.endpoint.is_empty() || settings.bucket.is_empty() {
+            return Err(storage_error(String::from(
+                "[storage.s3] needs an endpoint and a bucket",
+            )));
+        }
+
+        Ok(Self {
+            settings: settings.clone(),
+            cache: LocalStore::new(&settings.cache_dir),
+            restored: Mutex::new(HashSet::new()),
+            dirty: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        let prefix: &str = self.settings.prefix.trim_matches('/');
+        match prefix.is_empty() {
+            true => format!(
+                "{}/{}/{}",
+                self.settings.endpoint.trim_end_matches('/'),
+                self.settings.bucket,
+                key
+            ),
+            false => format!(
+                "{}/{}/{}/{}",
+                self.settings.endpoint.trim_end_matches('/'),
+                self.settings.bucket,
+                prefix,
+                key
+            ),
+        }
+    }
+
+    /// Runs curl with the request signed for the bucket, returning the http
+    /// status. The credentials go in on stdin so they never show up in the
+    /// process list.
+    fn curl(&self, args: &[&str]) -> Result<u16, ErrorArrayItem> {
+        let mut child = Command::new("curl")
+            .args([
+                "--silent",
+                "--show-error",
+                "--config",
+                "-",
+                "--aws-sigv4",
+                &format!("aws:amz:{}:s3", self.settings.region),
+                "--write-out",
+                "%{http_code}",
+                "--max-time",
+                &self.settings.timeout_secs.to_string(),
+            ])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(
+                format!(
+                    "user = \"{}:{}\"\n",
+                    self.settings.access_key, self.settings.secret_key
+                )
+                .as_bytes(),
+            )?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(storage_error(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ));
+        }
+
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| storage_error(String::from("curl returned no status")))
+    }
+
+    /// Downloads the key into the cache unless it's there already. The cache
+    /// is never older than the bucket, only this node writes its prefix.
+    fn restore(&self, key: &str) -> Result<(), ErrorArrayItem> {
+        let mut restored = self
+            .restored
+            .lock()
+            .map_err(|err| storage_error(err.to_string()))?;
+        if restored.contains(key) {
+            return Ok(());
+        }
+
+        let path: PathBuf = self.cache.path(key);
+        if !path.exists() {
+            LocalStore::create_parent(&path)?;
+            let temp: String = format!("{}.download", path.display());
+            match self.curl(&["--output", &temp, &self.url(key)])? {
+                200 => {
+                    fs::rename(&temp, &path)?;
+                    log!(LogLevel::Info, "Restored {} from {}", key, self.url(key));
+                }
+                404 => {
+                    let _ = fs::remove_file(&temp);
+                }
+                code => {
+                    let _ = fs::remove_file(&temp);
+                    return Err(storage_error(format!(
+                        "Downloading {} answered {}",
+                        self.url(key),
+                        code
+                    )));
+                }
+            }
+        }
+
+        restored.insert(key.to_owned());
+        Ok(())
+    }
+
+    fn mark_dirty(&self, key: &str) -> Result<(), ErrorArrayItem> {
+        self.dirty
+            .lock()
+            .map_err(|err| storage_error(err.to_string()))?
+            .insert(key.to_owned());
+        Ok(())
+    }
+}
+
+impl Storage for S3Store {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, ErrorArrayItem> {
+        self.restore(key)?;
+        self.cache.read(key)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), ErrorArrayItem> {
+        // Nothing to merge with, the download can be skipped
+        if let Ok(mut restored) = self.restored.lock() {
+            restored.insert(key.to_owned());
+        }
+        self.cache.write(key, data)?;
+        self.mark_dirty(key)
+    }
+
+    fn append_line(&self, key: &str, line: &str) -> Result<(), ErrorArrayItem> {
+        self.restore(key)?;
+        self.cache.append_line(key, line)?;
+        self.mark_dirty(key)
+    }
+
+    fn read_lines(&self, key: &str) -> Result<Vec<String>, ErrorArrayItem> {
+        self.restore(key)?;
+        self.cache.read_lines(key)
+    }
+
+    fn replace_lines(&self, key: &str, lines: &[String]) -> Result<(), ErrorArrayItem> {
+        self.restore(key)?;
+        self.cache.replace_lines(key, lines)?;
+        self.mark_dirty(key)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.cache.path(key))
+    }
+
+    /// Uploads every changed key, the ones that fail stay dirty for the
+    /// next flush
+    fn flush(&self) -> Result<(), ErrorArrayItem> {
+        let keys: Vec<String> = self
+            .dirty
+            .lock()
+            .map_err(|err| storage_error(err.to_string()))?
+            .drain()
+            .collect();
+
+        let mut failed: Vec<String> = Vec::new();
+        for key in keys {
+            let path: String = self.cache.path(&key).display().to_string();
+            let result: Result<(), ErrorArrayItem> =
+                match self.curl(&["--upload-file", &path, &self.url(&key)]) {
+                    Ok(200..=299) => Ok(()),
+                    Ok(code) => Err(storage_error(format!("the bucket answered {}", code))),
+                    Err(err) => Err(err),
+                };
+
+            if let Err(err) = result {
+                log!(LogLevel::Warn, "Failed to upload {}: {}", key, err);
+                failed.push(key);
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let count: usize = failed.len();
+        self.dirty
+            .lock()
+            .map_err(|err| storage_error(err.to_string()))?
+            .extend(failed);
+        Err(storage_error(format!(
+            "{} keys are only in {} so far",
+            count, self.settings.cache_dir
+        )))
+    }
+}
+
+/// Where the local store has always kept everything
+const LOCAL_DIR: &str = "/opt/artisan";
+
+static STORE: OnceCell<Box<dyn Storage>> = OnceCell::new();
+
+/// The configured store, opened on first use. Whenever the configured one
+/// can't be opened that's local files. Asking before the global state exists
+/// is an error, handing out local files then would split keys across stores.
+pub fn storage() -> Result<&'static dyn Storage, ErrorArrayItem> {
+    let settings: StorageSettings = match GLOBAL_STATE.get() {
+        Some(gs) => gs.settings.storage.clone(),
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::AppState,
+                "The storage was used before the settings were loaded",
+            ))
+        }
+    };
+
+    Ok(STORE
+        .get_or_init(|| {
+            let opened: Result<Box<dyn Storage>, ErrorArrayItem> = match settings.backend {
+                StorageBackend::Local => Ok(Box::new(LocalStore::new(&settings.local_dir))),
+                StorageBackend::Sqlite => SqliteStorage::open(&settings.sqlite_path)
+                    .map(|store| Box::new(store) as Box<dyn Storage>),
+                StorageBackend::S3 => {
+                    S3Store::new(&settings.s3).map(|store| Box::new(store) as Box<dyn Storage>)
+                }
+            };
+
+            opened.unwrap_or_else(|err| {
+                log!(
+                    LogLevel::Error,
+                    "Couldn't open the {:?} storage, keeping local files: {}",
+                    settings.backend,
+                    err
+                );
+                Box::new(LocalStore::new(LOCAL_DIR))
+            })
+        })
+        .as_ref())
+}
+
+/// Runs storage work on the blocking pool. The object store reads and
+/// uploads through curl, async code mustn't wait on that on a worker.
+pub async fn off_worker<T, F>(work: F) -> Result<T, ErrorArrayItem>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ErrorArrayItem> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| storage_error(format!("The storage work panicked: {}", err)))?
+}
+
+/// Puts a file something else writes into the store, for state like the
+/// status snapshot the middleware saves on its own
+pub fn keep_file(key: &str, path: &str) -> Result<(), ErrorArrayItem> {
+    let store: &dyn Storage = storage()?;
+    if store.local_path(key).as_deref() == Some(Path::new(path)) {
+        return Ok(());
+    }
+    store.write(key, &fs::read(path)?)
+}
+
+/// Brings back a file kept with `keep_file` when this node lost it, as it
+/// does when it's reprovisioned
+pub fn restore_file(key: &str, path: &str) -> Result<bool, ErrorArrayItem> {
+    if Path::new(path).exists() {
+        return Ok(false);
+    }
+
+    match storage()?.read(key)? {
+        Some(data) => {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, data)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
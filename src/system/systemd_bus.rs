@@ -0,0 +1,511 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Notify, OnceCell};
+use zbus::fdo::PropertiesProxy;
+use zbus::names::InterfaceName;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{proxy, Connection};
+
+use super::control::GLOBAL_STATE;
+use super::events::ManagerEvent;
+
+/// One connection to the system bus for the whole manager, opened on first
+/// use
+static BUS: OnceCell<Connection> = OnceCell::const_new();
+
+/// Latest state of every unit being watched, kept current by its
+/// PropertiesChanged signals so nothing has to ask systemd on every tick
+static UNITS: Lazy<Mutex<HashMap<String, UnitState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Raised when a service changes state or a job on one finishes. It keeps a
+/// permit, a change that comes in while the loop is busy isn't lost.
+static UNIT_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Whether systemd's job signals are followed, the loop waiting on
+/// `UNIT_CHANGED` has to poll without them
+static FOLLOWING_JOBS: AtomicBool = AtomicBool::new(false);
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Manager {
+    fn subscribe(&self) -> zbus::Result<()>;
+    fn reload(&self) -> zbus::Result<()>;
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn kill_unit(&self, name: &str, whom: &str, signal: i32) -> zbus::Result<()>;
+    fn get_unit_file_state(&self, file: &str) -> zbus::Result<String>;
+    fn freeze_unit(&self, name: &str) -> zbus::Result<()>;
+    fn thaw_unit(&self, name: &str) -> zbus::Result<()>;
+    fn enable_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+        force: bool,
+    ) -> zbus::Result<(bool, Vec<(String, String, String)>)>;
+    fn disable_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+    ) -> zbus::Result<Vec<(String, String, String)>>;
+    fn mask_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+        force: bool,
+    ) -> zbus::Result<Vec<(String, String, String)>>;
+    fn unmask_unit_files(
+        &self,
+        files: &[&str],
+        runtime: bool,
+    ) -> zbus::Result<Vec<(String, String, String)>>;
+    #[zbus(signal)]
+    fn job_removed(
+        &self,
+        id: u32,
+        job: OwnedObjectPath,
+        unit: String,
+        result: String,
+    ) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Unit {
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn sub_state(&self) -> zbus::Result<String>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Service",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Service {
+    #[zbus(property, name = "ExecMainPID")]
+    fn exec_main_pid(&self) -> zbus::Result<u32>;
+    #[zbus(property)]
+    fn result(&self) -> zbus::Result<String>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitState {
+    /// `active`, `inactive`, `failed`, `activating`, `deactivating` or
+    /// `reloading`
+    pub active_state: String,
+    pub sub_state: String,
+    /// 0 while the service has no main process
+    pub main_pid: u32,
+    /// `success` when the service last exited cleanly
+    pub result: String,
+}
+
+impl UnitState {
+    /// Up or on its way up or down, anything but fully stopped
+    pub fn is_active(&self) -> bool {
+        !matches!(self.active_state.as_str(), "inactive" | "failed")
+    }
+}
+
+fn bus_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+/// App names double as unit names, systemd wants the suffix
+fn unit_name(name: &str) -> String {
+    match name.ends_with(".service") {
+        true => name.to_owned(),
+        false => format!("{}.service", name),
+    }
+}
+
+async fn bus() -> Result<&'static Connection, ErrorArrayItem> {
+    BUS.get_or_try_init(|| async {
+        let connection: Connection = Connection::system().await?;
+        // systemd only sends unit signals while someone is subscribed
+        ManagerProxy::new(&connection).await?.subscribe().await?;
+        Ok::<Connection, zbus::Error>(connection)
+    })
+    .await
+    .map_err(|err| bus_error(format!("Can't reach systemd over D-Bus: {}", err)))
+}
+
+async fn manager() -> Result<ManagerProxy<'static>, ErrorArrayItem> {
+    ManagerProxy::new(bus().await?)
+        .await
+        .map_err(|err| bus_error(err.to_string()))
+}
+
+async fn unit_path(unit: &str) -> Result<OwnedObjectPath, ErrorArrayItem> {
+    manager()
+        .await?
+        .load_unit(unit)
+        .await
+        .map_err(|err| bus_error(format!("systemd doesn't know {}: {}", unit, err)))
+}
+
+/// Reads the unit's properties straight from systemd, skipping the proxies'
+/// own caches
+async fn read_state(path: &OwnedObjectPath) -> zbus::Result<UnitState> {
+    let connection: &Connection = BUS.get().ok_or(zbus::Error::Unsupported)?;
+    let unit: UnitProxy = UnitProxy::builder(connection)
+        .path(path.clone())?
+        .cache_properties(zbus::CacheProperties::No)
+        .build()
+        .await?;
+    let service: ServiceProxy = ServiceProxy::builder(connection)
+        .path(path.clone())?
+        .cache_properties(zbus::CacheProperties::No)
+        .build()
+        .await?;
+
+    Ok(UnitState {
+        active_state: unit.active_state().await?,
+        sub_state: unit.sub_state().await?,
+        main_pid: service.exec_main_pid().await?,
+        result: service.result().await?,
+    })
+}
+
+/// Caches the unit's state, a change of its active state wakes the loop
+/// reconciling the apps and goes out on the event bus
+fn record(unit: &str, state: UnitState) {
+    let previous: Option<UnitState> = match UNITS.lock() {
+        Ok(mut units) => units.insert(unit.to_owned(), state.clone()),
+        Err(_) => return,
+    };
+
+    let changed: bool = previous.map_or(false, |previous| {
+        previous.active_state != state.active_state || previous.main_pid != state.main_pid
+    });
+    if !changed {
+        return;
+    }
+
+    UNIT_CHANGED.notify_one();
+    if let Some(gs) = GLOBAL_STATE.get() {
+        gs.events.publish(ManagerEvent::UnitStateChanged {
+            name: Stringy::from(unit.trim_end_matches(".service")),
+            active_state: state.active_state,
+            sub_state: state.sub_state,
+        });
+    }
+}
+
+/// Follows the unit's PropertiesChanged signals, the cached state is dropped
+/// when the stream ends so the next read starts a new watch
+fn watch(unit: String, path: OwnedObjectPath) {
+    tokio::spawn(async move {
+        let watched: zbus::Result<()> = async {
+            let connection: &Connection = BUS.get().ok_or(zbus::Error::Unsupported)?;
+            let properties: PropertiesProxy = PropertiesProxy::builder(connection)
+                .destination("org.freedesktop.systemd1")?
+                .path(path.clone())?
+                .build()
+                .await?;
+            let mut changes = properties.receive_properties_changed().await?;
+
+            // Anything that changed before the subscription went through
+            record(&unit, read_state(&path).await?);
+
+            while let Some(change) = changes.next().await {
+                let interface: InterfaceName = change.args()?.interface_name;
+                if !matches!(
+                    interface.as_str(),
+                    "org.freedesktop.systemd1.Unit" | "org.freedesktop.systemd1.Service"
+                ) {
+                    continue;
+                }
+
+                let state: UnitState = read_state(&path).await?;
+                log!(
+                    LogLevel::Trace,
+                    "{} is {} ({})",
+                    unit,
+                    state.active_state,
+                    state.sub_state
+                );
+                record(&unit, state);
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = watched {
+            log!(LogLevel::Warn, "Stopped watching {}: {}", unit, err);
+        }
+        if let Ok(mut units) = UNITS.lock() {
+            units.remove(&unit);
+        }
+    });
+}
+
+/// Follows the jobs systemd finishes, they catch services that come up or go
+/// down before anything watches them. Once this is running the reconcile
+/// loop waits for changes instead of polling.
+pub async fn follow_jobs() -> Result<(), ErrorArrayItem> {
+    if FOLLOWING_JOBS.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let mut jobs = match manager().await?.receive_job_removed().await {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            FOLLOWING_JOBS.store(false, Ordering::Relaxed);
+            return Err(bus_error(format!("Can't follow systemd's jobs: {}", err)));
+        }
+    };
+
+    tokio::spawn(async move {
+        while let Some(job) = jobs.next().await {
+            let args = match job.args() {
+                Ok(args) => args,
+                Err(_) => continue,
+            };
+            if !args.unit.ends_with(".service") {
+                continue;
+            }
+
+            log!(
+                LogLevel::Trace,
+                "systemd finished a job on {}: {}",
+                args.unit,
+                args.result
+            );
+            UNIT_CHANGED.notify_one();
+        }
+
+        log!(
+            LogLevel::Warn,
+            "Lost systemd's job signals, falling back to polling"
+        );
+        FOLLOWING_JOBS.store(false, Ordering::Relaxed);
+    });
+
+    Ok(())
+}
+
+pub fn follows_jobs() -> bool {
+    FOLLOWING_JOBS.load(Ordering::Relaxed)
+}
+
+/// Returns once a service changed state, or after `fallback` so drift that
+/// no signal announced is still caught
+pub async fn next_unit_change(fallback: Duration) {
+    tokio::select! {
+        _ = UNIT_CHANGED.notified() => (),
+        _ = tokio::time::sleep(fallback) => (),
+    }
+}
+
+/// The unit's state, from the signals when it's watched already. The first
+/// read of a unit starts watching it.
+pub async fn unit_state(name: &str) -> Result<UnitState, ErrorArrayItem> {
+    if let Some(state) = watched_unit_state(name) {
+        return Ok(state);
+    }
+
+    let unit: String = unit_name(name);
+    let path: OwnedObjectPath = unit_path(&unit).await?;
+    let state: UnitState = read_state(&path)
+        .await
+        .map_err(|err| bus_error(format!("Can't read the state of {}: {}", unit, err)))?;
+
+    let fresh: bool = match UNITS.lock() {
+        Ok(mut units) if !units.contains_key(&unit) => {
+            units.insert(unit.clone(), state.clone());
+            true
+        }
+        _ => false,
+    };
+    if fresh {
+        watch(unit, path);
+    }
+    Ok(state)
+}
+
+/// What the signals last said about the unit, `None` while it isn't watched
+pub fn watched_unit_state(name: &str) -> Option<UnitState> {
+    UNITS
+        .lock()
+        .ok()
+        .and_then(|units| units.get(&unit_name(name)).cloned())
+}
+
+pub async fn is_active(name: &str) -> Result<bool, ErrorArrayItem> {
+    unit_state(name).await.map(|state| state.is_active())
+}
+
+pub async fn start_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .start_unit(&unit, "replace")
+        .await
+        .map(|_| ())
+        .map_err(|err| bus_error(format!("Failed to start {}: {}", unit, err)))
+}
+
+pub async fn stop_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .stop_unit(&unit, "replace")
+        .await
+        .map(|_| ())
+        .map_err(|err| bus_error(format!("Failed to stop {}: {}", unit, err)))
+}
+
+pub async fn restart_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .restart_unit(&unit, "replace")
+        .await
+        .map(|_| ())
+        .map_err(|err| bus_error(format!("Failed to restart {}: {}", unit, err)))
+}
+
+/// The same as `systemctl daemon-reload`, picks up changed unit files
+pub async fn reload_daemon() -> Result<(), ErrorArrayItem> {
+    manager()
+        .await?
+        .reload()
+        .await
+        .map_err(|err| bus_error(format!("Failed to reload systemd: {}", err)))
+}
+
+/// `enabled`, `disabled`, `masked`, `static` and the like, whether the unit
+/// starts at boot or can be started at all
+pub async fn unit_file_state(name: &str) -> Result<String, ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .get_unit_file_state(&unit)
+        .await
+        .map_err(|err| {
+            bus_error(format!(
+                "Can't read the unit file state of {}: {}",
+                unit, err
+            ))
+        })
+}
+
+/// The same as `systemctl enable`, the unit starts at boot
+pub async fn enable_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .enable_unit_files(&[unit.as_str()], false, false)
+        .await
+        .map_err(|err| bus_error(format!("Failed to enable {}: {}", unit, err)))?;
+    reload_daemon().await
+}
+
+pub async fn disable_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .disable_unit_files(&[unit.as_str()], false)
+        .await
+        .map_err(|err| bus_error(format!("Failed to disable {}: {}", unit, err)))?;
+    reload_daemon().await
+}
+
+/// The same as `systemctl mask`, nothing can start the unit until it's
+/// unmasked. A unit file in /etc/systemd/system is replaced by the mask.
+pub async fn mask_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .mask_unit_files(&[unit.as_str()], false, true)
+        .await
+        .map_err(|err| bus_error(format!("Failed to mask {}: {}", unit, err)))?;
+    reload_daemon().await
+}
+
+pub async fn unmask_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .unmask_unit_files(&[unit.as_str()], false)
+        .await
+        .map_err(|err| bus_error(format!("Failed to unmask {}: {}", unit, err)))?;
+    reload_daemon().await
+}
+
+/// Freezes every process of the unit through its cgroup.freeze, systemd
+/// thaws it again before stopping it
+pub async fn freeze_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .freeze_unit(&unit)
+        .await
+        .map_err(|err| bus_error(format!("Failed to freeze {}: {}", unit, err)))
+}
+
+pub async fn thaw_unit(name: &str) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .thaw_unit(&unit)
+        .await
+        .map_err(|err| bus_error(format!("Failed to thaw {}: {}", unit, err)))
+}
+
+/// Sends the signal to every process of the unit
+pub async fn kill_unit(name: &str, signal: i32) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .kill_unit(&unit, "all", signal)
+        .await
+        .map_err(|err| bus_error(format!("Failed to signal {}: {}", unit, err)))
+}
+
+/// Sends the signal to the unit's main process only
+pub async fn kill_main(name: &str, signal: i32) -> Result<(), ErrorArrayItem> {
+    let unit: String = unit_name(name);
+    manager()
+        .await?
+        .kill_unit(&unit, "main", signal)
+        .await
+        .map_err(|err| bus_error(format!("Failed to signal {}: {}", unit, err)))
+}
+
+/// Waits for the unit to come up or go down, as far as its signals tell.
+/// Returns whether it got there in time.
+pub async fn wait_for(name: &str, active: bool, timeout: Duration) -> Result<bool, ErrorArrayItem> {
+    let deadline: tokio::time::Instant = tokio::time::Instant::now() + timeout;
+    loop {
+        let state: UnitState = unit_state(name).await?;
+        let settled: bool = match active {
+            true => state.active_state == "active",
+            false => !state.is_active(),
+        };
+        if settled {
+            return Ok(true);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Ok(false);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,140 @@
+use artisan_middleware::aggregator::{AppStatus, Metrics};
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::commands::CustomCommand;
+use crate::network::status_value;
+
+use super::settings::{TelemetryLevel, TelemetrySettings};
+
+/// Buckets metrics are rounded to under `TelemetryLevel::Aggregates`
+const CPU_BUCKET_PERCENT: f64 = 5.0;
+const MEMORY_BUCKET_BYTES: f64 = 64.0 * 1024.0 * 1024.0;
+const TRANSFER_BUCKET_BYTES: u64 = 1024 * 1024;
+
+/// Sections of the status json that break usage down per process or port
+const DETAIL_SECTIONS: &[&str] = &["ports", "process"];
+
+/// Sections that are usage at all, withheld entirely under
+/// `TelemetryLevel::Nothing`
+const USAGE_SECTIONS: &[&str] = &["ports", "process", "disk", "oom_kills"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryReport {
+    pub default: TelemetryLevel,
+    /// Only the overrides, every other app gets `default`
+    pub tenants: BTreeMap<String, TelemetryLevel>,
+}
+
+fn bucket(value: f64, size: f64) -> f64 {
+    (value / size).round() * size
+}
+
+fn coarsen(mut metrics: Metrics) -> Metrics {
+    metrics.cpu_usage = bucket(metrics.cpu_usage as f64, CPU_BUCKET_PERCENT) as _;
+    metrics.memory_usage = bucket(metrics.memory_usage as f64, MEMORY_BUCKET_BYTES) as _;
+    if let Some(net) = metrics.other.as_mut() {
+        net.rx_bytes = net.rx_bytes / TRANSFER_BUCKET_BYTES * TRANSFER_BUCKET_BYTES;
+        net.tx_bytes = net.tx_bytes / TRANSFER_BUCKET_BYTES * TRANSFER_BUCKET_BYTES;
+    }
+    metrics
+}
+
+/// What may leave the node of the app's metrics
+pub fn outbound_metrics(level: TelemetryLevel, metrics: Option<Metrics>) -> Option<Metrics> {
+    match level {
+        TelemetryLevel::Full => metrics,
+        TelemetryLevel::Aggregates => metrics.map(coarsen),
+        TelemetryLevel::Nothing => None,
+    }
+}
+
+/// `status_json` for statuses sent off the node. The lifecycle state is
+/// always kept, the portal can't manage the app without it.
+pub async fn outbound_status_json(
+    settings: &TelemetrySettings,
+    name: &Stringy,
+    status: &AppStatus,
+) -> Option<String> {
+    outbound_status_value(settings, name, status)
+        .await
+        .map(|value| value.to_string())
+}
+
+/// `outbound_status_json` before it's written out
+pub async fn outbound_status_value(
+    settings: &TelemetrySettings,
+    name: &Stringy,
+    status: &AppStatus,
+) -> Option<Value> {
+    let level: TelemetryLevel = settings.level_for(name.as_str());
+    if level == TelemetryLevel::Full {
+        return status_value(name, status).await;
+    }
+
+    let mut status: AppStatus = status.clone();
+    status.metrics = outbound_metrics(level, status.metrics.take());
+
+    let mut value: Value = status_value(name, &status).await?;
+    if let Some(object) = value.as_object_mut() {
+        let withheld: &[&str] = match level {
+            TelemetryLevel::Nothing => USAGE_SECTIONS,
+            _ => DETAIL_SECTIONS,
+        };
+        for section in withheld {
+            object.remove(*section);
+        }
+    }
+    Some(value)
+}
+
+/// Why the command can't be answered over the command port, `None` when it
+/// can. Per sample history of a restricted app is never sent, and neither
+/// are node wide breakdowns while any app is restricted. All of them stay
+/// available on the admin socket.
+pub fn withheld_command(
+    settings: &TelemetrySettings,
+    app_id: &Stringy,
+    command: &CustomCommand,
+) -> Option<String> {
+    match command {
+        CustomCommand::UsageHistory(..)
+        | CustomCommand::LedgerSamples(..)
+        | CustomCommand::PrettyStatus(false)
+        | CustomCommand::Ports(false) => match settings.level_for(app_id.as_str()) {
+            TelemetryLevel::Full => None,
+            _ => Some(format!(
+                "The telemetry policy of {} keeps its samples on the node",
+                app_id
+            )),
+        },
+        CustomCommand::Ports(true)
+        | CustomCommand::TopTalkers(_)
+        | CustomCommand::PrettyStatus(true) => {
+            let restricted: bool = settings.default != TelemetryLevel::Full
+                || settings
+                    .tenants
+                    .values()
+                    .any(|level| *level != TelemetryLevel::Full);
+            restricted.then(|| {
+                String::from(
+                    "Node wide breakdowns stay on the node while any app's telemetry is restricted",
+                )
+            })
+        }
+        _ => None,
+    }
+}
+
+pub fn telemetry_report(settings: &TelemetrySettings) -> TelemetryReport {
+    TelemetryReport {
+        default: settings.default,
+        tenants: settings
+            .tenants
+            .iter()
+            .map(|(name, level)| (name.clone(), *level))
+            .collect(),
+    }
+}
@@ -0,0 +1,315 @@
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Mutex;
+
+use super::control::GLOBAL_STATE;
+use super::settings::TimezoneSettings;
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Zones already read from zoneinfo, by configured name. Names that failed
+/// to load are kept as UTC so the warning is only logged once.
+static ZONES: Lazy<Mutex<HashMap<String, Zone>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// UTC offsets of a zone over time, as read from its TZif file
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub name: String,
+    /// Start of each period and the index of its type, oldest first
+    transitions: Vec<(i64, usize)>,
+    /// Offset in seconds and abbreviation. The first one holds before the
+    /// first transition.
+    types: Vec<(i32, String)>,
+}
+
+impl Zone {
+    fn utc() -> Self {
+        Self::fixed("UTC", 0)
+    }
+
+    fn fixed(name: &str, offset: i32) -> Self {
+        Self {
+            name: name.to_owned(),
+            transitions: Vec::new(),
+            types: vec![(offset, name.to_owned())],
+        }
+    }
+
+    /// Offset in seconds and abbreviation in effect at `timestamp`. Past the
+    /// last transition the last offset holds.
+    pub fn offset_at(&self, timestamp: u64) -> (i32, &str) {
+        let timestamp: i64 = timestamp as i64;
+        let index: usize = match self
+            .transitions
+            .partition_point(|(start, _)| *start <= timestamp)
+        {
+            0 => 0,
+            period => self.transitions[period - 1].1,
+        };
+
+        match self.types.get(index) {
+            Some((offset, abbreviation)) => (*offset, abbreviation),
+            None => (0, "UTC"),
+        }
+    }
+
+    /// Days since the epoch on the local calendar, what daily rollovers
+    /// count in
+    pub fn local_day(&self, timestamp: u64) -> i64 {
+        (timestamp as i64 + self.offset_at(timestamp).0 as i64).div_euclid(DAY)
+    }
+
+    /// When the local day starts, as a unix timestamp. The offset is taken
+    /// a day's start away from UTC midnight, close enough around a change.
+    pub fn day_start(&self, day: i64) -> u64 {
+        let utc_midnight: i64 = day * DAY;
+        let offset: i32 = self
+            .offset_at(
+                (utc_midnight - self.offset_at(utc_midnight.max(0) as u64).0 as i64).max(0) as u64,
+            )
+            .0;
+        (utc_midnight - offset as i64).max(0) as u64
+    }
+}
+
+/// `+05:30` or `-08:00`
+fn parse_fixed_offset(name: &str) -> Option<i32> {
+    let (sign, rest) = match name.as_bytes().first()? {
+        b'+' => (1, &name[1..]),
+        b'-' => (-1, &name[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i32 = hours.parse().ok().filter(|hours| *hours <= 14)?;
+    let minutes: i32 = minutes.parse().ok().filter(|minutes| *minutes < 60)?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+fn split(data: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    match data.len() >= len {
+        true => Some(data.split_at(len)),
+        false => None,
+    }
+}
+
+/// isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt
+fn tzif_counts(data: &[u8]) -> Option<[usize; 6]> {
+    if data.get(..4)? != b"TZif" {
+        return None;
+    }
+
+    let mut counts: [usize; 6] = [0; 6];
+    for (index, count) in counts.iter_mut().enumerate() {
+        let start: usize = 20 + index * 4;
+        *count = u32::from_be_bytes(data.get(start..start + 4)?.try_into().ok()?) as usize;
+    }
+    Some(counts)
+}
+
+/// Reads the transitions of a TZif file, the 64 bit block when there is one.
+/// The POSIX rule in the footer isn't evaluated, zoneinfo carries
+/// transitions far enough ahead for what we format.
+fn parse_tzif(name: &str, data: &[u8]) -> Option<Zone> {
+    let mut counts: [usize; 6] = tzif_counts(data)?;
+    let mut body: &[u8] = data.get(44..)?;
+    let mut time_size: usize = 4;
+
+    if *data.get(4)? >= b'2' {
+        let [isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt] = counts;
+        let v1_len: usize = timecnt * 5 + typecnt * 6 + charcnt + leapcnt * 8 + isstdcnt + isutcnt;
+        body = body.get(v1_len..)?;
+        counts = tzif_counts(body)?;
+        body = body.get(44..)?;
+        time_size = 8;
+    }
+
+    let [_, _, _, timecnt, typecnt, charcnt] = counts;
+    let (times, body) = split(body, timecnt * time_size)?;
+    let (indices, body) = split(body, timecnt)?;
+    let (infos, body) = split(body, typecnt * 6)?;
+    let (chars, _) = split(body, charcnt)?;
+
+    let mut types: Vec<(i32, String)> = Vec::with_capacity(typecnt);
+    for info in infos.chunks_exact(6) {
+        let offset: i32 = i32::from_be_bytes(info[..4].try_into().ok()?);
+        let abbreviation: String = chars
+            .get(info[5] as usize..)?
+            .split(|byte| *byte == 0)
+            .next()
+            .map(|abbreviation| String::from_utf8_lossy(abbreviation).into_owned())?;
+        types.push((offset, abbreviation));
+    }
+
+    let mut transitions: Vec<(i64, usize)> = Vec::with_capacity(timecnt);
+    for (time, index) in times.chunks_exact(time_size).zip(indices) {
+        let start: i64 = match time_size {
+            4 => i32::from_be_bytes(time.try_into().ok()?) as i64,
+            _ => i64::from_be_bytes(time.try_into().ok()?),
+        };
+        if *index as usize >= types.len() {
+            return None;
+        }
+        transitions.push((start, *index as usize));
+    }
+
+    if types.is_empty() {
+        return None;
+    }
+
+    Some(Zone {
+        name: name.to_owned(),
+        transitions,
+        types,
+    })
+}
+
+/// `UTC`, a fixed offset like `+02:00`, `localtime` for the node's
+/// /etc/localtime or an IANA name like `Europe/Berlin`
+fn load_zone(name: &str) -> Option<Zone> {
+    match name {
+        "" | "UTC" | "utc" => return Some(Zone::utc()),
+        "localtime" => {
+            return parse_tzif(name, &fs::read("/etc/localtime").ok()?);
+        }
+        _ => (),
+    }
+
+    if let Some(offset) = parse_fixed_offset(name) {
+        return Some(Zone::fixed(name, offset));
+    }
+
+    // Names come from the settings file, but they still don't get to walk
+    // out of zoneinfo
+    if name.starts_with('/') || name.split('/').any(|part| part == "..") {
+        return None;
+    }
+
+    parse_tzif(name, &fs::read(format!("{}/{}", ZONEINFO_DIR, name)).ok()?)
+}
+
+fn zone_named(name: &str) -> Zone {
+    let mut zones = match ZONES.lock() {
+        Ok(zones) => zones,
+        Err(_) => return load_zone(name).unwrap_or_else(Zone::utc),
+    };
+
+    zones
+        .entry(name.to_owned())
+        .or_insert_with(|| match load_zone(name) {
+            Some(zone) => zone,
+            None => {
+                log!(
+                    LogLevel::Warn,
+                    "Unknown timezone {}, falling back to UTC",
+                    name
+                );
+                Zone::utc()
+            }
+        })
+        .clone()
+}
+
+/// Formatting runs before the global state exists too, early output falls
+/// back to UTC
+fn timezone_settings() -> TimezoneSettings {
+    GLOBAL_STATE
+        .get()
+        .map(|gs| gs.settings.timezone.clone())
+        .unwrap_or_default()
+}
+
+/// The tenant's own zone when it has one, the node's otherwise
+pub fn zone_for(tenant: Option<&str>) -> Zone {
+    let settings: TimezoneSettings = timezone_settings();
+
+    match tenant.and_then(|tenant| settings.tenants.get(tenant)) {
+        Some(name) => zone_named(name),
+        None => zone_named(&settings.node),
+    }
+}
+
+/// Day count since the epoch of a proleptic Gregorian date
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year: i64 = if month <= 2 { year - 1 } else { year };
+    let era: i64 = year.div_euclid(400);
+    let year_of_era: i64 = year.rem_euclid(400);
+    let month_index: i64 = (month as i64 + 9) % 12;
+    let day_of_year: i64 = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era: i64 = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date of a day count since the epoch
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z: i64 = days + 719_468;
+    let era: i64 = z.div_euclid(146_097);
+    let day_of_era: i64 = z.rem_euclid(146_097);
+    let year_of_era: i64 =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year: i64 = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index: i64 = (5 * day_of_year + 2) / 153;
+
+    let day: u32 = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month: u32 = match month_index < 10 {
+        true => month_index + 3,
+        false => month_index - 9,
+    } as u32;
+    let year: i64 = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// `2026-10-16 14:03 CEST` in the zone of the tenant, or of the node
+pub fn format_timestamp(timestamp: u64, tenant: Option<&str>) -> String {
+    let zone: Zone = zone_for(tenant);
+    let (offset, abbreviation) = zone.offset_at(timestamp);
+
+    let local: i64 = timestamp as i64 + offset as i64;
+    let (year, month, day) = civil_from_days(local.div_euclid(DAY));
+    let seconds: i64 = local.rem_euclid(DAY);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} {}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        abbreviation
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimezoneReport {
+    pub node: String,
+    /// Offset from UTC right now, in seconds
+    pub node_offset: i32,
+    pub node_time: String,
+    /// Tenants with a zone of their own
+    pub tenants: BTreeMap<String, String>,
+}
+
+pub fn timezone_report() -> TimezoneReport {
+    let now: u64 = current_timestamp();
+    let settings: TimezoneSettings = timezone_settings();
+    let zone: Zone = zone_for(None);
+
+    TimezoneReport {
+        node: zone.name.clone(),
+        node_offset: zone.offset_at(now).0,
+        node_time: format_timestamp(now, None),
+        tenants: settings
+            .tenants
+            .iter()
+            .map(|(tenant, name)| (tenant.clone(), zone_named(name).name))
+            .collect(),
+    }
+}
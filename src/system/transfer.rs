@@ -0,0 +1,358 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::core::types::rwarc::LockWithTimeout;
+use artisan_middleware::dusa_collection_utils::core::types::stringy::Stringy;
+use artisan_middleware::dusa_collection_utils::log;
+use artisan_middleware::dusa_collection_utils::platform::functions::{create_hash, truncate};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const TRANSFER_DIR: &str = "/opt/artisan/transfers";
+
+/// Bytes carried by a single chunk
+pub const TRANSFER_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Largest file we'll move in either direction
+pub const TRANSFER_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Transfers that haven't been touched for this long are dropped, in seconds
+const TRANSFER_TIMEOUT: u64 = 60 * 60;
+
+static TRANSFER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub static TRANSFERS: Lazy<LockWithTimeout<HashMap<Stringy, Transfer>>> =
+    Lazy::new(|| LockWithTimeout::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    /// The app that opened the transfer, no other app can touch it
+    pub owner: Stringy,
+    pub direction: TransferDirection,
+    pub path: PathBuf,
+    pub size: u64,
+    pub checksum: String,
+    pub received: BTreeSet<u64>,
+    pub last_active: u64,
+}
+
+impl Transfer {
+    pub fn chunk_count(&self) -> u64 {
+        (self.size + TRANSFER_CHUNK_SIZE - 1) / TRANSFER_CHUNK_SIZE
+    }
+
+    pub fn manifest(&self, transfer_id: &Stringy) -> TransferManifest {
+        TransferManifest {
+            transfer_id: transfer_id.clone(),
+            direction: self.direction,
+            size: self.size,
+            chunk_size: TRANSFER_CHUNK_SIZE,
+            chunk_count: self.chunk_count(),
+            checksum: self.checksum.clone(),
+            received: self.received.iter().copied().collect(),
+        }
+    }
+}
+
+/// Everything the other side needs to drive or resume a transfer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferManifest {
+    pub transfer_id: Stringy,
+    pub direction: TransferDirection,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub checksum: String,
+    pub received: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferChunk {
+    pub index: u64,
+    pub checksum: String,
+    pub data: String,
+}
+
+pub fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub fn file_checksum(path: &Path) -> Result<String, ErrorArrayItem> {
+    let mut file: File = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer: Vec<u8> = vec![0; TRANSFER_CHUNK_SIZE as usize];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn new_transfer_id(seed: &str) -> Stringy {
+    let count: u64 = TRANSFER_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let hash = create_hash(format!("{}-{}-{}", seed, current_timestamp(), count));
+    truncate(&*hash, 20).to_owned()
+}
+
+fn transfer_error(message: String) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::GeneralError, message)
+}
+
+fn not_found(transfer_id: &Stringy, kind: &str) -> ErrorArrayItem {
+    ErrorArrayItem::new(
+        Errors::NotFound,
+        format!("No {} with id {}", kind, transfer_id),
+    )
+}
+
+async fn prune_stale_transfers() -> Result<(), ErrorArrayItem> {
+    let mut transfers = TRANSFERS.try_write().await?;
+
+    transfers.retain(|id, transfer| {
+        let alive = transfer.last_active + TRANSFER_TIMEOUT > current_timestamp();
+        if !alive {
+            log!(LogLevel::Info, "Dropping stale transfer {}", id);
+            if transfer.direction == TransferDirection::Upload {
+                let _ = fs::remove_file(&transfer.path);
+            }
+        }
+        alive
+    });
+
+    Ok(())
+}
+
+/// Starts sending a file on this node to the caller
+pub async fn open_download(
+    owner: &Stringy,
+    path: PathBuf,
+) -> Result<TransferManifest, ErrorArrayItem> {
+    prune_stale_transfers().await?;
+
+    let size: u64 = fs::metadata(&path)?.len();
+    if size > TRANSFER_MAX_SIZE {
+        return Err(transfer_error(format!(
+            "{} is larger than the {} byte transfer cap",
+            path.display(),
+            TRANSFER_MAX_SIZE
+        )));
+    }
+
+    let transfer: Transfer = Transfer {
+        owner: owner.clone(),
+        direction: TransferDirection::Download,
+        checksum: file_checksum(&path)?,
+        path: path.clone(),
+        size,
+        received: BTreeSet::new(),
+        last_active: current_timestamp(),
+    };
+
+    let transfer_id: Stringy = new_transfer_id(&path.to_string_lossy());
+    let manifest: TransferManifest = transfer.manifest(&transfer_id);
+    TRANSFERS.try_write().await?.insert(transfer_id, transfer);
+
+    Ok(manifest)
+}
+
+pub async fn read_chunk(
+    owner: &Stringy,
+    transfer_id: &Stringy,
+    index: u64,
+) -> Result<TransferChunk, ErrorArrayItem> {
+    let mut transfers = TRANSFERS.try_write().await?;
+    let transfer: &mut Transfer = transfers
+        .get_mut(transfer_id)
+        .filter(|transfer| transfer.owner == *owner)
+        .filter(|transfer| transfer.direction == TransferDirection::Download)
+        .ok_or_else(|| not_found(transfer_id, "download"))?;
+
+    if index >= transfer.chunk_count() {
+        return Err(transfer_error(format!("Chunk {} is out of range", index)));
+    }
+
+    let mut file: File = File::open(&transfer.path)?;
+    file.seek(SeekFrom::Start(index * TRANSFER_CHUNK_SIZE))?;
+
+    let mut data: Vec<u8> = Vec::with_capacity(TRANSFER_CHUNK_SIZE as usize);
+    file.take(TRANSFER_CHUNK_SIZE).read_to_end(&mut data)?;
+
+    transfer.received.insert(index);
+    transfer.last_active = current_timestamp();
+
+    Ok(TransferChunk {
+        index,
+        checksum: checksum(&data),
+        data: hex::encode(data),
+    })
+}
+
+/// Starts receiving a file from the caller into the staging directory
+pub async fn begin_upload(
+    owner: &Stringy,
+    name: &str,
+    size: u64,
+    expected_checksum: &str,
+) -> Result<TransferManifest, ErrorArrayItem> {
+    prune_stale_transfers().await?;
+
+    if size > TRANSFER_MAX_SIZE {
+        return Err(transfer_error(format!(
+            "{} bytes is larger than the {} byte transfer cap",
+            size, TRANSFER_MAX_SIZE
+        )));
+    }
+
+    fs::create_dir_all(TRANSFER_DIR)?;
+
+    let transfer_id: Stringy = new_transfer_id(name);
+    let path: PathBuf = Path::new(TRANSFER_DIR).join(format!("{}.part", transfer_id));
+    File::create(&path)?.set_len(size)?;
+
+    let transfer: Transfer = Transfer {
+        owner: owner.clone(),
+        direction: TransferDirection::Upload,
+        path,
+        size,
+        checksum: expected_checksum.to_lowercase(),
+        received: BTreeSet::new(),
+        last_active: current_timestamp(),
+    };
+
+    let manifest: TransferManifest = transfer.manifest(&transfer_id);
+    TRANSFERS.try_write().await?.insert(transfer_id, transfer);
+
+    Ok(manifest)
+}
+
+pub async fn write_chunk(
+    owner: &Stringy,
+    transfer_id: &Stringy,
+    index: u64,
+    chunk_checksum: &str,
+    data: &str,
+) -> Result<TransferManifest, ErrorArrayItem> {
+    let mut transfers = TRANSFERS.try_write().await?;
+    let transfer: &mut Transfer = transfers
+        .get_mut(transfer_id)
+        .filter(|transfer| transfer.owner == *owner)
+        .filter(|transfer| transfer.direction == TransferDirection::Upload)
+        .ok_or_else(|| not_found(transfer_id, "upload"))?;
+
+    if index >= transfer.chunk_count() {
+        return Err(transfer_error(format!("Chunk {} is out of range", index)));
+    }
+
+    let bytes: Vec<u8> = hex::decode(data)
+        .map_err(|err| transfer_error(format!("Chunk {} isn't valid hex: {}", index, err)))?;
+
+    let offset: u64 = index * TRANSFER_CHUNK_SIZE;
+    let expected_len: u64 = TRANSFER_CHUNK_SIZE.min(transfer.size - offset);
+    if bytes.len() as u64 != expected_len {
+        return Err(transfer_error(format!(
+            "Chunk {} should be {} bytes, got {}",
+            index,
+            expected_len,
+            bytes.len()
+        )));
+    }
+
+    if checksum(&bytes) != chunk_checksum.to_lowercase() {
+        return Err(transfer_error(format!(
+            "Chunk {} failed its checksum",
+            index
+        )));
+    }
+
+    let mut file: File = OpenOptions::new().write(true).open(&transfer.path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&bytes)?;
+
+    transfer.received.insert(index);
+    transfer.last_active = current_timestamp();
+
+    Ok(transfer.manifest(transfer_id))
+}
+
+pub async fn transfer_status(
+    owner: &Stringy,
+    transfer_id: &Stringy,
+) -> Result<TransferManifest, ErrorArrayItem> {
+    match TRANSFERS.try_read().await?.get(transfer_id) {
+        Some(transfer) if transfer.owner == *owner => Ok(transfer.manifest(transfer_id)),
+        _ => Err(not_found(transfer_id, "transfer")),
+    }
+}
+
+/// Closes out a transfer. Uploads are verified against the checksum given
+/// when they began and the path of the completed file is returned. An
+/// upload that's still missing chunks stays open so they can be resent.
+pub async fn finish_transfer(
+    owner: &Stringy,
+    transfer_id: &Stringy,
+) -> Result<PathBuf, ErrorArrayItem> {
+    let transfer: Transfer = {
+        let mut transfers = TRANSFERS.try_write().await?;
+        let transfer: &Transfer = transfers
+            .get(transfer_id)
+            .filter(|transfer| transfer.owner == *owner)
+            .ok_or_else(|| not_found(transfer_id, "transfer"))?;
+
+        let missing: Vec<String> = (0..transfer.chunk_count())
+            .filter(|index| !transfer.received.contains(index))
+            .map(|index| index.to_string())
+            .collect();
+        if transfer.direction == TransferDirection::Upload && !missing.is_empty() {
+            return Err(transfer_error(format!(
+                "Upload {} is missing chunks {}",
+                transfer_id,
+                missing.join(", ")
+            )));
+        }
+
+        match transfers.remove(transfer_id) {
+            Some(transfer) => transfer,
+            None => return Err(not_found(transfer_id, "transfer")),
+        }
+    };
+
+    if transfer.direction == TransferDirection::Download {
+        return Ok(transfer.path);
+    }
+
+    if file_checksum(&transfer.path)? != transfer.checksum {
+        let _ = fs::remove_file(&transfer.path);
+        return Err(transfer_error(format!(
+            "Upload {} failed its checksum",
+            transfer_id
+        )));
+    }
+
+    let completed: PathBuf = transfer.path.with_extension("complete");
+    fs::rename(&transfer.path, &completed)?;
+    log!(
+        LogLevel::Info,
+        "Received upload {} ({} bytes)",
+        transfer_id,
+        transfer.size
+    );
+
+    Ok(completed)
+}
@@ -0,0 +1,48 @@
+use super::control::GLOBAL_STATE;
+use super::settings::{UnitSettings, UnitSystem};
+
+const IEC: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+const SI: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+/// Formatting runs before the global state exists too, early logs fall back
+/// to the defaults
+fn unit_settings() -> UnitSettings {
+    GLOBAL_STATE
+        .get()
+        .map(|gs| gs.settings.units.clone())
+        .unwrap_or_default()
+}
+
+fn with_precision(value: f64, settings: &UnitSettings) -> String {
+    let formatted: String = format!("{:.*}", settings.precision, value);
+    match settings.decimal_separator {
+        '.' => formatted,
+        separator => formatted.replace('.', &separator.to_string()),
+    }
+}
+
+/// Byte counts in the configured unit system, `1.50 MiB` or `1.57 MB`
+pub fn format_bytes(bytes: u64) -> String {
+    let settings: UnitSettings = unit_settings();
+    let (base, units): (f64, &[&str; 5]) = match settings.system {
+        UnitSystem::Iec => (1024.0, &IEC),
+        UnitSystem::Si => (1000.0, &SI),
+    };
+
+    let mut value: f64 = bytes as f64;
+    let mut unit: usize = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{} {}", with_precision(value, &settings), units[unit]),
+    }
+}
+
+/// CPU shares and other percentages
+pub fn format_percent(percent: f64) -> String {
+    format!("{}%", with_precision(percent, &unit_settings()))
+}
@@ -0,0 +1,137 @@
+use artisan_middleware::dusa_collection_utils::core::errors::{ErrorArrayItem, Errors};
+use artisan_middleware::dusa_collection_utils::core::functions::current_timestamp;
+use artisan_middleware::dusa_collection_utils::core::logger::LogLevel;
+use artisan_middleware::dusa_collection_utils::log;
+use serde::Serialize;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::control::GlobalState;
+use super::decisions::Decision;
+use super::settings::{MemoryWatchdogSettings, WatchdogAction};
+use super::units::format_bytes;
+
+static RSS_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_RSS_BYTES: AtomicU64 = AtomicU64::new(0);
+static SAMPLED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the ceiling action fired, cleared when we drop back under it so a
+/// reload that doesn't free anything isn't repeated every minute
+static CEILING_HANDLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManagerMemory {
+    pub rss_bytes: u64,
+    pub peak_rss_bytes: u64,
+    pub warn_bytes: u64,
+    pub ceiling_bytes: u64,
+    pub sampled_at: u64,
+}
+
+/// Resident set size of the manager from /proc/self/statm
+fn own_rss_bytes() -> Result<u64, ErrorArrayItem> {
+    let statm: String = fs::read_to_string("/proc/self/statm")?;
+
+    // size resident shared text lib data dt, all in pages
+    let resident_pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| ErrorArrayItem::new(Errors::GeneralError, "Malformed /proc/self/statm"))?;
+
+    let page_size = procfs::page_size()
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?;
+
+    Ok(resident_pages * page_size as u64)
+}
+
+fn act_on_ceiling(gs: &Arc<GlobalState>, settings: &MemoryWatchdogSettings, rss: u64) {
+    log!(
+        LogLevel::Error,
+        "Manager RSS of {} crossed the {} ceiling",
+        format_bytes(rss),
+        format_bytes(settings.ceiling_bytes)
+    );
+
+    let decision = |action: &str| {
+        Decision::new(
+            "watchdog",
+            None,
+            action,
+            String::from("the manager's memory crossed its ceiling"),
+        )
+        .input("rss_bytes", rss)
+        .input("ceiling_bytes", settings.ceiling_bytes)
+        .record()
+    };
+
+    match settings.action {
+        WatchdogAction::None => (),
+        WatchdogAction::Reload => {
+            decision("reload");
+            log!(LogLevel::Warn, "Reloading to release application handles");
+            gs.signals.signal_reload();
+        }
+        // Shuts down cleanly and relies on the unit's Restart= to bring us back
+        WatchdogAction::Restart => {
+            decision("restart");
+            log!(
+                LogLevel::Warn,
+                "Shutting down so systemd restarts the manager"
+            );
+            gs.signals.signal_shutdown();
+        }
+    }
+}
+
+/// Samples the manager's own memory use and reacts to the configured limits
+pub async fn watch_manager_memory(gs: &Arc<GlobalState>) -> Result<(), ErrorArrayItem> {
+    let settings: &MemoryWatchdogSettings = &gs.settings.memory_watchdog;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let rss: u64 = own_rss_bytes()?;
+    RSS_BYTES.store(rss, Ordering::Relaxed);
+    PEAK_RSS_BYTES.fetch_max(rss, Ordering::Relaxed);
+    SAMPLED_AT.store(current_timestamp(), Ordering::Relaxed);
+
+    if settings.ceiling_bytes > 0 && rss >= settings.ceiling_bytes {
+        if !CEILING_HANDLED.swap(true, Ordering::Relaxed) {
+            act_on_ceiling(gs, settings, rss);
+        }
+        return Ok(());
+    }
+
+    CEILING_HANDLED.store(false, Ordering::Relaxed);
+
+    if settings.warn_bytes > 0 && rss >= settings.warn_bytes {
+        log!(
+            LogLevel::Warn,
+            "Manager RSS is {}, above the {} warning threshold",
+            format_bytes(rss),
+            format_bytes(settings.warn_bytes)
+        );
+    }
+
+    Ok(())
+}
+
+pub fn manager_memory(settings: &MemoryWatchdogSettings) -> ManagerMemory {
+    ManagerMemory {
+        rss_bytes: RSS_BYTES.load(Ordering::Relaxed),
+        peak_rss_bytes: PEAK_RSS_BYTES.load(Ordering::Relaxed),
+        warn_bytes: settings.warn_bytes,
+        ceiling_bytes: settings.ceiling_bytes,
+        sampled_at: SAMPLED_AT.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether the last sample was over the warning threshold, counted as a
+/// warning in Info
+pub fn memory_warning(settings: &MemoryWatchdogSettings) -> bool {
+    settings.enabled
+        && settings.warn_bytes > 0
+        && RSS_BYTES.load(Ordering::Relaxed) >= settings.warn_bytes
+}